@@ -229,6 +229,12 @@
 //!                             | [`Value`]::Continuation(Handle)       |                             |
 //!                             |                             |
 //!                             |                             |
+//!                             | [`Value`]::Coroutine(Handle)       |                             |
+//!                             |                             |
+//!                             |                             |
+//!                             | [`Value`]::Iter(Handle)       |                             |
+//!                             |                             |
+//!                             |                             |
 //!                             | [`Value`]::CallFrame(Handle)       |                             |
 //!                             |                             |
 //!                             |                             |
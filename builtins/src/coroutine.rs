@@ -0,0 +1,162 @@
+use bridge_adapters::add_builtin;
+use compile_state::state::SloshVm;
+use slvm::{CoroutineStatus, VMError, VMResult, Value};
+
+fn make_coroutine(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [lambda] = registers {
+        vm.make_coroutine(*lambda, false)
+    } else {
+        Err(VMError::new_vm(
+            "make-coroutine: takes one argument (a lambda)",
+        ))
+    }
+}
+
+fn make_generator(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [lambda] = registers {
+        let generator = vm.make_coroutine(*lambda, true)?;
+        // A generator is a zero argument callable returning :*iter-empty* when done, so it is an
+        // iterator.
+        let key = vm.intern("is-iter");
+        vm.set_heap_property_interned(generator, key, Value::True);
+        Ok(generator)
+    } else {
+        Err(VMError::new_vm(
+            "make-generator: takes one argument (a lambda)",
+        ))
+    }
+}
+
+fn resume(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    match registers {
+        [Value::Coroutine(h)] => vm.resume(*h, None),
+        [Value::Coroutine(h), arg] => vm.resume(*h, Some(*arg)),
+        _ => Err(VMError::new_vm(
+            "resume: takes a coroutine and an optional value",
+        )),
+    }
+}
+
+fn coroutine_status(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [Value::Coroutine(h)] = registers {
+        let status = match vm.get_coroutine(*h).status {
+            CoroutineStatus::New => "new",
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Dead => "dead",
+        };
+        Ok(Value::Keyword(vm.intern_static(status)))
+    } else {
+        Err(VMError::new_vm("coroutine-status: takes one coroutine"))
+    }
+}
+
+fn is_coroutine(_vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    match registers {
+        [Value::Coroutine(_)] => Ok(Value::True),
+        [_] => Ok(Value::False),
+        _ => Err(VMError::new_vm("coroutine?: takes one argument")),
+    }
+}
+
+pub fn add_coroutine_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "make-coroutine",
+        make_coroutine,
+        r#"Usage: (make-coroutine lambda) -> coroutine
+
+Create a coroutine that runs lambda.  Resume it (with resume or by calling it) to run it until it
+yields or returns, the first resume may pass a value as lambda's argument and later resumes pass
+the value the pending yield returns.  Once lambda returns the coroutine is :dead and resuming it
+is an error.
+
+Section: core
+
+Example:
+(def co (make-coroutine (fn () (let (total 0) (while #t (set! total (+ total (yield total))))))))
+(test::assert-equal 0 (resume co))
+(test::assert-equal 5 (resume co 5))
+(test::assert-equal 5 (co 0))
+(test::assert-equal 8 (co 3))
+(test::assert-true (coroutine? co))
+"#,
+    );
+    add_builtin(
+        env,
+        "make-generator",
+        make_generator,
+        r#"Usage: (make-generator lambda) -> generator
+
+Create a generator, a coroutine that runs the zero argument lambda and is an iterator of the
+values it yields.  Once lambda returns the generator produces :*iter-empty* (see iter).  Usually
+created with the generator or defgen macros.
+
+Section: iterator
+
+Example:
+(def g (make-generator (fn () (yield 1) (yield 2))))
+(test::assert-true (iter::iter? g))
+(test::assert-equal 1 (g))
+(test::assert-equal 2 (g))
+(test::assert-equal :*iter-empty* (g))
+(test::assert-equal :*iter-empty* (g))
+"#,
+    );
+    add_builtin(
+        env,
+        "resume",
+        resume,
+        r#"Usage: (resume coroutine value?) -> value
+
+Run coroutine until it yields or returns and produce the yielded or returned value.  The first
+resume passes value (if provided) to the coroutine's lambda, later resumes make the pending yield
+return value (nil if not provided).  Calling a coroutine is the same as resuming it.
+
+Section: core
+
+Example:
+(def co (make-coroutine (fn (x) (yield (* x 2)) (+ x 1))))
+(test::assert-equal :new (coroutine-status co))
+(test::assert-equal 4 (resume co 2))
+(test::assert-equal :suspended (coroutine-status co))
+(test::assert-equal 3 (resume co))
+(test::assert-equal :dead (coroutine-status co))
+"#,
+    );
+    add_builtin(
+        env,
+        "coroutine-status",
+        coroutine_status,
+        r#"Usage: (coroutine-status coroutine) -> :new | :suspended | :running | :dead
+
+Return the state of a coroutine.  It is :new until first resumed, :suspended at a yield, :running
+while resumed and :dead once its lambda returns (or raises an error).
+
+Section: core
+
+Example:
+(def co (make-coroutine (fn (self) (yield (coroutine-status self)))))
+(test::assert-equal :new (coroutine-status co))
+(test::assert-equal :running (resume co co))
+(resume co)
+(test::assert-equal :dead (coroutine-status co))
+"#,
+    );
+    add_builtin(
+        env,
+        "coroutine?",
+        is_coroutine,
+        r#"Usage: (coroutine? expression) -> t/nil
+
+True if expression is a coroutine (or generator).
+
+Section: type
+
+Example:
+(test::assert-true (coroutine? (make-coroutine (fn () 1))))
+(test::assert-true (coroutine? (make-generator (fn () 1))))
+(test::assert-false (coroutine? (fn () 1)))
+"#,
+    );
+}
@@ -0,0 +1,161 @@
+use bridge_adapters::add_builtin;
+use compile_state::state::SloshVm;
+use slvm::{Coverage, LineCoverage, VMError, VMResult, Value};
+use std::fs;
+use std::io::ErrorKind;
+
+/// Write coverage to the lcov file lcov_file, replacing it unless merge is true.
+///
+/// Merging into an existing file (it is created if needed) lets several runs (for instance each
+/// script in a test suite) build up one report.
+pub fn write_coverage(coverage: &LineCoverage, lcov_file: &str, merge: bool) -> VMResult<()> {
+    if !merge {
+        return fs::write(lcov_file, coverage.to_lcov()).map_err(|e| {
+            VMError::new("io", format!("coverage: unable to write {lcov_file}: {e}"))
+        });
+    }
+    let mut merged = match fs::read_to_string(lcov_file) {
+        Ok(text) => LineCoverage::from_lcov(&text).map_err(|e| {
+            VMError::new("io", format!("coverage: unable to merge {lcov_file}: {e}"))
+        })?,
+        Err(e) if e.kind() == ErrorKind::NotFound => LineCoverage::new(),
+        Err(e) => {
+            return Err(VMError::new(
+                "io",
+                format!("coverage: unable to read {lcov_file}: {e}"),
+            ))
+        }
+    };
+    merged.merge(coverage);
+    fs::write(lcov_file, merged.to_lcov())
+        .map_err(|e| VMError::new("io", format!("coverage: unable to write {lcov_file}: {e}")))
+}
+
+fn coverage_start(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if !registers.is_empty() {
+        return Err(VMError::new_vm("coverage-start: takes no arguments"));
+    }
+    if vm.coverage().is_none() {
+        vm.start_coverage(Coverage::new());
+        Ok(Value::True)
+    } else {
+        Ok(Value::False)
+    }
+}
+
+fn coverage_stop(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if !registers.is_empty() {
+        return Err(VMError::new_vm("coverage-stop: takes no arguments"));
+    }
+    if vm.stop_coverage().is_some() {
+        Ok(Value::True)
+    } else {
+        Ok(Value::False)
+    }
+}
+
+fn coverage_write(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (file, merge) = match registers {
+        [file] => (file, false),
+        [file, Value::Keyword(i)] if vm.get_interned(*i) == "merge" => (file, true),
+        _ => {
+            return Err(VMError::new_vm(
+                "coverage-write: takes a file name and optional :merge",
+            ))
+        }
+    };
+    let file = file.get_string(vm)?.to_string();
+    if let Some(coverage) = vm.coverage() {
+        write_coverage(&coverage.line_coverage(), &file, merge)?;
+        Ok(Value::True)
+    } else {
+        Ok(Value::False)
+    }
+}
+
+pub fn add_coverage_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "coverage-start",
+        coverage_start,
+        r#"Usage: (coverage-start) -> boolean
+
+Start recording which source lines are executed, returns false if already recording
+(for instance when started with --coverage).  Use coverage-write to save the results as
+an lcov report.
+
+Section: core
+
+Example:
+(let (started (coverage-start))
+    (test::assert-false (coverage-start))
+    (when started (test::assert-true (coverage-stop))))
+"#,
+    );
+    add_builtin(
+        env,
+        "coverage-stop",
+        coverage_stop,
+        r#"Usage: (coverage-stop) -> boolean
+
+Stop recording coverage and discard the results, returns true if coverage was being recorded.
+
+Section: core
+
+Example:
+(let (started (coverage-start))
+    (when started (do
+        (test::assert-true (coverage-stop))
+        (test::assert-false (coverage-stop)))))
+"#,
+    );
+    add_builtin(
+        env,
+        "coverage-write",
+        coverage_write,
+        r#"Usage: (coverage-write file [:merge]) -> boolean
+
+Write the coverage recorded so far to the lcov file, replacing it.  With :merge the
+coverage is merged into the file instead (created if it does not exist), this allows the
+results from several runs to be collected into one report.  Returns false if coverage is
+not being recorded.
+
+Section: core
+
+Example:
+(with-temp (fn (tmp)
+    (let (lcov (str tmp "/test.lcov")
+          started (coverage-start))
+        (test::assert-true (coverage-write lcov))
+        (test::assert-true (fs-exists? lcov))
+        (test::assert-true (coverage-write lcov :merge))
+        (test::assert-error (coverage-write lcov :append))
+        (when started (coverage-stop)))))
+"#,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_coverage() {
+        let file = std::env::temp_dir().join(format!("slosh-coverage-{}.lcov", std::process::id()));
+        let file = file.to_str().expect("temp dir is not utf8").to_string();
+        let mut coverage = LineCoverage::new();
+        coverage.add("test.slosh", 1, 2);
+
+        // Each write replaces the report.
+        write_coverage(&coverage, &file, false).unwrap();
+        write_coverage(&coverage, &file, false).unwrap();
+        let written = LineCoverage::from_lcov(&fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(written.files()["test.slosh"][&1], 2);
+
+        // Unless merging.
+        write_coverage(&coverage, &file, true).unwrap();
+        let written = LineCoverage::from_lcov(&fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(written.files()["test.slosh"][&1], 4);
+        let _ = fs::remove_file(&file);
+    }
+}
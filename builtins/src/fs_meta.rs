@@ -2,10 +2,11 @@ use bridge_macros::sl_sh_fn;
 use bridge_types::VarArgs;
 use compile_state::state::SloshVm;
 use shell::builtins::expand_tilde;
+use shell::dirs;
 use sl_compiler::load_eval::apply_callable;
 use slvm::{from_i56, VMError, VMResult, Value};
 use std::path::{Path, PathBuf};
-use std::{env, fs, io, time};
+use std::{fs, io, time};
 
 use glob::glob;
 
@@ -17,30 +18,6 @@ use std::thread;
 use std::time::SystemTime;
 use walkdir::{DirEntry, WalkDir};
 
-fn cd_expand_all_dots(cd: String) -> String {
-    let mut all_dots = false;
-    if cd.len() > 2 {
-        all_dots = true;
-        for ch in cd.chars() {
-            if ch != '.' {
-                all_dots = false;
-                break;
-            }
-        }
-    }
-    if all_dots {
-        let mut new_cd = String::new();
-        let paths_up = cd.len() - 2;
-        new_cd.push_str("../");
-        for _i in 0..paths_up {
-            new_cd.push_str("../");
-        }
-        new_cd
-    } else {
-        cd
-    }
-}
-
 /// Usage: (sleep milliseconds) -> nil
 ///
 /// Sleep for *at least* the provided milliseconds (must be a positive integer),
@@ -63,7 +40,8 @@ fn sleep(millis: i64) -> VMResult<()> {
 
 /// Usage: (cd dir-to-change-to)
 ///
-/// Change directory.
+/// Change directory.  With no dir changes to HOME, "-" is the previous directory and "-N" the
+/// Nth directory in the cd history.  Relative directories are also searched for in CDPATH.
 ///
 /// Section: file
 ///
@@ -77,33 +55,33 @@ fn sleep(millis: i64) -> VMResult<()> {
 #[sl_sh_fn(fn_name = "cd")]
 fn cd(arg: Option<String>) -> VMResult<Value> {
     let fn_name = "cd";
-    let home = match env::var("HOME") {
-        Ok(val) => val,
-        Err(_) => "/".to_string(),
-    };
-    let old_dir = match env::var("OLDPWD") {
-        Ok(val) => val,
-        Err(_) => home.to_string(),
-    };
-    let new_dir = match arg {
-        Some(arg) => expand_tilde(arg.into()).to_string_lossy().to_string(),
-        None => home,
-    };
-    let new_dir = if new_dir == "-" { &old_dir } else { &new_dir };
-    let new_dir = cd_expand_all_dots(new_dir.to_string());
-    let root = Path::new(&new_dir);
-    if let Ok(oldpwd) = env::current_dir() {
-        env::set_var("OLDPWD", oldpwd);
-    }
-    if let Err(e) = env::set_current_dir(root) {
-        eprintln!("{} Error changing to {}, {}", fn_name, root.display(), e);
+    let arg = arg.map(|arg| expand_tilde(arg.into()));
+    if let Err(e) = dirs::cd(arg) {
+        eprintln!("{} {}", fn_name, e);
         Ok(Value::Nil)
     } else {
-        env::set_var("PWD", env::current_dir()?);
         Ok(Value::True)
     }
 }
 
+/// Usage: (dir-stack) -> vector
+///
+/// Returns the shell directory stack (see pushd, popd and dirs) as a vector of strings, the
+/// current directory first.
+///
+/// Section: file
+///
+/// Example:
+/// (test::assert-equal (env "PWD") (first (dir-stack)))
+#[sl_sh_fn(fn_name = "dir-stack", takes_env = true)]
+fn dir_stack(environment: &mut SloshVm) -> VMResult<Value> {
+    let dirs = dirs::dir_stack()
+        .iter()
+        .map(|dir| environment.alloc_string(dir.to_string_lossy().to_string()))
+        .collect();
+    Ok(environment.alloc_vector(dirs))
+}
+
 pub fn get_file(p: &str) -> Option<PathBuf> {
     let p = expand_tilde(p.into());
     Some(p.to_path_buf())
@@ -673,6 +651,7 @@ fn fs_meta(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
 
 pub fn add_fs_meta_builtins(env: &mut SloshVm) {
     intern_cd(env);
+    intern_dir_stack(env);
     intern_path_exists(env);
     intern_is_file(env);
     intern_is_dir(env);
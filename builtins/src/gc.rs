@@ -0,0 +1,109 @@
+use bridge_adapters::add_builtin;
+use compile_state::state::SloshVm;
+use slvm::vm_hashmap::VMHashMap;
+use slvm::{VMError, VMResult, Value};
+use std::time::Duration;
+
+fn millis(d: Duration) -> Value {
+    Value::Float((d.as_secs_f64() * 1000.0).into())
+}
+
+fn gc_stats(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if !registers.is_empty() {
+        return Err(VMError::new_vm("gc-stats: takes no arguments"));
+    }
+    // The pool maps are only held in Rust until the result is built so don't collect them.
+    vm.pause_gc();
+    let stats = vm.gc_stats();
+    let mut pools = VMHashMap::new();
+    for (name, pool) in vm.gc_pool_stats() {
+        let mut map = VMHashMap::new();
+        for (key, val) in [
+            ("live", pool.live),
+            ("old", pool.old),
+            ("capacity", pool.capacity),
+        ] {
+            let key = Value::Keyword(vm.intern_static(key));
+            map.insert(vm, key, (val as i64).into());
+        }
+        let key = Value::Keyword(vm.intern_static(name));
+        let map = vm.alloc_map(map);
+        pools.insert(vm, key, map);
+    }
+    let mut map = VMHashMap::new();
+    for (key, val) in [
+        ("minor-collections", (stats.minor_collections as i64).into()),
+        ("major-collections", (stats.major_collections as i64).into()),
+        ("total-pause-ms", millis(stats.total_pause)),
+        ("last-pause-ms", millis(stats.last_pause)),
+        ("max-pause-ms", millis(stats.max_pause)),
+    ] {
+        let key = Value::Keyword(vm.intern_static(key));
+        map.insert(vm, key, val);
+    }
+    let key = Value::Keyword(vm.intern_static("pools"));
+    let pools = vm.alloc_map(pools);
+    map.insert(vm, key, pools);
+    let res = vm.alloc_map(map);
+    vm.unpause_gc();
+    Ok(res)
+}
+
+fn gc_collect(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let major = match registers {
+        [] => true,
+        [Value::Keyword(i)] if vm.get_interned(*i) == "minor" => false,
+        [Value::Keyword(i)] if vm.get_interned(*i) == "major" => true,
+        _ => {
+            return Err(VMError::new_vm(
+                "gc-collect: takes an optional :major or :minor",
+            ))
+        }
+    };
+    vm.gc_collect(major);
+    Ok(Value::Nil)
+}
+
+pub fn add_gc_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "gc-stats",
+        gc_stats,
+        r#"Usage: (gc-stats) -> map
+
+Return garbage collector statistics: :minor-collections, :major-collections, :total-pause-ms,
+:last-pause-ms, :max-pause-ms and :pools.  Pools maps each heap pool (:objects, :pairs,
+:values, etc) to a map of its :live and :old (survived a collection) object counts and its
+:capacity.
+
+Section: core
+
+Example:
+(let (stats (gc-stats))
+    (test::assert-equal :Int (type (get stats :minor-collections)))
+    (test::assert-equal :Float (type (get stats :max-pause-ms)))
+    (test::assert-true (>= (get (get (get stats :pools) :pairs) :capacity) 512)))
+"#,
+    );
+    add_builtin(
+        env,
+        "gc-collect",
+        gc_collect,
+        r#"Usage: (gc-collect [:major | :minor]) -> nil
+
+Run a garbage collection now.  A major collection (the default) traces and frees objects from
+all generations, a minor collection only frees objects that have not survived a previous
+collection.
+
+Section: core
+
+Example:
+(let (before (get (gc-stats) :major-collections))
+    (gc-collect)
+    (test::assert-equal (+ before 1) (get (gc-stats) :major-collections)))
+(let (before (get (gc-stats) :minor-collections))
+    (gc-collect :minor)
+    (test::assert-equal (+ before 1) (get (gc-stats) :minor-collections)))
+"#,
+    );
+}
@@ -0,0 +1,1170 @@
+//! Native iterators for the iter namespace.  Adapters are lazy Value::Iter objects (see
+//! slvm::seq_iter) and accept anything iter accepts, consumers step them from Rust.
+
+use bridge_adapters::add_builtin;
+use compile_state::state::{SloshVm, SloshVmTrait};
+use sl_compiler::load_eval::eval_exp;
+use slvm::seq_iter::SeqIter;
+use slvm::vm_hashmap::{VMHashMap, ValHash};
+use slvm::{VMError, VMResult, Value};
+use std::collections::VecDeque;
+
+/// Run f with garbage collection paused, used while building iterators since the parts are not
+/// rooted until the iterator is allocated.  No lisp code runs while paused.
+fn gc_paused<T>(vm: &mut SloshVm, f: impl FnOnce(&mut SloshVm) -> VMResult<T>) -> VMResult<T> {
+    vm.pause_gc();
+    let res = f(vm);
+    vm.unpause_gc();
+    res
+}
+
+/// Run f with vals kept alive (f gets a vector holding them), used by consumers to hold their
+/// iterator, function and result while calling back into lisp.
+fn with_roots<T>(
+    vm: &mut SloshVm,
+    vals: Vec<Value>,
+    f: impl FnOnce(&mut SloshVm, Value) -> VMResult<T>,
+) -> VMResult<T> {
+    vm.pause_gc();
+    let roots = vm.alloc_vector(vals);
+    vm.heap_sticky(roots);
+    vm.unpause_gc();
+    let res = f(vm, roots);
+    vm.heap_unsticky(roots);
+    res
+}
+
+fn set_root(vm: &mut SloshVm, roots: Value, idx: usize, val: Value) -> VMResult<()> {
+    if let Value::Vector(h) = roots {
+        vm.get_vector_mut(h)?[idx] = val;
+    }
+    Ok(())
+}
+
+/// Return lambda as something the VM can call from Rust.  Compiled forms (like + or car) are
+/// wrapped in a lambda taking arity args.
+fn callable(vm: &mut SloshVm, lambda: Value, arity: usize) -> VMResult<Value> {
+    match lambda {
+        Value::Special(_) | Value::Symbol(_) => {
+            let params: Vec<Value> = (0..arity)
+                .map(|i| Value::Symbol(vm.intern(&format!("arg{i}"))))
+                .collect();
+            let mut call = vec![lambda];
+            call.extend_from_slice(&params);
+            let form = gc_paused(vm, |vm| {
+                let params = vm.alloc_list_ro(params);
+                let call = vm.alloc_list_ro(call);
+                let fn_ = Value::Symbol(vm.specials().fn_);
+                Ok(vm.alloc_list_ro(vec![fn_, params, call]))
+            })?;
+            // Compiling can collect, keep the form alive until it is done.
+            vm.heap_sticky(form);
+            let res = eval_exp(vm, form);
+            vm.heap_unsticky(form);
+            res
+        }
+        Value::Value(h) => callable(vm, vm.get_value(h), arity),
+        _ => Ok(lambda),
+    }
+}
+
+fn get_size(vm: &SloshVm, name: &str, val: Value) -> VMResult<usize> {
+    match val.get_int(vm) {
+        Ok(i) if i >= 0 => Ok(i as usize),
+        _ => Err(VMError::new_vm(format!(
+            "{name}: requires a non-negative integer, got {}",
+            val.display_type(vm)
+        ))),
+    }
+}
+
+fn iter_iter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [thing] = registers {
+        vm.make_iter(*thing)
+    } else {
+        Err(VMError::new_vm("iter: takes one argument"))
+    }
+}
+
+fn iter_is_iter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [thing] = registers {
+        Ok(if vm.is_iter(*thing) {
+            Value::True
+        } else {
+            Value::False
+        })
+    } else {
+        Err(VMError::new_vm("iter?: takes one argument"))
+    }
+}
+
+fn vec_iter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [vector @ Value::Vector(_)] = registers {
+        Ok(vm.alloc_iter(SeqIter::Vector {
+            vector: *vector,
+            idx: 0,
+        }))
+    } else {
+        Err(VMError::new_vm("vec-iter: takes a vector"))
+    }
+}
+
+fn list_iter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [list @ (Value::Pair(_) | Value::List(_, _) | Value::Nil)] = registers {
+        Ok(vm.alloc_iter(SeqIter::List { list: *list }))
+    } else {
+        Err(VMError::new_vm("list-iter: takes a list"))
+    }
+}
+
+fn string_iter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [string @ (Value::String(_) | Value::StringConst(_))] = registers {
+        Ok(vm.alloc_iter(SeqIter::Chars {
+            string: *string,
+            byte_idx: 0,
+        }))
+    } else {
+        Err(VMError::new_vm("string-iter: takes a string"))
+    }
+}
+
+fn file_iter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [io @ Value::Io(_)] = registers {
+        Ok(vm.alloc_iter(SeqIter::Lines {
+            io: *io,
+            buf: Vec::new(),
+        }))
+    } else {
+        Err(VMError::new_vm("file-iter: takes a file"))
+    }
+}
+
+fn range(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (start, end, step) = match registers {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.get_int(vm)?),
+        _ => return Err(VMError::new_vm("range: takes start, end and optional step")),
+    };
+    if step == 0 {
+        return Err(VMError::new_vm("range: step can not be 0"));
+    }
+    let end = if end.is_nil() {
+        None
+    } else {
+        Some(end.get_int(vm)?)
+    };
+    let next = start.get_int(vm)?;
+    Ok(vm.alloc_iter(SeqIter::Range { next, end, step }))
+}
+
+/// Make an adapter from (name iter lambda) args.
+fn iter_lambda_adapter(
+    vm: &mut SloshVm,
+    registers: &[Value],
+    name: &str,
+    make: fn(Value, Value) -> SeqIter,
+) -> VMResult<Value> {
+    if let [iter, lambda] = registers {
+        // Resolve lambda first, it may compile code and that can not happen with the GC paused.
+        let lambda = callable(vm, *lambda, 1)?;
+        gc_paused(vm, |vm| {
+            let iter = vm.make_iter(*iter)?;
+            Ok(vm.alloc_iter(make(iter, lambda)))
+        })
+    } else {
+        Err(VMError::new_vm(format!(
+            "{name}: takes an iterator and a lambda"
+        )))
+    }
+}
+
+/// Make an adapter from (name iter size) args.
+fn iter_size_adapter(
+    vm: &mut SloshVm,
+    registers: &[Value],
+    name: &str,
+    make: fn(Value, usize) -> SeqIter,
+) -> VMResult<Value> {
+    if let [iter, size] = registers {
+        let size = get_size(vm, name, *size)?;
+        gc_paused(vm, |vm| {
+            let iter = vm.make_iter(*iter)?;
+            Ok(vm.alloc_iter(make(iter, size)))
+        })
+    } else {
+        Err(VMError::new_vm(format!(
+            "{name}: takes an iterator and a count"
+        )))
+    }
+}
+
+/// Make an adapter from (name iter+) args.
+fn iters_adapter(
+    vm: &mut SloshVm,
+    registers: &[Value],
+    name: &str,
+    make: fn(Vec<Value>) -> SeqIter,
+) -> VMResult<Value> {
+    if registers.is_empty() {
+        return Err(VMError::new_vm(format!(
+            "{name}: requires at least one iterator"
+        )));
+    }
+    gc_paused(vm, |vm| {
+        let iters = registers
+            .iter()
+            .map(|i| vm.make_iter(*i))
+            .collect::<VMResult<Vec<Value>>>()?;
+        Ok(vm.alloc_iter(make(iters)))
+    })
+}
+
+fn map(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_lambda_adapter(vm, registers, "map", |iter, lambda| SeqIter::Map {
+        iter,
+        lambda,
+    })
+}
+
+fn filter(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_lambda_adapter(vm, registers, "filter", |iter, lambda| SeqIter::Filter {
+        iter,
+        lambda,
+    })
+}
+
+fn take_while(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_lambda_adapter(vm, registers, "take-while", |iter, lambda| {
+        SeqIter::TakeWhile { iter, lambda }
+    })
+}
+
+fn drop_while(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_lambda_adapter(vm, registers, "drop-while", |iter, lambda| {
+        SeqIter::DropWhile {
+            iter,
+            lambda,
+            dropping: true,
+        }
+    })
+}
+
+fn flat_map(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_lambda_adapter(vm, registers, "flat-map", |iter, lambda| SeqIter::FlatMap {
+        iter,
+        lambda,
+        inner: Value::Undefined,
+    })
+}
+
+fn take(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_size_adapter(vm, registers, "take", |iter, remaining| SeqIter::Take {
+        iter,
+        remaining,
+    })
+}
+
+fn drop(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iter_size_adapter(vm, registers, "drop", |iter, skip| SeqIter::Drop {
+        iter,
+        skip,
+    })
+}
+
+fn partition(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [_, size] = registers {
+        if get_size(vm, "partition", *size)? == 0 {
+            return Err(VMError::new_vm("partition: size must be at least 1"));
+        }
+    }
+    iter_size_adapter(vm, registers, "partition", |iter, size| {
+        SeqIter::Partition {
+            iter,
+            size,
+            buf: Vec::new(),
+        }
+    })
+}
+
+fn windowed(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [_, size] = registers {
+        if get_size(vm, "windowed", *size)? == 0 {
+            return Err(VMError::new_vm("windowed: size must be at least 1"));
+        }
+    }
+    iter_size_adapter(vm, registers, "windowed", |iter, size| SeqIter::Windowed {
+        iter,
+        size,
+        window: VecDeque::with_capacity(size),
+    })
+}
+
+fn enumerate(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (iter, idx) = match registers {
+        [iter] => (*iter, 0),
+        [iter, start] => (*iter, start.get_int(vm)?),
+        _ => {
+            return Err(VMError::new_vm(
+                "enumerate: takes an iterator and optional start index",
+            ))
+        }
+    };
+    gc_paused(vm, |vm| {
+        let iter = vm.make_iter(iter)?;
+        Ok(vm.alloc_iter(SeqIter::Enumerate { iter, idx }))
+    })
+}
+
+fn zip(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iters_adapter(vm, registers, "zip", |iters| SeqIter::Zip {
+        buf: Vec::with_capacity(iters.len()),
+        iters,
+    })
+}
+
+fn chain(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iters_adapter(vm, registers, "chain", |iters| SeqIter::Chain {
+        iters,
+        idx: 0,
+    })
+}
+
+fn interleave(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    iters_adapter(vm, registers, "interleave", |iters| SeqIter::Interleave {
+        iters,
+        idx: 0,
+    })
+}
+
+fn dedupe(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [iter] = registers {
+        gc_paused(vm, |vm| {
+            let iter = vm.make_iter(*iter)?;
+            Ok(vm.alloc_iter(SeqIter::Dedupe {
+                iter,
+                last: Value::Undefined,
+            }))
+        })
+    } else {
+        Err(VMError::new_vm("dedupe: takes an iterator"))
+    }
+}
+
+fn cycle(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [iter] = registers {
+        gc_paused(vm, |vm| {
+            let iter = vm.make_iter(*iter)?;
+            Ok(vm.alloc_iter(SeqIter::Cycle {
+                iter,
+                seen: Vec::new(),
+                idx: 0,
+            }))
+        })
+    } else {
+        Err(VMError::new_vm("cycle: takes an iterator"))
+    }
+}
+
+/// Call f with each element of iter (rooted at roots[0]) until it is empty.
+fn for_each(
+    vm: &mut SloshVm,
+    iter: Value,
+    mut f: impl FnMut(&mut SloshVm, Value) -> VMResult<()>,
+) -> VMResult<()> {
+    loop {
+        let val = vm.iter_next_value(iter)?;
+        if vm.is_iter_empty(val) {
+            return Ok(());
+        }
+        f(vm, val)?;
+    }
+}
+
+fn reduce(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [iter, acc, lambda] = registers {
+        let lambda = callable(vm, *lambda, 2)?;
+        let iter = gc_paused(vm, |vm| vm.make_iter(*iter))?;
+        with_roots(vm, vec![iter, lambda, *acc], |vm, roots| {
+            let mut acc = *acc;
+            for_each(vm, iter, |vm, val| {
+                acc = vm.call_fn(lambda, &[acc, val])?;
+                set_root(vm, roots, 2, acc)
+            })?;
+            Ok(acc)
+        })
+    } else {
+        Err(VMError::new_vm(
+            "reduce: takes an iterator, initial value and a reducing lambda",
+        ))
+    }
+}
+
+/// Collect the elements of the iterator in registers into a vector.
+fn collect_into_vec(vm: &mut SloshVm, registers: &[Value], name: &str) -> VMResult<Value> {
+    if let [iter] = registers {
+        let iter = gc_paused(vm, |vm| vm.make_iter(*iter))?;
+        let res = gc_paused(vm, |vm| Ok(vm.alloc_vector(Vec::new())))?;
+        with_roots(vm, vec![iter, res], |vm, _roots| {
+            for_each(vm, iter, |vm, val| {
+                if let Value::Vector(h) = res {
+                    vm.get_vector_mut(h)?.push(val);
+                }
+                Ok(())
+            })?;
+            Ok(res)
+        })
+    } else {
+        Err(VMError::new_vm(format!("{name}: takes an iterator")))
+    }
+}
+
+fn collect(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let res = collect_into_vec(vm, registers, "collect")?;
+    if let Value::Vector(h) = res {
+        let vals = vm.get_vector(h).to_vec();
+        if vals.is_empty() {
+            Ok(Value::Nil)
+        } else {
+            gc_paused(vm, |vm| Ok(vm.alloc_list_ro(vals)))
+        }
+    } else {
+        Ok(Value::Nil)
+    }
+}
+
+fn collect_vec(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    collect_into_vec(vm, registers, "collect-vec")
+}
+
+fn collect_map(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [iter] = registers {
+        let iter = gc_paused(vm, |vm| vm.make_iter(*iter))?;
+        let res = gc_paused(vm, |vm| Ok(vm.alloc_map(VMHashMap::new())))?;
+        with_roots(vm, vec![iter, res], |vm, _roots| {
+            for_each(vm, iter, |vm, val| {
+                let (key, val) = match val {
+                    Value::Vector(h) if vm.get_vector(h).len() == 2 => {
+                        let v = vm.get_vector(h);
+                        (v[0], v[1])
+                    }
+                    Value::Pair(_) | Value::List(_, _) => {
+                        val.get_pair(vm).expect("pair or list is a pair")
+                    }
+                    _ => {
+                        return Err(VMError::new_vm(format!(
+                            "collect-map: elements must be key/value pairs or vectors, got {}",
+                            val.display_type(vm)
+                        )))
+                    }
+                };
+                let id = ValHash::from_value(vm, key);
+                if let Value::Map(h) = res {
+                    vm.get_map_mut(h)?.insert_id(id, val);
+                }
+                Ok(())
+            })?;
+            Ok(res)
+        })
+    } else {
+        Err(VMError::new_vm("collect-map: takes an iterator"))
+    }
+}
+
+fn collect_str(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (iter, sep) = match registers {
+        [iter] => (*iter, String::new()),
+        [iter, sep] => (*iter, sep.pretty_value(vm)),
+        _ => {
+            return Err(VMError::new_vm(
+                "collect-str: takes an iterator and optional separator",
+            ))
+        }
+    };
+    let iter = gc_paused(vm, |vm| vm.make_iter(iter))?;
+    let mut res = String::new();
+    let mut first = true;
+    with_roots(vm, vec![iter], |vm, _roots| {
+        for_each(vm, iter, |vm, val| {
+            if !first {
+                res.push_str(&sep);
+            }
+            first = false;
+            res.push_str(&val.pretty_value(vm));
+            Ok(())
+        })
+    })?;
+    Ok(vm.alloc_string(res))
+}
+
+fn count(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [iter] = registers {
+        let iter = gc_paused(vm, |vm| vm.make_iter(*iter))?;
+        let mut count = 0_i64;
+        with_roots(vm, vec![iter], |vm, _roots| {
+            for_each(vm, iter, |_vm, _val| {
+                count += 1;
+                Ok(())
+            })
+        })?;
+        Ok(count.into())
+    } else {
+        Err(VMError::new_vm("count: takes an iterator"))
+    }
+}
+
+fn group_by(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [iter, lambda] = registers {
+        let lambda = callable(vm, *lambda, 1)?;
+        let (iter, res) = gc_paused(vm, |vm| {
+            Ok((vm.make_iter(*iter)?, vm.alloc_map(VMHashMap::new())))
+        })?;
+        with_roots(vm, vec![iter, lambda, res, Value::Nil], |vm, roots| {
+            let Value::Map(map) = res else {
+                return Ok(res);
+            };
+            for_each(vm, iter, |vm, val| {
+                // Keep val alive while calling lambda.
+                set_root(vm, roots, 3, val)?;
+                let key = vm.call_fn(lambda, &[val])?;
+                match vm.get_map(map).get(vm, key) {
+                    Some(Value::Vector(group)) => vm.get_vector_mut(group)?.push(val),
+                    _ => {
+                        let group = gc_paused(vm, |vm| Ok(vm.alloc_vector(vec![val])))?;
+                        let id = ValHash::from_value(vm, key);
+                        vm.get_map_mut(map)?.insert_id(id, group);
+                    }
+                }
+                Ok(())
+            })?;
+            Ok(res)
+        })
+    } else {
+        Err(VMError::new_vm(
+            "group-by: takes an iterator and a key lambda",
+        ))
+    }
+}
+
+pub fn add_iter_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "iter::iter",
+        iter_iter,
+        r#"Usage: (iter thing) -> iterator
+
+Return thing as an iterator if possible (if it is an iterator just return thing).
+Lists, vectors, strings (by character) and files (by line) are wrapped in a native
+iterator.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-true (iter? (iter '(1 2 3))))
+(test::assert-true (iter? (iter [1 2 3])))
+(test::assert-true (iter? (iter "abc")))
+(test::assert-true (iter? (iter (iter '(1 2 3)))))
+(test::assert-error (iter 1))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::iter?",
+        iter_is_iter,
+        r#"Usage: (iter? thing) -> #t/#f
+
+Return true if thing is an iterator, false otherwise.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-true (iter? (list-iter '(1 2 3))))
+(test::assert-true (iter? (mk-iter :*iter-empty*)))
+(test::assert-false (iter? '(1 2 3)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::vec-iter",
+        vec_iter,
+        r#"Usage: (vec-iter vector) -> iterator
+
+Iterator that wraps a vector.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (test-vec-iter (vec-iter [1 2 3]))
+    (test::assert-equal 1 (test-vec-iter))
+    (test::assert-equal 2 (test-vec-iter))
+    (test::assert-equal 3 (test-vec-iter))
+    (test::assert-equal :*iter-empty* (test-vec-iter))
+    (set! test-vec-iter (vec-iter (vec)))
+    (test::assert-equal :*iter-empty* (test-vec-iter)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::list-iter",
+        list_iter,
+        r#"Usage: (list-iter list) -> iterator
+
+Iterator that wraps a list.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (test-list-iter (list-iter '(1 2 3)))
+    (test::assert-equal 1 (test-list-iter))
+    (test::assert-equal 2 (test-list-iter))
+    (test::assert-equal 3 (test-list-iter))
+    (test::assert-equal :*iter-empty* (test-list-iter))
+    (set! test-list-iter (list-iter '()))
+    (test::assert-equal :*iter-empty* (test-list-iter)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::string-iter",
+        string_iter,
+        r#"Usage: (string-iter string) -> iterator
+
+Iterator that wraps a string.  Each element is the next character.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (test-string-iter (string-iter "123"))
+    (test::assert-equal \1 (test-string-iter))
+    (test::assert-equal \2 (test-string-iter))
+    (test::assert-equal \3 (test-string-iter))
+    (test::assert-equal :*iter-empty* (test-string-iter)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::file-iter",
+        file_iter,
+        r#"Usage: (file-iter file) -> iterator
+
+Iterator that wraps a file.  Each call produces the next line (with
+trailing newline).  The line buffer is reused so reading a file only
+allocates the line strings.
+
+Section: iterator
+
+Example:
+(import iter)
+(with-temp-file (fn (file-name)
+    (let (tst-file (fopen file-name :create :truncate))
+        (defer (fclose tst-file))
+        (fprn tst-file "line 1")
+        (fprn tst-file "line 2")
+        (fprn tst-file "line 3")
+        (fpr tst-file "line 4"))
+    (let (tst-file (fopen file-name), test-iter (file-iter tst-file))
+        (defer (fclose tst-file))
+        (test::assert-equal "line 1\n" (test-iter))
+        (test::assert-equal "line 2\n" (test-iter))
+        (test::assert-equal "line 3\n" (test-iter))
+        (test::assert-equal "line 4" (test-iter))
+        (test::assert-equal :*iter-empty* (test-iter)))))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::range",
+        range,
+        r#"Usage: (range start end step?) -> iterator
+
+Iterator that generates numbers from start up to (not including) end, step
+(default 1) is added for each number and may be negative.  If end is nil the
+range does not end.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (test-iter (range 3 6))
+    (test::assert-equal 3 (test-iter))
+    (test::assert-equal 4 (test-iter))
+    (test::assert-equal 5 (test-iter))
+    (test::assert-equal :*iter-empty* (test-iter)))
+(test::assert-equal '(10 7 4 1) (collect (range 10 0 -3)))
+(test::assert-equal '(0 2 4) (collect (take (range 0 nil 2) 3)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::enumerate",
+        enumerate,
+        r#"Usage: (enumerate iter start?) -> iterator
+
+Iterator that wraps an iterator and generates pairs of current index and value.
+Index is 0 based by default, takes an optional second parameter with the start
+index.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (test-iter (enumerate (vec-iter [:a :b :c])))
+    (let ([i v] (test-iter)) (test::assert-equal 0 i) (test::assert-equal :a v))
+    (let ([i v] (test-iter)) (test::assert-equal 1 i) (test::assert-equal :b v))
+    (let ([i v] (test-iter)) (test::assert-equal 2 i) (test::assert-equal :c v))
+    (test::assert-equal :*iter-empty* (test-iter)))
+(let (test-iter (enumerate (vec-iter [:a :b :c]) 5))
+    (let ([i v] (test-iter)) (test::assert-equal 5 i) (test::assert-equal :a v))
+    (let ([i v] (test-iter)) (test::assert-equal 6 i) (test::assert-equal :b v))
+    (let ([i v] (test-iter)) (test::assert-equal 7 i) (test::assert-equal :c v))
+    (test::assert-equal :*iter-empty* (test-iter)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::map",
+        map,
+        r#"Usage: (map iter lambda) -> iterator
+
+Iterator that applies a lambda to each element of another iterator- is lazy.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (test-map-iter (map (list-iter '(1 2 3)) (fn (x) (* x 2))))
+    (test::assert-equal 2 (test-map-iter))
+    (test::assert-equal 4 (test-map-iter))
+    (test::assert-equal 6 (test-map-iter))
+    (test::assert-equal :*iter-empty* (test-map-iter)))
+(test::assert-equal "1-2-3" (collect-str (map [1 2 3] str) "-"))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::filter",
+        filter,
+        r#"Usage: (filter iter lambda) -> iterator
+
+Returns a filter-iter around iter.
+Iterator that applies a lambda to each element to determine if is returned- is lazy.
+
+Section: iterator
+
+Example:
+(let (test-iter (iter::filter (iter::vec-iter [1 2 3]) (fn (x) (not (= x 2)))))
+    (test::assert-equal 1 (test-iter))
+    (test::assert-equal 3 (test-iter))
+    (test::assert-equal :*iter-empty* (test-iter)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::take",
+        take,
+        r#"Usage: (take iter n) -> iterator
+
+Iterator over the first n elements of iter.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 2) (collect (take [1 2 3 4] 2)))
+(test::assert-equal '(1 2) (collect (take [1 2] 5)))
+(test::assert-equal '(0 1 2) (collect (take (range 0 nil) 3)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::drop",
+        drop,
+        r#"Usage: (drop iter n) -> iterator
+
+Iterator over the elements of iter after the first n.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(3 4) (collect (drop [1 2 3 4] 2)))
+(test::assert-equal nil (collect (drop [1 2] 5)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::take-while",
+        take_while,
+        r#"Usage: (take-while iter lambda) -> iterator
+
+Iterator over the elements of iter until lambda returns false for one.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 2) (collect (take-while [1 2 3 1] (fn (x) (< x 3)))))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::drop-while",
+        drop_while,
+        r#"Usage: (drop-while iter lambda) -> iterator
+
+Iterator over the elements of iter starting with the first that lambda returns
+false for.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(3 1) (collect (drop-while [1 2 3 1] (fn (x) (< x 3)))))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::zip",
+        zip,
+        r#"Usage: (zip iter & iters) -> iterator
+
+Iterator of vectors holding the next element of each iterator, ends when any
+of the iterators ends.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal (list [1 :a] [2 :b]) (collect (zip [1 2 3] '(:a :b))))
+(let (out "")
+    (for [n ch] in (zip (range 0 3) "abc") (set! out (str out ch n)))
+    (test::assert-equal "a0b1c2" out))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::chain",
+        chain,
+        r#"Usage: (chain iter & iters) -> iterator
+
+Iterator over all the elements of each iterator in turn.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 2 3 4) (collect (chain [1 2] '(3) (range 4 5))))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::interleave",
+        interleave,
+        r#"Usage: (interleave iter & iters) -> iterator
+
+Iterator that takes one element from each iterator in turn, ends when the next
+iterator to take from ends.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 :a 2 :b 3) (collect (interleave [1 2 3] [:a :b])))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::flat-map",
+        flat_map,
+        r#"Usage: (flat-map iter lambda) -> iterator
+
+Iterator over the elements of the results of applying lambda to each element of
+iter, lambda must return something iter accepts.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 1 2 2) (collect (flat-map [1 2] (fn (x) [x x]))))
+(test::assert-equal '(\a \b \c \d) (collect (flat-map ["ab" "" "cd"] iter)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::partition",
+        partition,
+        r#"Usage: (partition iter n) -> iterator
+
+Iterator of vectors of the next n elements of iter, the last vector holds any
+left over elements.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal (list [1 2] [3 4] [5]) (collect (partition (range 1 6) 2)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::windowed",
+        windowed,
+        r#"Usage: (windowed iter n) -> iterator
+
+Iterator of vectors of each n consecutive elements of iter (a sliding window).
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal (list [1 2 3] [2 3 4]) (collect (windowed [1 2 3 4] 3)))
+(test::assert-equal nil (collect (windowed [1 2] 3)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::dedupe",
+        dedupe,
+        r#"Usage: (dedupe iter) -> iterator
+
+Iterator that skips elements equal to the previous element.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 2 1 3) (collect (dedupe [1 1 2 2 2 1 3 3])))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::cycle",
+        cycle,
+        r#"Usage: (cycle iter) -> iterator
+
+Iterator that repeats the elements of iter forever (remembering them the first
+time through).
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(1 2 1 2 1) (collect (take (cycle [1 2]) 5)))
+(test::assert-equal nil (collect (take (cycle []) 5)))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::reduce",
+        reduce,
+        r#"Usage: (reduce iter init-val reducing-fn) -> result
+
+reduce is used to amalgamate an iterator and an initial value,
+according to the reducing function provided. The reducing-fcn should be a function
+of two arguments. In the first iteration of reduce, the init-val will be used as
+the first argument to the reducing-fcn and (iter) will be used as the
+second argument. For all subsequent iterations, The result from the previous
+application of the reducing-fcn will be used as the first argument to the
+reducing-fcn and the second argument will be the next item in the collection
+when the collection is empty reduce will return the amalgamated result.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-true (= 15 (iter::reduce (iter::vec-iter [1 2 3 4 5]) 0 +)))
+(test::assert-true (= 16 (iter::reduce (iter::vec-iter [1 2 3 4 5]) 1 +)))
+(test::assert-true (= "one hoopy frood" (iter::reduce (iter::vec-iter ["one " "hoopy " "frood"]) "" str)))
+(test::assert-equal 6 (reduce '(1 2 3) 0 (fn (acc x) (+ acc x))))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::collect",
+        collect,
+        r#"Usage: (collect iter) -> list
+
+Collect the elements of iter into a list.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal '(2 4 6) (collect (map [1 2 3] (fn (x) (* x 2)))))
+(test::assert-equal nil (collect []))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::collect-vec",
+        collect_vec,
+        r#"Usage: (collect-vec iter) -> vector
+
+Collect the elements of iter into a vector.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal [2 4 6] (collect-vec (map '(1 2 3) (fn (x) (* x 2)))))
+(test::assert-equal [] (collect-vec []))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::collect-map",
+        collect_map,
+        r#"Usage: (collect-map iter) -> map
+
+Collect the elements of iter into a map, each element must be a key/value pair
+or a two element vector.  Later keys replace earlier ones.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (m (collect-map (zip [:a :b] [1 2])))
+    (test::assert-equal 1 (get m :a))
+    (test::assert-equal 2 (get m :b)))
+(test::assert-equal 3 (get (collect-map (list (cons :x 1) (cons :x 3))) :x))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::collect-str",
+        collect_str,
+        r#"Usage: (collect-str iter separator?) -> string
+
+Collect the elements of iter into a string (as str would), with separator
+between elements if provided.
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal "cba" (collect-str (vec-iter-rev [\a \b \c])))
+(test::assert-equal "1, 2, 3" (collect-str (range 1 4) ", "))
+(test::assert-equal "" (collect-str []))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::count",
+        count,
+        r#"Usage: (count iter) -> int
+
+Count the elements of iter (this consumes the iterator).
+
+Section: iterator
+
+Example:
+(import iter)
+(test::assert-equal 3 (count (filter (range 0 6) (fn (x) (= 0 (% x 2))))))
+(test::assert-equal 0 (count nil))
+"#,
+    );
+    add_builtin(
+        env,
+        "iter::group-by",
+        group_by,
+        r#"Usage: (group-by iter lambda) -> map
+
+Group the elements of iter into a map of the result of lambda applied to each
+element to a vector of the elements (in order) with that result.
+
+Section: iterator
+
+Example:
+(import iter)
+(let (groups (group-by (range 0 7) (fn (x) (if (= 0 (% x 2)) :even :odd))))
+    (test::assert-equal [0 2 4 6] (get groups :even))
+    (test::assert-equal [1 3 5] (get groups :odd)))
+"#,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coroutine::add_coroutine_builtins;
+    use crate::string::add_str_builtins;
+    use compile_state::state::new_slosh_vm;
+    use compiler_test_utils::exec;
+
+    fn new_iter_vm() -> SloshVm {
+        let mut vm = new_slosh_vm();
+        add_iter_builtins(&mut vm);
+        add_coroutine_builtins(&mut vm);
+        add_str_builtins(&mut vm);
+        vm
+    }
+
+    fn run(vm: &mut SloshVm, code: &'static str) -> String {
+        let v = exec(vm, code);
+        v.display_value(vm)
+    }
+
+    #[test]
+    fn test_adapters_lazy() {
+        let mut vm = new_iter_vm();
+        run(&mut vm, "(def calls 0)");
+        run(
+            &mut vm,
+            "(def it (iter::map (iter::range 0 nil) (fn (x) (inc! calls) (* x 2))))",
+        );
+        // Building a pipeline does not pull anything.
+        assert_eq!(run(&mut vm, "calls"), "0");
+        assert_eq!(run(&mut vm, "(iter::collect (iter::take it 3))"), "(0 2 4)");
+        assert_eq!(run(&mut vm, "calls"), "3");
+        // take stopped without pulling a fourth element.
+        assert_eq!(run(&mut vm, "(it)"), "6");
+        assert_eq!(run(&mut vm, "calls"), "4");
+
+        run(&mut vm, "(set! calls 0)");
+        assert_eq!(
+            run(&mut vm, "(iter::collect (iter::take-while (iter::map (iter::range 0 nil) (fn (x) (inc! calls) x)) (fn (x) (< x 3))))"),
+            "(0 1 2)"
+        );
+        assert_eq!(run(&mut vm, "calls"), "4");
+
+        run(&mut vm, "(set! calls 0)");
+        assert_eq!(
+            run(&mut vm, "(iter::collect (iter::take (iter::filter (iter::map (iter::range 0 nil) (fn (x) (inc! calls) x)) (fn (x) (> x 4))) 2))"),
+            "(5 6)"
+        );
+        assert_eq!(run(&mut vm, "calls"), "7");
+
+        // Closures (with captures) and builtins as the mapped function.
+        assert_eq!(
+            run(&mut vm, "(let (n 10) (iter::collect (iter::take (iter::map (iter::range 0 nil) (fn (x) (+ x n))) 2)))"),
+            "(10 11)"
+        );
+        assert_eq!(
+            run(
+                &mut vm,
+                "(iter::collect (iter::map [\"a\" \"b\"] str-upper))"
+            ),
+            "(\"A\" \"B\")"
+        );
+    }
+
+    #[test]
+    fn test_adapters_in_generator() {
+        let mut vm = new_iter_vm();
+        // The generator's stack grows while collect and map (builtins) are still running.
+        run(
+            &mut vm,
+            "(def deep (fn (n) (if (= n 0) 0 (+ 1 (deep (- n 1))))))",
+        );
+        run(
+            &mut vm,
+            "(def gen (make-generator (fn () (yield (iter::collect (iter::map [100 200] deep))) (yield (deep 300)))))",
+        );
+        assert_eq!(run(&mut vm, "(gen)"), "(100 200)");
+        assert_eq!(run(&mut vm, "(gen)"), "300");
+    }
+}
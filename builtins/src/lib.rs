@@ -8,11 +8,16 @@ use slvm::{VMError, VMResult, Value};
 pub mod bridge_macro_tests;
 pub mod collections;
 pub mod conversions;
+pub mod coroutine;
+pub mod coverage;
 pub mod fs_meta;
 pub mod fs_temp;
+pub mod gc;
 pub mod io;
+pub mod iter;
 pub mod math;
 pub mod print;
+pub mod profile;
 pub mod rand;
 pub mod string;
 
@@ -169,6 +174,26 @@ fn gensym(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     Ok(Value::Symbol(sym))
 }
 
+fn compute_restarts(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if !registers.is_empty() {
+        return Err(VMError::new_vm(
+            "compute-restarts: takes no arguments".to_string(),
+        ));
+    }
+    Ok(vm.restarts())
+}
+
+fn set_restarts(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if registers.len() != 1 {
+        return Err(VMError::new_vm(
+            "%set-restarts!: takes one argument".to_string(),
+        ));
+    }
+    let old = vm.restarts();
+    vm.set_restarts(registers[0]);
+    Ok(old)
+}
+
 fn expand_macro(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     if registers.len() != 1 {
         return Err(VMError::new_vm(
@@ -231,6 +256,25 @@ pub fn add_misc_builtins(env: &mut SloshVm) {
     env.set_global_builtin("sizeof-value", sizeof_value);
     env.set_global_builtin("gensym", gensym);
     env.set_global_builtin("expand-macro", expand_macro);
+    // Internal, restart-case sets the restarts for its expression (they revert on return).
+    env.set_global_builtin("%set-restarts!", set_restarts);
+    bridge_adapters::add_builtin(
+        env,
+        "compute-restarts",
+        compute_restarts,
+        "Usage: (compute-restarts)
+
+Return the list of active restarts (innermost first), see restart-case.  Each restart is a map
+with :name, :report (a description or nil), :params (the parameter list) and :invoke (a function
+that invokes the restart with its arguments).
+
+Section: core
+
+Example:
+(test::assert-equal nil (compute-restarts))
+(test::assert-equal 'retry (restart-case (let (r (first (compute-restarts))) (r :name)) (retry () nil)))
+",
+    );
     bridge_adapters::add_builtin(
         env,
         "get-globals",
@@ -0,0 +1,87 @@
+use bridge_adapters::add_builtin;
+use compile_state::state::SloshVm;
+use slvm::{Profiler, VMError, VMResult, Value};
+use std::fs;
+
+/// Default file the folded stacks are written to.
+pub const DEFAULT_FOLDED_FILE: &str = "slosh-profile.folded";
+
+/// Number of chunks and lines to include in a printed profile report.
+const REPORT_LIMIT: usize = 25;
+
+/// Print the report for profiler to stderr and write its folded stacks to folded_file.
+pub fn report_profile(profiler: &Profiler, folded_file: &str) -> VMResult<()> {
+    eprint!("{}", profiler.report(REPORT_LIMIT));
+    fs::write(folded_file, profiler.folded_stacks()).map_err(|e| {
+        VMError::new(
+            "io",
+            format!("profile: unable to write folded stacks to {folded_file}: {e}"),
+        )
+    })?;
+    eprintln!("Folded stacks written to {folded_file}");
+    Ok(())
+}
+
+fn call_thunk(vm: &mut SloshVm, thunk: Value) -> VMResult<Value> {
+    match thunk {
+        Value::Lambda(h) => {
+            let l = vm.get_lambda(h);
+            vm.do_call(l, &[], None)
+        }
+        Value::Closure(h) => {
+            let (l, tcaps) = vm.get_closure(h);
+            let caps = Vec::from(tcaps);
+            vm.do_call(l, &[], Some(&caps[..]))
+        }
+        _ => Err(VMError::new_vm(
+            "profile-fn: first argument must be a function with no arguments",
+        )),
+    }
+}
+
+fn profile_fn(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (thunk, folded_file) = match registers {
+        [thunk] => (*thunk, DEFAULT_FOLDED_FILE.to_string()),
+        [thunk, file] => (*thunk, file.get_string(vm)?.to_string()),
+        _ => {
+            return Err(VMError::new_vm(
+                "profile-fn: takes a function and an optional file name",
+            ))
+        }
+    };
+    // If we are nested inside another profile then fold our results into it when done.
+    let outer = vm.start_profiling(Profiler::new());
+    let res = call_thunk(vm, thunk);
+    let profiler = vm.stop_profiling().expect("profiler went missing");
+    let report = report_profile(&profiler, &folded_file);
+    if let Some(mut outer) = outer {
+        outer.merge(profiler);
+        vm.start_profiling(outer);
+    }
+    let res = res?;
+    report?;
+    Ok(res)
+}
+
+pub fn add_profile_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "profile-fn",
+        profile_fn,
+        r#"Usage: (profile-fn (fn () ...) [folded-file])
+
+Call the function (which takes no arguments) with the profiler on and return its result.
+A report of the most expensive chunks and source lines (by sampled time and instructions)
+is printed to stderr and the folded call stacks (for flamegraph tools) are written to
+folded-file (default slosh-profile.folded).  Usually used via the profile macro.
+
+Section: core
+
+Example:
+(with-temp (fn (tmp)
+    (let (folded (get-temp-file tmp))
+        (test::assert-equal 6 (profile-fn (fn () (+ 1 2 3)) folded))
+        (test::assert-true (fs-exists? folded)))))
+"#,
+    );
+}
@@ -191,7 +191,9 @@ pub struct Specials {
     pub str_: Interned,
     pub let_: Interned,
     pub let_while: Interned,
+    pub match_: Interned,
     pub call_cc: Interned,
+    pub yield_: Interned,
     pub defer: Interned,
     pub on_error: Interned,
     pub while_: Interned,
@@ -281,10 +283,19 @@ Example:
 (test::assert-equal "Two" test-do-two)
 (test::assert-equal "Three" test-do-three)
 "#),
-            fn_: add_special(vm, "fn", "Usage: (fn (param*) expr*) -> exprN
+            fn_: add_special(vm, "fn", "Usage: (fn (param*) expr*) -> exprN or (fn ((param*) expr*)+) -> exprN
 
 Create a function (lambda).
 
+Params after % are optional (nil or the value after := if not provided) and a
+param after & gets a list of any remaining args.  Params after &key are keyword
+arguments, they are passed as :name value pairs after the other args and are
+nil (or the value after :=) when not provided.
+
+A function can have multiple arities by providing a list of ((param*) expr*)
+instead of the params.  The first arity that accepts the number of args is
+called and an error listing the valid arities is raised if none do.
+
 Section: core
 
 Example:
@@ -306,7 +317,15 @@ Example:
 (test::assert-equal 12 test-fn1)
 (test::assert-equal 21 test-fn2)
 (test::assert-equal 30 test-fn3)
-(test::assert-equal 63 ((fn (x y z) (set! test-fn1 x)(set! test-fn2 y)(set! test-fn3 z)(+ x y z)) 12 21 30))"),
+(test::assert-equal 63 ((fn (x y z) (set! test-fn1 x)(set! test-fn2 y)(set! test-fn3 z)(+ x y z)) 12 21 30))
+(def test-fn-key (fn (x &key y z := 3) (list x y z)))
+(test::assert-equal '(1 nil 3) (test-fn-key 1))
+(test::assert-equal '(1 2 5) (test-fn-key 1 :z 5 :y 2))
+(def test-fn-multi (fn (() :none) ((x) x) ((x & r) r)))
+(test::assert-equal :none (test-fn-multi))
+(test::assert-equal 1 (test-fn-multi 1))
+(test::assert-equal '(2 3) (test-fn-multi 1 2 3))
+(test::assert-error ((fn ((x) x) ((x y) y)) 1 2 3))"),
             mac_: add_special(vm, "macro", "Usage: (macro (args) `(apply + ,@args))
 
 Define an anonymous macro.
@@ -992,8 +1011,90 @@ Example:
   (test::assert-equal 1 x)
   (test::assert-equal 2 y)
   (test::assert-equal 3 z))
+"#),
+            match_: add_special(vm, "match", r#"Usage: (match expression (pattern [:when guard] form*)*) -> result
+
+Evaluate expression and match it against each pattern in order.  The forms of the first clause
+whose pattern matches (and whose guard, if any, is true) are evaluated in an implicit do with the
+pattern's variables bound.  If nothing matches a :match error with the value is raised (the
+older match macro returned nil), use a _ or nil clause last to provide a default.
+Patterns:
+- _ matches anything.
+- nil as the pattern of a clause matches anything, it is the default clause (use 'nil to match a
+  nil value).
+- symbol matches anything and binds it to the value.
+- literals (numbers, strings, chars, keywords, #t, #f) and 'quoted values match equal values.
+- [pattern* & rest-pattern] matches a vector with a pattern for each item, an optional & pattern
+  matches the rest of the items as a list (or nil).
+- (list pattern* & rest-pattern) is like [] but matches a list.
+- {pattern key*} matches a map that contains each key with its value matching pattern (like a
+  destructuring let).
+- (:Type pattern?) matches a value of Type (see type) and optionally pattern.
+- (or pattern+) matches any of the patterns, each alternative must bind the same symbols.
+
+Section: conditional
+
+Example:
+(def b 0)
+(defn select-option (a)
+    (match a (1 "opt-one")
+             (2 (set! b 5) "opt-two")
+             (3 (str "opt" "-three"))
+             (_ "default")))
+(test::assert-equal "opt-one" (select-option 1))
+(test::assert-equal "opt-two" (select-option 2))
+(test::assert-equal b 5)
+(test::assert-equal "opt-three" (select-option 3))
+(test::assert-equal "default" (select-option 4))
+(test::assert-equal :none (match nil ('nil :none) (nil :default)))
+(test::assert-equal :default (match 1 ('nil :none) (nil :default)))
+(defn shape-area (shape)
+    (match shape
+        ([:circle r] (* 3 r r))
+        ((list :rect w h) (* w h))
+        ({w :w h :h} (* w h))
+        ([:square s & _] (* s s))))
+(test::assert-equal 12 (shape-area [:circle 2]))
+(test::assert-equal 6 (shape-area '(:rect 2 3)))
+(test::assert-equal 8 (shape-area {:w 2 :h 4}))
+(test::assert-equal 9 (shape-area [:square 3 :red]))
+(defn classify (x)
+    (match x
+        ((:Int n) :when (< n 0) :negative)
+        ((or 0 0.0) :zero)
+        ((:Int) :int)
+        ((:String s) (str "string " s))
+        ([] :empty)
+        ([first & rest] rest)))
+(test::assert-equal :negative (classify -5))
+(test::assert-equal :zero (classify 0))
+(test::assert-equal :zero (classify 0.0))
+(test::assert-equal :int (classify 5))
+(test::assert-equal "string abc" (classify "abc"))
+(test::assert-equal :empty (classify []))
+(test::assert-equal '(2 3) (classify [1 2 3]))
+(test::assert-equal :match (car (get-error (classify 1.5))))
 "#),
             call_cc: add_special(vm, "call/cc", ""),
+            yield_: add_special(vm, "yield", r#"Usage: (yield value?) -> resume-value
+
+Suspend the running coroutine (or generator) and return value (nil if not
+provided) from the resume that ran it.  The yield evaluates to the value passed
+to the next resume (nil if none).  The coroutine keeps its own stack so
+yielding and resuming do not copy anything.  It is an error to yield outside a
+coroutine or across a builtin that calls back into lisp (apply for instance).
+
+Section: core
+
+Example:
+(def co (make-coroutine (fn (x) (let (y (yield (+ x 1))) (yield (* y 2)) :done))))
+(test::assert-equal 2 (resume co 1))
+(test::assert-equal 20 (resume co 10))
+(test::assert-equal :done (resume co))
+(test::assert-equal :dead (coroutine-status co))
+(test::assert-error (resume co))
+(test::assert-error (yield 1))
+"#),
             defer: add_special(vm, "defer", ""),
             on_error: add_special(vm, "on-raised-error", r#"Usage: (on-raised-error (fn (error) ...))
 
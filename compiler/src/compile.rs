@@ -9,8 +9,9 @@ use crate::compile::compile_call::{
     compile_call, compile_call_myself, compile_call_reg, compile_callg,
 };
 use crate::compile::compile_cond::{compile_and, compile_if, compile_or, compile_while};
-use crate::compile::compile_fn::compile_fn;
+use crate::compile::compile_fn::{compile_fn, compile_multi_fn, is_multi_arity};
 use crate::compile::compile_let::{compile_let, compile_let_while};
+use crate::compile::compile_match::compile_match;
 use crate::compile::compile_math::compile_math;
 use crate::compile::compile_seq::{compile_cons, compile_vec};
 use crate::compile::compile_store::{compile_def, compile_set};
@@ -21,6 +22,7 @@ mod compile_call;
 mod compile_cond;
 pub mod compile_fn;
 mod compile_let;
+mod compile_match;
 mod compile_math;
 mod compile_seq;
 mod compile_store;
@@ -56,14 +58,18 @@ fn compile_special(
                 }
             }
             Value::Special(i) if i == env.specials().fn_ => {
-                if cdr.len() > 1 {
+                if is_multi_arity(env, cdr) {
+                    compile_multi_fn(env, state, cdr, result, false)?
+                } else if cdr.len() > 1 {
                     compile_fn(env, state, cdr[0], &cdr[1..], result, false)?
                 } else {
                     return Err(VMError::new_compile("Malformed fn form."));
                 }
             }
             Value::Special(i) if i == env.specials().mac_ => {
-                if cdr.len() > 1 {
+                if is_multi_arity(env, cdr) {
+                    compile_multi_fn(env, state, cdr, result, true)?
+                } else if cdr.len() > 1 {
                     compile_fn(env, state, cdr[0], &cdr[1..], result, true)?
                 } else {
                     return Err(VMError::new_compile("Malformed macro form."));
@@ -128,6 +134,8 @@ fn compile_special(
                 compile_call_myself(env, state, cdr, result, false)?
             }
             Value::Special(i) if i == env.specials().eq => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.len() <= 1 {
                     return Err(VMError::new_compile("Requires at least two arguments."));
                 } else {
@@ -144,8 +152,11 @@ fn compile_special(
                         env.own_line(),
                     )?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().equal => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.len() <= 1 {
                     return Err(VMError::new_compile("Requires at least two arguments. 2"));
                 } else {
@@ -162,8 +173,11 @@ fn compile_special(
                         env.own_line(),
                     )?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().type_ => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.len() != 1 {
                     return Err(VMError::new_compile("Requires one argument."));
                 } else {
@@ -175,8 +189,11 @@ fn compile_special(
                         env.own_line(),
                     )?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().not => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.len() != 1 {
                     return Err(VMError::new_compile("Requires one argument."));
                 } else {
@@ -185,8 +202,11 @@ fn compile_special(
                         .chunk
                         .encode2(NOT, result as u16, (result + 1) as u16, env.own_line())?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().err => {
+                let tail = state.tail;
+                state.tail = false;
                 let len = cdr.len();
                 if len != 1 && len != 2 {
                     return Err(VMError::new_compile("Requires one or two arguments."));
@@ -203,6 +223,7 @@ fn compile_special(
                         .chunk
                         .encode2(ERR, result as u16, (result + 1) as u16, env.own_line())?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().and => {
                 compile_and(env, state, cdr, result)?;
@@ -233,6 +254,9 @@ fn compile_special(
             Value::Special(i) if i == env.specials().let_while => {
                 compile_let_while(env, state, cdr, result)?;
             }
+            Value::Special(i) if i == env.specials().match_ => {
+                compile_match(env, state, cdr, result)?;
+            }
             Value::Special(i) if i == env.specials().call_cc => {
                 if cdr.len() != 1 {
                     return Err(VMError::new_compile("Requires one argument."));
@@ -242,6 +266,17 @@ fn compile_special(
                     .chunk
                     .encode2(CCC, result as u16, result as u16, env.own_line())?;
             }
+            Value::Special(i) if i == env.specials().yield_ => {
+                let tail = state.tail;
+                state.tail = false;
+                match cdr.len() {
+                    0 => state.chunk.encode1(REGN, result as u16, env.own_line())?,
+                    1 => compile(env, state, cdr[0], result)?,
+                    _ => return Err(VMError::new_compile("Requires zero or one argument.")),
+                }
+                state.chunk.encode1(YIELD, result as u16, env.own_line())?;
+                state.tail = tail;
+            }
             Value::Special(i) if i == env.specials().defer => {
                 if !cdr.is_empty() {
                     compile_fn(env, state, Value::Nil, &cdr[0..], result, false)?;
@@ -261,9 +296,14 @@ fn compile_special(
                 state.chunk.encode1(ONERR, result as u16, env.own_line())?;
             }
             Value::Special(i) if i == env.specials().get => {
+                let tail = state.tail;
+                state.tail = false;
                 compile_get(env, state, cdr, result)?;
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().mk_err => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.is_empty() || cdr.len() > 2 {
                     return Err(VMError::new_compile(
                         "Wrong number of ars (requires one or two).",
@@ -285,8 +325,11 @@ fn compile_special(
                         env.own_line(),
                     )?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().is_err => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.len() != 1 {
                     return Err(VMError::new_compile("Requires one argument."));
                 } else {
@@ -298,8 +341,11 @@ fn compile_special(
                         env.own_line(),
                     )?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().is_ok => {
+                let tail = state.tail;
+                state.tail = false;
                 if cdr.len() != 1 {
                     return Err(VMError::new_compile("Requires one argument."));
                 } else {
@@ -311,6 +357,7 @@ fn compile_special(
                         env.own_line(),
                     )?;
                 }
+                state.tail = tail;
             }
             Value::Special(i) if i == env.specials().ret => {
                 if cdr.len() != 1 {
@@ -520,6 +567,8 @@ fn compile_list(
         Value::Builtin(builtin) => compile_call(env, state, Value::Builtin(builtin), cdr, result)?,
         Value::Lambda(h) => compile_call(env, state, Value::Lambda(h), cdr, result)?,
         Value::Continuation(h) => compile_call(env, state, Value::Continuation(h), cdr, result)?,
+        Value::Coroutine(h) => compile_call(env, state, Value::Coroutine(h), cdr, result)?,
+        Value::Iter(h) => compile_call(env, state, Value::Iter(h), cdr, result)?,
         Value::Pair(_) | Value::List(_, _) => {
             let (ncar, ncdr) = car.get_pair(env).expect("Pair/List not a Pair or List?");
             if let Value::List(h, idx) = ncdr {
@@ -3,7 +3,8 @@ use crate::compile::util::get_args_iter;
 use crate::pass1::pass1;
 use crate::{compile, CompileState, SloshVm};
 use compile_state::state::SloshVmTrait;
-use slvm::{VMError, VMResult, Value, CLOSE, CONST, JMPNU, MOV, SRET};
+use slvm::vm_hashmap::VMHashMap;
+use slvm::{Arities, Chunk, Interned, VMError, VMResult, Value, CLOSE, CONST, JMPNU, MOV, SRET};
 use std::sync::Arc;
 
 pub fn mk_state(
@@ -20,19 +21,68 @@ pub fn mk_state(
     let mut opt_comps = Vec::new();
     let mut destructures = Vec::new();
     let mut next_is_opt = false;
+    let key_i = env.intern("&key");
+    let mut keys: Option<(usize, Vec<(Interned, Value)>)> = None;
     new_state.chunk.dbg_args = Some(Vec::new());
     let mut total_args = 0_usize;
     for a in args_iter {
         if next_is_opt {
-            opt_comps.pop();
-            opt_comps.push(a);
+            if let Some((_, key_args)) = keys.as_mut() {
+                if let Some((_, default)) = key_args.last_mut() {
+                    *default = a;
+                }
+            } else {
+                opt_comps.pop();
+                opt_comps.push(a);
+            }
             next_is_opt = false;
             continue;
         }
         let a = resolve_destruct_containers(env, a);
+        if let Some((_, key_args)) = keys.as_mut() {
+            match a {
+                Value::Symbol(i) => key_args.push((i, Value::Nil)),
+                Value::Keyword(i) if i == env.specials().equal && !key_args.is_empty() => {
+                    next_is_opt = true
+                }
+                _ => {
+                    return Err(VMError::new_compile(format!(
+                        "invalid args, &key must be followed by symbols (with optional := default) got {}",
+                        a.display_value(env)
+                    )))
+                }
+            }
+            continue;
+        }
         match a {
+            Value::Symbol(i) if i == key_i => {
+                if rest {
+                    return Err(VMError::new_compile(
+                        "invalid args, & and &key can not be combined",
+                    ));
+                }
+                // Keyword args are collected into an unnamed rest arg and destructured from it.
+                rest = true;
+                new_state.symbols.borrow_mut().reserve_reg();
+                if let Some(dbg_args) = new_state.chunk.dbg_args.as_mut() {
+                    dbg_args.push(env.specials().scratch);
+                }
+                if opt {
+                    new_state.chunk.opt_args += 1;
+                    opt_comps.push(Value::Nil);
+                } else {
+                    new_state.chunk.args += 1;
+                }
+                total_args += 1;
+                keys = Some((total_args, Vec::new()));
+            }
             Value::Symbol(i) => {
                 if i == env.specials().rest {
+                    if rest {
+                        return Err(VMError::new_compile(
+                            "invalid args, & and &key can not be combined",
+                        ));
+                    }
                     rest = true;
                 } else if i == env.specials().optional {
                     opt = true;
@@ -99,17 +149,37 @@ pub fn mk_state(
         }
     }
     new_state.chunk.rest = rest;
+    if let Some((reg, key_args)) = keys {
+        let or_i = env.intern("or");
+        let mut map = VMHashMap::new();
+        let mut defaults = VMHashMap::new();
+        for (i, default) in &key_args {
+            let key = Value::Keyword(*i);
+            map.insert(env, Value::Symbol(*i), key);
+            defaults.insert(env, key, *default);
+        }
+        env.pause_gc();
+        let defaults = env.alloc_map(defaults);
+        map.insert(env, Value::Keyword(or_i), defaults);
+        let map = env.alloc_map(map);
+        // Will be unstickied when destructured.
+        env.heap_sticky(map);
+        env.unpause_gc();
+        if let Value::Map(handle) = map {
+            destructures.push(DestructType::Map(handle, reg));
+        }
+        new_state.chunk.key_args = Some(key_args.iter().map(|(i, _)| *i).collect());
+    }
     Ok((new_state, opt_comps, destructures))
 }
 
-pub(crate) fn compile_fn(
+/// Compile a single arity fn (args and body) into a chunk.
+fn fn_chunk(
     env: &mut SloshVm,
     state: &mut CompileState,
     args: Value,
     cdr: &[Value],
-    result: usize,
-    is_macro: bool,
-) -> VMResult<()> {
+) -> VMResult<Chunk> {
     let (mut new_state, opt_comps, destructure_patterns) = mk_state(env, state, args)?;
     for r in cdr.iter() {
         pass1(env, &mut new_state, *r)?;
@@ -146,19 +216,29 @@ pub(crate) fn compile_fn(
     new_state
         .chunk
         .encode1(SRET, reserved as u16, env.own_line())?;
-    let mut closure = false;
     if !new_state.symbols.borrow().captures.borrow().is_empty() {
         let mut caps = Vec::new();
         for (_, _, c) in new_state.symbols.borrow().captures.borrow().iter() {
             caps.push(*c as u32);
         }
         new_state.chunk.captures = Some(caps);
-        closure = true;
     }
     new_state.chunk.input_regs = reserved;
     new_state.chunk.extra_regs = new_state.max_regs - reserved;
+    Ok(new_state.chunk)
+}
+
+/// Put the lambda for chunk into result, closing it if it captures.
+fn emit_lambda(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    chunk: Chunk,
+    result: usize,
+    is_macro: bool,
+) -> VMResult<()> {
+    let closure = chunk.captures.is_some();
     env.pause_gc();
-    let lambda = env.alloc_lambda(Arc::new(new_state.chunk));
+    let lambda = env.alloc_lambda(Arc::new(chunk));
     env.unpause_gc();
     if is_macro {
         // Unwrap safe since we just allocated lambda on the heap.
@@ -175,3 +255,84 @@ pub(crate) fn compile_fn(
     }
     Ok(())
 }
+
+pub(crate) fn compile_fn(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    args: Value,
+    cdr: &[Value],
+    result: usize,
+    is_macro: bool,
+) -> VMResult<()> {
+    let chunk = fn_chunk(env, state, args, cdr)?;
+    emit_lambda(env, state, chunk, result, is_macro)
+}
+
+/// Is this the cdr of a multi-arity fn, ie ((args) body*)+ vs (args) body*?
+pub(crate) fn is_multi_arity(env: &SloshVm, cdr: &[Value]) -> bool {
+    let Some((args, _)) = cdr.first().and_then(|c| c.get_pair(env)) else {
+        return false;
+    };
+    match args {
+        Value::Nil => true,
+        Value::Pair(_) | Value::List(_, _) => {
+            // A destructured first arg will look like (vec ...) or (make-hash ...).
+            !matches!(args.get_pair(env), Some((Value::Symbol(i), _)) if i == env.specials().vec || i == env.specials().make_hash)
+        }
+        _ => false,
+    }
+}
+
+/// Split a multi-arity clause, ((args) body*), into args and body.
+pub(crate) fn arity_clause(env: &SloshVm, clause: Value) -> VMResult<(Value, Vec<Value>)> {
+    match clause.get_pair(env) {
+        Some((args @ (Value::Nil | Value::Pair(_) | Value::List(_, _)), body)) => {
+            let body: Vec<Value> = body.iter(env).collect();
+            if body.is_empty() {
+                Err(VMError::new_compile(
+                    "Malformed fn form, arity has no body.",
+                ))
+            } else {
+                Ok((args, body))
+            }
+        }
+        _ => Err(VMError::new_compile(format!(
+            "Malformed fn form, expected ((args) body*) for each arity got {}",
+            clause.display_value(env)
+        ))),
+    }
+}
+
+/// Compile a multi-arity fn, the lambda for each arity becomes a constant of a chunk that only
+/// dispatches on the number of arguments (see make_call in the vm).
+pub(crate) fn compile_multi_fn(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    is_macro: bool,
+) -> VMResult<()> {
+    let line = env.own_line().unwrap_or(1);
+    let mut chunk = Chunk::new(state.chunk.file_name, line);
+    let mut arities = Vec::with_capacity(cdr.len());
+    for clause in cdr {
+        let (args, body) = arity_clause(env, *clause)?;
+        arities.push(Arc::new(fn_chunk(env, state, args, &body)?));
+    }
+    let mut caps = Vec::new();
+    env.pause_gc();
+    for arity in &arities {
+        if let Some(arity_caps) = &arity.captures {
+            caps.extend_from_slice(arity_caps);
+        }
+        // The lambdas are constants so the GC keeps the arities alive.
+        let lambda = env.alloc_lambda(arity.clone());
+        chunk.add_constant(lambda);
+    }
+    env.unpause_gc();
+    if !caps.is_empty() {
+        chunk.captures = Some(caps);
+    }
+    chunk.arities = Some(Arities::new(arities));
+    emit_lambda(env, state, chunk, result, is_macro)
+}
@@ -0,0 +1,615 @@
+use compile_state::state::{CompileState, Symbols};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use slvm::opcodes::*;
+use slvm::{Interned, VMError, VMResult, Value};
+
+use crate::compile::destructure::{resolve_destruct_containers, setup_dbg};
+use crate::{compile, mkconst, SloshVm, SloshVmTrait};
+
+/// A match pattern, parsed from the reader form.
+enum Pattern {
+    Wildcard,
+    Bind(Interned),
+    Literal(Value),
+    /// Sequence pattern, the vector or list types it matches, element patterns and optional rest.
+    Seq(&'static [&'static str], Vec<Pattern>, Option<Box<Pattern>>),
+    /// Map pattern, (pattern, key) pairs.
+    Map(Vec<(Pattern, Value)>),
+    /// Type keyword and pattern for the value.
+    Type(Value, Box<Pattern>),
+    Or(Vec<Pattern>),
+}
+
+const VECTOR_TYPES: &[&str] = &["Vector"];
+const LIST_TYPES: &[&str] = &["Pair", "Nil"];
+const MAP_TYPES: &[&str] = &["Map"];
+
+fn parse_seq(env: &mut SloshVm, items: &[Value]) -> VMResult<(Vec<Pattern>, Option<Box<Pattern>>)> {
+    let mut pats = Vec::new();
+    let mut items = items.iter();
+    while let Some(item) = items.next() {
+        match item {
+            Value::Symbol(i) if *i == env.specials().rest => {
+                let rest = if let Some(rest) = items.next() {
+                    parse_pattern(env, *rest)?
+                } else {
+                    return Err(VMError::new_compile(
+                        "match: & must be followed by a pattern",
+                    ));
+                };
+                if items.next().is_some() {
+                    return Err(VMError::new_compile("match: only one pattern can follow &"));
+                }
+                return Ok((pats, Some(Box::new(rest))));
+            }
+            Value::Symbol(i) if *i == env.specials().optional => {
+                return Err(VMError::new_compile(
+                    "match: optionals (%) are not valid in a pattern",
+                ));
+            }
+            _ => pats.push(parse_pattern(env, *item)?),
+        }
+    }
+    Ok((pats, None))
+}
+
+fn parse_pattern(env: &mut SloshVm, pattern: Value) -> VMResult<Pattern> {
+    let wildcard = env.intern("_");
+    let pattern = resolve_destruct_containers(env, pattern);
+    match pattern {
+        Value::Symbol(i) if i == wildcard => Ok(Pattern::Wildcard),
+        Value::Symbol(i) => Ok(Pattern::Bind(i)),
+        Value::Vector(h) => {
+            let items = env.get_vector(h).to_vec();
+            env.heap_unsticky(pattern);
+            let (pats, rest) = parse_seq(env, &items)?;
+            Ok(Pattern::Seq(VECTOR_TYPES, pats, rest))
+        }
+        Value::Map(h) => {
+            let items: Vec<(Value, Value)> = env.get_map(h).iter().collect();
+            env.heap_unsticky(pattern);
+            let mut pats = Vec::new();
+            for (pat, key) in items {
+                pats.push((parse_pattern(env, pat)?, key));
+            }
+            Ok(Pattern::Map(pats))
+        }
+        Value::Pair(_) | Value::List(_, _) => {
+            let items: Vec<Value> = pattern.iter(env).collect();
+            match items[0] {
+                Value::Symbol(i) if i == env.specials().quote && items.len() == 2 => {
+                    Ok(Pattern::Literal(items[1]))
+                }
+                Value::Symbol(i) if i == env.specials().list => {
+                    let (pats, rest) = parse_seq(env, &items[1..])?;
+                    Ok(Pattern::Seq(LIST_TYPES, pats, rest))
+                }
+                Value::Symbol(i) if i == env.specials().or && items.len() > 1 => {
+                    let mut alts = Vec::new();
+                    for alt in &items[1..] {
+                        alts.push(parse_pattern(env, *alt)?);
+                    }
+                    let names = bound_names(&alts[0]);
+                    if alts[1..].iter().any(|alt| bound_names(alt) != names) {
+                        return Err(VMError::new_compile(
+                            "match: or pattern alternatives must bind the same names",
+                        ));
+                    }
+                    Ok(Pattern::Or(alts))
+                }
+                Value::Keyword(_) if items.len() <= 2 => {
+                    let pat = if let Some(pat) = items.get(1) {
+                        parse_pattern(env, *pat)?
+                    } else {
+                        Pattern::Wildcard
+                    };
+                    Ok(Pattern::Type(items[0], Box::new(pat)))
+                }
+                _ => Err(VMError::new_compile(format!(
+                    "match: invalid pattern {}",
+                    pattern.display_value(env)
+                ))),
+            }
+        }
+        Value::True
+        | Value::False
+        | Value::Nil
+        | Value::Byte(_)
+        | Value::Int(_)
+        | Value::Float(_)
+        | Value::CodePoint(_)
+        | Value::CharCluster(_, _)
+        | Value::CharClusterLong(_)
+        | Value::Keyword(_)
+        | Value::StringConst(_)
+        | Value::String(_) => Ok(Pattern::Literal(pattern)),
+        _ => Err(VMError::new_compile(format!(
+            "match: invalid pattern {}",
+            pattern.display_value(env)
+        ))),
+    }
+}
+
+fn add_bound_names(pattern: &Pattern, names: &mut HashSet<Interned>) {
+    match pattern {
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+        Pattern::Bind(i) => {
+            names.insert(*i);
+        }
+        Pattern::Seq(_, pats, rest) => {
+            for pat in pats {
+                add_bound_names(pat, names);
+            }
+            if let Some(rest) = rest {
+                add_bound_names(rest, names);
+            }
+        }
+        Pattern::Map(pats) => {
+            for (pat, _) in pats {
+                add_bound_names(pat, names);
+            }
+        }
+        Pattern::Type(_, pat) => add_bound_names(pat, names),
+        // Alternatives all bind the same names (checked when parsed).
+        Pattern::Or(alts) => add_bound_names(&alts[0], names),
+    }
+}
+
+fn bound_names(pattern: &Pattern) -> HashSet<Interned> {
+    let mut names = HashSet::new();
+    add_bound_names(pattern, &mut names);
+    names
+}
+
+/// Compiles the tests and bindings for the patterns of one match clause.
+struct PatternCompiler {
+    symbols: Rc<RefCell<Symbols>>,
+    /// Jump (index) taken when the pattern does not match.
+    fail: usize,
+    /// Names bound by the pattern so far, or alternatives reuse these registers.
+    bindings: HashMap<Interned, usize>,
+}
+
+impl PatternCompiler {
+    fn reserve(&self, count: usize) -> usize {
+        let mut symbols = self.symbols.borrow_mut();
+        let start = symbols.reserve_reg();
+        for _ in 1..count {
+            symbols.reserve_reg();
+        }
+        start
+    }
+
+    /// Jump to fail unless the type of the value in reg is one of types.
+    fn type_test(
+        &self,
+        env: &mut SloshVm,
+        state: &mut CompileState,
+        types: &[Value],
+        reg: usize,
+    ) -> VMResult<()> {
+        let type_reg = self.reserve(3);
+        let ok = state.chunk.add_jump(0);
+        state
+            .chunk
+            .encode2(TYPE, type_reg as u16, reg as u16, env.own_line())?;
+        for t in types {
+            mkconst(env, state, *t, type_reg + 1)?;
+            state.chunk.encode3(
+                EQ,
+                (type_reg + 2) as u16,
+                type_reg as u16,
+                (type_reg + 1) as u16,
+                env.own_line(),
+            )?;
+            state
+                .chunk
+                .encode2(JMPT, (type_reg + 2) as u16, ok as u16, env.own_line())?;
+        }
+        state.chunk.encode1(JMP, self.fail as u16, env.own_line())?;
+        state.chunk.update_jump(ok, state.chunk.code.len() as u32);
+        Ok(())
+    }
+
+    fn type_names(env: &mut SloshVm, names: &[&'static str]) -> Vec<Value> {
+        names
+            .iter()
+            .map(|name| Value::Keyword(env.intern_static(name)))
+            .collect()
+    }
+
+    fn compile_seq(
+        &mut self,
+        env: &mut SloshVm,
+        state: &mut CompileState,
+        pats: &[Pattern],
+        rest: &Option<Box<Pattern>>,
+        reg: usize,
+    ) -> VMResult<()> {
+        // One more register than patterns for either the rest or to detect extra items.
+        let len = pats.len() + 1;
+        let start = self.reserve(len);
+        let op = if rest.is_some() { LDSCR } else { LDSC };
+        state
+            .chunk
+            .encode3(op, start as u16, len as u16, reg as u16, env.own_line())?;
+        if rest.is_none() {
+            state.chunk.encode2(
+                JMPNU,
+                (start + pats.len()) as u16,
+                self.fail as u16,
+                env.own_line(),
+            )?;
+        }
+        if !pats.is_empty() {
+            state.chunk.encode3(
+                JMPRU,
+                start as u16,
+                pats.len() as u16,
+                self.fail as u16,
+                env.own_line(),
+            )?;
+        }
+        for (i, pat) in pats.iter().enumerate() {
+            self.compile_pattern(env, state, pat, start + i)?;
+        }
+        if let Some(rest) = rest {
+            self.compile_pattern(env, state, rest, start + pats.len())?;
+        }
+        Ok(())
+    }
+
+    fn compile_pattern(
+        &mut self,
+        env: &mut SloshVm,
+        state: &mut CompileState,
+        pattern: &Pattern,
+        reg: usize,
+    ) -> VMResult<()> {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Bind(i) => {
+                let bind_reg = if let Some(bind_reg) = self.bindings.get(i) {
+                    *bind_reg
+                } else {
+                    let bind_reg = self.symbols.borrow_mut().insert(*i);
+                    if let Some(lets) = &mut state.lets {
+                        lets.insert(*i, bind_reg);
+                    }
+                    setup_dbg(env, state, bind_reg, *i);
+                    self.bindings.insert(*i, bind_reg);
+                    bind_reg
+                };
+                state
+                    .chunk
+                    .encode2(MOV, bind_reg as u16, reg as u16, env.own_line())?;
+            }
+            Pattern::Literal(val) => {
+                let test_reg = self.reserve(2);
+                state
+                    .chunk
+                    .encode2(MOV, test_reg as u16, reg as u16, env.own_line())?;
+                mkconst(env, state, *val, test_reg + 1)?;
+                // EQUAL treats nil and #f as the same, these must be the identical value.
+                let op = match val {
+                    Value::True | Value::False | Value::Nil | Value::Keyword(_) => EQ,
+                    _ => EQUAL,
+                };
+                state.chunk.encode3(
+                    op,
+                    test_reg as u16,
+                    test_reg as u16,
+                    (test_reg + 1) as u16,
+                    env.own_line(),
+                )?;
+                state
+                    .chunk
+                    .encode2(JMPF, test_reg as u16, self.fail as u16, env.own_line())?;
+            }
+            Pattern::Seq(types, pats, rest) => {
+                let types = Self::type_names(env, types);
+                self.type_test(env, state, &types, reg)?;
+                self.compile_seq(env, state, pats, rest, reg)?;
+            }
+            Pattern::Map(pats) => {
+                let types = Self::type_names(env, MAP_TYPES);
+                self.type_test(env, state, &types, reg)?;
+                if !pats.is_empty() {
+                    let start = self.reserve(pats.len());
+                    for (i, (_, key)) in pats.iter().enumerate() {
+                        compile(env, state, *key, start + i)?;
+                    }
+                    state.chunk.encode3(
+                        MDSC,
+                        start as u16,
+                        pats.len() as u16,
+                        reg as u16,
+                        env.own_line(),
+                    )?;
+                    state.chunk.encode3(
+                        JMPRU,
+                        start as u16,
+                        pats.len() as u16,
+                        self.fail as u16,
+                        env.own_line(),
+                    )?;
+                    for (i, (pat, _)) in pats.iter().enumerate() {
+                        self.compile_pattern(env, state, pat, start + i)?;
+                    }
+                }
+            }
+            Pattern::Type(t, pat) => {
+                self.type_test(env, state, &[*t], reg)?;
+                self.compile_pattern(env, state, pat, reg)?;
+            }
+            Pattern::Or(alts) => {
+                let fail = self.fail;
+                let ok = state.chunk.add_jump(0);
+                for (i, alt) in alts.iter().enumerate() {
+                    if i + 1 < alts.len() {
+                        let next = state.chunk.add_jump(0);
+                        self.fail = next;
+                        self.compile_pattern(env, state, alt, reg)?;
+                        state.chunk.encode1(JMP, ok as u16, env.own_line())?;
+                        state.chunk.update_jump(next, state.chunk.code.len() as u32);
+                    } else {
+                        self.fail = fail;
+                        self.compile_pattern(env, state, alt, reg)?;
+                    }
+                }
+                state.chunk.update_jump(ok, state.chunk.code.len() as u32);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compile a clause, (pattern [:when guard] body*), jumping to done after the body.
+fn compile_clause(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    clause: Value,
+    val_reg: usize,
+    result: usize,
+    done: usize,
+    tail: bool,
+) -> VMResult<usize> {
+    let items: Vec<Value> = match clause {
+        Value::Pair(_) | Value::List(_, _) => clause.iter(env).collect(),
+        _ => {
+            return Err(VMError::new_compile(
+                "match: clause must be a list, (pattern [:when guard] body*)",
+            ))
+        }
+    };
+    // A nil pattern is the default clause (as in the match macro this replaced), 'nil matches nil.
+    let pattern = if items[0].is_nil() {
+        Pattern::Wildcard
+    } else {
+        parse_pattern(env, items[0])?
+    };
+    let mut body = &items[1..];
+    let guard = match body.first() {
+        Some(Value::Keyword(i)) if *i == env.intern("when") => {
+            if let Some(guard) = body.get(1) {
+                body = &body[2..];
+                Some(*guard)
+            } else {
+                return Err(VMError::new_compile(
+                    "match: :when must be followed by a guard",
+                ));
+            }
+        }
+        _ => None,
+    };
+
+    let symbols = Rc::new(RefCell::new(Symbols::with_let(state.symbols.clone())));
+    state.symbols = symbols.clone();
+    state.lets = Some(HashMap::new());
+    let start_defers = state.defers;
+    let mut pattern_compiler = PatternCompiler {
+        symbols: symbols.clone(),
+        fail: state.chunk.add_jump(0),
+        bindings: HashMap::new(),
+    };
+    pattern_compiler.compile_pattern(env, state, &pattern, val_reg)?;
+    let fail = pattern_compiler.fail;
+    if let Some(guard) = guard {
+        let guard_reg = state.reserved_regs();
+        compile(env, state, guard, guard_reg)?;
+        state
+            .chunk
+            .encode2(JMPF, guard_reg as u16, fail as u16, env.own_line())?;
+    }
+    let free_reg = state.reserved_regs();
+    if body.is_empty() {
+        state.chunk.encode1(REGN, free_reg as u16, env.own_line())?;
+    }
+    for (i, r) in body.iter().enumerate() {
+        if i + 1 == body.len() {
+            state.tail = tail;
+        }
+        compile(env, state, *r, free_reg)?;
+        state.tail = false;
+    }
+    state
+        .chunk
+        .encode2(MOV, result as u16, free_reg as u16, env.own_line())?;
+    for _ in start_defers..state.defers {
+        state.chunk.encode0(DFRPOP, env.own_line())?;
+    }
+    state.defers = start_defers;
+    state.chunk.encode1(JMP, done as u16, env.own_line())?;
+    state.chunk.update_jump(fail, state.chunk.code.len() as u32);
+    let regs_count = symbols.borrow().regs_count();
+    if state.max_regs < regs_count {
+        state.max_regs = regs_count;
+    }
+    Ok(regs_count)
+}
+
+fn match_inner(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+    tail: bool,
+) -> VMResult<()> {
+    let outer_symbols = state.symbols.clone();
+    let mut first_reg = outer_symbols.borrow().regs_count();
+    while first_reg <= result {
+        // Make sure we do not step on the result or any other regs in temp use below it.
+        first_reg = outer_symbols.borrow_mut().reserve_reg();
+    }
+    let val_reg = outer_symbols.borrow_mut().reserve_reg();
+    compile(env, state, cdr[0], val_reg)?;
+    let done = state.chunk.add_jump(0);
+    let mut max_reg = val_reg + 1;
+    for clause in &cdr[1..] {
+        let clause_max = compile_clause(env, state, *clause, val_reg, result, done, tail)?;
+        state.symbols = outer_symbols.clone();
+        if clause_max > max_reg {
+            max_reg = clause_max;
+        }
+    }
+    // No clause matched, raise a :match error with the value.
+    let err_reg = outer_symbols.borrow().regs_count();
+    let kw = Value::Keyword(env.intern("match"));
+    let msg = Value::StringConst(env.intern("no match for value: "));
+    mkconst(env, state, kw, err_reg)?;
+    mkconst(env, state, msg, err_reg + 2)?;
+    state
+        .chunk
+        .encode2(MOV, (err_reg + 3) as u16, val_reg as u16, env.own_line())?;
+    state.chunk.encode3(
+        STR,
+        (err_reg + 1) as u16,
+        (err_reg + 2) as u16,
+        (err_reg + 3) as u16,
+        env.own_line(),
+    )?;
+    state
+        .chunk
+        .encode2(ERR, err_reg as u16, (err_reg + 1) as u16, env.own_line())?;
+    if state.max_regs < err_reg + 3 {
+        state.max_regs = err_reg + 3;
+    }
+    state.chunk.update_jump(done, state.chunk.code.len() as u32);
+    for i in first_reg..max_reg {
+        if i != result {
+            state.chunk.encode1(CLRREG, i as u16, env.own_line())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn compile_match(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    cdr: &[Value],
+    result: usize,
+) -> VMResult<()> {
+    if cdr.is_empty() {
+        return Err(VMError::new_compile(
+            "Too few arguments, need at least 1 got 0.",
+        ));
+    }
+    let old_symbols = state.symbols.clone();
+    let old_tail = state.tail;
+    let old_lets = state.lets.take();
+    state.symbols = Rc::new(RefCell::new(Symbols::with_let(old_symbols.clone())));
+    state.tail = false;
+    let old_defers = state.defers;
+    let result = match_inner(env, state, cdr, result, old_tail);
+    state.tail = old_tail;
+    state.symbols = old_symbols;
+    state.defers = old_defers;
+    state.lets = old_lets;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{assert_vals, exec, exec_compile_error, exec_runtime_error, read_test};
+    use compile_state::state::new_slosh_vm;
+
+    fn assert_match(code: &'static str, expected: &'static str) {
+        let mut env = new_slosh_vm();
+        let result = exec(&mut env, code);
+        let expected = read_test(&mut env, expected);
+        assert_vals(&env, expected, result);
+    }
+
+    #[test]
+    fn test_match_literals() {
+        assert_match("(match 2 (1 :one) (2 :two) (_ :other))", ":two");
+        assert_match("(match 3 (1 :one) (2 :two) (_ :other))", ":other");
+        assert_match("(match \"b\" (\"a\" 1) (\"b\" 2))", "2");
+        assert_match("(match :k (:j 1) (:k 2))", "2");
+        assert_match("(match nil (#f 1) ('nil 2))", "2");
+        assert_match("(match #f ('nil 1) (_ 2))", "2");
+        assert_match("(match 'foo ('bar 1) ('foo 2))", "2");
+        assert_match("(match '(1 2) ('(1 2) :quoted))", ":quoted");
+        assert_match("(match 1 (1))", "nil");
+    }
+
+    #[test]
+    fn test_match_nil_default() {
+        assert_match("(match 4 (1 :one) (2 :two) (nil :default))", ":default");
+        assert_match("(match nil (nil :default) ('nil :nil))", ":default");
+        assert_match("(match [1] ([a] (list a nil)) (nil :default))", "(1 nil)");
+        // Only the whole pattern of a clause is a default, nested nil is a literal.
+        assert_match("(match [2] ([nil] :nil) (_ :other))", ":other");
+        assert_match("(match '(nil) ((list nil) :nil) (_ :other))", ":nil");
+    }
+
+    #[test]
+    fn test_match_destructure() {
+        assert_match("(match [1 2] ([a] :one) ([a b] (+ a b)))", "3");
+        assert_match("(match [1 2 3] ([a & r] r))", "(2 3)");
+        assert_match("(match [1] ([a & r] r))", "nil");
+        assert_match("(match [] ([a & r] :some) ([] :empty))", ":empty");
+        assert_match("(match '(1 2) ([a b] :vec) ((list a b) :list))", ":list");
+        assert_match("(match nil ((list) :empty))", ":empty");
+        assert_match("(match {:a 1} ({a :a b :b} :both) ({a :a} a))", "1");
+        assert_match(
+            "(match {:a [1 [2 3]]} ({[x [y z]] :a} (list x y z)))",
+            "(1 2 3)",
+        );
+        assert_match(
+            "(do (def f (fn (l acc) (match l ((list) acc) ((list x & r) (recur r (+ acc x)))))) (f '(1 2 3 4) 0))",
+            "10",
+        );
+        assert_match("((match [1 2] ([a b] (fn () (list a b)))))", "(1 2)");
+        assert_match("(let (x 10) (match 3 (x (+ x 1))) x)", "10");
+    }
+
+    #[test]
+    fn test_match_types_guards_or() {
+        assert_match("(match 1.5 ((:Int) :int) ((:Float f) f))", "1.5");
+        assert_match(
+            "(match [1 [2 3]] ([a (:Vector [b c])] (list a b c)))",
+            "(1 2 3)",
+        );
+        assert_match(
+            "(match 5 (n :when (> n 10) :big) (n :when (> n 3) :mid) (_ :small))",
+            ":mid",
+        );
+        assert_match("(match [:y 6] ((or [:x v] [:y v]) v))", "6");
+        assert_match("(match 0.0 ((or 0 0.0) :zero) (_ :other))", ":zero");
+    }
+
+    #[test]
+    fn test_match_errors() {
+        let mut env = new_slosh_vm();
+        exec_runtime_error(&mut env, "(match 42 (\"a\" 1))");
+        exec_runtime_error(&mut env, "(match [1 2 3] ([a b] 1))");
+        exec_compile_error(&mut env, "(match 1 ((or [:x v] [:y w]) 1))");
+        exec_compile_error(&mut env, "(match 1 ([a %] 1))");
+        exec_compile_error(&mut env, "(match 1 ((foo a) 1))");
+        exec_compile_error(&mut env, "(match 1 (a :when))");
+    }
+}
@@ -97,6 +97,21 @@ pub(crate) fn compile_math(
     car: Value,
     cdr: &[Value],
     result: usize,
+) -> VMResult<bool> {
+    // The math forms use the values of their arguments so none of them are tail calls.
+    let tail = state.tail;
+    state.tail = false;
+    let res = compile_math_form(env, state, car, cdr, result);
+    state.tail = tail;
+    res
+}
+
+fn compile_math_form(
+    env: &mut SloshVm,
+    state: &mut CompileState,
+    car: Value,
+    cdr: &[Value],
+    result: usize,
 ) -> VMResult<bool> {
     match car {
         Value::Special(i) if i == env.specials().inc => {
@@ -1,7 +1,12 @@
 #[cfg(test)]
 mod tests {
-    use compile_state::state::new_slosh_vm;
-    use compiler_test_utils::{assert_vals, exec, read_test};
+    use crate::{compile, CompileState};
+    use compile_state::state::{new_slosh_vm, SloshVmTrait};
+    use compiler_test_utils::{
+        assert_vals, exec, exec_compile_error, exec_runtime_error, read_test,
+    };
+    use slvm::{Value, RET, STACK_CAP};
+    use std::sync::Arc;
 
     #[test]
     fn test_def_set() {
@@ -153,6 +158,112 @@ mod tests {
         assert_vals(&env, expected, result);
     }
 
+    #[test]
+    fn test_fn_multi_arity() {
+        let mut env = new_slosh_vm();
+        let result = exec(
+            &mut env,
+            "(do (def fnx (fn (() 0) ((x) x) ((x y) (list x y)) ((x y & r) r))) (list (fnx) (fnx 1) (fnx 1 2) (fnx 1 2 3 4)))",
+        );
+        let expected = read_test(&mut env, "(0 1 (1 2) (3 4))");
+        assert_vals(&env, expected, result);
+
+        let result = exec(
+            &mut env,
+            "(do (def fnx (fn ((n) (fnx n 0)) ((n acc) (if (= n 0) acc (recur (- n 1) (+ acc n)))))) (fnx 4))",
+        );
+        let expected = read_test(&mut env, "10");
+        assert_vals(&env, expected, result);
+
+        let result = exec(
+            &mut env,
+            "(do (def fnx (let (a 1 b 2) (fn ((x) (list a x)) ((x y) (list b x y))))) (list (fnx 3) (fnx 3 4)))",
+        );
+        let expected = read_test(&mut env, "((1 3) (2 3 4))");
+        assert_vals(&env, expected, result);
+
+        let result = exec(
+            &mut env,
+            "(do (def fnx (fn (([a b]) (list b a)) (([a b] c) c))) (list (fnx [1 2]) (fnx [1 2] 3)))",
+        );
+        let expected = read_test(&mut env, "((2 1) 3)");
+        assert_vals(&env, expected, result);
+
+        exec_runtime_error(&mut env, "((fn ((x) x) ((x y) y)) 1 2 3)");
+        exec_compile_error(&mut env, "(fn ((x) x) (x))");
+    }
+
+    #[test]
+    fn test_fn_multi_arity_captures() {
+        let mut env = new_slosh_vm();
+        // Only a later arity captures, and arities after one with captures.
+        let result = exec(
+            &mut env,
+            "(do (def fnx (let (a 1 b 2 c 3) (fn (() 0) ((x) x) ((x y) (list a b x y)) ((x y z) (list c x y z))))) (list (fnx) (fnx 4) (fnx 4 5) (fnx 4 5 6)))",
+        );
+        let expected = read_test(&mut env, "(0 4 (1 2 4 5) (3 4 5 6))");
+        assert_vals(&env, expected, result);
+
+        // Captures are shared with the enclosing scope.
+        let result = exec(
+            &mut env,
+            "(do (def fnx (let (a 1) (fn ((x) (set! a x)) ((x y) (list a x y))))) (fnx 7) (fnx 8 9))",
+        );
+        let expected = read_test(&mut env, "(7 8 9)");
+        assert_vals(&env, expected, result);
+
+        // recur stays in its own arity, in a closure and with a rest arity after it.
+        let result = exec(
+            &mut env,
+            "(do (def fnx (let (step 2) (fn ((n) (fnx n 0)) ((n acc) (if (<= n 0) acc (recur (- n step) (+ acc n)))) ((n acc & r) r)))) (list (fnx 6) (fnx 5 1) (fnx 1 2 3)))",
+        );
+        let expected = read_test(&mut env, "(12 10 (3))");
+        assert_vals(&env, expected, result);
+    }
+
+    #[test]
+    fn test_fn_multi_arity_error() {
+        let mut env = new_slosh_vm();
+        let mut arity_error = |code: &'static str| {
+            let exp = read_test(&mut env, code);
+            let mut state = CompileState::new();
+            compile(&mut env, &mut state, exp, 0).unwrap();
+            state.chunk.encode0(RET, Some(1)).unwrap();
+            let err = env.execute(Arc::new(state.chunk)).unwrap_err();
+            env.reset();
+            err.to_string()
+        };
+        assert_eq!(
+            arity_error("((fn ((x) x) ((x y) y)) 1 2 3)"),
+            "[rt]: Wrong number of arguments (3), valid arities: 1, 2."
+        );
+        assert_eq!(
+            arity_error("((fn ((x % y) x) ((a b c d & r) r)) 1 2 3)"),
+            "[rt]: Wrong number of arguments (3), valid arities: 1-2, 4+."
+        );
+    }
+
+    #[test]
+    fn test_fn_key_args() {
+        let mut env = new_slosh_vm();
+        let result = exec(
+            &mut env,
+            "(do (def fnx (fn (a &key b c := 10) (list a b c))) (list (fnx 1) (fnx 1 :c 3) (fnx 1 :c 4 :b 2)))",
+        );
+        let expected = read_test(&mut env, "((1 nil 10) (1 nil 3) (1 2 4))");
+        assert_vals(&env, expected, result);
+
+        let result = exec(
+            &mut env,
+            "(do (def fnx (fn (a % b := 2 &key c := (+ a b)) (list a b c))) (list (fnx 1) (fnx 1 3 :c 5)))",
+        );
+        let expected = read_test(&mut env, "((1 2 3) (1 3 5))");
+        assert_vals(&env, expected, result);
+
+        exec_compile_error(&mut env, "(fn (a & r &key b) a)");
+        exec_compile_error(&mut env, "(fn (a &key :b) a)");
+    }
+
     #[test]
     fn test_captures() {
         let mut env = new_slosh_vm();
@@ -182,6 +293,136 @@ mod tests {
         assert_vals(&env, expected, result);
     }
 
+    #[test]
+    fn test_tail_call_collection_captures() {
+        let mut env = new_slosh_vm();
+        // A tail call to a collection (m k) returns straight to the caller, it must clear the
+        // lambda's registers like SRET does.  Otherwise the caller's next CONST writes through the
+        // capture boxes left behind and changes d and m.
+        let result = exec(
+            &mut env,
+            "(def mk (fn (d m) (fn (k) (d) (m :a) (m k))))(def fnx (mk (fn () 1) {:a 1, :b 2}))(list (fnx :a) 3 (fnx :b) 4)",
+        );
+        let expected = read_test(&mut env, "(1 3 2 4)");
+        assert_vals(&env, expected, result);
+    }
+
+    #[test]
+    fn test_math_args_not_tail() {
+        let mut env = new_slosh_vm();
+        // (id x) in an argument position must return to the math form, not out of the lambda.
+        exec(&mut env, "(def id (fn (y) y))");
+        for (form, expected) in [
+            ("(+ 1 (id x))", "3"),
+            ("(- 1 (id x))", "-1"),
+            ("(* 3 (id x))", "6"),
+            ("(/ 4 (id x))", "2"),
+            ("(== 0 (id x))", "#f"),
+            ("(< 3 (id x))", "#f"),
+            ("(<= 3 (id x))", "#f"),
+            ("(> 1 (id x))", "#f"),
+            ("(>= 1 (id x))", "#f"),
+        ] {
+            let result = exec(&mut env, format!("((fn (x) {form}) 2)"));
+            let expected = read_test(&mut env, expected);
+            assert_vals(&env, expected, result);
+        }
+
+        // The tail flag is restored after a math form, this would overflow the stack otherwise.
+        let result = exec(
+            &mut env,
+            "(def count-down (fn (n) (if (> n 0) (count-down (- n 1)) (+ n 0))))(count-down 100000)",
+        );
+        let expected = read_test(&mut env, "0");
+        assert_vals(&env, expected, result);
+    }
+
+    #[test]
+    fn test_special_args_not_tail() {
+        let mut env = new_slosh_vm();
+        exec(&mut env, "(def id (fn (y) y))");
+        for (form, expected) in [
+            ("(identical? 1 (id x))", "#f"),
+            ("(= 1 (id x))", "#f"),
+            ("(type (id x))", ":Int"),
+            ("(not (id x))", "#f"),
+            ("(get [1 2 3] (id x))", "3"),
+            ("(err? (id x))", "#f"),
+            ("(ok? (id x))", "#t"),
+        ] {
+            let result = exec(&mut env, format!("((fn (x) {form}) 2)"));
+            let expected = read_test(&mut env, expected);
+            assert_vals(&env, expected, result);
+        }
+        let result = exec(&mut env, "(err? ((fn (x) (mk-err :test (id x))) 2))");
+        let expected = read_test(&mut env, "#t");
+        assert_vals(&env, expected, result);
+        exec_runtime_error(&mut env, "((fn (x) (err :test (id x))) 2)");
+    }
+
+    #[test]
+    fn test_coroutine() {
+        let mut env = new_slosh_vm();
+
+        let lambda = exec(
+            &mut env,
+            "(fn (x) (let (inner (fn (y) (yield y))) (let (z (inner (+ x 1))) (inner (* z 2)) :done)))",
+        );
+        let co = env.make_coroutine(lambda, false).unwrap();
+        env.set_named_global("co", co);
+        let result = exec(&mut env, "(list (co 1) (co 10) (co) 3)");
+        let expected = read_test(&mut env, "(2 20 :done 3)");
+        assert_vals(&env, expected, result);
+        exec_runtime_error(&mut env, "(co)");
+        exec_runtime_error(&mut env, "(yield 1)");
+
+        let lambda = exec(&mut env, "(fn () (yield 1) (yield 2))");
+        let generator = env.make_coroutine(lambda, true).unwrap();
+        env.set_named_global("gen", generator);
+        let result = exec(&mut env, "(list (gen) (gen) (gen) (gen))");
+        let expected = read_test(&mut env, "(1 2 :*iter-empty* :*iter-empty*)");
+        assert_vals(&env, expected, result);
+    }
+
+    #[test]
+    fn test_coroutine_stack() {
+        let mut env = new_slosh_vm();
+
+        // Lots of short lived generators only get small stacks.
+        let lambda = exec(&mut env, "(fn (x) (yield x) (yield (+ x 1)))");
+        env.set_named_global("gen-fn", lambda);
+        for i in 0..10_000 {
+            let Value::Coroutine(h) = env.make_coroutine(lambda, true).unwrap() else {
+                panic!("not a coroutine");
+            };
+            let result = env.resume(h, Some(i.into())).unwrap();
+            assert_eq!(result.get_int(&env).unwrap(), i);
+            assert!(env.get_coroutine(h).stack.len() < STACK_CAP);
+        }
+
+        // The stack grows for deeper calls and keeps its contents across a yield.
+        exec(
+            &mut env,
+            "(def deep (fn (n) (if (= n 0) (yield :bottom) (+ 1 (deep (- n 1))))))",
+        );
+        let lambda = exec(&mut env, "(fn () (deep 200))");
+        let co = env.make_coroutine(lambda, false).unwrap();
+        env.set_named_global("co", co);
+        let result = exec(&mut env, "(list (co) (co 0))");
+        let expected = read_test(&mut env, "(:bottom 200)");
+        assert_vals(&env, expected, result);
+
+        // Running out of stack is an error, in a coroutine or not.
+        let lambda = exec(&mut env, "(fn () (deep 100000))");
+        let co = env.make_coroutine(lambda, false).unwrap();
+        env.set_named_global("co", co);
+        exec_runtime_error(&mut env, "(co)");
+        exec_runtime_error(&mut env, "(deep 100000)");
+        let result = exec(&mut env, "(+ 1 2)");
+        let expected = read_test(&mut env, "3");
+        assert_vals(&env, expected, result);
+    }
+
     #[test]
     fn test_on_raised_error() {
         let mut env = new_slosh_vm();
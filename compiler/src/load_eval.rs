@@ -298,7 +298,7 @@ fn run_script(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     r
 }
 
-fn eval_exp(vm: &mut SloshVm, exp: Value) -> VMResult<Value> {
+pub fn eval_exp(vm: &mut SloshVm, exp: Value) -> VMResult<Value> {
     let line_num = 1;
     let mut state = CompileState::new_state("none/eval", line_num, None);
     state.chunk.dbg_args = Some(Vec::new());
@@ -415,6 +415,12 @@ pub fn apply_callable(vm: &mut SloshVm, lambda: Value, args: &[Value]) -> VMResu
             vm.heap_unsticky(exp);
             res
         }
+        Value::Iter(handle) if args.is_empty() => vm.iter_next(handle),
+        Value::Coroutine(handle) => match args {
+            [] => vm.resume(handle, None),
+            [arg] => vm.resume(handle, Some(*arg)),
+            _ => Err(VMError::new_vm("Coroutine takes zero or one argument.")),
+        },
         Value::Value(handle) => {
             // Need to deref and try again.
             apply_callable(vm, vm.get_value(handle), args)
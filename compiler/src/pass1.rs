@@ -1,4 +1,4 @@
-use crate::compile_fn::mk_state;
+use crate::compile_fn::{arity_clause, is_multi_arity, mk_state};
 use crate::{CompileState, SloshVm};
 use slvm::{from_i56, VMResult, Value};
 
@@ -13,7 +13,15 @@ pub fn pass1(env: &mut SloshVm, state: &mut CompileState, exp: Value) -> VMResul
                 if i == fn_ || i == mac_ {
                     // XXX boo on this collect.
                     let cdr = cdr.iter(env).collect::<Vec<Value>>();
-                    if !cdr.is_empty() {
+                    if is_multi_arity(env, &cdr) {
+                        for clause in cdr {
+                            let (args, body) = arity_clause(env, clause)?;
+                            let (mut new_state, _, _) = mk_state(env, state, args)?;
+                            for r in body {
+                                pass1(env, &mut new_state, r)?;
+                            }
+                        }
+                    } else if !cdr.is_empty() {
                         let (mut new_state, _, _) = mk_state(env, state, cdr[0])?;
                         for r in cdr[1..].iter() {
                             pass1(env, &mut new_state, *r)?;
@@ -52,6 +60,8 @@ pub fn pass1(env: &mut SloshVm, state: &mut CompileState, exp: Value) -> VMResul
         Value::Lambda(_) => {}
         Value::Closure(_) => {}
         Value::Continuation(_) => {}
+        Value::Coroutine(_) => {}
+        Value::Iter(_) => {}
         Value::CallFrame(_) => {}
         Value::Value(_) => {}
 
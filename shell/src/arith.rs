@@ -0,0 +1,571 @@
+//! Shell arithmetic, the expression inside $((..)).
+//! Expressions are parsed with the rest of the line and evaluated (reading and assigning
+//! variables) when the arg is expanded.  All math is on 64 bit signed integers.
+
+use crate::command_data::unbound_variable;
+use crate::jobs::Jobs;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A binary arithmetic operator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+    Comma,
+}
+
+impl ArithOp {
+    /// The operator as written in an expression.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::Pow => "**",
+            Self::Shl => "<<",
+            Self::Shr => ">>",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::BitAnd => "&",
+            Self::BitXor => "^",
+            Self::BitOr => "|",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::Comma => ",",
+        }
+    }
+
+    fn from_symbol(symbol: &str) -> Option<Self> {
+        Some(match symbol {
+            "+" => Self::Add,
+            "-" => Self::Sub,
+            "*" => Self::Mul,
+            "/" => Self::Div,
+            "%" => Self::Rem,
+            "**" => Self::Pow,
+            "<<" => Self::Shl,
+            ">>" => Self::Shr,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            "&" => Self::BitAnd,
+            "^" => Self::BitXor,
+            "|" => Self::BitOr,
+            "&&" => Self::And,
+            "||" => Self::Or,
+            "," => Self::Comma,
+            _ => return None,
+        })
+    }
+
+    /// Binding power, higher binds tighter.
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Comma => 1,
+            Self::Or => 4,
+            Self::And => 5,
+            Self::BitOr => 6,
+            Self::BitXor => 7,
+            Self::BitAnd => 8,
+            Self::Eq | Self::Ne => 9,
+            Self::Lt | Self::Le | Self::Gt | Self::Ge => 10,
+            Self::Shl | Self::Shr => 11,
+            Self::Add | Self::Sub => 12,
+            Self::Mul | Self::Div | Self::Rem => 13,
+            Self::Pow => 14,
+        }
+    }
+
+    fn apply(&self, left: i64, right: i64) -> io::Result<i64> {
+        Ok(match self {
+            Self::Add => left.wrapping_add(right),
+            Self::Sub => left.wrapping_sub(right),
+            Self::Mul => left.wrapping_mul(right),
+            Self::Div | Self::Rem if right == 0 => {
+                return Err(io::Error::new(ErrorKind::Other, "division by 0"))
+            }
+            Self::Div => left.wrapping_div(right),
+            Self::Rem => left.wrapping_rem(right),
+            Self::Pow => {
+                if right < 0 {
+                    return Err(io::Error::new(ErrorKind::Other, "exponent less than 0"));
+                }
+                left.wrapping_pow(right.try_into().unwrap_or(u32::MAX))
+            }
+            Self::Shl => left.wrapping_shl(right as u32),
+            Self::Shr => left.wrapping_shr(right as u32),
+            Self::Lt => (left < right) as i64,
+            Self::Le => (left <= right) as i64,
+            Self::Gt => (left > right) as i64,
+            Self::Ge => (left >= right) as i64,
+            Self::Eq => (left == right) as i64,
+            Self::Ne => (left != right) as i64,
+            Self::BitAnd => left & right,
+            Self::BitXor => left ^ right,
+            Self::BitOr => left | right,
+            Self::And => (left != 0 && right != 0) as i64,
+            Self::Or => (left != 0 || right != 0) as i64,
+            Self::Comma => right,
+        })
+    }
+}
+
+/// A parsed arithmetic expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArithExpr {
+    /// Integer literal.
+    Num(i64),
+    /// Variable reference, unset or empty is 0.
+    Var(String),
+    /// An expression in parentheses.
+    Group(Box<ArithExpr>),
+    /// Unary -, +, ! or ~.
+    Unary(char, Box<ArithExpr>),
+    /// Binary operator.
+    Binary(ArithOp, Box<ArithExpr>, Box<ArithExpr>),
+    /// Assignment to a variable, the op is set for compound assignments (+= etc).
+    Assign(String, Option<ArithOp>, Box<ArithExpr>),
+    /// Increment (++) or decrement (--), true if postfix.
+    IncDec(String, i64, bool),
+    /// cond ? then : else
+    Cond(Box<ArithExpr>, Box<ArithExpr>, Box<ArithExpr>),
+}
+
+impl Display for ArithExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Num(n) => write!(f, "{n}"),
+            Self::Var(name) => write!(f, "{name}"),
+            Self::Group(expr) => write!(f, "({expr})"),
+            // Keep - -x from becoming the decrement --x.
+            Self::Unary(op, expr)
+                if matches!(**expr, Self::Unary(_, _) | Self::IncDec(_, _, false)) =>
+            {
+                write!(f, "{op} {expr}")
+            }
+            Self::Unary(op, expr) => write!(f, "{op}{expr}"),
+            Self::Binary(ArithOp::Comma, left, right) => write!(f, "{left}, {right}"),
+            Self::Binary(op, left, right) => write!(f, "{left} {} {right}", op.symbol()),
+            Self::Assign(name, None, expr) => write!(f, "{name} = {expr}"),
+            Self::Assign(name, Some(op), expr) => write!(f, "{name} {}= {expr}", op.symbol()),
+            Self::IncDec(name, delta, post) => {
+                let op = if *delta > 0 { "++" } else { "--" };
+                if *post {
+                    write!(f, "{name}{op}")
+                } else {
+                    write!(f, "{op}{name}")
+                }
+            }
+            Self::Cond(cond, then, els) => write!(f, "{cond} ? {then} : {els}"),
+        }
+    }
+}
+
+fn get_var(name: &str, jobs: &Jobs) -> io::Result<i64> {
+    let val = match jobs.get_env_or_local_var(name.as_ref()) {
+        Some(val) => val,
+        None if jobs.options().nounset => return Err(unbound_variable(name.as_ref())),
+        None => OsString::new(),
+    };
+    let val = val.to_string_lossy();
+    let val = val.trim();
+    if val.is_empty() {
+        return Ok(0);
+    }
+    let (neg, digits) = if let Some(digits) = val.strip_prefix('-') {
+        (true, digits)
+    } else {
+        (false, val.strip_prefix('+').unwrap_or(val))
+    };
+    match parse_number(digits) {
+        Some(n) if neg => Ok(n.wrapping_neg()),
+        Some(n) => Ok(n),
+        None => Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{name}: value is not a number: {val}"),
+        )),
+    }
+}
+
+/// Set a var, an exported var stays in the environment otherwise it is a local var.
+fn set_var(name: &str, val: i64, jobs: &mut Jobs) {
+    let val: OsString = val.to_string().into();
+    if env::var_os(name).is_some() {
+        env::set_var(name, val);
+    } else {
+        jobs.set_local_var(name.into(), val);
+    }
+}
+
+impl ArithExpr {
+    /// Evaluate the expression, assignments update variables in jobs.
+    pub fn eval(&self, jobs: &mut Jobs) -> io::Result<i64> {
+        match self {
+            Self::Num(n) => Ok(*n),
+            Self::Var(name) => get_var(name, jobs),
+            Self::Group(expr) => expr.eval(jobs),
+            Self::Unary(op, expr) => {
+                let val = expr.eval(jobs)?;
+                Ok(match op {
+                    '-' => val.wrapping_neg(),
+                    '!' => (val == 0) as i64,
+                    '~' => !val,
+                    _ => val,
+                })
+            }
+            Self::Binary(ArithOp::And, left, right) => {
+                Ok((left.eval(jobs)? != 0 && right.eval(jobs)? != 0) as i64)
+            }
+            Self::Binary(ArithOp::Or, left, right) => {
+                Ok((left.eval(jobs)? != 0 || right.eval(jobs)? != 0) as i64)
+            }
+            Self::Binary(op, left, right) => {
+                let left = left.eval(jobs)?;
+                op.apply(left, right.eval(jobs)?)
+            }
+            Self::Assign(name, op, expr) => {
+                let val = expr.eval(jobs)?;
+                let val = if let Some(op) = op {
+                    op.apply(get_var(name, jobs)?, val)?
+                } else {
+                    val
+                };
+                set_var(name, val, jobs);
+                Ok(val)
+            }
+            Self::IncDec(name, delta, post) => {
+                let old = get_var(name, jobs)?;
+                let new = old.wrapping_add(*delta);
+                set_var(name, new, jobs);
+                Ok(if *post { old } else { new })
+            }
+            Self::Cond(cond, then, els) => {
+                if cond.eval(jobs)? != 0 {
+                    then.eval(jobs)
+                } else {
+                    els.eval(jobs)
+                }
+            }
+        }
+    }
+}
+
+/// Parse an integer literal, decimal, hex (0x) or octal (leading 0).
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if text.len() > 1 && text.starts_with('0') {
+        i64::from_str_radix(&text[1..], 8).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn malformed(msg: impl Display) -> io::Error {
+    io::Error::new(ErrorKind::Other, format!("arithmetic syntax error: {msg}"))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Longest first so the longest operator is matched.
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "=", "?", ":", "(", ")", ",",
+];
+
+fn read_name(chars: &mut Peekable<Chars>) -> String {
+    let mut name = String::new();
+    while let Some(ch) = chars.peek().copied() {
+        if ch.is_ascii_alphanumeric() || ch == '_' {
+            name.push(ch);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    name
+}
+
+fn tokenize(text: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.peek().copied() {
+        if ch.is_whitespace() {
+            chars.next();
+        } else if ch.is_ascii_digit() {
+            let literal = read_name(&mut chars);
+            let num = parse_number(&literal)
+                .ok_or_else(|| malformed(format!("invalid number {literal}")))?;
+            tokens.push(Token::Num(num));
+        } else if ch.is_ascii_alphabetic() || ch == '_' {
+            tokens.push(Token::Name(read_name(&mut chars)));
+        } else if ch == '$' {
+            // $NAME and ${NAME} are the same as NAME.
+            chars.next();
+            let braced = chars.next_if_eq(&'{').is_some();
+            let name = read_name(&mut chars);
+            if name.is_empty() || (braced && chars.next() != Some('}')) {
+                return Err(malformed("bad variable reference"));
+            }
+            tokens.push(Token::Name(name));
+        } else {
+            let rest = chars.clone().collect::<String>();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| malformed(format!("unexpected {ch}")))?;
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+/// A literal 0 divisor is an error when parsing instead of when evaluating.
+fn check_divisor(op: ArithOp, right: &ArithExpr) -> io::Result<()> {
+    if matches!(op, ArithOp::Div | ArithOp::Rem) && *right == ArithExpr::Num(0) {
+        Err(malformed("division by 0"))
+    } else {
+        Ok(())
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+const ASSIGN_PRECEDENCE: u8 = 2;
+const COND_PRECEDENCE: u8 = 3;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> io::Result<()> {
+        match self.next() {
+            Some(Token::Op(next)) if next == op => Ok(()),
+            _ => Err(malformed(format!("expected {op}"))),
+        }
+    }
+
+    fn prefix(&mut self) -> io::Result<ArithExpr> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(ArithExpr::Num(n)),
+            Some(Token::Name(name)) => match self.peek() {
+                Some(Token::Op(op @ ("++" | "--"))) => {
+                    let delta = if *op == "++" { 1 } else { -1 };
+                    self.pos += 1;
+                    Ok(ArithExpr::IncDec(name, delta, true))
+                }
+                _ => Ok(ArithExpr::Var(name)),
+            },
+            Some(Token::Op("(")) => {
+                let expr = self.expr(0)?;
+                self.expect(")")?;
+                Ok(ArithExpr::Group(Box::new(expr)))
+            }
+            Some(Token::Op(op @ ("++" | "--"))) => match self.next() {
+                Some(Token::Name(name)) => Ok(ArithExpr::IncDec(
+                    name,
+                    if op == "++" { 1 } else { -1 },
+                    false,
+                )),
+                _ => Err(malformed(format!("{op} requires a variable"))),
+            },
+            Some(Token::Op(op @ ("-" | "+" | "!" | "~"))) => {
+                let op = op.chars().next().expect("operator is not empty");
+                Ok(ArithExpr::Unary(op, Box::new(self.prefix()?)))
+            }
+            Some(Token::Op(op)) => Err(malformed(format!("unexpected {op}"))),
+            None => Err(malformed("missing operand")),
+        }
+    }
+
+    fn expr(&mut self, min_precedence: u8) -> io::Result<ArithExpr> {
+        let mut left = self.prefix()?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            if op == "?" {
+                if COND_PRECEDENCE < min_precedence {
+                    break;
+                }
+                self.pos += 1;
+                let then = self.expr(0)?;
+                self.expect(":")?;
+                let els = self.expr(COND_PRECEDENCE)?;
+                left = ArithExpr::Cond(Box::new(left), Box::new(then), Box::new(els));
+            } else if op.len() > 1 && op.ends_with('=') && !matches!(op, "<=" | ">=" | "==" | "!=")
+                || op == "="
+            {
+                if ASSIGN_PRECEDENCE < min_precedence {
+                    break;
+                }
+                let ArithExpr::Var(name) = left else {
+                    return Err(malformed(format!("{op} requires a variable")));
+                };
+                self.pos += 1;
+                let bin_op = ArithOp::from_symbol(&op[..op.len() - 1]);
+                let right = self.expr(ASSIGN_PRECEDENCE)?;
+                if let Some(bin_op) = bin_op {
+                    check_divisor(bin_op, &right)?;
+                }
+                left = ArithExpr::Assign(name, bin_op, Box::new(right));
+            } else if let Some(bin_op) = ArithOp::from_symbol(op) {
+                let precedence = bin_op.precedence();
+                if precedence < min_precedence {
+                    break;
+                }
+                self.pos += 1;
+                // ** is right associative, the rest are left associative.
+                let next_min = if bin_op == ArithOp::Pow {
+                    precedence
+                } else {
+                    precedence + 1
+                };
+                let right = self.expr(next_min)?;
+                check_divisor(bin_op, &right)?;
+                left = ArithExpr::Binary(bin_op, Box::new(left), Box::new(right));
+            } else {
+                break;
+            }
+        }
+        Ok(left)
+    }
+}
+
+/// Parse the text of an arithmetic expression (the part between $(( and ))).
+pub fn parse_arith(text: &str) -> io::Result<ArithExpr> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let expr = parser.expr(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Token::Op(op)) => Err(malformed(format!("unexpected {op}"))),
+        Some(_) => Err(malformed("missing operator")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, jobs: &mut Jobs) -> i64 {
+        parse_arith(text).unwrap().eval(jobs).unwrap()
+    }
+
+    #[test]
+    fn test_arith() {
+        let mut jobs = Jobs::new(false);
+        assert_eq!(eval("1 + 2 * 3", &mut jobs), 7);
+        assert_eq!(eval("(1 + 2) * 3", &mut jobs), 9);
+        assert_eq!(eval("10 - 4 - 3", &mut jobs), 3);
+        assert_eq!(eval("2 ** 3 ** 2", &mut jobs), 512);
+        assert_eq!(eval("-2 ** 2", &mut jobs), 4);
+        assert_eq!(eval("7 / 2 + 7 % 2", &mut jobs), 4);
+        assert_eq!(eval("0x10 + 010 + 1 << 2", &mut jobs), 100);
+        assert_eq!(eval("5 & 3 | 8 ^ 1", &mut jobs), 9);
+        assert_eq!(eval("~0 + !0 + !5", &mut jobs), 0);
+        assert_eq!(
+            eval("1 < 2 && 2 <= 2 && 3 > 2 && 3 >= 4 || 1 == 1", &mut jobs),
+            1
+        );
+        assert_eq!(eval("1 != 1 ? 10 : 2 > 1 ? 20 : 30", &mut jobs), 20);
+        assert_eq!(eval("1, 2, 3", &mut jobs), 3);
+        assert_eq!(eval("ARITH_TEST_UNSET", &mut jobs), 0);
+
+        assert_eq!(eval("x = 5", &mut jobs), 5);
+        assert_eq!(eval("x += 2 * 3", &mut jobs), 11);
+        assert_eq!(eval("x <<= 1", &mut jobs), 22);
+        assert_eq!(eval("x++ + 0", &mut jobs), 22);
+        assert_eq!(eval("++x", &mut jobs), 24);
+        assert_eq!(eval("x--", &mut jobs), 24);
+        assert_eq!(eval("$x + ${x}", &mut jobs), 46);
+        assert_eq!(eval("y = z = x / 2", &mut jobs), 11);
+        assert_eq!(jobs.get_local_var("y".as_ref()), Some("11".as_ref()));
+        assert_eq!(jobs.get_local_var("z".as_ref()), Some("11".as_ref()));
+        // Short circuit does not assign.
+        assert_eq!(eval("0 && (y = 1)", &mut jobs), 0);
+        assert_eq!(eval("1 ? y : (y = 2)", &mut jobs), 11);
+
+        jobs.set_local_var("bad".into(), "abc".into());
+        assert!(parse_arith("bad + 1").unwrap().eval(&mut jobs).is_err());
+        assert!(parse_arith("1 / (x - x)").unwrap().eval(&mut jobs).is_err());
+        assert!(parse_arith("2 ** (x - 24)")
+            .unwrap()
+            .eval(&mut jobs)
+            .is_err());
+        assert!(parse_arith("2 ** -1").unwrap().eval(&mut jobs).is_err());
+        for bad in [
+            "1 +", "(1", "1 2", "3 = 4", "++1", "1 ? 2", "08", "1 @ 2", "", "1 % 0", "x / 0",
+            "x /= 0", "x %= 00",
+        ] {
+            assert!(parse_arith(bad).is_err(), "{bad} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_arith_display() {
+        for text in [
+            "1 + 2 * (3 - x)",
+            "x += y++ - --z",
+            "- -x + -(1)",
+            "a ? b : c ? d : e",
+            "!x || ~y && 1 << 2",
+            "x = 1, y = 2",
+        ] {
+            let expr = parse_arith(text).unwrap();
+            assert_eq!(expr.to_string(), text);
+            assert_eq!(parse_arith(&expr.to_string()).unwrap(), expr);
+        }
+    }
+}
@@ -1,48 +1,77 @@
-use shell::config::get_config;
+use shell::config::{get_config, Config};
 use shell::jobs::Jobs;
-use shell::platform::{Platform, Sys, STDIN_FILENO};
-use shell::run::{run_one_command, setup_shell_tty};
+use shell::platform::{FromFileDesc, Platform, Sys, STDIN_FILENO};
+use shell::run::{run_lines, run_one_command, setup_shell_tty};
+use shell::signals::TrapSignal;
 use sl_liner::Prompt;
 use std::env;
 use std::ffi::OsString;
-use std::io::ErrorKind;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::mem::ManuallyDrop;
 
 fn main() {
-    if let Some(config) = get_config() {
-        let shell_terminal = STDIN_FILENO;
-        // See if we are running interactively.
-        let is_tty = Sys::is_tty(shell_terminal);
-        if config.command.is_none() && config.script.is_none() {
-            if is_tty {
-                setup_shell_tty(shell_terminal);
-                let code = start_interactive();
-                std::process::exit(code);
-            } else {
-                // No tty, just read stdin and do something with it..
-                /* XXXX TODO
-                jobs.reap_procs();
-                if let Err(err) = run_one_command(&res, &mut jobs) {
-                    eprintln!("ERROR executing {res}: {err}");
-                }
-                std::process::exit(code);
-                */
-            }
-        } else if config.command.is_some() {
-            let mut command = config.command.unwrap();
-            command.push(' ');
-            command.push_str(&config.args.join(" "));
-            let mut jobs = Jobs::new(false);
+    let status = if let Some(config) = get_config() {
+        run(config)
+    } else {
+        0
+    };
+    std::process::exit(status);
+}
 
-            /* Put ourselves in our own process group.  */
-            if let Err(err) = Sys::set_self_pgroup() {
-                eprintln!("Couldn't put the shell in its own process group: {err}")
-            }
+/// Run the shell for config and return the exit status.
+fn run(config: Config) -> i32 {
+    let shell_terminal = STDIN_FILENO;
+    // See if we are running interactively.
+    let is_tty = Sys::is_tty(shell_terminal);
+    let exe_name = env::args_os().next().unwrap_or_else(|| "shell".into());
+    if let Some(command) = config.command {
+        // -c command [name [args...]], name is $0 and args the positional parameters.
+        let mut args = config.args.into_iter().map(OsString::from);
+        let name = args.next().unwrap_or(exe_name);
+        let mut jobs = Jobs::new(false);
+        jobs.set_positional(name, args.collect());
 
-            if let Err(err) = run_one_command(&command, &mut jobs) {
-                eprintln!("Error running {}: {}", command, err);
-                return;
-            }
+        /* Put ourselves in our own process group.  */
+        if let Err(err) = Sys::set_self_pgroup() {
+            eprintln!("Couldn't put the shell in its own process group: {err}")
         }
+
+        let status = run_one_command(&command, &mut jobs).unwrap_or_else(|err| {
+            eprintln!("Error running {}: {}", command, err);
+            1
+        });
+        jobs.reap_procs();
+        jobs.run_trap(TrapSignal::Exit);
+        status
+    } else if let Some(script) = config.script {
+        let file = match File::open(&script) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("{script}: {err}");
+                return 127;
+            }
+        };
+        let mut jobs = Jobs::new(false);
+        jobs.set_positional(
+            script.into(),
+            config.args.into_iter().map(|a| a.into()).collect(),
+        );
+        let status = run_lines(BufReader::new(file), &mut jobs);
+        jobs.run_trap(TrapSignal::Exit);
+        status
+    } else if is_tty {
+        setup_shell_tty(shell_terminal);
+        start_interactive()
+    } else {
+        // No tty, read commands from stdin.
+        // Read a byte at a time so commands that read stdin get the input after their line.
+        let stdin = ManuallyDrop::new(unsafe { File::from_file_desc(STDIN_FILENO) });
+        let mut jobs = Jobs::new(false);
+        jobs.set_positional(exe_name, vec![]);
+        let status = run_lines(BufReader::with_capacity(1, &*stdin), &mut jobs);
+        jobs.run_trap(TrapSignal::Exit);
+        status
     }
 }
 
@@ -71,6 +100,7 @@ pub fn start_interactive() -> i32 {
         eprintln!("Error loading history: {e}");
     }
     let mut jobs = Jobs::new(true);
+    let mut status = 0;
     loop {
         let res = match con.read_line(Prompt::from("shell> "), None) {
             Ok(input) => input,
@@ -95,9 +125,11 @@ pub fn start_interactive() -> i32 {
 
         con.history.push(&res).expect("Failed to push history.");
         jobs.reap_procs();
-        if let Err(err) = run_one_command(&res, &mut jobs) {
+        status = run_one_command(&res, &mut jobs).unwrap_or_else(|err| {
             eprintln!("ERROR executing {res}: {err}");
-        }
+            1
+        });
     }
-    0
+    jobs.run_trap(TrapSignal::Exit);
+    status
 }
@@ -1,10 +1,14 @@
 use crate::command_data::Arg;
-use crate::jobs::Jobs;
-use crate::platform::{Platform, RLimit, RLimitVals, Sys};
+use crate::dirs;
+use crate::jobs::{Job, JobStatus, Jobs, ShellOptions, Trap};
+use crate::platform::{OsSignal, Pid, Platform, RLimit, RLimitVals, Sys};
+use crate::signals::TrapSignal;
 use std::collections::HashSet;
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::path::{Path, PathBuf};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 fn set_arg_flags<S: AsRef<str>>(
     args: &mut HashSet<char>,
@@ -128,41 +132,6 @@ where
     0
 }
 
-pub fn cd(arg: Option<PathBuf>) -> i32 {
-    let home: OsString = match env::var_os("HOME") {
-        Some(val) => val,
-        None => "/".into(),
-    };
-    let old_dir: OsString = match env::var_os("OLDPWD") {
-        Some(val) => val,
-        None => home.clone(),
-    };
-    let new_dir: PathBuf = match arg {
-        Some(arg) => arg,
-        None => home.into(),
-    };
-    let new_dir = if new_dir.as_os_str() == "-" {
-        old_dir.into()
-    } else {
-        new_dir
-    };
-    let new_dir = cd_expand_all_dots(new_dir);
-    let root = Path::new(&new_dir);
-    if let Ok(oldpwd) = env::current_dir() {
-        env::set_var("OLDPWD", oldpwd);
-    }
-    if let Err(e) = env::set_current_dir(root) {
-        eprintln!("cd: Error changing to {}, {}", root.display(), e);
-        -1
-    } else {
-        match env::current_dir() {
-            Ok(dir) => env::set_var("PWD", dir),
-            Err(err) => eprintln!("cd: error setting PWD: {err}"),
-        }
-        0
-    }
-}
-
 /// If path start with HOME then replace with ~.
 pub fn compress_tilde(path: &str) -> Option<String> {
     if let Ok(mut home) = env::var("HOME") {
@@ -179,31 +148,6 @@ pub fn compress_tilde(path: &str) -> Option<String> {
     }
 }
 
-fn cd_expand_all_dots(cd: PathBuf) -> PathBuf {
-    let mut all_dots = false;
-    let cd_ref = cd.to_string_lossy();
-    if cd_ref.len() > 2 {
-        all_dots = true;
-        for ch in cd_ref.chars() {
-            if ch != '.' {
-                all_dots = false;
-                break;
-            }
-        }
-    }
-    if all_dots {
-        let mut new_cd = OsString::new();
-        let paths_up = cd_ref.len() - 2;
-        new_cd.push("../");
-        for _i in 0..paths_up {
-            new_cd.push("../");
-        }
-        new_cd.into()
-    } else {
-        cd
-    }
-}
-
 fn export(key: OsString, val: OsString) -> i32 {
     let key_str = key.to_string_lossy();
     if key.is_empty() || key_str.contains('=') || key_str.contains('\0') {
@@ -272,6 +216,363 @@ fn set_var(jobs: &mut Jobs, key: OsString, val: OsString) -> i32 {
     0
 }
 
+/// Job id for a job builtin argument, a job spec or job number (the current job if None).
+fn job_arg(arg: Option<&OsStr>, jobs: &Jobs) -> Result<u32, String> {
+    let arg = arg.map(|a| a.to_string_lossy());
+    match arg.as_deref() {
+        None => jobs
+            .current_job()
+            .ok_or_else(|| "no current job".to_string()),
+        Some(arg) if arg.starts_with('%') => jobs.find_job(arg),
+        Some(arg) => match arg.parse() {
+            Ok(job_num) if jobs.job_ids().contains(&job_num) => Ok(job_num),
+            _ => Err(format!("{arg}: no such job")),
+        },
+    }
+}
+
+/// Job id for a wait or kill argument, a job spec or the pid of a process in a job.
+fn job_or_pid_arg(arg: &OsStr, jobs: &Jobs) -> Result<u32, String> {
+    let arg = arg.to_string_lossy();
+    if arg.starts_with('%') {
+        jobs.find_job(&arg)
+    } else {
+        let pid = Pid::from_str(&arg).map_err(|_| format!("{arg}: not a pid or job spec"))?;
+        jobs.job_for_pid(pid)
+            .ok_or_else(|| format!("pid {pid} is not a child of this shell"))
+    }
+}
+
+/// The jobs builtin, -l also lists the pids and -p only lists the process group ids.
+/// Finished jobs are reported (and removed) first.
+fn list_jobs<I>(args: I, jobs: &mut Jobs) -> i32
+where
+    I: Iterator<Item = OsString>,
+{
+    jobs.reap_procs();
+    let mut long = false;
+    let mut pids_only = false;
+    let mut ids = vec![];
+    for arg in args {
+        match arg.to_str() {
+            Some("-l") => long = true,
+            Some("-p") => pids_only = true,
+            _ => match job_arg(Some(&arg), jobs) {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    eprintln!("jobs: {err}");
+                    return 1;
+                }
+            },
+        }
+    }
+    if ids.is_empty() {
+        ids = jobs.job_ids();
+    }
+    for id in ids {
+        if let Some(job) = jobs.get_job(id) {
+            if pids_only {
+                println!("{}", job.pgid());
+            } else if long {
+                let pids: Vec<String> = job.pids().iter().map(|s| s.pid().to_string()).collect();
+                println!("{} ({})", jobs.job_line(job), pids.join(" "));
+            } else {
+                println!("{}", jobs.job_line(job));
+            }
+        }
+    }
+    0
+}
+
+/// The wait builtin, waits for the jobs (or jobs containing pids) in args or all jobs if no
+/// args.  Returns the status of the last job waited for.
+fn wait<I>(args: I, jobs: &mut Jobs) -> i32
+where
+    I: Iterator<Item = OsString>,
+{
+    let args: Vec<OsString> = args.collect();
+    if args.is_empty() {
+        for id in jobs.job_ids() {
+            jobs.wait_job(id);
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in args {
+        status = match job_or_pid_arg(&arg, jobs) {
+            Ok(id) => jobs.wait_job(id).unwrap_or(-66),
+            Err(err) => {
+                eprintln!("wait: {err}");
+                127
+            }
+        };
+    }
+    status
+}
+
+/// The kill builtin, kill [-s SIGNAL | -n NUM | -SIGNAL] job_spec|pid... or kill -l.
+fn kill<I>(args: I, jobs: &mut Jobs) -> i32
+where
+    I: Iterator<Item = OsString>,
+{
+    let mut args = args.peekable();
+    let mut signal = Sys::parse_signal("TERM");
+    match args.peek().and_then(|a| a.to_str()) {
+        Some("-l") | Some("-L") => {
+            for (name, num) in Sys::signal_names() {
+                println!("{num:>2}) {name}");
+            }
+            return 0;
+        }
+        Some("-s") | Some("-n") => {
+            args.next();
+            signal = args
+                .next()
+                .and_then(|s| Sys::parse_signal(&s.to_string_lossy()));
+        }
+        Some(arg) if arg.starts_with('-') => {
+            signal = Sys::parse_signal(&arg[1..]);
+            args.next();
+        }
+        _ => {}
+    }
+    let Some(signal) = signal else {
+        eprintln!("kill: invalid signal");
+        return 1;
+    };
+    let cont = Sys::parse_signal("CONT");
+    let mut status = 0;
+    let mut has_arg = false;
+    for arg in args {
+        has_arg = true;
+        let arg_str = arg.to_string_lossy();
+        let result = if arg_str.starts_with('%') {
+            match jobs.find_job(&arg_str) {
+                Ok(id) => {
+                    let job = jobs.get_job(id).expect("job from find_job");
+                    let stopped = job.status() == JobStatus::Stopped;
+                    let result = kill_job(job, signal);
+                    // A stopped job will not see the signal until it is continued.
+                    match (result, cont) {
+                        (Ok(()), Some(cont)) if stopped && signal != cont => {
+                            kill_job(job, cont).map_err(|e| format!("{arg_str}: {e}"))
+                        }
+                        (result, _) => result.map_err(|e| format!("{arg_str}: {e}")),
+                    }
+                }
+                Err(err) => Err(err),
+            }
+        } else {
+            match Pid::from_str(&arg_str) {
+                Ok(pid) => {
+                    Sys::send_signal(pid, signal, false).map_err(|e| format!("{arg_str}: {e}"))
+                }
+                Err(_) => Err(format!("{arg_str}: not a pid or job spec")),
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("kill: {err}");
+            status = 1;
+        }
+    }
+    if !has_arg {
+        eprintln!("kill: kill [-s SIGNAL | -n NUM | -SIGNAL] pid|job_spec... or kill -l");
+        return 1;
+    }
+    status
+}
+
+/// Send signal to a job, to the process group if the job has its own.
+fn kill_job(job: &Job, signal: OsSignal) -> Result<(), io::Error> {
+    // Non-interactive jobs share the shell's process group so signal each process.
+    if job.interactive() && job.pgid() != job.shell_pid() {
+        Sys::send_signal(job.pgid(), signal, true)
+    } else {
+        for pid in job.pids() {
+            Sys::send_signal(pid.pid(), signal, false)?;
+        }
+        Ok(())
+    }
+}
+
+/// The disown builtin, removes jobs from the jobs list so they are no longer reported or
+/// waited for.  -a disowns all jobs and -r all running jobs, with no args the current job.
+fn disown<I>(args: I, jobs: &mut Jobs) -> i32
+where
+    I: Iterator<Item = OsString>,
+{
+    let mut ids = vec![];
+    let mut status = 0;
+    let mut has_arg = false;
+    for arg in args {
+        has_arg = true;
+        match arg.to_str() {
+            Some("-a") => ids.extend(jobs.job_ids()),
+            Some("-r") => ids.extend(jobs.job_ids().into_iter().filter(|id| {
+                jobs.get_job(*id)
+                    .map(|job| job.status() == JobStatus::Running)
+                    .unwrap_or_default()
+            })),
+            _ => match job_arg(Some(&arg), jobs) {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    eprintln!("disown: {err}");
+                    status = 1;
+                }
+            },
+        }
+    }
+    if !has_arg {
+        match jobs.current_job() {
+            Some(id) => ids.push(id),
+            None => {
+                eprintln!("disown: no current job");
+                return 1;
+            }
+        }
+    }
+    for id in ids {
+        jobs.remove_job(id);
+    }
+    status
+}
+
+/// Print a trap in a form that can be reused as input (trap -- 'command' SIG).
+fn print_trap(sig: TrapSignal, trap: &Trap) {
+    match trap {
+        Trap::Command(command) => {
+            println!("trap -- '{}' {sig}", command.replace('\'', "'\\''"))
+        }
+        Trap::Ignore => println!("trap -- '' {sig}"),
+        Trap::Handler => println!("# {sig} is trapped by a lisp function"),
+    }
+}
+
+/// The trap builtin, sets a command to run when the shell receives a signal (or EXIT).
+/// trap COMMAND SIG..., trap '' SIG... ignores and trap - SIG... (or trap SIG) resets to the
+/// default.  With no args or -p prints the traps, -l lists the signal names.
+fn trap<I>(args: I, jobs: &mut Jobs) -> i32
+where
+    I: Iterator<Item = OsString>,
+{
+    let mut args: Vec<String> = args.map(|a| a.to_string_lossy().to_string()).collect();
+    match args.first().map(|a| a.as_str()) {
+        None | Some("-p") => {
+            let sigs: Vec<TrapSignal> = args
+                .iter()
+                .skip(1)
+                .filter_map(|a| TrapSignal::from_name(a))
+                .collect();
+            for (sig, trap) in jobs.traps() {
+                if sigs.is_empty() || sigs.contains(&sig) {
+                    print_trap(sig, trap);
+                }
+            }
+            return 0;
+        }
+        Some("-l") => {
+            for (name, num) in Sys::signal_names() {
+                println!("{num:2}) SIG{name}");
+            }
+            return 0;
+        }
+        Some("--") => {
+            args.remove(0);
+        }
+        _ => {}
+    }
+    if args.is_empty() {
+        return trap(std::iter::empty(), jobs);
+    }
+    // A single signal arg resets it.
+    if args.len() == 1 && TrapSignal::from_name(&args[0]).is_some() {
+        args.insert(0, "-".to_string());
+    }
+    let action = args.remove(0);
+    if args.is_empty() {
+        eprintln!("trap: usage: trap [-lp] [[command] signal ...]");
+        return 1;
+    }
+    let trap = match action.as_str() {
+        "-" => None,
+        "" => Some(Trap::Ignore),
+        _ => Some(Trap::Command(action)),
+    };
+    let mut status = 0;
+    for arg in args {
+        match TrapSignal::from_name(&arg) {
+            Some(sig) => {
+                if let Err(err) = jobs.set_trap(sig, trap.clone()) {
+                    eprintln!("trap: {arg}: {err}");
+                    status = 1;
+                }
+            }
+            None => {
+                eprintln!("trap: {arg}: invalid signal specification");
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// The set builtin, turns shell options on (-) or off (+).
+/// Supports -e (errexit), -u (nounset) and -o NAME for any option, -o or +o alone prints the
+/// options.
+fn set_options<I>(args: I, jobs: &mut Jobs) -> i32
+where
+    I: Iterator<Item = OsString>,
+{
+    fn print_options(jobs: &Jobs, as_commands: bool) {
+        for name in ShellOptions::NAMES {
+            let val = jobs.options().get(name).unwrap_or_default();
+            if as_commands {
+                println!("set {}o {name}", if val { '-' } else { '+' });
+            } else {
+                println!("{name}\t{}", if val { "on" } else { "off" });
+            }
+        }
+    }
+    let mut args = args.peekable();
+    if args.peek().is_none() {
+        print_options(jobs, false);
+        return 0;
+    }
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        let (on, flags) = if let Some(flags) = arg.strip_prefix('-') {
+            (true, flags)
+        } else if let Some(flags) = arg.strip_prefix('+') {
+            (false, flags)
+        } else {
+            eprintln!("set: invalid argument {arg}");
+            return 1;
+        };
+        for flag in flags.chars() {
+            let name = match flag {
+                'e' => "errexit".to_string(),
+                'u' => "nounset".to_string(),
+                'o' => match args.next() {
+                    Some(name) => name.to_string_lossy().to_string(),
+                    None => {
+                        print_options(jobs, !on);
+                        continue;
+                    }
+                },
+                _ => {
+                    eprintln!("set: invalid option {flag}");
+                    return 1;
+                }
+            };
+            if !jobs.options_mut().set(&name, on) {
+                eprintln!("set: invalid option name {name}");
+                return 1;
+            }
+        }
+    }
+    0
+}
+
 fn alias<I>(args: I, jobs: &mut Jobs) -> i32
 where
     I: Iterator<Item = OsString>,
@@ -326,6 +627,17 @@ where
     0
 }
 
+/// Names of the commands handled by [`run_builtin`].
+pub const BUILTINS: &[&str] = &[
+    "cd", "pushd", "popd", "dirs", "fg", "bg", "jobs", "wait", "kill", "disown", "export", "umask",
+    "unset", "alias", "unalias", "ulimit", "set", "shift", "trap",
+];
+
+/// True if command is a shell builtin (run in the shell process by [`run_builtin`]).
+pub fn is_builtin(command: &str) -> bool {
+    BUILTINS.contains(&command)
+}
+
 pub fn run_builtin<'arg, I>(command: &OsStr, args: &mut I, jobs: &mut Jobs) -> Option<i32>
 where
     I: Iterator<Item = &'arg Arg>,
@@ -336,22 +648,64 @@ where
         "cd" => {
             let arg = args.next();
             if args.next().is_none() {
-                cd(arg.map(|s| s.into()))
+                match dirs::cd(arg.map(|s| s.into())) {
+                    Ok(print) => {
+                        if print {
+                            if let Ok(dir) = env::current_dir() {
+                                println!("{}", dir.display());
+                            }
+                        }
+                        0
+                    }
+                    Err(err) => {
+                        eprintln!("cd: {err}");
+                        1
+                    }
+                }
             } else {
                 eprintln!("cd: too many arguments!");
                 1
             }
         }
+        "pushd" | "popd" => {
+            let arg = args.next();
+            if args.next().is_some() {
+                eprintln!("{command_str}: too many arguments!");
+                1
+            } else {
+                let result = if command_str == "pushd" {
+                    dirs::pushd(arg.as_deref())
+                } else {
+                    dirs::popd(arg.as_deref())
+                };
+                match result.and_then(|_| dirs::dirs(std::iter::empty())) {
+                    Ok(()) => 0,
+                    Err(err) => {
+                        eprintln!("{command_str}: {err}");
+                        1
+                    }
+                }
+            }
+        }
+        "dirs" => match dirs::dirs(args) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("dirs: {err}");
+                1
+            }
+        },
         "fg" => {
-            if let Some(arg) = args.next() {
-                if args.next().is_none() {
-                    if let Some(Ok(job_num)) = arg.to_str().map(|s| s.parse()) {
+            let arg = args.next();
+            if args.next().is_none() {
+                match job_arg(arg.as_deref(), jobs) {
+                    Ok(job_num) => {
                         jobs.foreground_job(job_num);
+                        0
+                    }
+                    Err(err) => {
+                        eprintln!("fg: {err}");
+                        1
                     }
-                    0
-                } else {
-                    eprintln!("fg: takes one argument!");
-                    1
                 }
             } else {
                 eprintln!("fg: takes one argument!");
@@ -359,29 +713,39 @@ where
             }
         }
         "bg" => {
-            if let Some(arg) = args.next() {
-                if args.next().is_none() {
-                    if let Some(Ok(job_num)) = arg.to_str().map(|s| s.parse()) {
-                        jobs.background_job(job_num);
+            let args: Vec<OsString> = args.collect();
+            let args = if args.is_empty() {
+                vec![None]
+            } else {
+                args.iter().map(|a| Some(a.as_os_str())).collect()
+            };
+            let mut status = 0;
+            for arg in args {
+                match job_arg(arg, jobs) {
+                    Ok(job_num) => jobs.background_job(job_num),
+                    Err(err) => {
+                        eprintln!("bg: {err}");
+                        status = 1;
                     }
-                    0
-                } else {
-                    eprintln!("fg: takes one argument!");
-                    1
                 }
-            } else {
-                eprintln!("fg: takes one argument!");
-                1
             }
+            status
         }
         "jobs" => {
-            if args.next().is_some() {
-                eprintln!("jobs: too many arguments!");
-                1
-            } else {
-                println!("{jobs}");
-                0
-            }
+            let args: Vec<OsString> = args.collect();
+            list_jobs(args.into_iter(), jobs)
+        }
+        "wait" => {
+            let args: Vec<OsString> = args.collect();
+            wait(args.into_iter(), jobs)
+        }
+        "kill" => {
+            let args: Vec<OsString> = args.collect();
+            kill(args.into_iter(), jobs)
+        }
+        "disown" => {
+            let args: Vec<OsString> = args.collect();
+            disown(args.into_iter(), jobs)
         }
         "export" => {
             fn split_export<S: AsRef<str>>(arg: S) -> i32 {
@@ -453,6 +817,35 @@ where
             let args: Vec<OsString> = args.collect();
             ulimit(args.into_iter(), jobs)
         }
+        "set" => {
+            let args: Vec<OsString> = args.collect();
+            set_options(args.into_iter(), jobs)
+        }
+        "shift" => {
+            let n = args.next();
+            let n = match n.as_deref().map(|n| n.to_string_lossy().parse::<usize>()) {
+                None => Ok(1),
+                Some(n) => n,
+            };
+            match n {
+                Ok(n) if args.next().is_none() => {
+                    if jobs.shift_positional(n) {
+                        0
+                    } else {
+                        eprintln!("shift: shift count out of range");
+                        1
+                    }
+                }
+                _ => {
+                    eprintln!("shift: takes an optional count");
+                    1
+                }
+            }
+        }
+        "trap" => {
+            let args: Vec<OsString> = args.collect();
+            trap(args.into_iter(), jobs)
+        }
         // Check for VAR_NAME=val before returning.
         _ => match (args.next(), args.next()) {
             (None, None) if command_str.contains('=') => {
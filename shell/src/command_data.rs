@@ -1,24 +1,106 @@
+use crate::arith::ArithExpr;
+use crate::expand::{expand_arg, expand_param, unescape};
 use crate::jobs::Jobs;
 use crate::platform::{FileDesc, FromFileDesc, Platform, Sys, STDIN_FILENO, STDOUT_FILENO};
 use std::collections::HashSet;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, ErrorKind, Write};
 use std::str::FromStr;
 
+/// Byte range of a parsed item in the source line.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+/// Operator of a ${VAR..} parameter expansion.  The bool on the default/assign/error/alternate
+/// operators is true for the : forms (an empty value is treated like an unset one).
+#[derive(Clone, Debug)]
+pub enum ParamOp {
+    /// ${VAR:-word}, use word if VAR is unset or empty.
+    Default(Box<Arg>, bool),
+    /// ${VAR:=word}, set VAR to word if it is unset or empty.
+    Assign(Box<Arg>, bool),
+    /// ${VAR:?word}, error with message word if VAR is unset or empty.
+    Error(Box<Arg>, bool),
+    /// ${VAR:+word}, use word if VAR is set and not empty (else empty).
+    Alternate(Box<Arg>, bool),
+    /// ${#VAR}, length of VAR in chars.
+    Length,
+    /// ${VAR#pat} or ${VAR##pat} (longest match), remove a matching prefix.
+    RemovePrefix(Box<Arg>, bool),
+    /// ${VAR%pat} or ${VAR%%pat} (longest match), remove a matching suffix.
+    RemoveSuffix(Box<Arg>, bool),
+    /// ${VAR/pat/rep} or ${VAR//pat/rep} (all matches), replace the longest match of pat.
+    Replace(Box<Arg>, Box<Arg>, bool),
+    /// ${VAR:offset:length}, substring in chars (negative values count from the end).
+    Substring(i64, Option<i64>),
+}
+
+impl Display for ParamOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let colon = |null: &bool| if *null { ":" } else { "" };
+        match self {
+            Self::Default(word, null) => write!(f, "{}-{word}", colon(null)),
+            Self::Assign(word, null) => write!(f, "{}={word}", colon(null)),
+            Self::Error(word, null) => write!(f, "{}?{word}", colon(null)),
+            Self::Alternate(word, null) => write!(f, "{}+{word}", colon(null)),
+            Self::Length => Ok(()),
+            Self::RemovePrefix(pat, true) => write!(f, "##{pat}"),
+            Self::RemovePrefix(pat, false) => write!(f, "#{pat}"),
+            Self::RemoveSuffix(pat, true) => write!(f, "%%{pat}"),
+            Self::RemoveSuffix(pat, false) => write!(f, "%{pat}"),
+            Self::Replace(pat, rep, true) => write!(f, "//{pat}/{rep}"),
+            Self::Replace(pat, rep, false) => write!(f, "/{pat}/{rep}"),
+            Self::Substring(offset, len) => {
+                // A space keeps a negative offset from looking like :-
+                let space = if *offset < 0 { " " } else { "" };
+                write!(f, ":{space}{offset}")?;
+                if let Some(len) = len {
+                    write!(f, ":{len}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 /// Arg to a command, either a direct string or a sub command to run to get the arg.
+/// Args are produced by the parser unexpanded, see [`crate::expand`] for the expansions done
+/// before a command is run.
 #[derive(Clone, Debug)]
 pub enum Arg {
-    /// Single string arg.
+    /// Single string arg (single quoted or already expanded), used as is.
     Str(OsString),
     /// A command to run to get the string arg.
     Command(Run),
     /// Env variable to use to set the arg.
     Var(OsString),
+    /// Env variable with a ${VAR..} operator applied.
+    Param(OsString, ParamOp),
     /// List of args that will be concatenated to make the arg.
     Compound(Vec<Arg>),
+    /// Arithmetic expansion $((expr)).
+    Arith(ArithExpr),
+    /// Process substitution <(run), a path to read the output of run.
+    ProcessIn(Run),
+    /// Process substitution >(run), a path to write to the input of run.
+    ProcessOut(Run),
+    /// Unquoted text, still contains any backslash escapes and is subject to brace, tilde and
+    /// glob expansion.
+    Unquoted(OsString),
+    /// A double quoted string, the result is not brace, tilde or glob expanded.
+    Quoted(Box<Arg>),
 }
 
 impl Arg {
@@ -45,6 +127,8 @@ impl Arg {
             Self::Var(var_name) => {
                 if let Some(val) = jobs.get_env_or_local_var(var_name) {
                     Ok(val)
+                } else if jobs.options().nounset {
+                    Err(unbound_variable(var_name))
                 } else {
                     Ok("".into())
                 }
@@ -56,10 +140,49 @@ impl Arg {
                 }
                 Ok(val.into())
             }
+            Self::Param(name, op) => expand_param(name, op, jobs),
+            Self::Arith(expr) => Ok(expr.eval(jobs)?.to_string().into()),
+            Self::ProcessIn(run) => process_subst(run, false, jobs),
+            Self::ProcessOut(run) => process_subst(run, true, jobs),
+            Self::Unquoted(text) => Ok(unescape(&text.to_string_lossy()).into()),
+            Self::Quoted(arg) => arg.resolve_arg(jobs),
         }
     }
 }
 
+/// Error for expanding an unset variable with nounset set.
+pub(crate) fn unbound_variable(name: &OsStr) -> io::Error {
+    io::Error::new(
+        ErrorKind::Other,
+        format!("{}: unbound variable", name.to_string_lossy()),
+    )
+}
+
+/// Start run in the background on a pipe and return a /dev/fd path to the other end of the pipe.
+/// If output is true then the path is written to (it is the input for run).
+fn process_subst(run: &Run, output: bool, jobs: &mut Jobs) -> io::Result<OsString> {
+    let (input, output_fd) = Sys::anon_pipe()?;
+    let mut run = run.clone();
+    let keep = if output {
+        run.push_stdin_front(Some(input));
+        output_fd
+    } else {
+        run.push_stdout_front(Some(output_fd));
+        input
+    };
+    let mut job = jobs.new_job();
+    job.set_interactive(false);
+    job.set_stealth(true);
+    job.mark_running();
+    // Closes the fd run uses in this process.
+    Sys::fork_run(&run, &mut job, jobs)?;
+    // The anon pipe is close on exec, the command needs to inherit it.
+    let fd = Sys::dup_fd(keep)?;
+    Sys::close_fd(keep)?;
+    jobs.push_proc_subst(fd, job);
+    Ok(format!("/dev/fd/{fd}").into())
+}
+
 impl TryFrom<&mut Arg> for FileDesc {
     type Error = ();
 
@@ -87,14 +210,23 @@ impl Display for Arg {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Str(os_str) => write!(f, "{}", os_str.to_string_lossy()),
+            // Keep a subshell from looking like arithmetic $((..)).
+            Self::Command(run @ Run::Subshell(_)) => write!(f, "$( {run})"),
             Self::Command(run) => write!(f, "$({run})"),
             Self::Var(var) => write!(f, "${}", var.to_string_lossy()),
+            Self::Param(var, ParamOp::Length) => write!(f, "${{#{}}}", var.to_string_lossy()),
+            Self::Param(var, op) => write!(f, "${{{}{op}}}", var.to_string_lossy()),
             Self::Compound(cargs) => {
                 for a in cargs {
                     write!(f, "{a}")?;
                 }
                 Ok(())
             }
+            Self::Arith(expr) => write!(f, "$(({expr}))"),
+            Self::ProcessIn(run) => write!(f, "<({run})"),
+            Self::ProcessOut(run) => write!(f, ">({run})"),
+            Self::Unquoted(text) => write!(f, "{}", text.to_string_lossy()),
+            Self::Quoted(arg) => write!(f, "{arg}"),
         }
     }
 }
@@ -113,6 +245,8 @@ enum RedirArg {
 impl Display for RedirArg {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            // Keep a process substitution from running into the redirect operator.
+            RedirArg::Path(arg @ (Arg::ProcessIn(_) | Arg::ProcessOut(_))) => write!(f, " {arg}"),
             RedirArg::Path(arg) => write!(f, "{arg}"),
             RedirArg::Fd(arg) => write!(f, "&{arg}"),
             RedirArg::InternalFd(_fd) => write!(f, ""),
@@ -125,7 +259,7 @@ impl Display for RedirArg {
 enum RedirType {
     /// An input file to open and dup to fd.
     In(FileDesc, RedirArg),
-    /// Inject Arg as data into the fd.
+    /// Inject Arg as data into the fd (here-documents and here-strings).
     InDirect(FileDesc, Arg),
     /// An output file to open (append) and dup to fd.
     Out(FileDesc, RedirArg),
@@ -139,7 +273,7 @@ impl Display for RedirType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             RedirType::In(fd, arg) => write!(f, " {fd}<{arg}"),
-            RedirType::InDirect(fd, arg) => write!(f, " {fd}<<{arg}"),
+            RedirType::InDirect(fd, arg) => write!(f, " {fd}<<<{arg}"),
             RedirType::Out(fd, arg) => write!(f, " {fd}>>{arg}"),
             RedirType::OutTrunc(fd, arg) => write!(f, " {fd}>{arg}"),
             RedirType::InOut(fd, arg) => write!(f, " {fd}<>{arg}"),
@@ -358,6 +492,39 @@ impl Redirects {
         self.redir_stack.push(redir);
     }
 
+    /// Produce a copy of the redirect stack with the paths and here-document/string data resolved.  The data
+    /// is written from a thread to a pipe so it can be any size, the command reads it from an
+    /// internal fd.
+    pub fn expand(&self, jobs: &mut Jobs) -> io::Result<Redirects> {
+        let mut redir_stack = Vec::with_capacity(self.redir_stack.len());
+        for redir in &self.redir_stack {
+            let resolve = |arg: &Arg, jobs: &mut Jobs| -> io::Result<RedirArg> {
+                Ok(RedirArg::Path(Arg::Str(arg.resolve_arg(jobs)?)))
+            };
+            if let RedirType::InDirect(fd, arg) = redir {
+                let data = arg.resolve_arg(jobs)?;
+                let (pread, pwrite) = Sys::anon_pipe()?;
+                let mut file = unsafe { File::from_file_desc(pwrite) };
+                std::thread::spawn(move || {
+                    // An error means the command did not read all the data, not a problem here.
+                    let _ = file.write_all(data.to_string_lossy().as_bytes());
+                });
+                redir_stack.push(RedirType::In(*fd, RedirArg::InternalFd(pread)));
+            } else if let RedirType::In(fd, RedirArg::Path(arg)) = redir {
+                redir_stack.push(RedirType::In(*fd, resolve(arg, jobs)?));
+            } else if let RedirType::Out(fd, RedirArg::Path(arg)) = redir {
+                redir_stack.push(RedirType::Out(*fd, resolve(arg, jobs)?));
+            } else if let RedirType::OutTrunc(fd, RedirArg::Path(arg)) = redir {
+                redir_stack.push(RedirType::OutTrunc(*fd, resolve(arg, jobs)?));
+            } else if let RedirType::InOut(fd, RedirArg::Path(arg)) = redir {
+                redir_stack.push(RedirType::InOut(*fd, resolve(arg, jobs)?));
+            } else {
+                redir_stack.push(redir.clone());
+            }
+        }
+        Ok(Redirects { redir_stack })
+    }
+
     /// Clear the redirect stack.
     pub fn clear(&mut self) {
         self.redir_stack.clear();
@@ -445,6 +612,8 @@ pub struct CommandWithArgs {
     #[allow(rustdoc::broken_intra_doc_links)]
     /// args[0] is the command.
     args: Vec<Arg>,
+    /// Source location of each arg (same length as args, default if not parsed from a line).
+    spans: Vec<Span>,
     stdios: Option<Redirects>,
 }
 
@@ -471,23 +640,57 @@ impl CommandWithArgs {
     pub fn new() -> Self {
         Self {
             args: vec![],
+            spans: vec![],
             stdios: None,
         }
     }
 
     /// Push a new arg onto the command, the first "arg" is the command itself.
     pub fn push_arg(&mut self, arg: Arg) {
+        self.push_arg_with_span(arg, Span::default());
+    }
+
+    /// Push a new arg and its location in the source onto the command.
+    pub fn push_arg_with_span(&mut self, arg: Arg, span: Span) {
         self.args.push(arg);
+        self.spans.push(span);
     }
 
     /// Push a new env var arg onto the command, the first "arg" is the command itself.
     pub fn push_env_var_arg(&mut self, arg: OsString) {
-        self.args.push(Arg::Var(arg));
+        self.push_arg(Arg::Var(arg));
     }
 
     /// Push a new env var arg onto the command, the first "arg" is the command itself.
     pub fn push_run_arg(&mut self, run: Run) {
-        self.args.push(Arg::Command(run));
+        self.push_arg(Arg::Command(run));
+    }
+
+    /// Source locations of the command (first) and args.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// Produce a copy of this command with the command and args expanded (brace, tilde,
+    /// parameter, command substitution and glob expansion) and here-documents ready to read.
+    /// This can run commands and the result only contains [`Arg::Str`] args.
+    pub fn expand(&self, jobs: &mut Jobs) -> io::Result<CommandWithArgs> {
+        let mut args = vec![];
+        for arg in &self.args {
+            expand_arg(arg, jobs, &mut args)?;
+        }
+        let mut stdios = self.stdios.as_ref().map(|s| s.expand(jobs)).transpose()?;
+        for fd in jobs.take_proc_subst_fds() {
+            // Keep the fd open (and at the same number) in the command.
+            stdios
+                .get_or_insert_with(Redirects::default)
+                .set_in_internal_fd(fd, fd, true);
+        }
+        Ok(CommandWithArgs {
+            spans: vec![Span::default(); args.len()],
+            args: args.into_iter().map(Arg::Str).collect(),
+            stdios,
+        })
     }
 
     /// Empty, not even the command is set.
@@ -676,7 +879,18 @@ impl Run {
         }
     }
 
+    /// Apply push to the last Run in seq (for an operator that binds tighter than seq's).
+    fn push_last(mut seq: Vec<Run>, new_run: Run, push: fn(Run, Run) -> Run) -> Vec<Run> {
+        if let Some(last) = seq.pop() {
+            seq.push(push(last, new_run));
+        } else {
+            seq.push(new_run);
+        }
+        seq
+    }
+
     /// Push Run onto an existing or create a new pipe sequence.
+    /// A pipe binds tighter than any other operator so it applies to the last command.
     pub fn push_pipe(self, new_run: Run) -> Self {
         match self {
             Run::Command(current) => Run::Pipe(vec![Run::Command(current), new_run]),
@@ -687,9 +901,9 @@ impl Run {
                 pipe.push(new_run);
                 Run::Pipe(pipe)
             }
-            Run::Sequence(seq) => Run::Pipe(vec![Run::Sequence(seq), new_run]),
-            Run::And(seq) => Run::Pipe(vec![Run::And(seq), new_run]),
-            Run::Or(seq) => Run::Pipe(vec![Run::Or(seq), new_run]),
+            Run::Sequence(seq) => Run::Sequence(Self::push_last(seq, new_run, Self::push_pipe)),
+            Run::And(seq) => Run::And(Self::push_last(seq, new_run, Self::push_pipe)),
+            Run::Or(seq) => Run::Or(Self::push_last(seq, new_run, Self::push_pipe)),
             Run::Subshell(current) => Run::Pipe(vec![Run::Subshell(current), new_run]),
             Run::Empty => new_run,
         }
@@ -722,7 +936,7 @@ impl Run {
                 Run::And(vec![Run::BackgroundCommand(current), new_run])
             }
             Run::Pipe(pipe) => Run::And(vec![Run::Pipe(pipe), new_run]),
-            Run::Sequence(seq) => Run::And(vec![Run::Sequence(seq), new_run]),
+            Run::Sequence(seq) => Run::Sequence(Self::push_last(seq, new_run, Self::push_and)),
             Run::And(mut seq) => {
                 seq.push(new_run);
                 Run::And(seq)
@@ -741,7 +955,7 @@ impl Run {
                 Run::Or(vec![Run::BackgroundCommand(current), new_run])
             }
             Run::Pipe(pipe) => Run::Or(vec![Run::Pipe(pipe), new_run]),
-            Run::Sequence(seq) => Run::Or(vec![Run::Or(seq), new_run]),
+            Run::Sequence(seq) => Run::Sequence(Self::push_last(seq, new_run, Self::push_or)),
             Run::And(seq) => Run::Or(vec![Run::And(seq), new_run]),
             Run::Or(mut seq) => {
                 seq.push(new_run);
@@ -5,6 +5,8 @@ pub struct Config {
     pub command: Option<String>,
     pub script: Option<String>,
     pub args: Vec<String>,
+    pub profile: bool,
+    pub coverage: Option<String>,
 }
 
 pub const VERSION_STRING: &str = env!("VERSION_STRING");
@@ -18,9 +20,13 @@ USAGE:
 FLAGS:
     -v, --version  Print the version, platform and revision of sl-sh then exit.
     -h, --help     Print help (this) and exit.
+    --profile      Profile the lisp code run, print a report and write folded stacks to
+                   slosh-profile.folded on exit.
 
 OPTIONS:
     -c             Command to run instead of entering the REPL.
+    --coverage     <file> Record the source lines executed and merge them into the lcov
+                   report file on exit.
 
 ARGS:
     <args>...      Script to run with arguments."#;
@@ -47,6 +53,8 @@ pub fn get_config() -> Option<Config> {
     let mut command: Option<String> = None;
     let mut script: Option<String> = None;
     let mut command_args: Vec<String> = Vec::new();
+    let mut profile = false;
+    let mut coverage: Option<String> = None;
 
     let mut args: Vec<OsString> = env::args_os().collect();
 
@@ -72,6 +80,12 @@ pub fn get_config() -> Option<Config> {
                         help(&exe_name);
                         return None;
                     }
+                    "--profile" if command.is_none() && script.is_none() => {
+                        profile = true;
+                    }
+                    "--coverage" if command.is_none() && script.is_none() => {
+                        coverage = Some(get_arg(&exe_name, &mut args)?);
+                    }
                     _ => {
                         if command.is_none() && script.is_none() {
                             script = Some(arg);
@@ -90,5 +104,7 @@ pub fn get_config() -> Option<Config> {
         command,
         script,
         args: command_args,
+        profile,
+        coverage,
     })
 }
@@ -0,0 +1,302 @@
+//! The directory stack (pushd/popd/dirs) and cd history.
+//!
+//! The current directory is process wide so this state is kept per thread here instead of in
+//! [`crate::jobs::Jobs`], this lets the lisp cd share the history with the shell builtins.
+
+use crate::builtins::compress_tilde;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Maximum number of directories remembered for cd -N.
+pub const DIR_HISTORY_MAX: usize = 20;
+
+#[derive(Default)]
+struct DirState {
+    // Stack of directories, does not include the current directory (the top of the stack).
+    stack: Vec<PathBuf>,
+    // Previously visited directories, most recent first.
+    history: VecDeque<PathBuf>,
+}
+
+thread_local! {
+    static DIR_STATE: RefCell<DirState> = RefCell::new(DirState::default());
+}
+
+fn current_dir() -> PathBuf {
+    env::current_dir().unwrap_or_else(|_| PathBuf::from(env::var_os("PWD").unwrap_or_default()))
+}
+
+fn home_dir() -> PathBuf {
+    env::var_os("HOME").unwrap_or_else(|| "/".into()).into()
+}
+
+/// A path with HOME replaced with ~ unless long is set.
+fn display_dir(dir: &Path, long: bool) -> String {
+    let dir = dir.to_string_lossy();
+    if long {
+        dir.to_string()
+    } else {
+        compress_tilde(&dir).unwrap_or_else(|| dir.to_string())
+    }
+}
+
+/// Turn a path of all dots (more than 2) into the parent directories, ie ... is ../../
+pub fn cd_expand_all_dots(cd: PathBuf) -> PathBuf {
+    let cd_ref = cd.to_string_lossy();
+    if cd_ref.len() > 2 && cd_ref.chars().all(|ch| ch == '.') {
+        let mut new_cd = OsString::new();
+        for _i in 0..cd_ref.len() - 1 {
+            new_cd.push("../");
+        }
+        new_cd.into()
+    } else {
+        cd
+    }
+}
+
+/// Search CDPATH for a relative directory.
+/// Returns the directory found and true if it came from a non-empty CDPATH entry (so the new
+/// directory should be printed).
+fn search_cdpath(dir: PathBuf) -> (PathBuf, bool) {
+    if dir.is_absolute() || dir.starts_with(".") || dir.starts_with("..") {
+        return (dir, false);
+    }
+    if let Some(cdpath) = env::var_os("CDPATH") {
+        for base in env::split_paths(&cdpath) {
+            if base.as_os_str().is_empty() {
+                if dir.is_dir() {
+                    return (dir, false);
+                }
+            } else {
+                let candidate = base.join(&dir);
+                if candidate.is_dir() {
+                    return (candidate, true);
+                }
+            }
+        }
+    }
+    (dir, false)
+}
+
+/// Change to dir, setting OLDPWD and PWD and recording the old directory in the cd history.
+fn change_dir(dir: &Path) -> Result<(), String> {
+    let old_dir = env::current_dir();
+    env::set_current_dir(dir).map_err(|e| format!("Error changing to {}, {}", dir.display(), e))?;
+    if let Ok(old_dir) = old_dir {
+        env::set_var("OLDPWD", &old_dir);
+        DIR_STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.history.retain(|d| d != &old_dir);
+            state.history.push_front(old_dir);
+            state.history.truncate(DIR_HISTORY_MAX);
+        });
+    }
+    match env::current_dir() {
+        Ok(dir) => {
+            DIR_STATE.with(|state| state.borrow_mut().history.retain(|d| d != &dir));
+            env::set_var("PWD", dir);
+        }
+        Err(err) => return Err(format!("error setting PWD: {err}")),
+    }
+    Ok(())
+}
+
+/// Change directory.
+///
+/// With no arg changes to HOME, - is the previous directory (OLDPWD) and -N is the Nth
+/// directory in the cd history (-1 is the same as -).  Relative directories are searched for
+/// in CDPATH.  Returns true if the new directory should be printed (it was not explicit).
+pub fn cd(arg: Option<PathBuf>) -> Result<bool, String> {
+    let arg = arg.unwrap_or_else(home_dir);
+    let arg_str = arg.to_string_lossy();
+    let (new_dir, print) = if arg_str == "-" {
+        let old_dir = env::var_os("OLDPWD").map(PathBuf::from);
+        (old_dir.unwrap_or_else(home_dir), true)
+    } else if let Some(n) = arg_str
+        .strip_prefix('-')
+        .and_then(|n| n.parse::<usize>().ok())
+    {
+        let dir = DIR_STATE.with(|state| state.borrow().history.get(n.wrapping_sub(1)).cloned());
+        (
+            dir.ok_or_else(|| format!("-{n}: no such entry in cd history"))?,
+            true,
+        )
+    } else {
+        search_cdpath(cd_expand_all_dots(arg))
+    };
+    change_dir(&new_dir)?;
+    Ok(print)
+}
+
+/// The directory stack, the current directory first.
+pub fn dir_stack() -> Vec<PathBuf> {
+    let mut dirs = vec![current_dir()];
+    DIR_STATE.with(|state| dirs.extend(state.borrow().stack.iter().rev().cloned()));
+    dirs
+}
+
+/// The cd history, most recent first.
+pub fn dir_history() -> Vec<PathBuf> {
+    DIR_STATE.with(|state| state.borrow().history.iter().cloned().collect())
+}
+
+fn set_dir_stack(mut dirs: Vec<PathBuf>) {
+    // Drop the current directory, stored with the top of the stack last.
+    dirs.remove(0);
+    dirs.reverse();
+    DIR_STATE.with(|state| state.borrow_mut().stack = dirs);
+}
+
+/// Parse a +N or -N stack index into an index from the top of the stack (0 is the current dir).
+fn stack_index(arg: &str, len: usize) -> Option<Result<usize, String>> {
+    let (from_top, n) = if let Some(n) = arg.strip_prefix('+') {
+        (true, n)
+    } else if let Some(n) = arg.strip_prefix('-') {
+        (false, n)
+    } else {
+        return None;
+    };
+    let n = match n.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return Some(Err(format!("{arg}: invalid number"))),
+    };
+    if n >= len {
+        Some(Err(format!("{arg}: directory stack index out of range")))
+    } else if from_top {
+        Some(Ok(n))
+    } else {
+        Some(Ok(len - 1 - n))
+    }
+}
+
+/// Push a directory onto the stack and change to it.
+///
+/// With no arg swaps the top two directories, with +N or -N rotates the stack so that the Nth
+/// directory (counting from the top with + or the bottom with -) is on top.
+pub fn pushd(arg: Option<&OsStr>) -> Result<(), String> {
+    let mut dirs = dir_stack();
+    let arg_str = arg.map(|a| a.to_string_lossy());
+    match arg_str.as_deref() {
+        None => {
+            if dirs.len() < 2 {
+                return Err("no other directory".to_string());
+            }
+            dirs.swap(0, 1);
+            change_dir(&dirs[0])?;
+            set_dir_stack(dirs);
+        }
+        Some(arg_str) => match stack_index(arg_str, dirs.len()) {
+            Some(idx) => {
+                dirs.rotate_left(idx?);
+                change_dir(&dirs[0])?;
+                set_dir_stack(dirs);
+            }
+            None => {
+                let dir = cd_expand_all_dots(PathBuf::from(arg.unwrap_or_default()));
+                let (dir, _) = search_cdpath(dir);
+                change_dir(&dir)?;
+                dirs.insert(0, current_dir());
+                set_dir_stack(dirs);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Pop the top directory off the stack and change to the new top.
+///
+/// With +N or -N removes the Nth directory (counting from the top with + or the bottom with -)
+/// instead.
+pub fn popd(arg: Option<&OsStr>) -> Result<(), String> {
+    let mut dirs = dir_stack();
+    if dirs.len() < 2 {
+        return Err("directory stack empty".to_string());
+    }
+    let idx = match arg.map(|a| a.to_string_lossy()) {
+        None => 0,
+        Some(arg) => match stack_index(&arg, dirs.len()) {
+            Some(idx) => idx?,
+            None => return Err(format!("{arg}: invalid argument")),
+        },
+    };
+    dirs.remove(idx);
+    if idx == 0 {
+        change_dir(&dirs[0])?;
+    }
+    set_dir_stack(dirs);
+    Ok(())
+}
+
+/// Print the directory stack.
+///
+/// -c clears the stack, -l prints full paths (no ~), -p prints one directory per line, -v prints
+/// one per line with its index, -h prints the cd history with the index for cd -N and +N or -N
+/// prints just that entry.
+pub fn dirs<I>(args: I) -> Result<(), String>
+where
+    I: Iterator<Item = OsString>,
+{
+    let mut long = false;
+    let mut per_line = false;
+    let mut numbered = false;
+    let mut history = false;
+    let mut entry = None;
+    let mut dirs = dir_stack();
+    for arg in args {
+        let arg = arg.to_string_lossy();
+        match &*arg {
+            "-c" => {
+                DIR_STATE.with(|state| state.borrow_mut().stack.clear());
+                return Ok(());
+            }
+            "-l" => long = true,
+            "-p" => per_line = true,
+            "-v" => numbered = true,
+            "-h" => history = true,
+            _ => match stack_index(&arg, dirs.len()) {
+                Some(idx) => entry = Some(idx?),
+                None => return Err(format!("{arg}: invalid argument")),
+            },
+        }
+    }
+    if history {
+        for (i, dir) in dir_history().iter().enumerate() {
+            println!("{:2}  {}", i + 1, display_dir(dir, long));
+        }
+    } else if let Some(idx) = entry {
+        println!("{}", display_dir(&dirs.remove(idx), long));
+    } else if numbered {
+        for (i, dir) in dirs.iter().enumerate() {
+            println!("{i:2}  {}", display_dir(dir, long));
+        }
+    } else if per_line {
+        for dir in &dirs {
+            println!("{}", display_dir(dir, long));
+        }
+    } else {
+        let dirs: Vec<String> = dirs.iter().map(|d| display_dir(d, long)).collect();
+        println!("{}", dirs.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_index() {
+        assert_eq!(stack_index("dir", 3), None);
+        assert_eq!(stack_index("+0", 3), Some(Ok(0)));
+        assert_eq!(stack_index("+2", 3), Some(Ok(2)));
+        assert_eq!(stack_index("-0", 3), Some(Ok(2)));
+        assert_eq!(stack_index("-2", 3), Some(Ok(0)));
+        assert!(matches!(stack_index("+3", 3), Some(Err(_))));
+        assert!(matches!(stack_index("-x", 3), Some(Err(_))));
+        assert_eq!(cd_expand_all_dots("...".into()), PathBuf::from("../../"));
+        assert_eq!(cd_expand_all_dots("..".into()), PathBuf::from(".."));
+    }
+}
@@ -0,0 +1,490 @@
+//! Expansion of parsed args, done when a command is run (not when it is parsed).
+//! Roughly a subset of <https://www.gnu.org/software/bash/manual/html_node/Shell-Expansions.html>
+//! applied in order: brace, tilde, parameter/command substitution, glob then quote removal.
+
+use crate::builtins::expand_tilde;
+use crate::command_data::{unbound_variable, Arg, ParamOp};
+use crate::glob::{expand_glob, GlobOutput};
+use crate::jobs::Jobs;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::ErrorKind;
+
+/// Piece of an arg, unquoted chars can be brace/glob syntax the rest are opaque.
+#[derive(Copy, Clone, Debug)]
+enum Piece<'arg> {
+    /// Unquoted, unescaped char.
+    Char(char),
+    /// Backslash escaped char, always literal.
+    Escaped(char),
+    /// Expanded ~.
+    Home,
+    /// Quoted string, literal once resolved.
+    Quoted(&'arg Arg),
+    /// Parameter or command substitution, the value can still be globbed.
+    Subst(&'arg Arg),
+}
+
+/// Remove the backslash escapes from unquoted text.
+pub(crate) fn unescape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            res.push(chars.next().unwrap_or('\\'));
+        } else {
+            res.push(ch);
+        }
+    }
+    res
+}
+
+fn to_pieces<'arg>(arg: &'arg Arg, pieces: &mut Vec<Piece<'arg>>) {
+    match arg {
+        Arg::Unquoted(text) => {
+            let text = text.to_string_lossy();
+            let mut chars = text.chars();
+            while let Some(ch) = chars.next() {
+                if ch == '\\' {
+                    pieces.push(Piece::Escaped(chars.next().unwrap_or('\\')));
+                } else {
+                    pieces.push(Piece::Char(ch));
+                }
+            }
+        }
+        Arg::Compound(args) => {
+            for arg in args {
+                to_pieces(arg, pieces);
+            }
+        }
+        Arg::Str(_) | Arg::Quoted(_) | Arg::ProcessIn(_) | Arg::ProcessOut(_) => {
+            pieces.push(Piece::Quoted(arg))
+        }
+        Arg::Var(_) | Arg::Param(_, _) | Arg::Command(_) | Arg::Arith(_) => {
+            pieces.push(Piece::Subst(arg))
+        }
+    }
+}
+
+/// Expand {..} expressions in arguments into multiple arguments.
+/// TODO Add range expressions, see <https://www.gnu.org/software/bash/manual/html_node/Brace-Expansion.html>
+fn expand_braces<'arg>(pieces: Vec<Piece<'arg>>, out: &mut Vec<Vec<Piece<'arg>>>) {
+    let mut well_formed = false;
+    let mut open = 0;
+    let mut close = 0;
+    let mut last_idx = 0;
+    let mut options = vec![];
+    let mut open_braces = 0;
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Char('{') => {
+                if open_braces == 0 {
+                    open = i;
+                    last_idx = i;
+                }
+                open_braces += 1;
+            }
+            Piece::Char(',') if open_braces == 1 => {
+                options.push((last_idx + 1, i));
+                last_idx = i;
+            }
+            Piece::Char('}') if open_braces > 0 => {
+                if open_braces == 1 {
+                    close = i;
+                    if !options.is_empty() {
+                        options.push((last_idx + 1, i));
+                        well_formed = true;
+                        break;
+                    }
+                }
+                open_braces -= 1;
+            }
+            _ => {}
+        }
+    }
+    if well_formed {
+        for (start, end) in options {
+            let mut new_pieces = pieces[..open].to_vec();
+            new_pieces.extend_from_slice(&pieces[start..end]);
+            new_pieces.extend_from_slice(&pieces[close + 1..]);
+            expand_braces(new_pieces, out);
+        }
+    } else {
+        out.push(pieces);
+    }
+}
+
+/// Expand ~ into home directory (at the start or after a : or =).
+/// TODO support other tilde expansions from <https://www.gnu.org/software/bash/manual/html_node/Tilde-Expansion.html>
+fn expand_tildes(pieces: &mut [Piece]) {
+    let mut last_ch = ' ';
+    for piece in pieces.iter_mut() {
+        if let Piece::Char(ch) = *piece {
+            if ch == '~' && (last_ch == ' ' || last_ch == ':' || last_ch == '=') {
+                *piece = Piece::Home;
+            }
+            last_ch = ch;
+        } else {
+            last_ch = '\0';
+        }
+    }
+}
+
+/// Resolve the substitutions in pieces then glob the result (if it contains unquoted glob chars).
+fn expand_globs(pieces: &[Piece], jobs: &mut Jobs, out: &mut Vec<OsString>) -> io::Result<()> {
+    let mut pattern = String::new();
+    let mut literal = String::new();
+    let mut has_glob = false;
+    for piece in pieces {
+        let quoted = match piece {
+            Piece::Char(ch) => {
+                has_glob = has_glob || matches!(ch, '*' | '?' | '[');
+                pattern.push(*ch);
+                literal.push(*ch);
+                continue;
+            }
+            Piece::Subst(arg) => {
+                let val = arg.resolve_arg(jobs)?;
+                let val = val.to_string_lossy();
+                has_glob = has_glob || val.contains(['*', '?', '[']);
+                pattern.push_str(&val);
+                literal.push_str(&val);
+                continue;
+            }
+            Piece::Escaped(ch) => ch.to_string(),
+            Piece::Home => expand_tilde("~".into()).to_string_lossy().to_string(),
+            Piece::Quoted(arg) => arg.resolve_arg(jobs)?.to_string_lossy().to_string(),
+        };
+        pattern.push_str(&glob::Pattern::escape(&quoted));
+        literal.push_str(&quoted);
+    }
+    if has_glob {
+        if let GlobOutput::Args(paths) = expand_glob(&pattern) {
+            out.extend(paths.into_iter().map(|p| p.into_os_string()));
+            return Ok(());
+        }
+    }
+    out.push(literal.into());
+    Ok(())
+}
+
+/// Resolve arg into a glob pattern, quoted and escaped parts match literally.
+fn resolve_pattern(arg: &Arg, jobs: &mut Jobs) -> io::Result<glob::Pattern> {
+    fn to_pattern(arg: &Arg, jobs: &mut Jobs, pattern: &mut String) -> io::Result<()> {
+        match arg {
+            Arg::Unquoted(text) => {
+                let text = text.to_string_lossy();
+                let mut chars = text.chars();
+                while let Some(ch) = chars.next() {
+                    if ch == '\\' {
+                        let ch = chars.next().unwrap_or('\\');
+                        pattern.push_str(&glob::Pattern::escape(&ch.to_string()));
+                    } else {
+                        pattern.push(ch);
+                    }
+                }
+            }
+            Arg::Compound(args) => {
+                for arg in args {
+                    to_pattern(arg, jobs, pattern)?;
+                }
+            }
+            Arg::Str(_) | Arg::Quoted(_) | Arg::ProcessIn(_) | Arg::ProcessOut(_) => {
+                let val = arg.resolve_arg(jobs)?;
+                pattern.push_str(&glob::Pattern::escape(&val.to_string_lossy()));
+            }
+            Arg::Var(_) | Arg::Param(_, _) | Arg::Command(_) | Arg::Arith(_) => {
+                pattern.push_str(&arg.resolve_arg(jobs)?.to_string_lossy());
+            }
+        }
+        Ok(())
+    }
+    let mut pattern = String::new();
+    to_pattern(arg, jobs, &mut pattern)?;
+    glob::Pattern::new(&pattern)
+        .map_err(|e| io::Error::new(ErrorKind::Other, format!("bad pattern {pattern}: {e}")))
+}
+
+/// Char boundaries of val (including 0 and the length).
+fn boundaries(val: &str) -> Vec<usize> {
+    let mut res: Vec<usize> = val.char_indices().map(|(i, _)| i).collect();
+    res.push(val.len());
+    res
+}
+
+/// Replace the longest matches of pattern in val with rep (only the first if not all).
+fn replace_matches(val: &str, pattern: &glob::Pattern, rep: &str, all: bool) -> String {
+    let bounds = boundaries(val);
+    let mut res = String::new();
+    let mut last = 0;
+    let mut i = 0;
+    while i < bounds.len() {
+        let start = bounds[i];
+        let found = bounds[i + 1..]
+            .iter()
+            .rev()
+            .find(|end| pattern.matches(&val[start..**end]));
+        if let Some(end) = found {
+            res.push_str(&val[last..start]);
+            res.push_str(rep);
+            last = *end;
+            if !all {
+                break;
+            }
+            i = bounds.iter().position(|b| b == end).unwrap_or(bounds.len());
+        } else {
+            i += 1;
+        }
+    }
+    res.push_str(&val[last..]);
+    res
+}
+
+/// Char offset into a string with len chars, negative offsets count back from the end.
+fn char_offset(offset: i64, len: usize) -> usize {
+    if offset < 0 {
+        len.saturating_sub(offset.unsigned_abs() as usize)
+    } else {
+        (offset as usize).min(len)
+    }
+}
+
+/// Resolve a ${VAR..} parameter expansion.
+pub(crate) fn expand_param(name: &OsStr, op: &ParamOp, jobs: &mut Jobs) -> io::Result<OsString> {
+    let val = jobs.get_env_or_local_var(name);
+    let is_unset = |null: &bool| match &val {
+        Some(val) => *null && val.is_empty(),
+        None => true,
+    };
+    let unset_ok = matches!(
+        op,
+        ParamOp::Default(_, _)
+            | ParamOp::Assign(_, _)
+            | ParamOp::Error(_, _)
+            | ParamOp::Alternate(_, _)
+    );
+    if val.is_none() && !unset_ok && jobs.options().nounset {
+        return Err(unbound_variable(name));
+    }
+    let val_str = val
+        .as_ref()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(match op {
+        ParamOp::Default(word, null) if is_unset(null) => word.resolve_arg(jobs)?,
+        ParamOp::Assign(word, null) if is_unset(null) => {
+            let word = word.resolve_arg(jobs)?;
+            jobs.set_local_var(name.into(), word.clone());
+            word
+        }
+        ParamOp::Error(word, null) if is_unset(null) => {
+            let msg = word.resolve_arg(jobs)?;
+            let msg = if msg.is_empty() {
+                "parameter null or not set".into()
+            } else {
+                msg.to_string_lossy().to_string()
+            };
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!("{}: {msg}", name.to_string_lossy()),
+            ));
+        }
+        ParamOp::Alternate(word, null) if !is_unset(null) => word.resolve_arg(jobs)?,
+        ParamOp::Alternate(_, _) => "".into(),
+        ParamOp::Default(_, _) | ParamOp::Assign(_, _) | ParamOp::Error(_, _) => {
+            val.unwrap_or_default()
+        }
+        ParamOp::Length => val_str.chars().count().to_string().into(),
+        ParamOp::RemovePrefix(pattern, longest) => {
+            let pattern = resolve_pattern(pattern, jobs)?;
+            let mut bounds = boundaries(&val_str);
+            if *longest {
+                bounds.reverse();
+            }
+            match bounds.iter().find(|i| pattern.matches(&val_str[..**i])) {
+                Some(i) => val_str[*i..].into(),
+                None => val_str.into(),
+            }
+        }
+        ParamOp::RemoveSuffix(pattern, longest) => {
+            let pattern = resolve_pattern(pattern, jobs)?;
+            let mut bounds = boundaries(&val_str);
+            if !*longest {
+                bounds.reverse();
+            }
+            match bounds.iter().find(|i| pattern.matches(&val_str[**i..])) {
+                Some(i) => val_str[..*i].into(),
+                None => val_str.into(),
+            }
+        }
+        ParamOp::Replace(pattern, rep, all) => {
+            let pattern = resolve_pattern(pattern, jobs)?;
+            let rep = rep.resolve_arg(jobs)?;
+            replace_matches(&val_str, &pattern, &rep.to_string_lossy(), *all).into()
+        }
+        ParamOp::Substring(offset, len) => {
+            let chars: Vec<char> = val_str.chars().collect();
+            let start = char_offset(*offset, chars.len());
+            let end = match len {
+                Some(len) if *len < 0 => char_offset(*len, chars.len()),
+                Some(len) => start.saturating_add(*len as usize).min(chars.len()),
+                None => chars.len(),
+            };
+            chars[start..end.max(start)]
+                .iter()
+                .collect::<String>()
+                .into()
+        }
+    })
+}
+
+/// Expand a parsed arg into zero or more args and append them to out.  This will resolve
+/// variables and run command substitutions.
+pub fn expand_arg(arg: &Arg, jobs: &mut Jobs, out: &mut Vec<OsString>) -> io::Result<()> {
+    if let Arg::Str(val) = arg {
+        // Nothing to expand, common for args that have already been expanded.
+        out.push(val.clone());
+        return Ok(());
+    }
+    let mut pieces = vec![];
+    to_pieces(arg, &mut pieces);
+    let mut words = vec![];
+    expand_braces(pieces, &mut words);
+    for mut word in words {
+        expand_tildes(&mut word);
+        expand_globs(&word, jobs, out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_line;
+
+    fn expand(input: &str) -> Vec<String> {
+        let mut jobs = Jobs::new(false);
+        jobs.set_local_var("EXPAND_TEST".into(), "val".into());
+        jobs.set_local_var("EXPAND_PATH".into(), "/usr/lib/file.tar.gz".into());
+        jobs.set_local_var("EXPAND_EMPTY".into(), "".into());
+        let job = parse_line(input).unwrap();
+        let crate::command_data::Run::Command(command) = job.commands() else {
+            panic!("expected a single command");
+        };
+        let command = command.expand(&mut jobs).unwrap();
+        let mut res = vec![];
+        if let Some(name) = command.command(&mut jobs) {
+            res.push(name.unwrap().to_string_lossy().to_string());
+        }
+        for arg in command.args_iter() {
+            res.push(
+                arg.resolve_arg(&mut jobs)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string(),
+            );
+        }
+        res
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("echo a{b,c}d"), ["echo", "abd", "acd"]);
+        assert_eq!(expand("echo {a,b{c,d}}"), ["echo", "a", "bc", "bd"]);
+        assert_eq!(expand("echo \\{a,b}"), ["echo", "{a,b}"]);
+        assert_eq!(expand("echo '{a,b}'"), ["echo", "{a,b}"]);
+        assert_eq!(
+            expand("echo ${EXPAND_TEST}-{x,y}"),
+            ["echo", "val-x", "val-y"]
+        );
+        assert_eq!(expand("echo \"$EXPAND_TEST x\""), ["echo", "val x"]);
+        assert_eq!(expand("echo ${EXPAND_TEST}s"), ["echo", "vals"]);
+        assert_eq!(expand("echo a\\ b"), ["echo", "a b"]);
+        assert_eq!(expand("echo '*.no-such-file'"), ["echo", "*.no-such-file"]);
+        assert_eq!(expand("echo *.no-such-file"), ["echo", "*.no-such-file"]);
+        let home = expand_tilde("~".into()).to_string_lossy().to_string();
+        assert_eq!(
+            expand("echo ~/x A=~ '~'"),
+            ["echo", &format!("{home}/x"), &format!("A={home}"), "~"]
+        );
+        assert!(expand("echo src/exp*.rs").contains(&"src/expand.rs".to_string()));
+        assert_eq!(expand("echo 'src/'exp*.rs"), ["echo", "src/expand.rs"]);
+    }
+
+    #[test]
+    fn test_expand_params() {
+        assert_eq!(expand("echo ${EXPAND_UNSET:-a b}"), ["echo", "a b"]);
+        assert_eq!(expand("echo ${EXPAND_EMPTY:-def}"), ["echo", "def"]);
+        assert_eq!(expand("echo x${EXPAND_EMPTY-def}"), ["echo", "x"]);
+        assert_eq!(expand("echo ${EXPAND_TEST:-def}"), ["echo", "val"]);
+        assert_eq!(
+            expand("echo ${EXPAND_TEST:+alt} x${EXPAND_UNSET:+alt}"),
+            ["echo", "alt", "x"]
+        );
+        assert_eq!(expand("echo ${#EXPAND_PATH}"), ["echo", "20"]);
+        assert_eq!(
+            expand("echo ${EXPAND_PATH#*/}"),
+            ["echo", "usr/lib/file.tar.gz"]
+        );
+        assert_eq!(expand("echo ${EXPAND_PATH##*/}"), ["echo", "file.tar.gz"]);
+        assert_eq!(
+            expand("echo ${EXPAND_PATH%.*}"),
+            ["echo", "/usr/lib/file.tar"]
+        );
+        assert_eq!(expand("echo ${EXPAND_PATH%%.*}"), ["echo", "/usr/lib/file"]);
+        assert_eq!(
+            expand("echo ${EXPAND_PATH%'.gz'}"),
+            ["echo", "/usr/lib/file.tar"]
+        );
+        assert_eq!(expand("echo ${EXPAND_PATH/l*/x}"), ["echo", "/usr/x"]);
+        assert_eq!(
+            expand("echo ${EXPAND_PATH//\\//:}"),
+            ["echo", ":usr:lib:file.tar.gz"]
+        );
+        assert_eq!(
+            expand("echo ${EXPAND_PATH/\\*/x}"),
+            ["echo", "/usr/lib/file.tar.gz"]
+        );
+        assert_eq!(expand("echo ${EXPAND_TEST/a}"), ["echo", "vl"]);
+        assert_eq!(expand("echo ${EXPAND_PATH:5:3}"), ["echo", "lib"]);
+        assert_eq!(expand("echo ${EXPAND_PATH: -6}"), ["echo", "tar.gz"]);
+        assert_eq!(expand("echo ${EXPAND_PATH:9:-7}"), ["echo", "file"]);
+        assert_eq!(expand("echo ${EXPAND_PATH:50}"), ["echo", ""]);
+
+        let mut jobs = Jobs::new(false);
+        let job = parse_line("echo ${EXPAND_NEW:=new} $EXPAND_NEW").unwrap();
+        let crate::command_data::Run::Command(command) = job.commands() else {
+            panic!("expected a single command");
+        };
+        let command = command.expand(&mut jobs).unwrap();
+        assert_eq!(command.to_string(), "echo new new");
+        let job = parse_line("echo ${EXPAND_UNSET:?missing}").unwrap();
+        let crate::command_data::Run::Command(command) = job.commands() else {
+            panic!("expected a single command");
+        };
+        let err = command.expand(&mut jobs).unwrap_err();
+        assert_eq!(err.to_string(), "EXPAND_UNSET: missing");
+    }
+
+    #[test]
+    fn test_expand_heredoc() {
+        use crate::platform::{FromFileDesc, STDIN_FILENO};
+        use std::io::Read;
+
+        let mut jobs = Jobs::new(false);
+        jobs.set_local_var("EXPAND_TEST".into(), "val".into());
+        let job = parse_line("cat <<EOF\n$EXPAND_TEST \\$x\nEOF").unwrap();
+        let crate::command_data::Run::Command(command) = job.commands() else {
+            panic!("expected a single command");
+        };
+        let command = command.expand(&mut jobs).unwrap();
+        let fds = command.get_internal_fds();
+        assert_eq!(fds.len(), 1);
+        let fd = *fds.iter().next().unwrap();
+        assert_ne!(fd, STDIN_FILENO);
+        let mut data = String::new();
+        let mut file = unsafe { std::fs::File::from_file_desc(fd) };
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "val $x\n");
+    }
+}
@@ -1,6 +1,8 @@
 use crate::command_data::Run;
 use crate::parse::parse_line;
-use crate::platform::{OsSignal, Pid, Platform, Sys, TermSettings, STDIN_FILENO};
+use crate::platform::{FileDesc, OsSignal, Pid, Platform, Sys, TermSettings, STDIN_FILENO};
+use crate::run::run_one_command;
+use crate::signals::{self, set_signal_disposition, SignalDisposition, TrapSignal};
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
@@ -52,6 +54,53 @@ impl PidStatus {
             PidStatus::Signaled(pid, _) => *pid,
         }
     }
+
+    /// Exit status of the process, 128 + signal if it was signaled and -66 if it is still running
+    /// or had an error.
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            PidStatus::Done(_, status) => *status,
+            PidStatus::Signaled(_, signal) => 128 + *signal,
+            PidStatus::Running(_) | PidStatus::Error(_) => -66,
+        }
+    }
+}
+
+/// Options that change how the shell runs commands (see the set builtin).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ShellOptions {
+    /// Stop running commands when a command fails (set -e).
+    pub errexit: bool,
+    /// Expanding an unset variable is an error (set -u).
+    pub nounset: bool,
+    /// The status of a pipeline is the last non-zero status of any command in it.
+    pub pipefail: bool,
+}
+
+impl ShellOptions {
+    /// Names of the options for set -o.
+    pub const NAMES: [&'static str; 3] = ["errexit", "nounset", "pipefail"];
+
+    /// Get the option name, None if not a valid option.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "errexit" => Some(self.errexit),
+            "nounset" => Some(self.nounset),
+            "pipefail" => Some(self.pipefail),
+            _ => None,
+        }
+    }
+
+    /// Set the option name to on, returns false if name is not a valid option.
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        match name {
+            "errexit" => self.errexit = on,
+            "nounset" => self.nounset = on,
+            "pipefail" => self.pipefail = on,
+            _ => return false,
+        }
+        true
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -64,6 +113,8 @@ pub struct Job {
     status: JobStatus,
     interactive: bool,
     stealth: bool, // If true don't report when background job ends.
+    // Set once a stopped job has been reported, cleared when it runs again.
+    reported_stop: bool,
 }
 
 impl Job {
@@ -78,9 +129,20 @@ impl Job {
             status: JobStatus::New,
             interactive,
             stealth: false,
+            reported_stop: false,
         }
     }
 
+    /// The job number.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The commands that make up the job.
+    pub fn names(&self) -> &[String] {
+        &self.names[..]
+    }
+
     /// True if this job is empty (contains no processes).
     pub fn is_empty(&self) -> bool {
         self.pids.is_empty()
@@ -109,6 +171,7 @@ impl Job {
     /// Mark the job as running.
     pub fn mark_running(&mut self) {
         self.status = JobStatus::Running;
+        self.reported_stop = false;
     }
 
     /// Mark the job status to done.
@@ -183,6 +246,34 @@ impl Job {
         self.interactive
     }
 
+    /// Set interactive, a job that is not interactive stays in the shell's process group and does
+    /// not take the terminal.
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// True if any process in the job has not finished (it is running or stopped).
+    pub fn has_running(&self) -> bool {
+        self.pids.iter().any(|s| matches!(s, PidStatus::Running(_)))
+    }
+
+    /// Exit status of each process in the job (in pipeline order).
+    pub fn exit_statuses(&self) -> Vec<i32> {
+        self.pids
+            .iter()
+            .map(|status| status.exit_status())
+            .collect()
+    }
+
+    /// True if any process in the job exited with an error or was signaled.
+    pub fn failed(&self) -> bool {
+        self.pids.iter().any(|status| match status {
+            PidStatus::Running(_) => false,
+            PidStatus::Done(_, status) => *status != 0,
+            PidStatus::Error(_) | PidStatus::Signaled(_, _) => true,
+        })
+    }
+
     /// The PID of the shell running this job.
     pub fn shell_pid(&self) -> Pid {
         self.shell_pid
@@ -191,22 +282,50 @@ impl Job {
 
 impl Display for Job {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(
-            f,
-            "[{}]\t{}\t{:?}\t{:?}",
-            self.id, self.status, self.pids, self.names
-        )
+        let status = if self.status == JobStatus::Done && self.failed() {
+            let status = self.exit_statuses().into_iter().rfind(|s| *s != 0);
+            format!("Exit {}", status.unwrap_or_default())
+        } else {
+            self.status.to_string()
+        };
+        write!(f, "{status}\t{}", self.names.join(" | "))
     }
 }
 
+/// The action for a trapped signal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// Run a shell command.
+    Command(String),
+    /// Ignore the signal.
+    Ignore,
+    /// The signal is handled by the embedding VM (a lisp function for instance).
+    Handler,
+}
+
 pub struct Jobs {
     next_job: u32,
     jobs: Vec<Job>,
+    // Job ids from least to most recently started or stopped, the last is the current job (%+).
+    job_order: Vec<u32>,
     shell_pid: Pid,
     interactive: bool,
     term_settings: Option<TermSettings>,
     alias: HashMap<String, Run>,
     local_vars: HashMap<OsString, OsString>,
+    // Processes started for process substitutions, only reported if they fail.
+    proc_subst_jobs: Vec<Job>,
+    // Fds for process substitutions that the command being expanded needs to inherit.
+    proc_subst_fds: Vec<FileDesc>,
+    options: ShellOptions,
+    // Exit status of each command in the last foreground job.
+    pipe_status: Vec<i32>,
+    errexit_failed: bool,
+    traps: HashMap<TrapSignal, Trap>,
+    // Signals with a Trap::Handler waiting for the embedding VM to run them.
+    pending_handlers: Vec<TrapSignal>,
+    // $0 followed by the positional parameters ($1, $2, ...).
+    positional: Vec<OsString>,
 }
 
 impl Jobs {
@@ -224,11 +343,20 @@ impl Jobs {
         Self {
             next_job: 0,
             jobs: vec![],
+            job_order: vec![],
             shell_pid,
             interactive,
             term_settings,
             alias: HashMap::new(),
             local_vars: HashMap::new(),
+            proc_subst_jobs: vec![],
+            proc_subst_fds: vec![],
+            options: ShellOptions::default(),
+            pipe_status: vec![0],
+            errexit_failed: false,
+            traps: HashMap::new(),
+            pending_handlers: vec![],
+            positional: vec![],
         }
     }
 
@@ -253,9 +381,162 @@ impl Jobs {
 
     /// Push job onto the list of jobs.
     pub fn push_job(&mut self, job: Job) {
+        self.touch_job(job.id);
         self.jobs.push(job);
     }
 
+    /// Make job_id the current job (it was just started or stopped).
+    fn touch_job(&mut self, job_id: u32) {
+        self.job_order.retain(|id| *id != job_id);
+        self.job_order.push(job_id);
+    }
+
+    /// Record a process substitution, fd is the end of the pipe the command will use and job is
+    /// the substituted process.
+    pub fn push_proc_subst(&mut self, fd: FileDesc, job: Job) {
+        self.proc_subst_fds.push(fd);
+        self.proc_subst_jobs.push(job);
+    }
+
+    /// Take the fds from process substitutions since the last call.
+    pub fn take_proc_subst_fds(&mut self) -> Vec<FileDesc> {
+        std::mem::take(&mut self.proc_subst_fds)
+    }
+
+    /// Forget any process substitutions, for a forked child that does not own them.
+    pub fn clear_proc_subst(&mut self) {
+        self.proc_subst_fds.clear();
+        self.proc_subst_jobs.clear();
+    }
+
+    /// The shell options.
+    pub fn options(&self) -> &ShellOptions {
+        &self.options
+    }
+
+    /// The shell options, for the set builtin.
+    pub fn options_mut(&mut self) -> &mut ShellOptions {
+        &mut self.options
+    }
+
+    /// Exit status of each command in the last foreground job.
+    pub fn pipe_status(&self) -> &[i32] {
+        &self.pipe_status[..]
+    }
+
+    /// Record the exit statuses of the last foreground job, also sets the PIPESTATUS env var.
+    pub fn set_pipe_status(&mut self, pipe_status: Vec<i32>) {
+        let val: Vec<String> = pipe_status.iter().map(|s| s.to_string()).collect();
+        env::set_var("PIPESTATUS", val.join(" "));
+        self.pipe_status = pipe_status;
+    }
+
+    /// True if the last job run stopped because a command failed with errexit set (the shell
+    /// should exit).
+    pub fn errexit_failed(&self) -> bool {
+        self.errexit_failed
+    }
+
+    /// Set whether the last job run failed with errexit set.
+    pub fn set_errexit_failed(&mut self, failed: bool) {
+        self.errexit_failed = failed;
+    }
+
+    /// The trap for sig if set.
+    pub fn trap(&self, sig: TrapSignal) -> Option<&Trap> {
+        self.traps.get(&sig)
+    }
+
+    /// All the traps, ordered by signal number (EXIT first).
+    pub fn traps(&self) -> Vec<(TrapSignal, &Trap)> {
+        let mut traps: Vec<(TrapSignal, &Trap)> = self.traps.iter().map(|(s, t)| (*s, t)).collect();
+        traps.sort_by_key(|(sig, _)| match sig {
+            TrapSignal::Exit => 0,
+            TrapSignal::Signal(sig) => *sig,
+        });
+        traps
+    }
+
+    /// Set (or with None remove) the trap for sig, this also sets the signal's disposition.
+    pub fn set_trap(&mut self, sig: TrapSignal, trap: Option<Trap>) -> Result<(), io::Error> {
+        if let TrapSignal::Signal(signal) = sig {
+            let disposition = match &trap {
+                None => SignalDisposition::Default,
+                Some(Trap::Ignore) => SignalDisposition::Ignore,
+                Some(_) => SignalDisposition::Trap,
+            };
+            set_signal_disposition(signal, disposition)?;
+        }
+        match trap {
+            Some(trap) => self.traps.insert(sig, trap),
+            None => self.traps.remove(&sig),
+        };
+        Ok(())
+    }
+
+    /// Remove the traps that do not survive into a subshell (everything but ignored signals).
+    pub fn reset_traps_for_subshell(&mut self) {
+        let sigs: Vec<TrapSignal> = self
+            .traps
+            .iter()
+            .filter(|(_, trap)| **trap != Trap::Ignore)
+            .map(|(sig, _)| *sig)
+            .collect();
+        for sig in sigs {
+            let _ = self.set_trap(sig, None);
+        }
+        self.pending_handlers.clear();
+    }
+
+    /// Run the traps for any signals received since the last call.
+    ///
+    /// Command traps are run here, signals with a [`Trap::Handler`] are queued for
+    /// [`Jobs::take_pending_handlers`] (and the trap pending flag is set again so a VM will see
+    /// them).
+    pub fn run_pending_traps(&mut self) {
+        if !signals::trap_pending() {
+            return;
+        }
+        for sig in signals::take_pending_signals() {
+            self.run_trap(sig);
+        }
+        if !self.pending_handlers.is_empty() {
+            signals::set_trap_pending();
+        }
+    }
+
+    /// Run the trap for sig now, the EXIT trap is run with this when the shell exits.
+    pub fn run_trap(&mut self, sig: TrapSignal) {
+        match self.traps.get(&sig) {
+            Some(Trap::Command(command)) => {
+                let command = command.clone();
+                // The trap should not change the status of the command it interrupted.
+                let last_status = env::var_os("LAST_STATUS");
+                let pipe_status = self.pipe_status.clone();
+                let errexit_failed = self.errexit_failed;
+                if let Err(err) = run_one_command(&command, self) {
+                    eprintln!("trap {sig}: {err}");
+                }
+                if let Some(last_status) = last_status {
+                    env::set_var("LAST_STATUS", last_status);
+                }
+                self.set_pipe_status(pipe_status);
+                self.errexit_failed = errexit_failed;
+            }
+            Some(Trap::Handler) => {
+                if !self.pending_handlers.contains(&sig) {
+                    self.pending_handlers.push(sig);
+                }
+            }
+            Some(Trap::Ignore) | None => {}
+        }
+    }
+
+    /// Take the signals whose [`Trap::Handler`] needs to be run.
+    pub fn take_pending_handlers(&mut self) -> Vec<TrapSignal> {
+        std::mem::take(&mut self.pending_handlers)
+    }
+
     /// Set not on a tty.
     pub fn set_no_tty(&mut self) {
         self.term_settings = None;
@@ -267,10 +548,112 @@ impl Jobs {
     }
 
     /// Get the mutable job for job_id if it exists.
+    pub fn get_job(&self, job_id: u32) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == job_id)
+    }
+
     pub fn get_job_mut(&mut self, job_id: u32) -> Option<&mut Job> {
         self.jobs.iter_mut().find(|job| job.id == job_id)
     }
 
+    /// Job ids in the order they were started.
+    pub fn job_ids(&self) -> Vec<u32> {
+        self.jobs.iter().map(|job| job.id).collect()
+    }
+
+    /// The current job (%+), the most recently started or stopped job.
+    pub fn current_job(&self) -> Option<u32> {
+        self.job_order.last().copied()
+    }
+
+    /// The previous job (%-), the job that was current before the current job.
+    pub fn previous_job(&self) -> Option<u32> {
+        self.job_order.iter().rev().nth(1).copied()
+    }
+
+    /// Format a job for display with a + for the current job and - for the previous job.
+    pub fn job_line(&self, job: &Job) -> String {
+        let marker = if Some(job.id) == self.current_job() {
+            '+'
+        } else if Some(job.id) == self.previous_job() {
+            '-'
+        } else {
+            ' '
+        };
+        format!("[{}]{marker}\t{job}", job.id)
+    }
+
+    /// Find the job id for a job spec.
+    /// Supports %N (job number), %+ or %% (current job), %- (previous job), %name (job whose
+    /// command starts with name) and %?name (job whose command contains name).
+    pub fn find_job(&self, spec: &str) -> Result<u32, String> {
+        let Some(spec) = spec.strip_prefix('%') else {
+            return Err(format!("{spec}: not a job spec"));
+        };
+        let matching = |matches: &dyn Fn(&str) -> bool| {
+            let mut found = self
+                .jobs
+                .iter()
+                .filter(|job| matches(&job.names.join(" | ")));
+            match (found.next(), found.next()) {
+                (Some(job), None) => Ok(job.id),
+                (Some(_), Some(_)) => Err(format!("%{spec}: ambiguous job spec")),
+                (None, _) => Err(format!("%{spec}: no such job")),
+            }
+        };
+        match spec {
+            "" | "+" | "%" => self
+                .current_job()
+                .ok_or_else(|| "no current job".to_string()),
+            "-" => self
+                .previous_job()
+                .ok_or_else(|| "no previous job".to_string()),
+            _ => {
+                if let Ok(id) = spec.parse::<u32>() {
+                    if self.jobs.iter().any(|job| job.id == id) {
+                        Ok(id)
+                    } else {
+                        Err(format!("%{spec}: no such job"))
+                    }
+                } else if let Some(name) = spec.strip_prefix('?') {
+                    matching(&|command| command.contains(name))
+                } else {
+                    matching(&|command| command.starts_with(spec))
+                }
+            }
+        }
+    }
+
+    /// The id of the job that contains pid.
+    pub fn job_for_pid(&self, pid: Pid) -> Option<u32> {
+        self.jobs
+            .iter()
+            .find(|job| job.pids.iter().any(|status| status.pid() == pid))
+            .map(|job| job.id)
+    }
+
+    /// Remove job job_id from the jobs list, it will no longer be reported or waited for.
+    pub fn remove_job(&mut self, job_id: u32) -> Option<Job> {
+        let idx = self.jobs.iter().position(|job| job.id == job_id)?;
+        self.job_order.retain(|id| *id != job_id);
+        Some(self.jobs.remove(idx))
+    }
+
+    /// Wait for job job_id to finish and remove it from the jobs list.
+    /// Returns the exit status of the job, None if it does not exist or it stopped.
+    pub fn wait_job(&mut self, job_id: u32) -> Option<i32> {
+        let job = self.get_job_mut(job_id)?;
+        let status = Sys::wait_job(job);
+        if job.has_running() {
+            // Stopped, leave it for fg/bg.
+            self.touch_job(job_id);
+            return None;
+        }
+        let job = self.remove_job(job_id)?;
+        let statuses = job.exit_statuses();
+        status.or(statuses.last().copied())
+    }
+
     /// Check any pids in a job by calling wait and updating the books.
     pub fn reap_procs(&mut self) {
         for job in self.jobs.iter_mut() {
@@ -290,14 +673,44 @@ impl Jobs {
                 }
             }
         }
-        // Remove any Done jobs.
+        // Reap process substitutions, failures are moved to the jobs list to be reported.
+        let mut i = 0;
+        while i < self.proc_subst_jobs.len() {
+            let job = &mut self.proc_subst_jobs[i];
+            let pids: Vec<Pid> = job.pids().iter().map(|s| s.pid()).collect();
+            for pid in &pids {
+                Sys::try_wait_pid(*pid, job);
+            }
+            if job.has_running() {
+                i += 1;
+            } else {
+                let mut job = self.proc_subst_jobs.remove(i);
+                if job.failed() {
+                    job.mark_done();
+                    job.set_stealth(false);
+                    self.jobs.push(job);
+                }
+            }
+        }
+        // Report newly stopped jobs.
+        for i in 0..self.jobs.len() {
+            let job = &self.jobs[i];
+            if job.status() == JobStatus::Stopped && !job.reported_stop {
+                let id = job.id;
+                self.touch_job(id);
+                eprintln!("{}", self.job_line(&self.jobs[i]));
+                self.jobs[i].reported_stop = true;
+            }
+        }
+        // Report and remove any Done jobs.
         let jobs_len = self.jobs.len();
         for i in (0..jobs_len).rev() {
             if let JobStatus::Done = self.jobs[i].status() {
                 if !self.jobs[i].stealth() {
-                    eprintln!("{}", self.jobs[i]);
+                    eprintln!("{}", self.job_line(&self.jobs[i]));
                 }
-                self.jobs.remove(i);
+                let job = self.jobs.remove(i);
+                self.job_order.retain(|id| *id != job.id);
             }
         }
     }
@@ -305,10 +718,17 @@ impl Jobs {
     /// Move the job for job_num to te foreground.
     pub fn foreground_job(&mut self, job_num: u32) {
         let term_settings = self.term_settings.clone();
+        if self.get_job(job_num).is_some() {
+            self.touch_job(job_num);
+        }
         if let Some(job) = self.get_job_mut(job_num) {
             if let Err(err) = Sys::foreground_job(job, &term_settings) {
                 eprintln!("Error making job {job_num} foreground in parent: {err}");
             }
+            if !job.has_running() {
+                // Finished in the foreground, no need to report it.
+                self.remove_job(job_num);
+            }
         } else {
             eprintln!("job number {job_num} is invalid");
         }
@@ -316,6 +736,9 @@ impl Jobs {
 
     /// Move the job for job_num to te background and running (start a stopped job in the background).
     pub fn background_job(&mut self, job_num: u32) {
+        if self.get_job(job_num).is_some() {
+            self.touch_job(job_num);
+        }
         if let Some(job) = self.get_job_mut(job_num) {
             if let Err(err) = Sys::background_job(job) {
                 eprintln!("Error making job {job_num} background in parent: {err}");
@@ -342,6 +765,18 @@ impl Jobs {
         self.alias.get(name.as_ref()).cloned()
     }
 
+    /// True if name is an alias.
+    pub fn has_alias<S: AsRef<str>>(&self, name: S) -> bool {
+        self.alias.contains_key(name.as_ref())
+    }
+
+    /// The names of all the aliases, sorted.
+    pub fn alias_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.alias.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
     /// Clears all the existing aliases.
     pub fn clear_aliases(&mut self) {
         self.alias.clear();
@@ -354,7 +789,7 @@ impl Jobs {
 
     /// Add an alias.
     pub fn add_alias(&mut self, name: String, value: String) -> Result<(), io::Error> {
-        let runj = parse_line(self, &value)?;
+        let runj = parse_line(&value)?;
         self.alias.insert(name, runj.into_run());
         Ok(())
     }
@@ -391,6 +826,47 @@ impl Jobs {
         self.local_vars.get(key).map(|s| s as &OsStr)
     }
 
+    /// Set $0 (name) and the positional parameters $1, $2, etc (args).
+    pub fn set_positional(&mut self, name: OsString, args: Vec<OsString>) {
+        self.positional = vec![name];
+        self.positional.extend(args);
+    }
+
+    /// The positional parameters ($1 on, not $0).
+    pub fn positional(&self) -> &[OsString] {
+        self.positional.get(1..).unwrap_or_default()
+    }
+
+    /// Remove the first n positional parameters, false if there are not n to remove.
+    pub fn shift_positional(&mut self, n: usize) -> bool {
+        if n > self.positional().len() {
+            false
+        } else {
+            self.positional.drain(1..n + 1);
+            true
+        }
+    }
+
+    /// Value of a special parameter: $0-$9 (positional), $# (number of positional parameters),
+    /// $@ and $* (all positional parameters), $? (last status) and $$ (shell pid).
+    fn special_var(&self, key: &OsStr) -> Option<OsString> {
+        let key = key.to_str()?;
+        match key {
+            "#" => Some(self.positional().len().to_string().into()),
+            "@" | "*" => Some(self.positional().join(OsStr::new(" "))),
+            "?" => Some(env::var_os("LAST_STATUS").unwrap_or_else(|| "0".into())),
+            "$" => Some(self.shell_pid.to_string().into()),
+            _ => {
+                let idx: usize = key.parse().ok()?;
+                if key.len() == 1 {
+                    self.positional.get(idx).cloned()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
     /// Remove the local var key.
     /// If key exists returns the removed value.
     pub fn remove_local_var(&mut self, key: &OsStr) -> Option<OsString> {
@@ -400,7 +876,9 @@ impl Jobs {
     /// First tries to fin key in the environment then checks local variable and returns None if
     /// not found.
     pub fn get_env_or_local_var(&self, key: &OsStr) -> Option<OsString> {
-        if let Some(val) = env::var_os(key) {
+        if let Some(val) = self.special_var(key) {
+            Some(val)
+        } else if let Some(val) = env::var_os(key) {
             Some(val)
         } else {
             self.get_local_var(key).map(|val| val.to_os_string())
@@ -411,8 +889,76 @@ impl Jobs {
 impl Display for Jobs {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         for job in &self.jobs {
-            writeln!(f, "{job}")?;
+            writeln!(f, "{}", self.job_line(job))?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn push_test_job(jobs: &mut Jobs, pid: &str, name: &str) {
+        let mut job = jobs.new_job();
+        job.add_process(Pid::from_str(pid).unwrap(), name);
+        job.mark_running();
+        jobs.push_job(job);
+    }
+
+    #[test]
+    fn test_find_job() {
+        let mut jobs = Jobs::new(false);
+        assert!(jobs.find_job("%").is_err());
+        push_test_job(&mut jobs, "100001", "sleep 10");
+        push_test_job(&mut jobs, "100002", "make all");
+        push_test_job(&mut jobs, "100003", "sleep 20");
+        assert_eq!(jobs.find_job("%2"), Ok(2));
+        assert_eq!(jobs.find_job("%"), Ok(3));
+        assert_eq!(jobs.find_job("%+"), Ok(3));
+        assert_eq!(jobs.find_job("%%"), Ok(3));
+        assert_eq!(jobs.find_job("%-"), Ok(2));
+        assert_eq!(jobs.find_job("%make"), Ok(2));
+        assert_eq!(jobs.find_job("%?20"), Ok(3));
+        assert_eq!(
+            jobs.find_job("%sleep"),
+            Err("%sleep: ambiguous job spec".to_string())
+        );
+        assert_eq!(jobs.find_job("%4"), Err("%4: no such job".to_string()));
+        assert_eq!(jobs.find_job("%?vi"), Err("%?vi: no such job".to_string()));
+        assert!(jobs.find_job("2").is_err());
+        assert_eq!(jobs.job_for_pid(Pid::from_str("100002").unwrap()), Some(2));
+        assert_eq!(
+            jobs.job_line(jobs.get_job(3).unwrap()),
+            "[3]+\tRunning\tsleep 20"
+        );
+        jobs.remove_job(3);
+        assert_eq!(jobs.find_job("%"), Ok(2));
+        assert_eq!(jobs.find_job("%-"), Ok(1));
+    }
+
+    #[test]
+    fn test_current_job_order() {
+        let mut jobs = Jobs::new(false);
+        push_test_job(&mut jobs, "100001", "sleep 10");
+        push_test_job(&mut jobs, "100002", "sleep 20");
+        push_test_job(&mut jobs, "100003", "sleep 30");
+        // Stopping the oldest job makes it the current job.
+        jobs.get_job_mut(1).unwrap().mark_stopped();
+        jobs.reap_procs();
+        assert_eq!(jobs.find_job("%+"), Ok(1));
+        assert_eq!(jobs.find_job("%-"), Ok(3));
+        assert_eq!(
+            jobs.job_line(jobs.get_job(1).unwrap()),
+            "[1]+\tStopped\tsleep 10"
+        );
+        // fg and bg make the job they continue current.
+        jobs.touch_job(2);
+        assert_eq!(jobs.find_job("%+"), Ok(2));
+        assert_eq!(jobs.find_job("%-"), Ok(1));
+        jobs.remove_job(2);
+        assert_eq!(jobs.find_job("%+"), Ok(1));
+        assert_eq!(jobs.find_job("%-"), Ok(3));
+    }
+}
@@ -1,6 +1,9 @@
+pub mod arith;
 pub mod builtins;
 pub mod command_data;
 pub mod config;
+pub mod dirs;
+pub mod expand;
 pub mod glob;
 pub mod jobs;
 pub mod parse;
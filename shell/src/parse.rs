@@ -1,17 +1,104 @@
 //! Shell reader/parser.
 //! Parses a string into a shell command.
-//! Roughly a subset of <https://www.gnu.org/software/bash/manual/html_node/Shell-Expansions.html>
+//! Parsing has no side effects (no commands are run and no variables or files are read), the
+//! args in the result are expanded when the command is run, see [`crate::expand`].
 
-use crate::builtins::expand_tilde;
-use crate::command_data::{Arg, CommandWithArgs, Redirects, Run};
-use crate::glob::{expand_glob, GlobOutput};
-use crate::jobs::Jobs;
+use crate::arith::parse_arith;
+use crate::command_data::{Arg, CommandWithArgs, ParamOp, Redirects, Run, Span};
 use crate::platform::{FileDesc, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::io;
 use std::io::ErrorKind;
 use std::iter::Peekable;
-use std::str::{Chars, FromStr};
+use std::str::{CharIndices, FromStr};
+use std::{fmt, io};
+
+/// Chars of the line being parsed, tracks the byte offset for spans.
+struct Input<'a> {
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    // Offset of the newline that ends a line with here-documents and where their bodies end.
+    heredoc: Option<(usize, usize)>,
+}
+
+impl<'a> Input<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            chars: text.char_indices().peekable(),
+            heredoc: None,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek().map(|(_, ch)| ch)
+    }
+
+    /// The char after the next char.
+    fn peek_second(&mut self) -> Option<char> {
+        let offset = self.offset();
+        self.text[offset..].chars().nth(1)
+    }
+
+    /// Byte offset of the next char (the input length at the end).
+    fn offset(&mut self) -> usize {
+        self.chars
+            .peek()
+            .map(|(i, _)| *i)
+            .unwrap_or(self.text.len())
+    }
+
+    /// Read the body of a here-document (lines after the current one until a line that is
+    /// delim).  The body is skipped when the main parse reaches the end of the current line.
+    fn read_heredoc_body(&mut self, delim: &str, strip_tabs: bool) -> Result<String, io::Error> {
+        let (newline, start) = if let Some(heredoc) = self.heredoc {
+            heredoc
+        } else {
+            let offset = self.offset();
+            let newline = self.text[offset..]
+                .find('\n')
+                .map(|i| i + offset)
+                .ok_or_else(unterminated_heredoc)?;
+            (newline, newline + 1)
+        };
+        let mut body = String::new();
+        let mut end = start;
+        for line in self.text[start..].split_inclusive('\n') {
+            end += line.len();
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let line = if strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line
+            };
+            if line == delim {
+                self.heredoc = Some((newline, end));
+                return Ok(body);
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        Err(unterminated_heredoc())
+    }
+}
+
+impl Iterator for Input<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (offset, ch) = self.chars.next()?;
+        if let Some((newline, end)) = self.heredoc {
+            if offset == newline {
+                // Skip the here-document bodies that follow this line.
+                self.heredoc = None;
+                while self.offset() < end {
+                    self.chars.next();
+                }
+            }
+        }
+        Some(ch)
+    }
+}
 
 /// Type of the current sequence being parsed.
 #[derive(Copy, Clone, Debug)]
@@ -123,8 +210,12 @@ struct ParseState {
     // Should not be None, is Option to allow ownership to change.
     command: Option<CommandWithArgs>,
     stdio: Redirects,
-    // Should not be None, is Option to allow ownership to change.
-    token: Option<String>,
+    // Unquoted text of the current token (escapes are kept for expansion).
+    token: String,
+    // Parts of the current token before token (quoted strings, substitutions, etc).
+    parts: Vec<Arg>,
+    token_start: Option<usize>,
+    token_end: usize,
     last_ch: char,
     current_seq: SeqType,
 }
@@ -133,14 +224,16 @@ impl ParseState {
     fn new() -> Self {
         let ret = ParsedJob::new();
         let command = Some(CommandWithArgs::new());
-        let token = Some(String::new());
         let last_ch = ' ';
         let current_seq = SeqType::Command;
         Self {
             ret,
             command,
             stdio: Redirects::default(),
-            token,
+            token: String::new(),
+            parts: vec![],
+            token_start: None,
+            token_end: 0,
             last_ch,
             current_seq,
         }
@@ -150,146 +243,50 @@ impl ParseState {
         self.command.as_mut().expect("invalid empty command")
     }
 
-    fn token(&mut self) -> &mut String {
-        self.token.as_mut().expect("invalid empty token")
-    }
-
-    fn take_token(&mut self) -> String {
-        let result = self.token.take().expect("invalid empty token");
-        self.token = Some(String::new());
-        result
-    }
-
-    /// Strip single quotes and Escapes from a string and then save it as an arg.
-    /// Note double quoted strings will have already been "resolved" and made single quoted by now
-    /// so no need to strip double quotes.
-    fn strip_quotes(&mut self, token: &str) {
-        if token.contains('\'') || token.contains('\\') {
-            let mut new_token = String::new();
-            let mut quoted = false;
-            let mut last_ch = ' ';
-            for ch in token.chars() {
-                match ch {
-                    '\\' if !quoted && last_ch != '\\' => {}
-                    '\'' if last_ch != '\\' => quoted = !quoted,
-                    _ => new_token.push(ch),
-                }
-                last_ch = ch;
-            }
-            self.command().push_arg(Arg::Str(new_token.into()));
-        } else {
-            self.command().push_arg(Arg::Str(token.into()));
+    /// Note that the current token starts at offset (if not already started).
+    fn start_token(&mut self, offset: usize) {
+        if self.token_start.is_none() {
+            self.token_start = Some(offset);
         }
     }
 
-    /// Expand tokens that are file expansions (globs) into multiple arguments.
-    fn expand_globs(&mut self, token: &str) {
-        match expand_glob(token) {
-            GlobOutput::Arg(arg) => self.strip_quotes(&arg.to_string_lossy()),
-            GlobOutput::Args(args) => {
-                for arg in args {
-                    self.strip_quotes(&arg.to_string_lossy());
-                }
-            }
-        }
-    }
-
-    /// Expand tokens that contain unquoted $ chars.
-    fn expand_params_comms(&mut self, jobs: &mut Jobs, token: &str) -> Result<(), io::Error> {
-        if token.contains('$') {
-            let mut chars = token.chars().peekable();
-            let mut token = String::new();
-            let mut last_ch = ' ';
-            let mut quoted = false;
-            while let Some(ch) = chars.next() {
-                match ch {
-                    '$' if last_ch != '\\' && !quoted => {
-                        let expansion = self.expand_var_or_command(jobs, &mut chars, None)?;
-                        token.push_str(&expansion);
-                    }
-                    '\'' if last_ch != '\\' => {
-                        quoted = !quoted;
-                    }
-                    _ => token.push(ch),
-                }
-                last_ch = ch;
-            }
-            self.expand_globs(&token);
-        } else {
-            self.expand_globs(token);
+    /// Add a part (quoted string, substitution) to the current token.
+    fn push_part(&mut self, arg: Arg) {
+        if !self.token.is_empty() {
+            let text = std::mem::take(&mut self.token);
+            self.parts.push(Arg::Unquoted(text.into()));
         }
-        Ok(())
+        self.parts.push(arg);
     }
 
-    /// Expand ~ into home directory.
-    /// TODO support other tilde expansions from <https://www.gnu.org/software/bash/manual/html_node/Tilde-Expansion.html>
-    fn expand_tildes(&mut self, jobs: &mut Jobs, token: &str) -> Result<(), io::Error> {
-        let ptok = expand_tilde(token.into());
-        let token = ptok.to_string_lossy();
-        self.expand_params_comms(jobs, &token)
-    }
-
-    /// Expand {..} expressions in arguments into multiple arguments.
-    /// TODO Add range expressions, see <https://www.gnu.org/software/bash/manual/html_node/Brace-Expansion.html>
-    fn expand_braces(&mut self, jobs: &mut Jobs, token: &str) -> Result<(), io::Error> {
-        let mut well_formed = false;
-        let mut open = 0;
-        let mut close = 0;
-        let mut last_idx = 0;
-        let mut options = vec![];
-        let mut open_braces = 0;
-        let mut last_ch = ' ';
-        let mut quoted = false;
-        for (i, ch) in token.chars().enumerate() {
-            if ch == '{' && last_ch != '\\' && !quoted {
-                if open_braces == 0 {
-                    open = i;
-                    last_idx = i;
-                }
-                open_braces += 1;
-            }
-            if open_braces == 1 && ch == ',' && last_ch != '\\' && !quoted {
-                options.push(&token[last_idx + 1..i]);
-                last_idx = i;
-            }
-            if ch == '}' && last_ch != '\\' && !quoted {
-                if open_braces == 1 {
-                    close = i;
-                    if !options.is_empty() {
-                        options.push(&token[last_idx + 1..i]);
-                        well_formed = true;
-                        break;
-                    }
+    /// If the current token is just a file descriptor number then take it.
+    fn take_fd_token(&mut self) -> Option<FileDesc> {
+        if self.parts.is_empty() {
+            if let Ok(fd) = FileDesc::from_str(&self.token) {
+                if fd >= STDIN_FILENO {
+                    self.token.clear();
+                    self.token_start = None;
+                    return Some(fd);
                 }
-                open_braces -= 1;
-            }
-            if ch == '\'' && last_ch != '\\' {
-                quoted = !quoted;
-            }
-
-            last_ch = ch;
-        }
-        if well_formed {
-            let prefix = &token[0..open];
-            let suffix = &token[close + 1..];
-            for middle in options {
-                let new_token = format!("{prefix}{middle}{suffix}");
-                self.expand_braces(jobs, &new_token)?;
             }
-        } else {
-            self.expand_tildes(jobs, token)?;
         }
-        Ok(())
+        None
     }
 
-    /// Process a token by applying expansions and saving to argument list.
-    /// TODO add process substitution and word splitting.
-    fn proc_token(&mut self, jobs: &mut Jobs) -> Result<(), io::Error> {
-        let token = self.take_token();
-        if !token.is_empty() {
-            self.expand_braces(jobs, &token)?;
+    /// Save the current token (unexpanded) to the argument list.
+    fn proc_token(&mut self) {
+        if !self.token.is_empty() {
+            let text = std::mem::take(&mut self.token);
+            self.parts.push(Arg::Unquoted(text.into()));
         }
-        Ok(())
+        let arg = match self.parts.len() {
+            0 => return,
+            1 => self.parts.pop().expect("we had one element..."),
+            _ => Arg::Compound(std::mem::take(&mut self.parts)),
+        };
+        let start = self.token_start.take().unwrap_or(self.token_end);
+        let span = Span::new(start, self.token_end);
+        self.command().push_arg_with_span(arg, span);
     }
 
     fn end_command(&mut self, background: bool) {
@@ -303,12 +300,9 @@ impl ParseState {
         }
     }
 
-    fn pipe_or(&mut self, jobs: &mut Jobs, ch: char, next_char: char) -> Result<(), io::Error> {
-        if self.last_ch == '\\' {
-            self.token().push('|');
-            self.last_ch = ' ';
-        } else if self.last_ch == '|' {
-            self.proc_token(jobs)?;
+    fn pipe_or(&mut self, ch: char, next_char: char) {
+        if self.last_ch == '|' {
+            self.proc_token();
             self.end_command(false);
             self.current_seq = SeqType::Or;
             self.last_ch = ' ';
@@ -316,63 +310,39 @@ impl ParseState {
             // If the next char is not a '|' then we have a pipe, else will loop and become an OR.
             self.last_ch = ch;
         } else {
-            self.proc_token(jobs)?;
+            self.proc_token();
             self.end_command(false);
             self.current_seq = SeqType::Pipe;
             self.last_ch = ' ';
         }
-        Ok(())
     }
 
-    fn seq(&mut self, jobs: &mut Jobs) -> Result<(), io::Error> {
-        self.proc_token(jobs)?;
+    fn seq(&mut self) {
+        self.proc_token();
         self.end_command(false);
         self.current_seq = SeqType::Sequence;
-        Ok(())
     }
 
-    fn and(&mut self, jobs: &mut Jobs) -> Result<(), io::Error> {
-        self.proc_token(jobs)?;
+    fn and(&mut self) {
+        self.proc_token();
         self.end_command(false);
         self.current_seq = SeqType::And;
         self.last_ch = ' ';
-        Ok(())
     }
 
-    fn redir_out(
-        &mut self,
-        jobs: &mut Jobs,
-        chars: &mut Peekable<Chars>,
-        end_char: Option<char>,
-    ) -> Result<(), io::Error> {
-        if self.last_ch == '\\' {
-            self.token().push('>');
-            self.last_ch = ' ';
-            return Ok(());
-        }
+    fn redir_out(&mut self, chars: &mut Input, end_char: Option<char>) -> Result<(), io::Error> {
         let amp = self.last_ch == '&';
-        let out_fd = if let Some(token) = &self.token {
-            if let Ok(fd) = FileDesc::from_str(token) {
-                if fd >= STDIN_FILENO {
-                    self.take_token();
-                    fd
-                } else {
-                    self.proc_token(jobs)?;
-                    STDOUT_FILENO
-                }
-            } else {
-                self.proc_token(jobs)?;
-                STDOUT_FILENO
-            }
+        let out_fd = if let Some(fd) = self.take_fd_token() {
+            fd
         } else {
-            self.proc_token(jobs)?;
+            self.proc_token();
             STDOUT_FILENO
         };
         let next_char = *chars.peek().unwrap_or(&' ');
         if next_char == '>' {
             chars.next();
             consume_whitespace(chars);
-            let fd_arg = read_arg(jobs, chars, end_char)?;
+            let fd_arg = read_arg(chars, end_char)?;
             self.stdio.set_out_path(out_fd, fd_arg, false);
             self.last_ch = ' ';
         } else {
@@ -380,7 +350,7 @@ impl ParseState {
                 chars.next(); // Consume the &
             }
             consume_whitespace(chars);
-            let fd_arg = read_arg(jobs, chars, end_char)?;
+            let fd_arg = read_arg(chars, end_char)?;
             if next_char == '&' {
                 self.stdio.set_out_fd(out_fd, fd_arg, true);
             } else {
@@ -394,41 +364,32 @@ impl ParseState {
         Ok(())
     }
 
-    fn redir_in(
-        &mut self,
-        jobs: &mut Jobs,
-        chars: &mut Peekable<Chars>,
-        end_char: Option<char>,
-    ) -> Result<(), io::Error> {
-        if self.last_ch == '\\' {
-            self.token().push('<');
-            self.last_ch = ' ';
-            return Ok(());
-        }
-        let in_fd = if let Some(token) = &self.token {
-            if let Ok(fd) = FileDesc::from_str(token) {
-                if fd >= STDIN_FILENO {
-                    self.take_token();
-                    fd
-                } else {
-                    self.proc_token(jobs)?;
-                    STDIN_FILENO
-                }
-            } else {
-                self.proc_token(jobs)?;
-                STDIN_FILENO
-            }
+    fn redir_in(&mut self, chars: &mut Input, end_char: Option<char>) -> Result<(), io::Error> {
+        let in_fd = if let Some(fd) = self.take_fd_token() {
+            fd
         } else {
-            self.proc_token(jobs)?;
+            self.proc_token();
             STDIN_FILENO
         };
         let next_char = *chars.peek().unwrap_or(&' ');
         if next_char == '<' {
             chars.next();
-            consume_whitespace(chars);
-            let fd_arg = read_arg(jobs, chars, end_char)?;
+            let data = match chars.peek() {
+                Some('<') => {
+                    // Here-string, word plus a newline.
+                    chars.next();
+                    consume_whitespace(chars);
+                    let word = read_arg(chars, end_char)?;
+                    Arg::Compound(vec![word, Arg::Str("\n".into())])
+                }
+                Some('-') => {
+                    chars.next();
+                    read_heredoc(chars, true)?
+                }
+                _ => read_heredoc(chars, false)?,
+            };
             self.last_ch = ' ';
-            self.stdio.set_in_direct(in_fd, fd_arg);
+            self.stdio.set_in_direct(in_fd, data);
         } else if next_char == '>' {
             // <> bidirectional fd.
             chars.next();
@@ -437,7 +398,7 @@ impl ParseState {
                 chars.next(); // Consume the &
             }
             consume_whitespace(chars);
-            let fd_arg = read_arg(jobs, chars, end_char)?;
+            let fd_arg = read_arg(chars, end_char)?;
             self.last_ch = ' ';
             if next_char == '&' {
                 self.stdio.set_in_out_fd(in_fd, fd_arg);
@@ -449,7 +410,7 @@ impl ParseState {
                 chars.next(); // Consume the &
             }
             consume_whitespace(chars);
-            let fd_arg = read_arg(jobs, chars, end_char)?;
+            let fd_arg = read_arg(chars, end_char)?;
             self.last_ch = ' ';
             if next_char == '&' {
                 self.stdio.set_in_fd(in_fd, fd_arg, true);
@@ -459,46 +420,6 @@ impl ParseState {
         }
         Ok(())
     }
-
-    fn expand_var_or_command(
-        &mut self,
-        jobs: &mut Jobs,
-        chars: &mut Peekable<Chars>,
-        end_char: Option<char>,
-    ) -> Result<String, io::Error> {
-        if let Some('(') = chars.peek() {
-            // Subshell to capture
-            chars.next();
-            let mut sub = parse_line_inner(jobs, chars, Some(')'))?;
-            if let Some(sub) = sub.commands.take() {
-                return Ok(Arg::Command(sub)
-                    .resolve_arg(jobs)?
-                    .to_string_lossy()
-                    .to_string());
-            }
-        } else {
-            // Env var
-            let name = if let Some('{') = chars.peek() {
-                chars.next();
-                let r = read_token(chars, Some('}'));
-                if let Some('}') = chars.peek() {
-                    chars.next();
-                    r
-                } else {
-                    return Err(io::Error::new(ErrorKind::Other, "bad substitution"));
-                }
-            } else {
-                read_token(chars, end_char)
-            };
-            if !name.is_empty() {
-                return Ok(Arg::Var(name.into())
-                    .resolve_arg(jobs)?
-                    .to_string_lossy()
-                    .to_string());
-            }
-        }
-        Ok("".to_string())
-    }
 }
 
 impl From<ParseState> for ParsedJob {
@@ -507,7 +428,7 @@ impl From<ParseState> for ParsedJob {
     }
 }
 
-fn consume_whitespace(chars: &mut Peekable<Chars>) {
+fn consume_whitespace(chars: &mut Input) {
     while let Some(ch) = chars.peek() {
         if ch.is_whitespace() {
             chars.next();
@@ -521,7 +442,7 @@ fn consume_whitespace(chars: &mut Peekable<Chars>) {
 /// consumes the end quote.
 /// This simply reads the chars until the next ' and puts them in a String Arg.
 /// Note, can not produce a string containing a ' character.
-fn read_simple_string(chars: &mut Peekable<Chars>) -> Result<Arg, io::Error> {
+fn read_simple_string(chars: &mut Input) -> Result<Arg, io::Error> {
     let mut res = String::new();
     let mut next_ch = chars.peek().copied();
     while let Some(ch) = next_ch {
@@ -533,23 +454,7 @@ fn read_simple_string(chars: &mut Peekable<Chars>) -> Result<Arg, io::Error> {
         res.push(ch);
         next_ch = chars.peek().copied();
     }
-    Err(io::Error::new(ErrorKind::Other, "unclosed string"))
-}
-
-fn read_chars_until(
-    chars: &mut Peekable<Chars>,
-    token: &mut String,
-    end_ch: char,
-) -> Result<(), io::Error> {
-    let mut next_ch = chars.next();
-    while let Some(ch) = next_ch {
-        token.push(ch);
-        if ch == end_ch {
-            return Ok(());
-        }
-        next_ch = chars.next();
-    }
-    Err(io::Error::new(ErrorKind::Other, "unclosed expression"))
+    Err(unclosed_string())
 }
 
 fn char_to_hex_num(ch: char) -> Result<u8, io::Error> {
@@ -572,7 +477,7 @@ fn char_to_hex_num(ch: char) -> Result<u8, io::Error> {
 }
 
 /// Read an ascii char from 0x00-0x7F endowed in a string as '\xXX' where X is single hex digit.
-fn escape_to_char(chars: &mut Peekable<Chars>) -> Result<char, io::Error> {
+fn escape_to_char(chars: &mut Input) -> Result<char, io::Error> {
     if let (Some(ch1), Some(ch2)) = (chars.next(), chars.peek()) {
         let ch_n: u8 = (char_to_hex_num(ch1)? * 16) + (char_to_hex_num(*ch2)?);
         if ch_n > 0x7f {
@@ -593,7 +498,7 @@ fn escape_to_char(chars: &mut Peekable<Chars>) -> Result<char, io::Error> {
 
 /// Read a UTF8 codepoint encoded in a string with '\uXXXXXXXX' or \u{XXXXXXXX}' where X is a
 /// single hex digit.  There can be 1-8 hex values (X- a nibble) in the encoding (Up to 4 bytes).
-fn read_utf_scalar(chars: &mut Peekable<Chars>) -> Result<char, io::Error> {
+fn read_utf_scalar(chars: &mut Input) -> Result<char, io::Error> {
     fn finish(char_u32: u32) -> Result<char, io::Error> {
         if let Some(val) = std::char::from_u32(char_u32) {
             Ok(val)
@@ -655,7 +560,7 @@ fn read_utf_scalar(chars: &mut Peekable<Chars>) -> Result<char, io::Error> {
 /// Read string surrounded by quote (").  Assumes chars is on the open quote and
 /// consumes the end quote.
 /// This version will handle interpolation and escape chars.
-fn read_string(jobs: &mut Jobs, chars: &mut Peekable<Chars>) -> Result<Arg, io::Error> {
+fn read_string(chars: &mut Input) -> Result<Arg, io::Error> {
     let mut res = String::new();
     let mut arg = None;
     let mut next_ch = chars.peek().copied();
@@ -672,7 +577,7 @@ fn read_string(jobs: &mut Jobs, chars: &mut Peekable<Chars>) -> Result<Arg, io::
             }
         } else if ch == '$' {
             chars.next();
-            let spec_arg = read_special_arg(jobs, chars, Some('"'))?;
+            let spec_arg = read_special_arg(chars, Some('"'))?;
             if let Some(Arg::Compound(mut args)) = arg {
                 if !res.is_empty() {
                     args.push(Arg::Str(res.into()));
@@ -772,10 +677,10 @@ fn read_string(jobs: &mut Jobs, chars: &mut Peekable<Chars>) -> Result<Arg, io::
             next_ch = chars.peek().copied();
         }
     }
-    Err(io::Error::new(ErrorKind::Other, "unclosed string"))
+    Err(unclosed_string())
 }
 
-fn read_token(chars: &mut Peekable<Chars>, end_char: Option<char>) -> String {
+fn read_token(chars: &mut Input, end_char: Option<char>) -> String {
     let end_char = end_char.unwrap_or(' ');
     let end_set = ['"', '\'', '$', '|', ';', '&', '<', '>', '(', ':', end_char];
     let mut res = String::new();
@@ -793,22 +698,23 @@ fn read_token(chars: &mut Peekable<Chars>, end_char: Option<char>) -> String {
     res
 }
 
-fn read_arg(
-    jobs: &mut Jobs,
-    chars: &mut Peekable<Chars>,
-    end_char_in: Option<char>,
-) -> Result<Arg, io::Error> {
+fn read_arg(chars: &mut Input, end_char_in: Option<char>) -> Result<Arg, io::Error> {
     let mut args = vec![];
     let end_char = end_char_in.unwrap_or(' ');
     let end_set = ['$', '|', ';', '&', '<', '>', '(', end_char];
     let mut res = String::new();
+    if let (Some(ch @ ('<' | '>')), Some('(')) = (chars.peek().copied(), chars.peek_second()) {
+        chars.next();
+        chars.next();
+        return read_process_subst(chars, ch);
+    }
     let mut next_ch = chars.peek().copied();
     while let Some(ch) = next_ch {
         if ch == '$' {
             args.push(Arg::Str(res.clone().into()));
             res.clear();
             chars.next();
-            let next_arg = read_special_arg(jobs, chars, end_char_in)?;
+            let next_arg = read_special_arg(chars, end_char_in)?;
             if let Arg::Compound(mut nargs) = next_arg {
                 args.append(&mut nargs);
             } else {
@@ -821,7 +727,7 @@ fn read_arg(
             next_ch = chars.peek().copied();
         } else if ch == '"' && ch != end_char {
             chars.next(); // Advance to opening quote.
-            args.push(read_string(jobs, chars)?);
+            args.push(read_string(chars)?);
             next_ch = chars.peek().copied();
         } else if !ch.is_whitespace() && !end_set.contains(&ch) {
             chars.next();
@@ -839,36 +745,48 @@ fn read_arg(
     })
 }
 
-fn read_special_arg(
-    jobs: &mut Jobs,
-    chars: &mut Peekable<Chars>,
-    end_char: Option<char>,
-) -> Result<Arg, io::Error> {
+/// Read a process substitution, <(run) or >(run), chars is after the '('.
+fn read_process_subst(chars: &mut Input, ch: char) -> Result<Arg, io::Error> {
+    let sub = parse_line_inner(chars, Some(')'))?
+        .commands
+        .ok_or_else(|| io::Error::new(ErrorKind::Other, "empty process substitution"))?;
+    Ok(if ch == '<' {
+        Arg::ProcessIn(sub)
+    } else {
+        Arg::ProcessOut(sub)
+    })
+}
+
+fn read_special_arg(chars: &mut Input, end_char: Option<char>) -> Result<Arg, io::Error> {
     let mut args = vec![];
-    if let Some('(') = chars.peek() {
+    if let (Some('('), Some('(')) = (chars.peek().copied(), chars.peek_second()) {
+        chars.next();
+        chars.next();
+        return read_arith(chars);
+    } else if let Some('(') = chars.peek() {
         // Subshell to capture
         chars.next();
-        let mut sub = parse_line_inner(jobs, chars, Some(')'))?;
+        let mut sub = parse_line_inner(chars, Some(')'))?;
         if let Some(sub) = sub.commands.take() {
             args.push(Arg::Command(sub));
         }
     } else {
         // Env var
-        let name = if let Some('{') = chars.peek() {
+        if let Some('{') = chars.peek() {
             chars.next();
-            let r = read_token(chars, Some('}'));
-            if let Some('}') = chars.peek() {
-                chars.next();
-                r
-            } else {
-                return Err(io::Error::new(ErrorKind::Other, "bad substitution"));
-            }
-        } else {
-            read_token(chars, end_char)
-        };
-        if !name.is_empty() {
-            args.push(Arg::Var(name.into()));
+            return read_param(chars);
+        }
+        // Special parameters and positional parameters are a single char.
+        if let Some(ch @ ('0'..='9' | '?' | '#' | '@' | '*' | '$')) = chars.peek().copied() {
+            chars.next();
+            return Ok(Arg::Var(ch.to_string().into()));
+        }
+        let name = read_token(chars, end_char);
+        if name.is_empty() {
+            // Not a substitution, just a $.
+            return Ok(Arg::Str("$".into()));
         }
+        args.push(Arg::Var(name.into()));
     }
     Ok(if args.len() == 1 {
         args.pop().expect("we had one element...")
@@ -877,88 +795,372 @@ fn read_special_arg(
     })
 }
 
-fn parse_line_inner(
-    jobs: &mut Jobs,
-    chars: &mut Peekable<Chars>,
-    end_char: Option<char>,
-) -> Result<ParsedJob, io::Error> {
-    let mut state = ParseState::new();
+/// Read an arithmetic expression up to the closing )), chars is after the $((.
+fn read_arith(chars: &mut Input) -> Result<Arg, io::Error> {
+    let mut text = String::new();
+    let mut depth = 0;
     while let Some(ch) = chars.next() {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' if chars.next() == Some(')') => return Ok(Arg::Arith(parse_arith(&text)?)),
+            ')' => break,
+            _ => {}
+        }
+        text.push(ch);
+    }
+    Err(io::Error::new(
+        ErrorKind::Other,
+        "arithmetic expansion missing closing ))",
+    ))
+}
+
+fn unterminated_heredoc() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "unterminated here-document")
+}
+
+/// Read a here-document delimiter and body (after the <<).  If any of the delimiter is quoted then
+/// the body is used as is else it is expanded like a double quoted string (without the quotes).
+fn read_heredoc(chars: &mut Input, strip_tabs: bool) -> Result<Arg, io::Error> {
+    consume_whitespace(chars);
+    let mut delim = String::new();
+    let mut quoted = false;
+    while let Some(ch) = chars.peek().copied() {
+        match ch {
+            '\'' | '"' => {
+                chars.next();
+                quoted = true;
+                for qch in chars.by_ref() {
+                    if qch == ch {
+                        break;
+                    }
+                    delim.push(qch);
+                }
+            }
+            '\\' => {
+                chars.next();
+                quoted = true;
+                if let Some(ch) = chars.next() {
+                    delim.push(ch);
+                }
+            }
+            ';' | '|' | '&' | '<' | '>' | '(' | ')' => break,
+            _ if ch.is_whitespace() => break,
+            _ => {
+                chars.next();
+                delim.push(ch);
+            }
+        }
+    }
+    if delim.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            "missing here-document delimiter",
+        ));
+    }
+    let body = chars.read_heredoc_body(&delim, strip_tabs)?;
+    if quoted {
+        return Ok(Arg::Str(body.into()));
+    }
+    let mut body_chars = Input::new(&body);
+    let mut parts = vec![];
+    let mut text = String::new();
+    while let Some(ch) = body_chars.next() {
+        match ch {
+            '\\' => match body_chars.peek().copied() {
+                Some(ch @ ('$' | '\\')) => {
+                    body_chars.next();
+                    text.push(ch);
+                }
+                Some('\n') => {
+                    body_chars.next();
+                }
+                _ => text.push(ch),
+            },
+            '$' => {
+                if !text.is_empty() {
+                    parts.push(Arg::Str(std::mem::take(&mut text).into()));
+                }
+                parts.push(read_special_arg(&mut body_chars, None)?);
+            }
+            _ => text.push(ch),
+        }
+    }
+    if !text.is_empty() || parts.is_empty() {
+        parts.push(Arg::Str(text.into()));
+    }
+    Ok(if parts.len() == 1 {
+        parts.pop().expect("we had one element...")
+    } else {
+        Arg::Compound(parts)
+    })
+}
+
+/// True if input ends inside a here-document (more lines are needed to finish the command).
+pub fn is_unterminated_heredoc(input: &str) -> bool {
+    matches!(parse_line(input), Err(err) if err.kind() == ErrorKind::UnexpectedEof)
+}
+
+/// Error payload for input that ends inside a quoted string.
+#[derive(Debug)]
+struct UnclosedString;
+
+impl Display for UnclosedString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "unclosed string")
+    }
+}
+
+impl Error for UnclosedString {}
+
+fn unclosed_string() -> io::Error {
+    io::Error::new(ErrorKind::Other, UnclosedString)
+}
+
+/// True if input is an incomplete command that more lines could finish, it ends with a \
+/// continuation or inside a quoted string or here-document.
+pub fn is_incomplete(input: &str) -> bool {
+    let trailing_escapes = input
+        .trim_end_matches('\n')
+        .chars()
+        .rev()
+        .take_while(|ch| *ch == '\\')
+        .count();
+    if trailing_escapes % 2 == 1 {
+        return true;
+    }
+    match parse_line(input) {
+        Err(err) => {
+            err.kind() == ErrorKind::UnexpectedEof
+                || err.get_ref().is_some_and(|e| e.is::<UnclosedString>())
+        }
+        Ok(_) => false,
+    }
+}
+
+fn bad_substitution() -> io::Error {
+    io::Error::new(ErrorKind::Other, "bad substitution")
+}
+
+/// Read the word argument of a ${..} operator up to (not including) one of the stop chars.
+fn read_param_word(chars: &mut Input, stop: &[char]) -> Result<Arg, io::Error> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    while let Some(ch) = chars.peek().copied() {
+        if stop.contains(&ch) {
+            break;
+        }
+        chars.next();
+        let part = match ch {
+            '\\' => {
+                text.push(ch);
+                text.push(chars.next().ok_or_else(bad_substitution)?);
+                continue;
+            }
+            '\'' => read_simple_string(chars)?,
+            '"' => Arg::Quoted(Box::new(read_string(chars)?)),
+            '$' => read_special_arg(chars, Some('}'))?,
+            _ => {
+                text.push(ch);
+                continue;
+            }
+        };
+        if !text.is_empty() {
+            parts.push(Arg::Unquoted(std::mem::take(&mut text).into()));
+        }
+        parts.push(part);
+    }
+    if !text.is_empty() || parts.is_empty() {
+        parts.push(Arg::Unquoted(text.into()));
+    }
+    Ok(if parts.len() == 1 {
+        parts.pop().expect("we had one element...")
+    } else {
+        Arg::Compound(parts)
+    })
+}
+
+/// Read a ${..} parameter expansion, chars is after the open brace and the close brace is consumed.
+fn read_param(chars: &mut Input) -> Result<Arg, io::Error> {
+    let length = if let Some('#') = chars.peek() {
+        chars.next();
+        true
+    } else {
+        false
+    };
+    let mut name = String::new();
+    while let Some(ch) = chars.peek().copied() {
+        if ch.is_alphanumeric() || ch == '_' {
+            chars.next();
+            name.push(ch);
+        } else {
+            break;
+        }
+    }
+    if name.is_empty() {
+        return Err(bad_substitution());
+    }
+    let next = chars.next().ok_or_else(bad_substitution)?;
+    let op = match next {
+        '}' if length => return Ok(Arg::Param(name.into(), ParamOp::Length)),
+        '}' => return Ok(Arg::Var(name.into())),
+        _ if length => return Err(bad_substitution()),
+        ':' => match chars.peek().copied() {
+            Some(ch @ ('-' | '=' | '?' | '+')) => {
+                chars.next();
+                default_op(ch, read_param_word(chars, &['}'])?, true)
+            }
+            _ => {
+                let mut range = String::new();
+                while let Some(ch) = chars.peek().copied() {
+                    if ch == '}' {
+                        break;
+                    }
+                    chars.next();
+                    range.push(ch);
+                }
+                let mut range = range.splitn(2, ':');
+                let num = |s: Option<&str>| s.map(|s| s.trim().parse::<i64>().ok());
+                match (num(range.next()), num(range.next())) {
+                    (Some(Some(offset)), Some(Some(len))) => ParamOp::Substring(offset, Some(len)),
+                    (Some(Some(offset)), None) => ParamOp::Substring(offset, None),
+                    _ => return Err(bad_substitution()),
+                }
+            }
+        },
+        '-' | '=' | '?' | '+' => default_op(next, read_param_word(chars, &['}'])?, false),
+        '#' | '%' => {
+            let longest = chars.peek() == Some(&next);
+            if longest {
+                chars.next();
+            }
+            let pattern = Box::new(read_param_word(chars, &['}'])?);
+            if next == '#' {
+                ParamOp::RemovePrefix(pattern, longest)
+            } else {
+                ParamOp::RemoveSuffix(pattern, longest)
+            }
+        }
+        '/' => {
+            let all = chars.peek() == Some(&'/');
+            if all {
+                chars.next();
+            }
+            let pattern = Box::new(read_param_word(chars, &['/', '}'])?);
+            let replacement = if let Some('/') = chars.peek() {
+                chars.next();
+                read_param_word(chars, &['}'])?
+            } else {
+                Arg::Str("".into())
+            };
+            ParamOp::Replace(pattern, Box::new(replacement), all)
+        }
+        _ => return Err(bad_substitution()),
+    };
+    if chars.next() != Some('}') {
+        return Err(bad_substitution());
+    }
+    Ok(Arg::Param(name.into(), op))
+}
+
+fn default_op(op: char, word: Arg, null: bool) -> ParamOp {
+    let word = Box::new(word);
+    match op {
+        '-' => ParamOp::Default(word, null),
+        '=' => ParamOp::Assign(word, null),
+        '?' => ParamOp::Error(word, null),
+        _ => ParamOp::Alternate(word, null),
+    }
+}
+
+fn parse_line_inner(chars: &mut Input, end_char: Option<char>) -> Result<ParsedJob, io::Error> {
+    let mut state = ParseState::new();
+    loop {
+        let start = chars.offset();
+        let Some(ch) = chars.next() else {
+            break;
+        };
         if let Some(end_ch) = end_char {
             if ch == end_ch {
                 break;
             }
         }
         let next_char = *chars.peek().unwrap_or(&' ');
-        if ch.is_whitespace() {
-            if state.last_ch == '\\' {
-                state.token().push(ch);
-                state.last_ch = ch;
-            } else {
-                state.proc_token(jobs)?;
-                consume_whitespace(chars);
+        if ch == '\n' && (state.token_start.is_some() || !state.command().is_empty()) {
+            // A newline ends a command unless it follows an operator.
+            state.seq();
+        } else if ch.is_whitespace() {
+            state.proc_token();
+            // Stop at a newline so it can end the command.
+            while let Some(ch) = chars.peek() {
+                if ch.is_whitespace() && *ch != '\n' {
+                    chars.next();
+                } else {
+                    break;
+                }
             }
         } else {
             match ch {
-                '\'' if state.last_ch != '\\' => {
-                    let arg_str = read_simple_string(chars)?;
-                    state.token().push('\'');
-                    state
-                        .token()
-                        .push_str(&arg_str.resolve_arg(jobs)?.to_string_lossy());
-                    state.token().push('\'');
-                    state.last_ch = ch;
+                '\\' => {
+                    // Keep the escape in the token for expansion, \newline is a continuation.
+                    match chars.next() {
+                        Some('\n') | None => {}
+                        Some(esc) => {
+                            state.start_token(start);
+                            state.token.push(ch);
+                            state.token.push(esc);
+                        }
+                    }
+                    state.last_ch = ' ';
                 }
-                '"' if state.last_ch != '\\' => {
-                    let arg_str = read_string(jobs, chars)?;
-                    // Single quote in token to avoid future expansions in string.
-                    state.token().push('\'');
-                    state
-                        .token()
-                        .push_str(&arg_str.resolve_arg(jobs)?.to_string_lossy());
-                    state.token().push('\'');
+                '\'' => {
+                    let arg = read_simple_string(chars)?;
+                    state.start_token(start);
+                    state.push_part(arg);
                     state.last_ch = ch;
                 }
-
-                '|' => {
-                    state.pipe_or(jobs, ch, next_char)?;
-                }
-                ';' if state.last_ch != '\\' => {
-                    state.seq(jobs)?;
-                }
-                '>' => {
-                    state.redir_out(jobs, chars, end_char)?;
+                '"' => {
+                    let arg = read_string(chars)?;
+                    state.start_token(start);
+                    state.push_part(Arg::Quoted(Box::new(arg)));
+                    state.last_ch = ch;
                 }
-                '<' => {
-                    state.redir_in(jobs, chars, end_char)?;
+                '$' => {
+                    let arg = read_special_arg(chars, end_char)?;
+                    state.start_token(start);
+                    state.push_part(arg);
+                    state.last_ch = ch;
                 }
-                '&' if state.last_ch == '\\' => {
-                    state.token().push('&');
+                '<' | '>' if next_char == '(' => {
+                    chars.next();
+                    let arg = read_process_subst(chars, ch)?;
+                    state.start_token(start);
+                    state.push_part(arg);
                     state.last_ch = ' ';
                 }
+                '#' if state.token_start.is_none() => {
+                    // Comment to the end of the line.
+                    while chars.peek().is_some_and(|ch| *ch != '\n') {
+                        chars.next();
+                    }
+                }
+                '|' => state.pipe_or(ch, next_char),
+                ';' => state.seq(),
+                '>' => state.redir_out(chars, end_char)?,
+                '<' => state.redir_in(chars, end_char)?,
                 '&' if next_char == '>' || next_char == '&' => {
                     state.last_ch = '&';
                 }
-                '&' if state.last_ch == '&' => {
-                    state.and(jobs)?;
-                }
+                '&' if state.last_ch == '&' => state.and(),
                 '&' => {
-                    state.proc_token(jobs)?;
+                    state.proc_token();
                     state.end_command(true);
                     state.last_ch = ' ';
                 }
-                '\\' => {
-                    if state.last_ch == '\\' {
-                        state.token().push('\\');
-                        state.last_ch = ' ';
-                    } else {
-                        state.last_ch = ch;
-                    }
-                }
-                '(' if state.last_ch != '$' && state.last_ch != '\\' => {
-                    state.proc_token(jobs)?;
+                '(' => {
+                    state.proc_token();
                     state.end_command(false);
-                    let mut sub = parse_line_inner(jobs, chars, Some(')'))?;
+                    let mut sub = parse_line_inner(chars, Some(')'))?;
                     if let Some(sub) = sub.commands.take() {
                         push_next_seq_run(
                             &mut state.ret,
@@ -967,38 +1169,26 @@ fn parse_line_inner(
                         );
                     }
                 }
-                '\n' if state.last_ch == '\\' => {
-                    state.last_ch = ' ';
-                }
-                '$' if state.last_ch != '\\' => {
-                    state.token().push(ch);
-                    let next_ch = chars.peek().unwrap_or(&' ');
-                    match next_ch {
-                        '{' => read_chars_until(chars, state.token(), '}')?,
-                        '(' => read_chars_until(chars, state.token(), ')')?,
-                        _ => {}
-                    }
-                    state.last_ch = ch;
-                }
                 _ => {
-                    if state.last_ch == '\\' {
-                        // Last char was a backslash and seems unremarkable so put it in the token.
-                        state.token().push('\\');
-                    }
-                    state.token().push(ch);
+                    state.start_token(start);
+                    state.token.push(ch);
                     state.last_ch = ch;
                 }
             }
         }
+        if state.token_start.is_some() {
+            state.token_end = chars.offset();
+        }
     }
-    state.proc_token(jobs)?;
+    state.proc_token();
     state.end_command(false);
     Ok(state.into())
 }
 
-pub fn parse_line(jobs: &mut Jobs, input: &str) -> Result<ParsedJob, io::Error> {
-    let mut chars = input.chars().peekable();
-    parse_line_inner(jobs, &mut chars, None)
+/// Parse input into a job.  This does not expand any args so has no side effects.
+pub fn parse_line(input: &str) -> Result<ParsedJob, io::Error> {
+    let mut chars = Input::new(input);
+    parse_line_inner(&mut chars, None)
 }
 
 #[cfg(test)]
@@ -1006,17 +1196,15 @@ mod tests {
     use super::*;
 
     fn test_parse(input: &str, expected: &str) {
-        let mut jobs = Jobs::new(false);
-        let pj = parse_line(&mut jobs, input).unwrap();
+        let pj = parse_line(input).unwrap();
         let pj_str = pj.to_string();
         assert_eq!(&pj_str, expected);
-        let pj = parse_line(&mut jobs, &pj_str).unwrap();
+        let pj = parse_line(&pj_str).unwrap();
         assert_eq!(&pj.to_string(), expected);
     }
 
     fn test_parse_once(input: &str, expected: &str) {
-        let mut jobs = Jobs::new(false);
-        let pj = parse_line(&mut jobs, input).unwrap();
+        let pj = parse_line(input).unwrap();
         let pj_str = pj.to_string();
         assert_eq!(&pj_str, expected);
     }
@@ -1094,4 +1282,140 @@ mod tests {
         test_parse_once("\"one\\u0a\ntwo\"", "one\x0A\ntwo");
         test_parse_once("\"one\\u0a\n\"", "one\x0A\n");
     }
+
+    #[test]
+    fn test_parse_unexpanded() {
+        test_parse("echo $HOME ${HOME}/x", "echo $HOME $HOME/x");
+        test_parse("echo a{b,c} ~/x *.rs", "echo a{b,c} ~/x *.rs");
+        test_parse("echo $(ls -al|grep x)", "echo $(ls -al | grep x)");
+        test_parse_once("echo \"$HOME $(ls)\" '$HOME'", "echo $HOME $(ls) $HOME");
+        // Parsing must not run the command substitution.
+        let file = std::env::temp_dir().join(format!("sl-sh-parse-{}", std::process::id()));
+        let line = format!("echo $(touch {})", file.display());
+        parse_line(&line).unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn test_param_parse() {
+        test_parse("echo ${X:-default}", "echo ${X:-default}");
+        test_parse("echo ${X-$HOME/x}", "echo ${X-$HOME/x}");
+        test_parse("echo ${X:=assigned}", "echo ${X:=assigned}");
+        test_parse("echo ${X:?not set}", "echo ${X:?not set}");
+        test_parse("echo ${X:+alt}", "echo ${X:+alt}");
+        test_parse("echo ${#X}", "echo ${#X}");
+        test_parse("echo ${X#*/}", "echo ${X#*/}");
+        test_parse("echo ${X##*/}", "echo ${X##*/}");
+        test_parse("echo ${X%.*}", "echo ${X%.*}");
+        test_parse("echo ${X%%.*}", "echo ${X%%.*}");
+        test_parse("echo ${X/a/b}", "echo ${X/a/b}");
+        test_parse("echo ${X//a/$Y}", "echo ${X//a/$Y}");
+        test_parse("echo ${X:2:3}", "echo ${X:2:3}");
+        test_parse("echo ${X: -2}", "echo ${X: -2}");
+        test_parse("echo a${X:-${Y:-z}}b", "echo a${X:-${Y:-z}}b");
+        assert!(parse_line("echo ${X:2:z}").is_err());
+        assert!(parse_line("echo ${X@}").is_err());
+        assert!(parse_line("echo ${X:-a").is_err());
+    }
+
+    #[test]
+    fn test_heredoc_parse() {
+        test_parse_once(
+            "cat <<EOF\nhello $X\n  there\nEOF",
+            "cat 0<<<hello $X\n  there\n",
+        );
+        test_parse_once("cat <<'EOF'\nhello $X\nEOF\n", "cat 0<<<hello $X\n");
+        test_parse_once("cat <<-\"EOF\"\n\thello\n\tEOF\n", "cat 0<<<hello\n");
+        test_parse_once("cat <<< $X", "cat 0<<<$X\n");
+        test_parse_once("cat <<<'a b'|wc", "cat 0<<<a b\n | wc");
+        test_parse_once(
+            "cat <<A 3<<B; echo x\na\nA\nb\nB\necho after",
+            "cat 0<<<a\n 3<<<b\n ; echo x ; echo after",
+        );
+        test_parse_once("echo a &&\necho b\n\necho c", "echo a && echo b ; echo c");
+        assert!(is_unterminated_heredoc("cat <<EOF\nhello"));
+        assert!(is_unterminated_heredoc("cat <<EOF"));
+        assert!(!is_unterminated_heredoc("cat <<EOF\nhello\nEOF"));
+        assert!(!is_unterminated_heredoc("cat 'unclosed"));
+        assert!(is_incomplete("cat 'unclosed"));
+        assert!(is_incomplete("echo \"a\nb"));
+        assert!(is_incomplete("echo a \\\n"));
+        assert!(is_incomplete("cat <<EOF\nhello"));
+        assert!(!is_incomplete("echo a \\\\\n"));
+        assert!(!is_incomplete("echo 'a'\n"));
+    }
+
+    #[test]
+    fn test_comment_special_param_parse() {
+        test_parse_once("echo a # it's a comment\necho b", "echo a ; echo b");
+        test_parse_once("# just a comment", "");
+        test_parse("echo a#b", "echo a#b");
+        test_parse("echo $1x $# $@ $? $$", "echo $1x $# $@ $? $$");
+        let pj = parse_line("echo $12").unwrap();
+        let Run::Command(command) = pj.commands() else {
+            panic!("expected a command");
+        };
+        let arg = command.args_iter().next().unwrap();
+        assert!(matches!(arg, Arg::Compound(args) if matches!(&args[0], Arg::Var(v) if v == "1")));
+    }
+
+    #[test]
+    fn test_process_subst_parse() {
+        test_parse("diff <(sort a) <(sort b)", "diff <(sort a) <(sort b)");
+        test_parse(
+            "ls | tee >(grep x) >/dev/null",
+            "ls | tee >(grep x) 1>/dev/null",
+        );
+        test_parse("cat < <(ls | wc)", "cat 0< <(ls | wc)");
+        test_parse("echo <(a; b)x", "echo <(a ; b)x");
+    }
+
+    #[test]
+    fn test_arith_parse() {
+        test_parse("echo $((1+2*(3 - x)))", "echo $((1 + 2 * (3 - x)))");
+        test_parse("echo \"$((i++))\"x", "echo $((i++))x");
+        test_parse("echo $(( ${a} << 2 ))$((b))", "echo $((a << 2))$((b))");
+        test_parse("echo $( (ls) )", "echo $( (ls))");
+        assert!(parse_line("echo $((1 +))").is_err());
+        assert!(parse_line("echo $((1 + 2)").is_err());
+        assert!(parse_line("echo $((1 + 2").is_err());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let pj = parse_line("a; b | c && d || e | f; g").unwrap();
+        let Run::Sequence(seq) = pj.commands() else {
+            panic!("expected a sequence");
+        };
+        assert_eq!(seq.len(), 3);
+        let Run::Or(or) = &seq[1] else {
+            panic!("expected an or");
+        };
+        let Run::And(and) = &or[0] else {
+            panic!("expected an and");
+        };
+        assert!(matches!(&and[0], Run::Pipe(pipe) if pipe.len() == 2));
+        assert!(matches!(&or[1], Run::Pipe(pipe) if pipe.len() == 2));
+        assert!(matches!(&seq[2], Run::Command(_)));
+        test_parse("a; b | c && d || e | f; g", "a ; b | c && d || e | f ; g");
+    }
+
+    #[test]
+    fn test_spans() {
+        let pj = parse_line("ls  -al \"a b\"c|grep $(ls x)").unwrap();
+        let Run::Pipe(pipe) = pj.commands() else {
+            panic!("expected a pipe");
+        };
+        let Run::Command(ls) = &pipe[0] else {
+            panic!("expected a command");
+        };
+        assert_eq!(
+            ls.spans(),
+            &[Span::new(0, 2), Span::new(4, 7), Span::new(8, 14)]
+        );
+        let Run::Command(grep) = &pipe[1] else {
+            panic!("expected a command");
+        };
+        assert_eq!(grep.spans(), &[Span::new(15, 19), Span::new(20, 27)]);
+    }
 }
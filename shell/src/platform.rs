@@ -6,6 +6,7 @@ pub use crate::platform::unix::*;
 use std::ffi::OsString;
 use std::io;
 use std::io::ErrorKind;
+use std::time::Duration;
 
 /// Abstraction for a "platform" (for instance Unix or Windows).
 pub trait Platform {
@@ -17,6 +18,16 @@ pub trait Platform {
     fn get_term_settings(terminal: FileDesc) -> Result<TermSettings, io::Error>;
     /// Restore terminal settings and put the shell back into the foreground.
     fn restore_terminal(term_settings: &TermSettings, shell_pid: Pid) -> Result<(), io::Error>;
+    /// Put terminal into raw mode (no echo, line editing or signal keys) with reads that return
+    /// after a tenth of a second without input.  Returns the previous settings.
+    fn set_raw_mode(terminal: FileDesc) -> Result<TermSettings, io::Error>;
+    /// Set the terminal settings (for instance restore the settings from set_raw_mode).
+    fn set_term_settings(terminal: FileDesc, term_settings: &TermSettings)
+        -> Result<(), io::Error>;
+    /// Wait up to timeout for input on terminal, true if there is input to read.
+    fn wait_for_input(terminal: FileDesc, timeout: Duration) -> Result<bool, io::Error>;
+    /// Number of columns of terminal if it is a terminal.
+    fn terminal_columns(terminal: FileDesc) -> Option<usize>;
     /// Put terminal in the foreground, loop until this succeeds.
     /// Used during shell startup.
     fn terminal_foreground(terminal: FileDesc);
@@ -44,8 +55,16 @@ pub trait Platform {
         -> Result<(), io::Error>;
     /// Move the job for job_num to te background and running (start a stopped job in the background).
     fn background_job(job: &mut Job) -> Result<(), io::Error>;
+    /// Send signal to pid, if group is true then pid is a process group id.
+    fn send_signal(pid: Pid, signal: OsSignal, group: bool) -> Result<(), io::Error>;
+    /// Signal for name, the name can have or leave off the SIG prefix or be the signal number.
+    fn parse_signal(name: &str) -> Option<OsSignal>;
+    /// The names (without the SIG prefix) and numbers of the supported signals.
+    fn signal_names() -> Vec<(&'static str, OsSignal)>;
     /// Duplicate a raw file descriptor to another file descriptor.
     fn dup2_fd(src_fd: FileDesc, dst_fd: FileDesc) -> Result<FileDesc, io::Error>;
+    /// Duplicate a raw file descriptor to a new file descriptor that is not closed on exec.
+    fn dup_fd(src_fd: FileDesc) -> Result<FileDesc, io::Error>;
     /// Get the current PID.
     fn getpid() -> Pid;
     /// Get the current machines hostname if available.
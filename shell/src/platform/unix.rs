@@ -9,6 +9,7 @@ use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::FromRawFd;
 use std::ptr;
 use std::str::FromStr;
+use std::time::Duration;
 
 use crate::command_data::{Arg, CommandWithArgs, Run};
 use crate::jobs::{Job, JobStatus, Jobs};
@@ -17,6 +18,7 @@ use crate::platform::{FromFileDesc, Platform, RLimit, RLimitVals};
 use crate::run::run_job;
 use crate::signals::test_clear_sigint;
 use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::sys::signal::{self, kill, SigHandler, Signal};
 use nix::sys::termios;
 use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
@@ -53,6 +55,53 @@ impl Platform for Sys {
         Ok(())
     }
 
+    fn set_raw_mode(terminal: UnixFileDesc) -> Result<UnixTermSettings, io::Error> {
+        let old = termios::tcgetattr(terminal)?;
+        let mut raw = old.clone();
+        raw.local_flags &= !(termios::LocalFlags::ICANON
+            | termios::LocalFlags::ECHO
+            | termios::LocalFlags::ISIG
+            | termios::LocalFlags::IEXTEN);
+        raw.input_flags &= !(termios::InputFlags::IXON | termios::InputFlags::ICRNL);
+        raw.control_chars[termios::SpecialCharacterIndices::VMIN as usize] = 0;
+        raw.control_chars[termios::SpecialCharacterIndices::VTIME as usize] = 1;
+        termios::tcsetattr(terminal, termios::SetArg::TCSANOW, &raw)?;
+        Ok(UnixTermSettings(old))
+    }
+
+    fn set_term_settings(
+        terminal: UnixFileDesc,
+        term_settings: &UnixTermSettings,
+    ) -> Result<(), io::Error> {
+        termios::tcsetattr(terminal, termios::SetArg::TCSANOW, &term_settings.0)?;
+        Ok(())
+    }
+
+    fn wait_for_input(terminal: UnixFileDesc, timeout: Duration) -> Result<bool, io::Error> {
+        let millis = u16::try_from(timeout.as_millis()).unwrap_or(u16::MAX);
+        let mut fds = [PollFd::new(terminal.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, PollTimeout::from(millis)) {
+            Ok(n) => Ok(n > 0),
+            Err(nix::errno::Errno::EINTR) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn terminal_columns(terminal: UnixFileDesc) -> Option<usize> {
+        let mut size = libc::winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let res = unsafe { libc::ioctl(terminal.0, libc::TIOCGWINSZ, &mut size) };
+        if res == 0 && size.ws_col > 0 {
+            Some(size.ws_col as usize)
+        } else {
+            None
+        }
+    }
+
     /// Put terminal in the foreground, loop until this succeeds.
     /// Used during shell startup.
     fn terminal_foreground(terminal: UnixFileDesc) {
@@ -135,6 +184,8 @@ impl Platform for Sys {
 
                     let redir_fds = run.get_internal_fds();
                     close_extra_fds(&redir_fds);
+                    jobs.clear_proc_subst();
+                    jobs.reset_traps_for_subshell();
                     jobs.set_interactive(false);
                     jobs.set_no_tty();
                     match run_job(run, jobs, false) {
@@ -218,7 +269,13 @@ impl Platform for Sys {
         loop {
             match input.read(&mut bytes) {
                 Ok(0) => {
-                    job.add_process(UnixPid(pid), program.to_string_lossy());
+                    // Name the process with its command line for the jobs list and job specs.
+                    let mut name = program.to_string_lossy().to_string();
+                    for arg in command.args_iter() {
+                        name.push(' ');
+                        name.push_str(&arg.to_string());
+                    }
+                    job.add_process(UnixPid(pid), name);
                     return Ok(());
                 }
                 Ok(8) => {
@@ -352,6 +409,33 @@ impl Platform for Sys {
         Ok(())
     }
 
+    /// Send signal to pid, if group is true then pid is a process group id.
+    fn send_signal(pid: UnixPid, signal: OsSignal, group: bool) -> Result<(), io::Error> {
+        let signal = Signal::try_from(signal)?;
+        let pid = if group { -pid.0 } else { pid.0 };
+        kill(unistd::Pid::from_raw(pid), signal)?;
+        Ok(())
+    }
+
+    /// Signal for name, the name can have or leave off the SIG prefix or be the signal number.
+    fn parse_signal(name: &str) -> Option<OsSignal> {
+        if let Ok(num) = name.parse::<i32>() {
+            return Signal::try_from(num).ok().map(|s| s as i32);
+        }
+        let name = name.to_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        Signal::iterator()
+            .find(|signal| &signal.as_str()[3..] == name)
+            .map(|signal| signal as i32)
+    }
+
+    /// The names (without the SIG prefix) and numbers of the supported signals.
+    fn signal_names() -> Vec<(&'static str, OsSignal)> {
+        Signal::iterator()
+            .map(|signal| (&signal.as_str()[3..], signal as i32))
+            .collect()
+    }
+
     /// Duplicate a raw file descriptor to another file descriptor.
     fn dup2_fd(src_fd: UnixFileDesc, dst_fd: UnixFileDesc) -> Result<UnixFileDesc, io::Error> {
         Ok(UnixFileDesc(unsafe {
@@ -359,6 +443,11 @@ impl Platform for Sys {
         }))
     }
 
+    /// Duplicate a raw file descriptor to a new file descriptor that is not closed on exec.
+    fn dup_fd(src_fd: UnixFileDesc) -> Result<UnixFileDesc, io::Error> {
+        Ok(UnixFileDesc(unsafe { cvt(libc::dup(src_fd.0))? }))
+    }
+
     /// Get the current PID.
     fn getpid() -> UnixPid {
         UnixPid(unistd::getpid().into())
@@ -437,6 +526,21 @@ impl Display for UnixPid {
     }
 }
 
+impl FromStr for UnixPid {
+    type Err = io::Error;
+
+    fn from_str(pid_str: &str) -> Result<Self, Self::Err> {
+        match pid_str.parse::<i32>() {
+            Ok(pid) if pid > 0 => Ok(Self(pid)),
+            Ok(pid) => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("invalid pid {pid}"),
+            )),
+            Err(err) => Err(io::Error::new(ErrorKind::Other, err)),
+        }
+    }
+}
+
 impl TryFrom<UnixPid> for i32 {
     type Error = Infallible;
 
@@ -1,9 +1,10 @@
 use crate::builtins::run_builtin;
 use crate::command_data::{CommandWithArgs, Run};
 use crate::jobs::{Job, Jobs};
-use crate::parse::parse_line;
-use crate::platform::{FileDesc, Platform, Sys};
+use crate::parse::{is_incomplete, parse_line};
+use crate::platform::{FileDesc, Platform, Sys, STDERR_FILENO};
 use crate::signals::{install_sigint_handler, mask_signals};
+use std::io::BufRead;
 use std::{env, io};
 
 pub fn setup_shell_tty(shell_terminal: FileDesc) {
@@ -28,14 +29,35 @@ pub fn setup_shell_tty(shell_terminal: FileDesc) {
 /// Finish a job run.
 ///
 /// If not a background job will wait for proc to end and return the exit status
-/// or -66 if the wait fails.  If it is a background job then return the pid of
+/// (128 + signal if killed by a signal) or -66 if the job stopped.  If it is a background job
+/// then return the pid of
 /// the last proc in the job.
 /// Also sets the LAST_STATUS env var to the exit status (if not background) or
-/// 0 if a background job.
+/// 0 if a background job.  With pipefail set the exit status is the last non-zero
+/// status in the job.
 fn finish_run(background: bool, mut job: Job, jobs: &mut Jobs) -> i32 {
     job.mark_running();
     let status = if !background {
-        if let Some(status) = Sys::wait_job(&mut job) {
+        let status = Sys::wait_job(&mut job);
+        // A job killed by a signal has no status from wait but is finished.
+        let status = status.or_else(|| {
+            (!job.has_running())
+                .then(|| job.exit_statuses().last().copied())
+                .flatten()
+        });
+        if let Some(status) = status {
+            let statuses = job.exit_statuses();
+            let status = if jobs.options().pipefail {
+                statuses
+                    .iter()
+                    .rev()
+                    .find(|s| **s != 0)
+                    .copied()
+                    .unwrap_or(0)
+            } else {
+                status
+            };
+            jobs.set_pipe_status(statuses);
             env::set_var("LAST_STATUS", format!("{}", status));
             status
         } else {
@@ -63,6 +85,7 @@ fn run_command(
     background: bool,
     stealth: bool,
 ) -> Result<i32, io::Error> {
+    let command = &command.expand(jobs)?;
     Ok(if let Some(command_name) = command.command(jobs) {
         let command_name = command_name?;
         if let Some(init_alias_run) = jobs.remove_alias(command_name.to_string_lossy()) {
@@ -80,7 +103,16 @@ fn run_command(
         } else {
             let mut args = command.args_iter();
             match run_builtin(&command_name, &mut args, jobs) {
-                Some(status) => status,
+                Some(status) => {
+                    // Builtins run in this process, close any pipes (process substitution etc).
+                    for fd in command.get_internal_fds() {
+                        if fd > STDERR_FILENO {
+                            let _ = Sys::close_fd(fd);
+                        }
+                    }
+                    jobs.set_pipe_status(vec![status]);
+                    status
+                }
                 None => {
                     let mut job = jobs.new_job();
                     job.set_stealth(stealth);
@@ -100,7 +132,32 @@ fn run_command(
     })
 }
 
+/// Run a && or || list, stops at the first failure for && or success for ||.
+/// Returns the status and true if the last command in the list was run.
+fn run_list(
+    seq: &[Run],
+    jobs: &mut Jobs,
+    force_background: bool,
+    and: bool,
+) -> Result<(i32, bool), io::Error> {
+    // XXXX should background == true be an error?
+    let mut status = 0;
+    for (i, r) in seq.iter().enumerate() {
+        status = run_job(r, jobs, force_background)?;
+        if (status == 0) != and {
+            return Ok((status, i == seq.len() - 1));
+        }
+    }
+    Ok((status, true))
+}
+
+/// Run a job and return its exit status.
+///
+/// If the errexit option is set then a sequence stops at the first command that fails (other
+/// than commands in a && or || list before the last) and [`Jobs::errexit_failed`] is set.
 pub fn run_job(run: &Run, jobs: &mut Jobs, force_background: bool) -> Result<i32, io::Error> {
+    // If a failure should trigger errexit, false for a && or || list that stopped early.
+    let mut check_errexit = true;
     let status = match run {
         Run::Command(command) => run_command(command, jobs, force_background, force_background)?,
         Run::BackgroundCommand(command) => run_command(command, jobs, true, false)?,
@@ -120,29 +177,17 @@ pub fn run_job(run: &Run, jobs: &mut Jobs, force_background: bool) -> Result<i32
             let mut status = 0;
             for r in seq {
                 status = run_job(r, jobs, force_background)?;
-            }
-            status
-        }
-        Run::And(seq) => {
-            // XXXX should background == true be an error?
-            let mut status = 0;
-            for r in seq {
-                status = run_job(r, jobs, force_background)?;
-                if status != 0 {
+                if jobs.errexit_failed() {
                     break;
                 }
             }
+            check_errexit = jobs.errexit_failed();
             status
         }
-        Run::Or(seq) => {
-            // XXXX should background == true be an error?
-            let mut status = 0;
-            for r in seq {
-                status = run_job(r, jobs, force_background)?;
-                if status == 0 {
-                    break;
-                }
-            }
+        Run::And(seq) | Run::Or(seq) => {
+            let (status, ran_last) =
+                run_list(seq, jobs, force_background, matches!(run, Run::And(_)))?;
+            check_errexit = ran_last && jobs.errexit_failed();
             status
         }
         Run::Subshell(sub_run) => {
@@ -159,17 +204,70 @@ pub fn run_job(run: &Run, jobs: &mut Jobs, force_background: bool) -> Result<i32
         }
         Run::Empty => 0,
     };
+    // The status of a background job is a pid, not an exit status.
+    let background = force_background
+        || match run {
+            Run::BackgroundCommand(_) => true,
+            Run::Pipe(pipe) => matches!(pipe.last(), Some(Run::BackgroundCommand(_))),
+            _ => false,
+        };
+    jobs.set_errexit_failed(check_errexit && !background && status != 0 && jobs.options().errexit);
+    // Between commands is a safe point to run traps for any signals received.
+    jobs.run_pending_traps();
     Ok(status)
 }
 
 pub fn run_one_command(command: &str, jobs: &mut Jobs) -> Result<i32, io::Error> {
     // Try to make sense out of whatever crap we get (looking at you fzf-tmux)
     // and make it work.
-    let commands = parse_line(jobs, command)?;
+    let commands = parse_line(command)?;
 
     run_job(commands.commands(), jobs, false)
 }
 
+/// Run the commands read from input (a script or stdin) a complete command at a time, lines
+/// are joined while the command is incomplete (see [`is_incomplete`]).
+///
+/// Returns the exit status of the last command run, stops early if a command fails with the
+/// errexit option set.
+pub fn run_lines<R: BufRead>(input: R, jobs: &mut Jobs) -> i32 {
+    let mut status = 0;
+    let mut command = String::new();
+    for line in input.lines() {
+        match line {
+            Ok(line) => {
+                command.push_str(&line);
+                command.push('\n');
+            }
+            Err(err) => {
+                eprintln!("Error reading input: {err}");
+                return 1;
+            }
+        }
+        if is_incomplete(&command) {
+            continue;
+        }
+        jobs.reap_procs();
+        status = run_one_command(&command, jobs).unwrap_or_else(|err| {
+            eprintln!("Error running {}: {err}", command.trim_end());
+            1
+        });
+        command.clear();
+        if jobs.errexit_failed() {
+            return status;
+        }
+    }
+    if !command.is_empty() {
+        // Incomplete at the end of input, this will report the error.
+        status = run_one_command(&command, jobs).unwrap_or_else(|err| {
+            eprintln!("Error running {}: {err}", command.trim_end());
+            1
+        });
+    }
+    jobs.reap_procs();
+    status
+}
+
 fn pipe_command(
     command: &CommandWithArgs,
     next_in: Option<FileDesc>,
@@ -177,7 +275,7 @@ fn pipe_command(
     job: &mut Job,
     jobs: &mut Jobs,
 ) -> Result<(), io::Error> {
-    let mut command = command.clone();
+    let mut command = command.expand(jobs)?;
     if let Some(command_name) = command.command(jobs) {
         let command_name = command_name?;
         if let Some(mut alias_run) = jobs.get_alias(command_name.to_string_lossy()) {
@@ -191,7 +289,7 @@ fn pipe_command(
             }
             match alias_run {
                 Run::Command(command) | Run::BackgroundCommand(command) => {
-                    Sys::fork_exec(&command, job, jobs)?;
+                    Sys::fork_exec(&command.expand(jobs)?, job, jobs)?;
                 }
                 _ => {
                     Sys::fork_run(&alias_run, job, jobs)?;
@@ -1,16 +1,129 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{fmt, io};
 
 use nix::{
     libc,
     sys::signal::{self, sigaction, SigHandler, Signal},
 };
 
+use crate::platform::{OsSignal, Platform, Sys};
+
 static SIG_INT: AtomicBool = AtomicBool::new(false);
+static SIG_INT_INSTALLED: AtomicBool = AtomicBool::new(false);
+// Bit (signal number - 1) is set for each trapped signal received but not yet handled.
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+static TRAP_PENDING: AtomicBool = AtomicBool::new(false);
 
 extern "C" fn sig_int_handle(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
     SIG_INT.store(true, Ordering::Relaxed);
 }
 
+// Only record the signal, the trap runs later at a safe point.
+extern "C" fn trap_handle(sig: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    if sig == libc::SIGINT {
+        SIG_INT.store(true, Ordering::Relaxed);
+    }
+    if (1..=64).contains(&sig) {
+        PENDING_SIGNALS.fetch_or(1_u64 << (sig - 1), Ordering::Relaxed);
+        TRAP_PENDING.store(true, Ordering::Release);
+    }
+}
+
+/// A signal or the EXIT pseudo signal (run when the shell exits) that can be trapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TrapSignal {
+    Exit,
+    Signal(OsSignal),
+}
+
+impl TrapSignal {
+    /// Parse a trap signal, EXIT (or 0) or a signal name (with or without SIG) or number.
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "0" || name.eq_ignore_ascii_case("EXIT") {
+            Some(Self::Exit)
+        } else {
+            Sys::parse_signal(name).map(Self::Signal)
+        }
+    }
+
+    /// The name of the signal without the SIG prefix.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Exit => "EXIT",
+            Self::Signal(sig) => Signal::try_from(*sig)
+                .map(|s| &s.as_str()[3..])
+                .unwrap_or("UNKNOWN"),
+        }
+    }
+}
+
+impl Display for TrapSignal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What to do when a signal is received.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SignalDisposition {
+    /// The system default action.
+    Default,
+    /// Ignore the signal, this is inherited by child processes.
+    Ignore,
+    /// Record the signal so its trap can run at the next safe point.
+    Trap,
+}
+
+/// Set the disposition of a signal, SIGINT goes back to the shell's handler for Default if it
+/// was installed.
+pub fn set_signal_disposition(sig: OsSignal, disposition: SignalDisposition) -> io::Result<()> {
+    let signal = Signal::try_from(sig).map_err(io::Error::from)?;
+    let handler = match disposition {
+        SignalDisposition::Default
+            if signal == Signal::SIGINT && SIG_INT_INSTALLED.load(Ordering::Relaxed) =>
+        {
+            SigHandler::SigAction(sig_int_handle)
+        }
+        SignalDisposition::Default => SigHandler::SigDfl,
+        SignalDisposition::Ignore => SigHandler::SigIgn,
+        SignalDisposition::Trap => SigHandler::SigAction(trap_handle),
+    };
+    let sig_action = signal::SigAction::new(
+        handler,
+        signal::SaFlags::SA_RESTART,
+        signal::SigSet::empty(),
+    );
+    unsafe { sigaction(signal, &sig_action) }
+        .map(|_| ())
+        .map_err(io::Error::from)
+}
+
+/// Flag that is set when a trapped signal has been received, a VM can poll this to run traps.
+pub fn trap_pending_flag() -> &'static AtomicBool {
+    &TRAP_PENDING
+}
+
+/// Is there a trapped signal waiting to be handled?
+pub fn trap_pending() -> bool {
+    TRAP_PENDING.load(Ordering::Acquire)
+}
+
+/// Mark traps as pending (for instance to get a VM to run handlers that were queued).
+pub fn set_trap_pending() {
+    TRAP_PENDING.store(true, Ordering::Release);
+}
+
+/// Take the trapped signals received since the last call (lowest signal number first).
+pub fn take_pending_signals() -> Vec<TrapSignal> {
+    TRAP_PENDING.store(false, Ordering::Release);
+    let pending = PENDING_SIGNALS.swap(0, Ordering::Relaxed);
+    (1..=64)
+        .filter(|sig| pending & (1_u64 << (sig - 1)) != 0)
+        .map(TrapSignal::Signal)
+        .collect()
+}
+
 pub fn install_sigint_handler() -> bool {
     let result = unsafe {
         let sig_action = signal::SigAction::new(
@@ -20,6 +133,7 @@ pub fn install_sigint_handler() -> bool {
         );
         sigaction(signal::SIGINT, &sig_action)
     };
+    SIG_INT_INSTALLED.store(result.is_ok(), Ordering::Relaxed);
 
     if let Err(errno) = result {
         eprint!("ERROR Failed to install SIGINT handler due to: ");
@@ -47,3 +161,24 @@ pub fn mask_signals() {
 pub fn test_clear_sigint() -> bool {
     SIG_INT.swap(false, Ordering::Relaxed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trap_signal() {
+        assert_eq!(TrapSignal::from_name("EXIT"), Some(TrapSignal::Exit));
+        assert_eq!(TrapSignal::from_name("0"), Some(TrapSignal::Exit));
+        let usr1 = TrapSignal::Signal(libc::SIGUSR1);
+        assert_eq!(TrapSignal::from_name("USR1"), Some(usr1));
+        assert_eq!(TrapSignal::from_name("sigusr1"), Some(usr1));
+        assert_eq!(
+            TrapSignal::from_name(&libc::SIGUSR1.to_string()),
+            Some(usr1)
+        );
+        assert_eq!(TrapSignal::from_name("BOGUS"), None);
+        assert_eq!(usr1.to_string(), "USR1");
+        assert_eq!(TrapSignal::Exit.to_string(), "EXIT");
+    }
+}
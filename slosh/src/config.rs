@@ -12,9 +12,13 @@ USAGE:
 FLAGS:
     -v, --version  Print the version, platform and revision of sl-sh then exit.
     -h, --help     Print help (this) and exit.
+    --profile      Profile the lisp code run, print a report and write folded stacks to
+                   slosh-profile.folded on exit.
 
 OPTIONS:
     -c             Command to run instead of entering the REPL.
+    --coverage     <file> Record the source lines executed and merge them into the lcov
+                   report file on exit.
 
 ARGS:
     <args>...      Script to run with arguments."#;
@@ -41,6 +45,8 @@ pub fn get_config() -> Option<Config> {
     let mut command: Option<String> = None;
     let mut script: Option<String> = None;
     let mut command_args: Vec<String> = Vec::new();
+    let mut profile = false;
+    let mut coverage: Option<String> = None;
 
     let mut args: Vec<OsString> = env::args_os().collect();
 
@@ -66,6 +72,12 @@ pub fn get_config() -> Option<Config> {
                         help(&exe_name);
                         return None;
                     }
+                    "--profile" if command.is_none() && script.is_none() => {
+                        profile = true;
+                    }
+                    "--coverage" if command.is_none() && script.is_none() => {
+                        coverage = Some(get_arg(&exe_name, &mut args)?);
+                    }
                     _ => {
                         if command.is_none() && script.is_none() {
                             script = Some(arg);
@@ -84,5 +96,7 @@ pub fn get_config() -> Option<Config> {
         command,
         script,
         args: command_args,
+        profile,
+        coverage,
     })
 }
@@ -0,0 +1,762 @@
+//! Per command completion specs.
+//!
+//! Specs are registered from lisp with complete-command (or loaded from bash complete commands)
+//! and describe the subcommands, flags and args of a command.  The shell builtins have default
+//! specs that a registered spec replaces.  Commands without a spec fall back to
+//! __completion_hook and then file completion.
+
+use crate::completions::{find_exes, get_dir_matches, get_path_matches};
+use crate::SHELL_ENV;
+use bridge_adapters::add_builtin;
+use compile_state::state::SloshVm;
+use shell::jobs::ShellOptions;
+use shell::platform::{FromFileDesc, Platform, Sys};
+use sl_compiler::load_eval::apply_callable;
+use slvm::{VMError, VMResult, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::rc::Rc;
+
+/// What an arg (or flag value) completes to.
+#[derive(Clone, Debug)]
+enum ArgType {
+    File,
+    Dir,
+    User,
+    Host,
+    Command,
+    EnvVar,
+    Job,
+    Alias,
+    Signal,
+    /// A fixed list of words.
+    Words(Vec<String>),
+    /// The words output by a shell command.
+    Output(String),
+    /// A lisp function called with the words on the line.
+    Lambda(Value),
+    /// All the completions of each type.
+    Any(Vec<ArgType>),
+}
+
+impl ArgType {
+    /// The keyword for a type that has one (the inverse of keyword_arg_type).
+    fn keyword_name(&self) -> Option<&'static str> {
+        Some(match self {
+            ArgType::File => "file",
+            ArgType::Dir => "dir",
+            ArgType::User => "user",
+            ArgType::Host => "host",
+            ArgType::Command => "command",
+            ArgType::EnvVar => "env",
+            ArgType::Job => "job",
+            ArgType::Alias => "alias",
+            ArgType::Signal => "signal",
+            _ => return None,
+        })
+    }
+}
+
+/// Completion spec for a command (or subcommand).
+#[derive(Clone, Debug, Default)]
+struct CompletionSpec {
+    subcommands: Vec<(String, CompletionSpec)>,
+    /// Flags and the type of their value (None if the flag does not take one).
+    flags: Vec<(String, Option<ArgType>)>,
+    /// Type of the positional args, None for files unless there are subcommands.
+    args: Option<ArgType>,
+}
+
+impl CompletionSpec {
+    fn with_args(args: ArgType) -> Self {
+        Self {
+            args: Some(args),
+            ..Default::default()
+        }
+    }
+
+    fn with_flags(mut self, flags: &[&str]) -> Self {
+        self.flags
+            .extend(flags.iter().map(|f| (f.to_string(), None)));
+        self
+    }
+}
+
+struct Registered {
+    // Global slot holding the spec value so any functions in it are not garbage collected.
+    slot: u32,
+    spec: Rc<CompletionSpec>,
+}
+
+thread_local! {
+    static COMPLETION_SPECS: RefCell<HashMap<String, Registered>> = RefCell::new(HashMap::new());
+}
+
+/// Default specs for the shell builtins.
+fn builtin_spec(command: &str) -> Option<CompletionSpec> {
+    use ArgType::*;
+    Some(match command {
+        "cd" | "pushd" => CompletionSpec::with_args(Dir),
+        "dirs" => {
+            CompletionSpec::with_args(Words(vec![])).with_flags(&["-c", "-l", "-p", "-v", "-h"])
+        }
+        "fg" | "bg" | "wait" | "disown" => CompletionSpec::with_args(Job),
+        "kill" => {
+            let mut spec = CompletionSpec::with_args(Job).with_flags(&["-l"]);
+            spec.flags.push(("-s".to_string(), Some(Signal)));
+            spec
+        }
+        "alias" | "unalias" => CompletionSpec::with_args(Alias),
+        "export" | "unset" => CompletionSpec::with_args(EnvVar),
+        "ulimit" => {
+            let flags: Vec<String> = "SHabcdefiklmnqrstuvxPRT"
+                .chars()
+                .map(|ch| format!("-{ch}"))
+                .collect();
+            let flags: Vec<&str> = flags.iter().map(|f| &f[..]).collect();
+            CompletionSpec::with_args(Words(vec!["unlimited".to_string()])).with_flags(&flags)
+        }
+        "set" => {
+            let mut spec = CompletionSpec::with_args(Words(vec![]))
+                .with_flags(&["-e", "-u", "+e", "+u", "+o"]);
+            let names = ShellOptions::NAMES.iter().map(|n| n.to_string()).collect();
+            spec.flags.push(("-o".to_string(), Some(Words(names))));
+            spec
+        }
+        "trap" => CompletionSpec::with_args(Any(vec![Signal, Words(vec!["EXIT".to_string()])]))
+            .with_flags(&["-l", "-p"]),
+        _ => return None,
+    })
+}
+
+fn string_arg(vm: &SloshVm, val: Value, fn_name: &str) -> VMResult<String> {
+    match val {
+        Value::String(h) => Ok(vm.get_string(h).to_string()),
+        Value::StringConst(i) | Value::Symbol(i) => Ok(vm.get_interned(i).to_string()),
+        _ => Err(VMError::new_compile(format!(
+            "{fn_name}: expected a string, got {}",
+            val.display_type(vm)
+        ))),
+    }
+}
+
+fn keyword_arg_type(name: &str) -> VMResult<ArgType> {
+    Ok(match name {
+        "file" => ArgType::File,
+        "dir" => ArgType::Dir,
+        "user" => ArgType::User,
+        "host" => ArgType::Host,
+        "command" => ArgType::Command,
+        "env" => ArgType::EnvVar,
+        "job" => ArgType::Job,
+        "alias" => ArgType::Alias,
+        "signal" => ArgType::Signal,
+        _ => {
+            return Err(VMError::new_compile(format!(
+                "complete-command: unknown completion type :{name}"
+            )))
+        }
+    })
+}
+
+fn parse_arg_type(vm: &SloshVm, val: Value) -> VMResult<ArgType> {
+    match val {
+        Value::Keyword(i) => keyword_arg_type(vm.get_interned(i)),
+        Value::String(_) | Value::StringConst(_) => {
+            Ok(ArgType::Output(string_arg(vm, val, "complete-command")?))
+        }
+        Value::Lambda(_) | Value::Closure(_) | Value::Builtin(_) => Ok(ArgType::Lambda(val)),
+        Value::Vector(_) | Value::List(_, _) | Value::Pair(_) => {
+            let mut words = vec![];
+            let mut types = vec![];
+            for v in val.iter(vm) {
+                match v {
+                    Value::String(_) | Value::StringConst(_) => {
+                        words.push(string_arg(vm, v, "complete-command")?)
+                    }
+                    _ => types.push(parse_arg_type(vm, v)?),
+                }
+            }
+            if types.is_empty() {
+                Ok(ArgType::Words(words))
+            } else {
+                if !words.is_empty() {
+                    types.push(ArgType::Words(words));
+                }
+                Ok(ArgType::Any(types))
+            }
+        }
+        _ => Err(VMError::new_compile(format!(
+            "complete-command: invalid completion type {}",
+            val.display_value(vm)
+        ))),
+    }
+}
+
+/// Parse a map of names to values (sorted by name) or a vector of names (with empty values).
+fn parse_named<T>(
+    vm: &SloshVm,
+    val: Value,
+    empty: impl Fn() -> T,
+    parse: impl Fn(&SloshVm, Value) -> VMResult<T>,
+) -> VMResult<Vec<(String, T)>> {
+    let mut named = vec![];
+    match val {
+        Value::Map(h) => {
+            for (k, v) in vm.get_map(h).iter() {
+                named.push((string_arg(vm, k, "complete-command")?, parse(vm, v)?));
+            }
+            named.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        Value::Vector(_) | Value::List(_, _) | Value::Pair(_) => {
+            for v in val.iter(vm) {
+                named.push((string_arg(vm, v, "complete-command")?, empty()));
+            }
+        }
+        _ => {
+            return Err(VMError::new_compile(format!(
+                "complete-command: expected a map or vector, got {}",
+                val.display_value(vm)
+            )))
+        }
+    }
+    Ok(named)
+}
+
+fn parse_spec(vm: &SloshVm, val: Value) -> VMResult<CompletionSpec> {
+    if val.is_nil() {
+        return Ok(CompletionSpec::default());
+    }
+    let Value::Map(h) = val else {
+        // Shorthand for a spec with just args.
+        return Ok(CompletionSpec::with_args(parse_arg_type(vm, val)?));
+    };
+    let mut spec = CompletionSpec::default();
+    for (k, v) in vm.get_map(h).iter() {
+        let key = match k {
+            Value::Keyword(i) => vm.get_interned(i),
+            _ => "",
+        };
+        match key {
+            "subcommands" => {
+                spec.subcommands = parse_named(vm, v, CompletionSpec::default, parse_spec)?
+            }
+            "flags" => {
+                spec.flags = parse_named(
+                    vm,
+                    v,
+                    || None,
+                    |vm, v| {
+                        if v.is_nil() {
+                            Ok(None)
+                        } else {
+                            parse_arg_type(vm, v).map(Some)
+                        }
+                    },
+                )?
+            }
+            "args" => spec.args = Some(parse_arg_type(vm, v)?),
+            _ => {
+                return Err(VMError::new_compile(format!(
+                    "complete-command: invalid spec key {}, expected :subcommands, :flags or :args",
+                    k.display_value(vm)
+                )))
+            }
+        }
+    }
+    Ok(spec)
+}
+
+fn register_spec(vm: &mut SloshVm, command: String, val: Value, spec: Option<CompletionSpec>) {
+    COMPLETION_SPECS.with(|specs| {
+        let mut specs = specs.borrow_mut();
+        match spec {
+            Some(spec) => {
+                let slot = specs
+                    .get(&command)
+                    .map(|r| r.slot)
+                    .unwrap_or_else(|| vm.reserve_global());
+                vm.set_global(slot, val);
+                let spec = Rc::new(spec);
+                specs.insert(command, Registered { slot, spec });
+            }
+            None => {
+                if let Some(registered) = specs.remove(&command) {
+                    vm.set_global(registered.slot, Value::Nil);
+                }
+            }
+        }
+    });
+}
+
+/// The words output by command (split on whitespace), empty if it fails.
+fn command_output(command: &str) -> Vec<String> {
+    let run = match shell::parse::parse_line(command) {
+        Ok(parsed) => parsed.into_run(),
+        Err(_) => return vec![],
+    };
+    let Ok((input, output)) = Sys::anon_pipe() else {
+        return vec![];
+    };
+    let mut run = run;
+    run.push_stdout_front(Some(output));
+    let res = SHELL_ENV.with(|jobs| match jobs.try_borrow_mut() {
+        Ok(mut jobs) => shell::run::run_job(&run, &mut jobs, true).is_ok(),
+        Err(_) => false,
+    });
+    let input = io::BufReader::new(unsafe { File::from_file_desc(input) });
+    if !res {
+        return vec![];
+    }
+    input
+        .lines()
+        .map_while(Result::ok)
+        .flat_map(|l| {
+            l.split_whitespace()
+                .map(|w| w.to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// First field of each line in a file like /etc/passwd.
+fn file_names(path: &str, sep: char) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|s| {
+            s.lines()
+                .filter(|l| !l.starts_with('#'))
+                .filter_map(|l| l.split(sep).next())
+                .map(|n| n.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn host_names() -> Vec<String> {
+    let mut hosts: Vec<String> = fs::read_to_string("/etc/hosts")
+        .map(|s| {
+            s.lines()
+                .map(|l| l.split('#').next().unwrap_or_default())
+                .flat_map(|l| {
+                    l.split_whitespace()
+                        .skip(1)
+                        .map(|h| h.to_string())
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+fn complete_lambda(vm: &mut SloshVm, func: Value, args: &[String], start: &str) -> Vec<String> {
+    let mut params: Vec<Value> = args
+        .iter()
+        .map(|a| {
+            let val = vm.alloc_string(a.clone());
+            vm.heap_sticky(val);
+            val
+        })
+        .collect();
+    let res = apply_callable(vm, func, &params);
+    params.drain(..).for_each(|val| vm.heap_unsticky(val));
+    match res {
+        Ok(Value::Nil) => get_dir_matches(start),
+        Ok(val) => match parse_arg_type(vm, val) {
+            Ok(ArgType::Lambda(_)) => vec![],
+            Ok(arg_type) => complete_type(vm, &arg_type, args, start),
+            Err(err) => {
+                eprintln!(
+                    "\nERROR: invalid completion {}: {err}",
+                    val.display_value(vm)
+                );
+                vec![]
+            }
+        },
+        Err(err) => {
+            eprintln!("\nERROR: completion function failed: {}", err.display(vm));
+            vec![]
+        }
+    }
+}
+
+fn complete_type(
+    vm: &mut SloshVm,
+    arg_type: &ArgType,
+    args: &[String],
+    start: &str,
+) -> Vec<String> {
+    let mut words = match arg_type {
+        ArgType::File => return get_dir_matches(start),
+        ArgType::Dir => return get_path_matches(start),
+        ArgType::Command => {
+            let mut ret = vec![];
+            find_exes(&mut ret, start);
+            return ret;
+        }
+        ArgType::Lambda(func) => return complete_lambda(vm, *func, args, start),
+        ArgType::Any(types) => {
+            return types
+                .iter()
+                .flat_map(|t| complete_type(vm, t, args, start))
+                .collect()
+        }
+        ArgType::User => file_names("/etc/passwd", ':'),
+        ArgType::Host => host_names(),
+        ArgType::EnvVar => env::vars().map(|(k, _)| k).collect(),
+        ArgType::Job => SHELL_ENV.with(|jobs| {
+            jobs.try_borrow()
+                .map(|jobs| jobs.job_ids().iter().map(|id| format!("%{id}")).collect())
+                .unwrap_or_default()
+        }),
+        ArgType::Alias => SHELL_ENV.with(|jobs| {
+            jobs.try_borrow()
+                .map(|jobs| jobs.alias_names())
+                .unwrap_or_default()
+        }),
+        ArgType::Signal => Sys::signal_names()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect(),
+        ArgType::Words(words) => words.clone(),
+        ArgType::Output(command) => command_output(command),
+    };
+    words.retain(|w| w.starts_with(start));
+    words
+}
+
+/// Complete start (the last word in args, args[0] is the command) using the spec for the
+/// command.  Returns None if the command has no spec.
+pub(crate) fn complete(vm: &mut SloshVm, args: &[String], start: &str) -> Option<Vec<String>> {
+    let command = args.first()?;
+    let registered =
+        COMPLETION_SPECS.with(|specs| specs.borrow().get(command).map(|r| r.spec.clone()));
+    let spec = match registered {
+        Some(spec) => spec,
+        None => Rc::new(builtin_spec(command)?),
+    };
+    let mut spec = &*spec;
+    // Type of the value for the previous word if it was a flag that takes one.
+    let mut flag_value: Option<&ArgType> = None;
+    let mut positional = 0;
+    let before = &args[1..args.len().saturating_sub(1).max(1)];
+    for word in before {
+        if flag_value.take().is_some() {
+            continue;
+        }
+        if let Some((_, value)) = spec.flags.iter().find(|(f, _)| f == word) {
+            flag_value = value.as_ref();
+        } else if !word.starts_with('-') {
+            match spec.subcommands.iter().find(|(name, _)| name == word) {
+                Some((_, sub)) if positional == 0 => spec = sub,
+                _ => positional += 1,
+            }
+        }
+    }
+    if let Some(value) = flag_value {
+        return Some(complete_type(vm, value, args, start));
+    }
+    if start.starts_with('-') && !spec.flags.is_empty() {
+        return Some(
+            spec.flags
+                .iter()
+                .map(|(f, _)| f.clone())
+                .filter(|f| f.starts_with(start))
+                .collect(),
+        );
+    }
+    let mut ret = vec![];
+    if positional == 0 {
+        ret.extend(
+            spec.subcommands
+                .iter()
+                .map(|(name, _)| name.clone())
+                .filter(|name| name.starts_with(start)),
+        );
+    }
+    match &spec.args {
+        Some(args_type) => ret.extend(complete_type(vm, args_type, args, start)),
+        None if spec.subcommands.is_empty() => ret.extend(get_dir_matches(start)),
+        None => {}
+    }
+    Some(ret)
+}
+
+fn complete_command(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    match registers {
+        [command] => {
+            let command = string_arg(vm, *command, "complete-command")?;
+            let slot = COMPLETION_SPECS.with(|specs| specs.borrow().get(&command).map(|r| r.slot));
+            Ok(slot.map(|slot| vm.get_global(slot)).unwrap_or(Value::Nil))
+        }
+        [command, spec_val] => {
+            let command = string_arg(vm, *command, "complete-command")?;
+            let spec = if spec_val.is_nil() {
+                None
+            } else {
+                Some(parse_spec(vm, *spec_val)?)
+            };
+            register_spec(vm, command, *spec_val, spec);
+            Ok(Value::Nil)
+        }
+        _ => Err(VMError::new_compile(
+            "complete-command: wrong number of args, expected command and optional spec",
+        )),
+    }
+}
+
+/// Convert one bash complete command (already split into words) into specs, returns the
+/// number of commands registered (0 if it uses an unsupported action such as -F).
+fn load_bash_complete(vm: &mut SloshVm, words: &[String]) -> usize {
+    let mut types = vec![];
+    let mut word_list = vec![];
+    let mut output = None;
+    let mut remove = false;
+    let mut words = words.iter().skip(1);
+    let mut commands = vec![];
+    while let Some(word) = words.next() {
+        let Some(opts) = word.strip_prefix('-').filter(|o| !o.is_empty()) else {
+            commands.push(word.clone());
+            commands.extend(words.cloned());
+            break;
+        };
+        for opt in opts.chars() {
+            match opt {
+                'f' => types.push(ArgType::File),
+                'd' => types.push(ArgType::Dir),
+                'u' => types.push(ArgType::User),
+                'c' => types.push(ArgType::Command),
+                'v' | 'e' => types.push(ArgType::EnvVar),
+                'j' => types.push(ArgType::Job),
+                'a' => types.push(ArgType::Alias),
+                'r' => remove = true,
+                'W' => {
+                    if let Some(list) = words.next() {
+                        word_list.extend(list.split_whitespace().map(|w| w.to_string()));
+                    }
+                }
+                'A' => match words.next().map(|a| &a[..]) {
+                    Some("file") => types.push(ArgType::File),
+                    Some("directory") => types.push(ArgType::Dir),
+                    Some("user") => types.push(ArgType::User),
+                    Some("hostname") => types.push(ArgType::Host),
+                    Some("command") => types.push(ArgType::Command),
+                    Some("variable") | Some("export") => types.push(ArgType::EnvVar),
+                    Some("alias") => types.push(ArgType::Alias),
+                    Some("job") => types.push(ArgType::Job),
+                    Some("signal") => types.push(ArgType::Signal),
+                    _ => {}
+                },
+                'o' => match words.next().map(|a| &a[..]) {
+                    Some("dirnames") => types.push(ArgType::Dir),
+                    Some("filenames") | Some("default") | Some("bashdefault") => {
+                        types.push(ArgType::File)
+                    }
+                    _ => {}
+                },
+                'C' => output = words.next().cloned(),
+                // Shell functions are bash code, can not use these.
+                'F' => return 0,
+                'G' | 'X' | 'P' | 'S' => {
+                    words.next();
+                }
+                _ => {}
+            }
+        }
+    }
+    if remove {
+        for command in commands {
+            register_spec(vm, command, Value::Nil, None);
+        }
+        return 0;
+    }
+    // Build the equivalent lisp value so complete-command returns something useful.
+    let mut values = vec![];
+    for name in types.iter().filter_map(ArgType::keyword_name) {
+        values.push(Value::Keyword(vm.intern_static(name)));
+    }
+    for w in &word_list {
+        values.push(Value::StringConst(vm.intern(w)));
+    }
+    let (arg_type, val) = match output {
+        // A command can only be used on its own (a string in a vector is a word).
+        Some(command) if values.is_empty() => (
+            ArgType::Output(command.clone()),
+            Value::StringConst(vm.intern(&command)),
+        ),
+        _ => {
+            if !word_list.is_empty() {
+                types.push(ArgType::Words(word_list));
+            }
+            let arg_type = if types.len() == 1 {
+                types.remove(0)
+            } else {
+                ArgType::Any(types)
+            };
+            (arg_type, vm.alloc_vector(values))
+        }
+    };
+    let count = commands.len();
+    for command in commands {
+        let spec = CompletionSpec::with_args(arg_type.clone());
+        register_spec(vm, command, val, Some(spec));
+    }
+    count
+}
+
+fn complete_load_bash(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let [file] = registers else {
+        return Err(VMError::new_compile(
+            "complete-load-bash: requires one argument, the file to load",
+        ));
+    };
+    let file = string_arg(vm, *file, "complete-load-bash")?;
+    let text = fs::read_to_string(&file)
+        .map_err(|e| VMError::new("io", format!("complete-load-bash: {file}: {e}")))?;
+    let mut count = 0;
+    for line in text.lines() {
+        let line = line.trim();
+        if !line.starts_with("complete ") {
+            continue;
+        }
+        // Use the shell parser to handle the quoting.
+        let words = shell::parse::parse_line(line).and_then(|parsed| {
+            SHELL_ENV.with(|jobs| {
+                let mut jobs = jobs.borrow_mut();
+                match parsed.commands() {
+                    shell::command_data::Run::Command(command) => {
+                        let command = command.expand(&mut jobs)?;
+                        Ok(command
+                            .args_iter()
+                            .map(|a| a.to_string())
+                            .collect::<Vec<String>>())
+                    }
+                    _ => Ok(vec![]),
+                }
+            })
+        });
+        match words {
+            Ok(mut words) => {
+                words.insert(0, "complete".to_string());
+                count += load_bash_complete(vm, &words);
+            }
+            Err(err) => eprintln!("complete-load-bash: {line}: {err}"),
+        }
+    }
+    Ok((count as i64).into())
+}
+
+pub(crate) fn add_completion_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "complete-command",
+        complete_command,
+        r#"Usage: (complete-command command) (complete-command command spec)
+
+Set the completion spec for command (a string), a nil spec removes it.  With just the command
+returns its spec (nil if none).  A spec is a map with any of these keys:
+:subcommands  a vector of names or a map of name to spec for the words after it
+:flags        a vector of flags or a map of flag to the type of its value (nil for none)
+:args         the type of the other args (default is files if there are no subcommands)
+A type is one of the keywords :file, :dir, :user, :host, :command, :env, :job, :alias or
+:signal, a vector of words (keywords in the vector add those types), a string that is a shell
+command whose output words are the completions or a function.  The function is called with the
+words on the line (the command first and the word being completed last) and returns a type
+(or nil for files).  A spec that is not a map is a type for :args.  The shell builtins have
+default specs, commands without a spec use __completion_hook.
+
+Section: shell
+
+Example:
+(complete-command "test-cmd" {:subcommands {"add" :file "list" nil} :flags {"-C" :dir "-v" nil}})
+(test::assert-equal :Map (type (complete-command "test-cmd")))
+(complete-command "test-cmd" nil)
+(test::assert-false (complete-command "test-cmd"))
+"#,
+    );
+    add_builtin(
+        env,
+        "complete-load-bash",
+        complete_load_bash,
+        r#"Usage: (complete-load-bash file)
+
+Load bash style complete commands from file and register them with complete-command.  Supports
+word lists (-W), -C (when it is the only action), the action flags (-f -d -u -c -v -e -j -a
+and -A) and -r, other lines (including those using -F) are ignored.  Returns the number of
+commands with completions added.
+
+Section: shell
+"#,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_slosh_vm_with_builtins;
+
+    fn words(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_complete_spec() {
+        let mut vm = new_slosh_vm_with_builtins();
+        let mut sub = CompletionSpec::with_args(ArgType::Words(words(&["one", "two"])));
+        sub.flags
+            .push(("-n".to_string(), Some(ArgType::Words(words(&["5", "10"])))));
+        let spec = CompletionSpec {
+            subcommands: vec![
+                ("sub".to_string(), sub),
+                ("other".to_string(), CompletionSpec::default()),
+            ],
+            flags: vec![("-v".to_string(), None)],
+            args: None,
+        };
+        register_spec(&mut vm, "tcmd".to_string(), Value::Nil, Some(spec));
+        let complete = |vm: &mut SloshVm, line: &[&str]| {
+            let args = words(line);
+            complete(vm, &args, line.last().unwrap())
+        };
+        assert_eq!(
+            complete(&mut vm, &["tcmd", ""]),
+            Some(words(&["sub", "other"]))
+        );
+        assert_eq!(
+            complete(&mut vm, &["tcmd", "-v", "s"]),
+            Some(words(&["sub"]))
+        );
+        assert_eq!(complete(&mut vm, &["tcmd", "-"]), Some(words(&["-v"])));
+        assert_eq!(
+            complete(&mut vm, &["tcmd", "sub", "t"]),
+            Some(words(&["two"]))
+        );
+        assert_eq!(
+            complete(&mut vm, &["tcmd", "sub", "-n", ""]),
+            Some(words(&["5", "10"]))
+        );
+        assert_eq!(
+            complete(&mut vm, &["tcmd", "sub", "-n", "5", "o"]),
+            Some(words(&["one"]))
+        );
+        assert_eq!(complete(&mut vm, &["not-a-spec-cmd", ""]), None);
+        assert_eq!(
+            complete(&mut vm, &["set", "-o", "pipe"]),
+            Some(words(&["pipefail"]))
+        );
+        assert_eq!(complete(&mut vm, &["ulimit", "-T"]), Some(words(&["-T"])));
+
+        load_bash_complete(&mut vm, &words(&["complete", "-W", "start stop", "svc"]));
+        assert_eq!(
+            complete(&mut vm, &["svc", "st"]),
+            Some(words(&["start", "stop"]))
+        );
+        load_bash_complete(&mut vm, &words(&["complete", "-r", "svc"]));
+        assert_eq!(complete(&mut vm, &["svc", "st"]), None);
+    }
+}
@@ -4,7 +4,7 @@ use sl_liner::{Completer, CursorPosition, Event, EventKind};
 use std::env;
 use std::path::Path;
 
-use crate::ENV;
+use crate::{completion_specs, ENV};
 use shell::builtins::compress_tilde;
 use shell::builtins::expand_tilde;
 use slvm::{VMResult, Value};
@@ -199,24 +199,32 @@ impl Completer for ShellCompleter {
                 HookResult::Path => get_path_matches(start),
                 HookResult::UseList(list) => list,
             },
-            CompType::Other => match self.run_hook() {
-                HookResult::Default => {
-                    if self
-                        .args
-                        .first()
-                        .map(|s| s.trim().starts_with('('))
-                        .unwrap_or(false)
-                    {
-                        let mut ret = vec![];
-                        ENV.with(|env| find_lisp_symbols(&env.borrow(), &mut ret, start));
-                        ret
-                    } else {
-                        get_dir_matches(start)
+            CompType::Other => {
+                let spec_comps = ENV.with(|env| {
+                    completion_specs::complete(&mut env.borrow_mut(), &self.args, start)
+                });
+                if let Some(comps) = spec_comps {
+                    return comps;
+                }
+                match self.run_hook() {
+                    HookResult::Default => {
+                        if self
+                            .args
+                            .first()
+                            .map(|s| s.trim().starts_with('('))
+                            .unwrap_or(false)
+                        {
+                            let mut ret = vec![];
+                            ENV.with(|env| find_lisp_symbols(&env.borrow(), &mut ret, start));
+                            ret
+                        } else {
+                            get_dir_matches(start)
+                        }
                     }
+                    HookResult::Path => get_path_matches(start),
+                    HookResult::UseList(list) => list,
                 }
-                HookResult::Path => get_path_matches(start),
-                HookResult::UseList(list) => list,
-            },
+            }
         }
     }
 
@@ -359,14 +367,14 @@ fn find_file_completions(org_start: &str, cur_path: &Path) -> Vec<String> {
     res
 }
 
-fn get_dir_matches(start: &str) -> Vec<String> {
+pub(crate) fn get_dir_matches(start: &str) -> Vec<String> {
     match env::current_dir() {
         Ok(p) => find_file_completions(start, &p),
         Err(_err) => Vec::new(),
     }
 }
 
-fn get_path_matches(start: &str) -> Vec<String> {
+pub(crate) fn get_path_matches(start: &str) -> Vec<String> {
     let mut res = get_dir_matches(start);
     res.drain(..).filter(|p| Path::new(&p).is_dir()).collect()
 }
@@ -398,6 +406,8 @@ fn find_lisp_things(
             Value::Lambda(_)
             | Value::Closure(_)
             | Value::Continuation(_)
+            | Value::Coroutine(_)
+            | Value::Iter(_)
             | Value::Builtin(_)
             | Value::Special(_) => {
                 if !symbols {
@@ -440,7 +450,7 @@ fn find_lisp_symbols(environment: &SloshVm, comps: &mut Vec<String>, org_start:
     find_lisp_things(environment, comps, start, true, need_quote)
 }
 
-fn find_exes(comps: &mut Vec<String>, start: &str) {
+pub(crate) fn find_exes(comps: &mut Vec<String>, start: &str) {
     let paths = if let Some(paths) = env::var_os("PATH") {
         env::split_paths(&paths)
             .map(|s| {
@@ -103,6 +103,63 @@ fn dump_stack(vm: &SloshVm) {
     }
 }
 
+/// The restarts active where the error was raised (see restart-case), innermost first.
+fn restarts(env: &SloshVm) -> Vec<Value> {
+    env.restarts().iter(env).collect()
+}
+
+fn restart_field(env: &mut SloshVm, restart: Value, field: &'static str) -> Value {
+    let key = Value::Keyword(env.intern_static(field));
+    match restart {
+        Value::Map(h) => env.get_map(h).get(env, key).unwrap_or(Value::Nil),
+        _ => Value::Nil,
+    }
+}
+
+fn list_restarts(env: &mut SloshVm) {
+    let restarts = restarts(env);
+    if restarts.is_empty() {
+        println!("No restarts.");
+    }
+    for (i, restart) in restarts.into_iter().enumerate() {
+        let name = restart_field(env, restart, "name");
+        let params = restart_field(env, restart, "params");
+        let report = restart_field(env, restart, "report");
+        let params = if params.is_nil() {
+            "()".to_string()
+        } else {
+            params.display_value(env)
+        };
+        let name = name.display_value(env);
+        match report.get_string(env) {
+            Ok(report) => println!("{i}: {name} {params} - {report}"),
+            Err(_) => println!("{i}: {name} {params}"),
+        }
+    }
+}
+
+/// Invoke restart number idx (from list_restarts) with args, this continues the program from the
+/// restart-case so returns the result of the interrupted expression.
+fn invoke_restart(env: &mut SloshVm, idx: usize, args: &[Value]) -> VMResult<Value> {
+    let Some(restart) = restarts(env).get(idx).copied() else {
+        return Err(VMError::new_vm(format!("No restart {idx}.")));
+    };
+    match restart_field(env, restart, "invoke") {
+        Value::Lambda(h) => {
+            let l = env.get_lambda(h);
+            env.do_call(l, args, None)
+        }
+        Value::Closure(h) => {
+            let (l, tcaps) = env.get_closure(h);
+            let caps = Vec::from(tcaps);
+            env.do_call(l, args, Some(&caps[..]))
+        }
+        _ => Err(VMError::new_vm(format!(
+            "Restart {idx} does not have an :invoke function."
+        ))),
+    }
+}
+
 pub fn debug(env: &mut SloshVm) {
     let abort = env.intern("abort");
     let globals = env.intern("globals");
@@ -110,6 +167,8 @@ pub fn debug(env: &mut SloshVm) {
     let regs = env.intern("regs");
     let regs_raw = env.intern("regs-raw");
     let stack = env.intern("stack");
+    let restarts_cmd = env.intern("restarts");
+    let restart_cmd = env.intern("restart");
     let mut con = Context::new();
 
     if let Err(e) = con.history.set_file_name_and_load_history("history_debug") {
@@ -120,7 +179,7 @@ pub fn debug(env: &mut SloshVm) {
             Ok(input) => input,
             Err(err) => match err.kind() {
                 ErrorKind::UnexpectedEof => {
-                    println!("Enter :abort to exit debug mode and abort the error.");
+                    println!("Enter :abort to exit debug mode and abort the error or :restarts to list restarts.");
                     continue;
                 }
                 ErrorKind::Interrupted => {
@@ -224,6 +283,32 @@ pub fn debug(env: &mut SloshVm) {
                     );
                 }
             }
+            Some(Ok(Value::Keyword(k))) if k == restarts_cmd => list_restarts(env),
+            Some(Ok(Value::Keyword(k))) if k == restart_cmd => {
+                let args: Result<Vec<Value>, _> = exps.collect();
+                let args = match args {
+                    Ok(args) => args,
+                    Err(err) => {
+                        println!("Reader error: {err}");
+                        continue;
+                    }
+                };
+                let Some(Ok(idx)) = args.first().map(|parm| parm.get_int(env)) else {
+                    println!("Usage: :restart N value* (N from :restarts).");
+                    continue;
+                };
+                let args = &args[1..];
+                match invoke_restart(env, idx.unsigned_abs() as usize, args) {
+                    Ok(res) => {
+                        if !res.is_nil() {
+                            println!("{}", res.display_value(env));
+                        }
+                        env.reset();
+                        return;
+                    }
+                    Err(err) => println!("Restart failed: {}", err.display(env)),
+                }
+            }
             Some(Err(err)) => println!("Reader error: {err}"),
             _ => {}
         }
@@ -288,3 +373,41 @@ pub fn builtin_dump_regs(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Valu
     }
     Ok(Value::Nil)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_slosh_vm_with_builtins_and_core;
+    use sl_compiler::pass1::pass1;
+    use sl_compiler::{compile, CompileState};
+    use slvm::RET;
+
+    fn execute(env: &mut SloshVm, code: &str) -> VMResult<Value> {
+        let exp = Reader::from_string(code.to_string(), env, "", 1, 0)
+            .next()
+            .unwrap()
+            .unwrap();
+        let mut state = CompileState::new_state("test", 1, None);
+        pass1(env, &mut state, exp).unwrap();
+        compile(env, &mut state, exp, 0).unwrap();
+        state.chunk.encode0(RET, env.own_line()).unwrap();
+        env.execute(Arc::new(state.chunk.clone()))
+    }
+
+    #[test]
+    fn test_invoke_restart() {
+        let mut env = new_slosh_vm_with_builtins_and_core();
+        let code = "(+ 100 (restart-case (err :x 1) (use-value (v) v) (other () 5)))";
+        assert!(execute(&mut env, code).is_err());
+        let names: Vec<String> = restarts(&env)
+            .into_iter()
+            .map(|r| restart_field(&mut env, r, "name").display_value(&env))
+            .collect();
+        assert_eq!(names, vec!["use-value", "other"]);
+        let res = invoke_restart(&mut env, 0, &[7.into()]).unwrap();
+        assert_eq!(res.get_int(&env).unwrap(), 107);
+        assert!(invoke_restart(&mut env, 2, &[]).is_err());
+        env.reset();
+        assert!(restarts(&env).is_empty());
+    }
+}
@@ -0,0 +1,527 @@
+//! Syntax highlighting for the REPL line editor.
+//!
+//! Lisp lines (starting with ( or $() are split on the same delimiters as the compiler reader and
+//! atoms are read with the [`Reader`] then looked up in the globals.  Shell lines are parsed with
+//! the shell parser and each command name is checked against the aliases, builtins and PATH.
+//! Colors come from the *syntax-theme* map so they can be changed from lisp.
+
+use crate::SHELL_ENV;
+use bridge_adapters::add_builtin;
+use builtins::add_global_value;
+use compile_state::state::{SloshVm, SloshVmTrait};
+use shell::builtins::is_builtin;
+use shell::command_data::{CommandWithArgs, Run, Span};
+use shell::parse::parse_line;
+use sl_compiler::reader::Reader;
+use slvm::vm_hashmap::VMHashMap;
+use slvm::{VMError, VMResult, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Name of the global map with the highlighting colors.
+const THEME_NAME: &str = "*syntax-theme*";
+
+/// Default colors, the :paren entry is a vector of colors cycled by nesting depth.
+const DEFAULT_THEME: &[(&str, &str)] = &[
+    ("default", "\x1b[39m"),
+    ("special", "\x1b[34m"),
+    ("macro", "\x1b[94m"),
+    ("global", "\x1b[36m"),
+    ("keyword", "\x1b[33m"),
+    ("string", "\x1b[35m"),
+    ("number", "\x1b[32m"),
+    ("comment", "\x1b[90m"),
+    ("unbalanced", "\x1b[31m"),
+    ("builtin", "\x1b[34m"),
+    ("alias", "\x1b[36m"),
+    ("executable", "\x1b[37m"),
+    ("unknown-command", "\x1b[31m"),
+    ("variable", "\x1b[33m"),
+];
+const DEFAULT_PARENS: &[&str] = &["\x1b[37m", "\x1b[36m", "\x1b[33m", "\x1b[34m"];
+
+thread_local! {
+    // Cache of command name to executable found, for the value of PATH it was built with.
+    static EXE_CACHE: RefCell<(Option<OsString>, HashMap<String, bool>)> =
+        RefCell::new((None, HashMap::new()));
+}
+
+/// Colors to use for one line, the defaults overridden by anything in *syntax-theme*.
+struct Theme {
+    colors: HashMap<&'static str, String>,
+    parens: Vec<String>,
+}
+
+impl Theme {
+    fn load(vm: &SloshVm) -> Self {
+        let mut colors: HashMap<&'static str, String> = DEFAULT_THEME
+            .iter()
+            .map(|(k, v)| (*k, v.to_string()))
+            .collect();
+        let mut parens: Vec<String> = DEFAULT_PARENS.iter().map(|s| s.to_string()).collect();
+        let theme = vm
+            .get_if_interned(THEME_NAME)
+            .and_then(|i| vm.global_intern_slot(i))
+            .map(|slot| vm.get_global(slot));
+        if let Some(Value::Map(handle)) = theme {
+            for (key, val) in vm.get_map(handle).iter() {
+                let Value::Keyword(key) = key else {
+                    continue;
+                };
+                let key = vm.get_interned(key);
+                if key == "paren" {
+                    let p: Vec<String> = val
+                        .iter(vm)
+                        .filter_map(|v| v.get_string(vm).ok().map(|s| s.to_string()))
+                        .collect();
+                    if !p.is_empty() {
+                        parens = p;
+                    } else if let Ok(s) = val.get_string(vm) {
+                        parens = vec![s.to_string()];
+                    }
+                } else if let Ok(s) = val.get_string(vm) {
+                    colors.insert(key, s.to_string());
+                }
+            }
+        }
+        Self { colors, parens }
+    }
+
+    fn color(&self, class: &str) -> &str {
+        self.colors.get(class).map(|s| &s[..]).unwrap_or("")
+    }
+
+    fn paren(&self, depth: usize) -> &str {
+        &self.parens[depth % self.parens.len()]
+    }
+}
+
+fn push_colored(out: &mut String, theme: &Theme, color: &str, text: &str) {
+    out.push_str(color);
+    out.push_str(text);
+    out.push_str(theme.color("default"));
+}
+
+/// Same as the reader, these end a symbol.
+fn is_delimiter(ch: char) -> bool {
+    matches!(
+        ch,
+        ' ' | '\t'
+            | '\n'
+            | ','
+            | '('
+            | ')'
+            | '#'
+            | '"'
+            | '~'
+            | '\''
+            | '`'
+            | '['
+            | ']'
+            | '{'
+            | '}'
+            | '\\'
+            | ';'
+    )
+}
+
+/// The theme class of a lisp atom (symbol, number or keyword), None for no color.
+fn atom_class(vm: &mut SloshVm, atom: &str) -> Option<&'static str> {
+    if atom.len() > 1 && atom.starts_with(':') {
+        return Some("keyword");
+    }
+    let first = atom.chars().next()?;
+    if first.is_ascii_digit() || matches!(first, '+' | '-' | '.') {
+        // Let the reader decide what is a number.
+        let mut reader = Reader::from_string(atom.to_string(), vm, "", 1, 0);
+        if let Some(Ok(Value::Int(_) | Value::Float(_))) = reader.next() {
+            return Some("number");
+        }
+    }
+    // A symbol that was never interned can not be a global (and interning here would leak).
+    let slot = vm
+        .get_if_interned(atom)
+        .and_then(|i| vm.global_intern_slot(i))?;
+    let val = vm.get_global(slot);
+    match val {
+        Value::Undefined => None,
+        Value::Special(_) => Some("special"),
+        Value::Lambda(_) | Value::Closure(_)
+            if vm.get_heap_property(val, ":macro") == Some(Value::True) =>
+        {
+            Some("macro")
+        }
+        _ => Some("global"),
+    }
+}
+
+fn highlight_lisp(vm: &mut SloshVm, theme: &Theme, line: &str) -> String {
+    let mut out = String::with_capacity(line.len() * 2);
+    // Closing delimiters expected for the open forms.
+    let mut stack: Vec<char> = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some((start, ch)) = chars.next() {
+        match ch {
+            ';' => {
+                let end = line[start..]
+                    .find('\n')
+                    .map(|i| start + i)
+                    .unwrap_or(line.len());
+                push_colored(&mut out, theme, theme.color("comment"), &line[start..end]);
+                while chars.peek().map(|(i, _)| *i < end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            '#' if matches!(chars.peek(), Some((_, '|'))) => {
+                let end = line[start + 2..]
+                    .find("|#")
+                    .map(|i| start + 2 + i + 2)
+                    .unwrap_or(line.len());
+                push_colored(&mut out, theme, theme.color("comment"), &line[start..end]);
+                while chars.peek().map(|(i, _)| *i < end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            '#' if matches!(chars.peek(), Some((_, '\\'))) => {
+                // Char literal, #\ then at least one char then anything up to a delimiter.
+                chars.next();
+                let mut end = chars
+                    .next()
+                    .map(|(i, c)| i + c.len_utf8())
+                    .unwrap_or(line.len());
+                while let Some((i, c)) = chars.peek() {
+                    if is_delimiter(*c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                push_colored(&mut out, theme, theme.color("string"), &line[start..end]);
+            }
+            '"' => {
+                let mut end = line.len();
+                while let Some((i, c)) = chars.next() {
+                    if c == '\\' {
+                        chars.next();
+                    } else if c == '"' {
+                        end = i + 1;
+                        break;
+                    }
+                }
+                push_colored(&mut out, theme, theme.color("string"), &line[start..end]);
+            }
+            '$' if matches!(chars.peek(), Some((_, '('))) => {
+                chars.next();
+                push_colored(&mut out, theme, theme.paren(stack.len()), "$(");
+                stack.push(')');
+            }
+            '(' | '[' | '{' => {
+                push_colored(
+                    &mut out,
+                    theme,
+                    theme.paren(stack.len()),
+                    &line[start..start + 1],
+                );
+                stack.push(match ch {
+                    '(' => ')',
+                    '[' => ']',
+                    _ => '}',
+                });
+            }
+            ')' | ']' | '}' => {
+                if stack.last() == Some(&ch) {
+                    stack.pop();
+                    push_colored(
+                        &mut out,
+                        theme,
+                        theme.paren(stack.len()),
+                        &line[start..start + 1],
+                    );
+                } else {
+                    push_colored(
+                        &mut out,
+                        theme,
+                        theme.color("unbalanced"),
+                        &line[start..start + 1],
+                    );
+                }
+            }
+            ' ' | '\t' | '\n' | ',' | '#' | '~' | '\'' | '`' => out.push(ch),
+            _ => {
+                let mut end = start + ch.len_utf8();
+                let mut escape = ch == '\\';
+                while let Some((i, c)) = chars.peek() {
+                    if !escape && is_delimiter(*c) {
+                        break;
+                    }
+                    escape = !escape && *c == '\\';
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let atom = &line[start..end];
+                match atom_class(vm, atom) {
+                    Some(class) => push_colored(&mut out, theme, theme.color(class), atom),
+                    None => out.push_str(atom),
+                }
+            }
+        }
+    }
+    out
+}
+
+/// True if path is a file with an execute bit set.
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// True if command is an executable on PATH (or a path to an executable), the result is cached
+/// until PATH changes or a new line is started.
+fn find_executable(command: &str, new_line: bool) -> bool {
+    if command.contains('/') {
+        return is_executable(Path::new(&*shell::builtins::expand_tilde(command.into())));
+    }
+    EXE_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let path = env::var_os("PATH");
+        if new_line || cache.0 != path {
+            cache.0 = path.clone();
+            cache.1.clear();
+        }
+        if let Some(found) = cache.1.get(command) {
+            return *found;
+        }
+        let found = path
+            .map(|p| env::split_paths(&p).any(|dir| is_executable(&dir.join(command))))
+            .unwrap_or(false);
+        cache.1.insert(command.to_string(), found);
+        found
+    })
+}
+
+/// The theme class for a shell command name.
+fn command_class(command: &str, new_line: bool) -> &'static str {
+    let is_alias = SHELL_ENV.with(|jobs| {
+        jobs.try_borrow()
+            .map(|jobs| jobs.has_alias(command))
+            .unwrap_or(false)
+    });
+    if is_alias {
+        "alias"
+    } else if is_builtin(command) {
+        "builtin"
+    } else if find_executable(command, new_line) {
+        "executable"
+    } else {
+        "unknown-command"
+    }
+}
+
+fn command_spans(
+    line: &str,
+    command: &CommandWithArgs,
+    new_line: bool,
+    spans: &mut Vec<(Span, &'static str)>,
+) {
+    let mut args = command.spans().iter();
+    if let Some(span) = args.next() {
+        // Only a literal name, not one from a variable or command substitution.
+        if let Some(name) = line
+            .get(span.start..span.end)
+            .filter(|n| !n.contains(['$', '"', '\'', '`']))
+        {
+            spans.push((*span, command_class(name, new_line)));
+        }
+    }
+    for span in args {
+        match line
+            .get(span.start..span.end)
+            .and_then(|a| a.chars().next())
+        {
+            Some('"' | '\'') => spans.push((*span, "string")),
+            Some('$') => spans.push((*span, "variable")),
+            _ => {}
+        }
+    }
+}
+
+fn run_spans(line: &str, run: &Run, new_line: bool, spans: &mut Vec<(Span, &'static str)>) {
+    match run {
+        Run::Command(command) | Run::BackgroundCommand(command) => {
+            command_spans(line, command, new_line, spans)
+        }
+        Run::Pipe(seq) | Run::Sequence(seq) | Run::And(seq) | Run::Or(seq) => {
+            for r in seq {
+                run_spans(line, r, new_line, spans);
+            }
+        }
+        Run::Subshell(sub_run) => run_spans(line, sub_run, new_line, spans),
+        Run::Empty => {}
+    }
+}
+
+fn highlight_shell(theme: &Theme, line: &str) -> String {
+    let mut spans = Vec::new();
+    match parse_line(line) {
+        Ok(parsed) => run_spans(line, parsed.commands(), line.len() <= 1, &mut spans),
+        // Incomplete (unclosed quote etc), leave it alone until it parses.
+        Err(_) => return line.to_string(),
+    }
+    spans.sort_by_key(|(span, _)| span.start);
+    let mut out = String::with_capacity(line.len() * 2);
+    let mut last = 0;
+    for (span, class) in spans {
+        if span.start < last || span.end > line.len() {
+            continue;
+        }
+        out.push_str(&line[last..span.start]);
+        push_colored(
+            &mut out,
+            theme,
+            theme.color(class),
+            &line[span.start..span.end],
+        );
+        last = span.end;
+    }
+    out.push_str(&line[last..]);
+    out
+}
+
+/// Highlight line for the line editor, returns line with color escape codes added.
+pub fn highlight(vm: &mut SloshVm, line: &str) -> String {
+    let theme = Theme::load(vm);
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('(') || trimmed.starts_with("$(") {
+        highlight_lisp(vm, &theme, line)
+    } else {
+        highlight_shell(&theme, line)
+    }
+}
+
+/// Usage: (syntax-highlight line)
+///
+/// Return line with the color escape codes the REPL uses for syntax highlighting.
+fn syntax_highlight(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let mut args = registers.iter();
+    if let (Some(line), None) = (args.next(), args.next()) {
+        let line = line.get_string(vm)?.to_string();
+        let res = highlight(vm, &line);
+        Ok(vm.alloc_string(res))
+    } else {
+        Err(VMError::new_compile(
+            "syntax-highlight: requires one argument",
+        ))
+    }
+}
+
+/// Add the *syntax-theme* map and the syntax-highlight builtin.
+pub(crate) fn add_highlight_builtins(env: &mut SloshVm) {
+    env.pause_gc();
+    let mut map = VMHashMap::with_capacity(DEFAULT_THEME.len() + 1);
+    for (key, color) in DEFAULT_THEME {
+        let key = Value::Keyword(env.intern_static(key));
+        let color = Value::StringConst(env.intern_static(color));
+        map.insert(env, key, color);
+    }
+    let parens = DEFAULT_PARENS
+        .iter()
+        .map(|c| Value::StringConst(env.intern_static(c)))
+        .collect();
+    let parens = env.alloc_vector(parens);
+    let key = Value::Keyword(env.intern_static("paren"));
+    map.insert(env, key, parens);
+    let theme = env.alloc_map(map);
+    add_global_value(
+        env,
+        THEME_NAME,
+        theme,
+        "Usage: (set! *syntax-theme*.:string *fg-green*)
+
+Map of the colors (terminal escape strings) used for syntax highlighting at the repl.  The keys
+are :default, :special, :macro, :global, :keyword, :string, :number, :comment, :unbalanced
+(delimiters that do not match), :builtin, :alias, :executable, :unknown-command and :variable.
+:paren is a vector of colors for delimiters that is cycled through by nesting depth.
+
+Section: shell
+",
+    );
+    env.unpause_gc();
+    add_builtin(
+        env,
+        "syntax-highlight",
+        syntax_highlight,
+        "Usage: (syntax-highlight line)
+
+Return line with the color escape codes used by the repl syntax highlighting (see *syntax-theme*).
+
+Section: shell
+
+Example:
+(test::assert-equal \"\\x1b[34mcd\\x1b[39m /tmp\" (syntax-highlight \"cd /tmp\"))
+",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_slosh_vm_with_builtins;
+
+    /// Remove the escape codes so a highlighted line can be compared to the original.
+    fn strip(line: &str) -> String {
+        let mut out = String::new();
+        let mut in_esc = false;
+        for ch in line.chars() {
+            if ch == '\x1b' {
+                in_esc = true;
+            } else if in_esc {
+                in_esc = ch != 'm';
+            } else {
+                out.push(ch);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_highlight_lisp() {
+        let mut vm = new_slosh_vm_with_builtins();
+        add_highlight_builtins(&mut vm);
+        for line in [
+            "(if #t (prn \"a \\\" b\" 1.5 :key) ; comment",
+            "(let (x [1 2] y {:a 1}) #\\a #| block |# x))",
+            "$(ls -l)",
+        ] {
+            assert_eq!(strip(&highlight(&mut vm, line)), line);
+        }
+        assert_eq!(atom_class(&mut vm, "if"), Some("special"));
+        assert_eq!(atom_class(&mut vm, "prn"), Some("global"));
+        assert_eq!(atom_class(&mut vm, "12_000"), Some("number"));
+        assert_eq!(atom_class(&mut vm, "-1.5e3"), Some("number"));
+        assert_eq!(atom_class(&mut vm, ":key"), Some("keyword"));
+        assert_eq!(atom_class(&mut vm, "not-a-global-xyz"), None);
+        let unbalanced = highlight(&mut vm, "(a))");
+        assert!(unbalanced.ends_with("\x1b[31m)\x1b[39m"));
+    }
+
+    #[test]
+    fn test_highlight_shell() {
+        let mut vm = new_slosh_vm_with_builtins();
+        add_highlight_builtins(&mut vm);
+        let line = "cd /tmp && not-a-command-xyz \"str\" $HOME | sh -c 'true'";
+        let res = highlight(&mut vm, line);
+        assert_eq!(strip(&res), line);
+        assert!(res.starts_with("\x1b[34mcd\x1b[39m"));
+        assert!(res.contains("\x1b[31mnot-a-command-xyz\x1b[39m"));
+        assert!(res.contains("\x1b[35m\"str\"\x1b[39m"));
+        assert!(res.contains("\x1b[33m$HOME\x1b[39m"));
+        assert!(res.contains("\x1b[37msh\x1b[39m"));
+        // Does not parse (unclosed quote) so left as is.
+        assert_eq!(highlight(&mut vm, "echo \"abc"), "echo \"abc");
+    }
+}
@@ -0,0 +1,764 @@
+//! Command history with metadata (time, directory, exit status, duration and session).
+//!
+//! Entries are appended to a tab separated file next to the line editor history with one write
+//! per entry, so several shells can share the file.  Entries added by other shells are read in
+//! (from where the last read ended) before each query.
+
+use bridge_adapters::add_builtin;
+use compile_state::state::SloshVm;
+use shell::platform::{FromFileDesc, Platform, Sys, STDIN_FILENO};
+use sl_console::event::Key;
+use sl_liner::keymap::KeyMap;
+use sl_liner::{Completer, Editor};
+use slvm::vm_hashmap::VMHashMap;
+use slvm::{VMError, VMResult, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of matches shown by the search widget.
+const SEARCH_ROWS: usize = 10;
+
+/// One command from the history.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct HistoryEntry {
+    pub command: String,
+    /// Seconds since the Unix epoch when the command started.
+    pub timestamp: u64,
+    pub cwd: String,
+    pub status: i32,
+    pub duration_ms: u64,
+    pub session: String,
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(ch) => out.push(ch),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+impl HistoryEntry {
+    /// The line for this entry in the history file (including the newline).
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.timestamp,
+            self.session,
+            self.status,
+            self.duration_ms,
+            escape(&self.cwd),
+            escape(&self.command)
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(6, '\t');
+        Some(Self {
+            timestamp: fields.next()?.parse().ok()?,
+            session: fields.next()?.to_string(),
+            status: fields.next()?.parse().ok()?,
+            duration_ms: fields.next()?.parse().ok()?,
+            cwd: unescape(fields.next()?),
+            command: unescape(fields.next()?),
+        })
+    }
+}
+
+/// Which entries a search or query includes.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HistoryFilter {
+    /// Only commands run in this directory.
+    pub dir: Option<String>,
+    /// Only commands that exited with status 0.
+    pub success: bool,
+    /// Only commands from this session.
+    pub session: Option<String>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.dir.as_ref().map(|d| *d == entry.cwd).unwrap_or(true)
+            && (!self.success || entry.status == 0)
+            && self
+                .session
+                .as_ref()
+                .map(|s| *s == entry.session)
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Default)]
+struct HistoryStore {
+    path: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+    // Bytes of the file read so far.
+    offset: u64,
+    session: String,
+}
+
+impl HistoryStore {
+    /// Read any entries added to the file (by this or another shell) since the last read.
+    fn refresh(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let Ok(mut file) = File::open(path) else {
+            return;
+        };
+        let mut new = String::new();
+        if file.seek(SeekFrom::Start(self.offset)).is_err()
+            || file.read_to_string(&mut new).is_err()
+        {
+            return;
+        }
+        // Only use complete lines, a line being written will be picked up next time.
+        let end = new.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        for line in new[..end].lines() {
+            if let Some(entry) = HistoryEntry::from_line(line) {
+                self.entries.push(entry);
+            }
+        }
+        self.offset += end as u64;
+    }
+
+    fn append(&mut self, entry: &HistoryEntry) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            // One write of the whole line with O_APPEND so shells sharing the file do not mix
+            // up their entries.
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(entry.to_line().as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static HISTORY: RefCell<HistoryStore> = RefCell::new(HistoryStore {
+        session: format!("{:x}-{:x}", std::process::id(), now_secs()),
+        ..Default::default()
+    });
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn current_dir() -> String {
+    env::current_dir()
+        .map(|d| d.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Use path for the history, loading the entries already in it.
+pub(crate) fn set_history_file(path: impl Into<PathBuf>) {
+    HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        history.path = Some(path.into());
+        history.entries.clear();
+        history.offset = 0;
+        history.refresh();
+    });
+}
+
+fn ensure_history_file() {
+    if HISTORY.with(|history| history.borrow().path.is_none()) {
+        set_history_file(format!("{}-meta", crate::history_file()));
+    }
+}
+
+/// The id of this shell's session.
+pub(crate) fn session_id() -> String {
+    HISTORY.with(|history| history.borrow().session.clone())
+}
+
+/// Record a command that started at start and has finished with status.
+pub(crate) fn record(command: &str, status: i32, start: SystemTime, duration: Duration) {
+    let entry = HistoryEntry {
+        command: command.to_string(),
+        timestamp: start
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        cwd: current_dir(),
+        status,
+        duration_ms: duration.as_millis() as u64,
+        session: session_id(),
+    };
+    HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        // Pick up other shells' entries first so ours stay in file order.
+        history.refresh();
+        match history.append(&entry) {
+            Ok(()) => history.refresh(),
+            Err(err) => eprintln!("Error saving history: {err}"),
+        }
+    });
+}
+
+/// Fuzzy match query against text, None if the chars of query are not all in text (in order).
+/// Higher scores are better matches, runs of matching chars and matches at the start of words
+/// score more.  Matching ignores case unless query has an upper case char.
+pub(crate) fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    if query.is_empty() {
+        return Some(0);
+    }
+    let ignore_case = !query.chars().any(|c| c.is_uppercase());
+    let fold = |c: char| {
+        if ignore_case {
+            c.to_lowercase().next().unwrap_or(c)
+        } else {
+            c
+        }
+    };
+    let mut score = 0;
+    let mut query_chars = query.chars().map(fold).peekable();
+    let mut prev: Option<char> = None;
+    let mut prev_matched = false;
+    let mut gap = 0;
+    for ch in text.chars() {
+        let Some(q) = query_chars.peek() else {
+            break;
+        };
+        if fold(ch) == *q {
+            query_chars.next();
+            score += 10;
+            if prev_matched {
+                score += 15;
+            }
+            if prev
+                .map(|p| p.is_whitespace() || matches!(p, '/' | '-' | '_' | '.' | '('))
+                .unwrap_or(true)
+            {
+                score += 10;
+            }
+            score -= gap.min(10);
+            gap = 0;
+            prev_matched = true;
+        } else {
+            if score > 0 {
+                gap += 1;
+            }
+            prev_matched = false;
+        }
+        prev = Some(ch);
+    }
+    if query_chars.peek().is_some() {
+        None
+    } else {
+        Some(score)
+    }
+}
+
+/// Weight of a use of a command by how long ago it was (the frecency buckets).
+fn recency_weight(timestamp: u64, now: u64) -> f64 {
+    let days = now.saturating_sub(timestamp) / (60 * 60 * 24);
+    match days {
+        0..=3 => 100.0,
+        4..=13 => 70.0,
+        14..=30 => 50.0,
+        31..=89 => 30.0,
+        _ => 10.0,
+    }
+}
+
+/// Commands matching query and filter, best first.  Each command is listed once (its most
+/// recent entry) and ranked by the fuzzy match and its frecency (how often and recently it was
+/// used).
+fn rank(entries: &[HistoryEntry], query: &str, filter: &HistoryFilter) -> Vec<HistoryEntry> {
+    let now = now_secs();
+    // command -> (latest entry, frecency, fuzzy score)
+    let mut found: HashMap<&str, (&HistoryEntry, f64, i64)> = HashMap::new();
+    for entry in entries.iter().filter(|e| filter.matches(e)) {
+        let weight = recency_weight(entry.timestamp, now);
+        if let Some((latest, frecency, _)) = found.get_mut(&entry.command[..]) {
+            *frecency += weight;
+            if entry.timestamp >= latest.timestamp {
+                *latest = entry;
+            }
+        } else if let Some(score) = fuzzy_score(query, &entry.command) {
+            found.insert(&entry.command, (entry, weight, score));
+        }
+    }
+    let mut ranked: Vec<(&HistoryEntry, f64)> = found
+        .into_values()
+        .map(|(entry, frecency, score)| (entry, (score as f64 + 10.0) * frecency.ln_1p()))
+        .collect();
+    ranked.sort_by(|a, b| {
+        b.1.total_cmp(&a.1)
+            .then_with(|| b.0.timestamp.cmp(&a.0.timestamp))
+    });
+    ranked.into_iter().map(|(e, _)| e.clone()).collect()
+}
+
+/// Search the history for query, see [`rank`].
+pub(crate) fn search(query: &str, filter: &HistoryFilter) -> Vec<HistoryEntry> {
+    ensure_history_file();
+    HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        history.refresh();
+        rank(&history.entries, query, filter)
+    })
+}
+
+/// Entries matching filter, most recent first.
+pub(crate) fn entries(filter: &HistoryFilter) -> Vec<HistoryEntry> {
+    ensure_history_file();
+    HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        history.refresh();
+        history
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Filter modes the search widget cycles through.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum SearchMode {
+    All,
+    Dir,
+    Success,
+}
+
+impl SearchMode {
+    fn next(self) -> Self {
+        match self {
+            SearchMode::All => SearchMode::Dir,
+            SearchMode::Dir => SearchMode::Success,
+            SearchMode::Success => SearchMode::All,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SearchMode::All => "all",
+            SearchMode::Dir => "directory",
+            SearchMode::Success => "successful",
+        }
+    }
+
+    fn filter(self) -> HistoryFilter {
+        match self {
+            SearchMode::All => HistoryFilter::default(),
+            SearchMode::Dir => HistoryFilter {
+                dir: Some(current_dir()),
+                ..Default::default()
+            },
+            SearchMode::Success => HistoryFilter {
+                success: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn draw_search(
+    out: &mut impl Write,
+    query: &str,
+    mode: SearchMode,
+    matches: &[HistoryEntry],
+    selected: usize,
+) -> io::Result<()> {
+    write!(out, "\r\x1b[J[{}] history> {query}", mode.name())?;
+    for (i, entry) in matches.iter().take(SEARCH_ROWS).enumerate() {
+        // Keep each match on one line.
+        let command: String = entry
+            .command
+            .chars()
+            .map(|c| if c == '\n' { ' ' } else { c })
+            .collect();
+        if i == selected {
+            write!(out, "\r\n\x1b[7m> {command}\x1b[0m")?;
+        } else {
+            write!(out, "\r\n  {command}")?;
+        }
+    }
+    let rows = matches.len().min(SEARCH_ROWS);
+    if rows > 0 {
+        write!(out, "\x1b[{rows}A")?;
+    }
+    let col = mode.name().len() + 12 + query.chars().count();
+    write!(out, "\r\x1b[{col}C")?;
+    out.flush()
+}
+
+/// Interactive fuzzy search of the history on the terminal, returns the selected command.
+///
+/// Typing filters the matches (ranked by frecency), up/down (or ctrl-p/ctrl-n) select, tab or
+/// ctrl-r cycles between all commands, commands run in the current directory and successful
+/// commands, enter accepts and esc, ctrl-c or ctrl-g cancel.
+pub(crate) fn search_widget(initial: &str) -> io::Result<Option<String>> {
+    let old_settings = Sys::set_raw_mode(STDIN_FILENO)?;
+    let res = search_loop(initial);
+    let mut out = io::stdout();
+    write!(out, "\r\x1b[J")?;
+    out.flush()?;
+    Sys::set_term_settings(STDIN_FILENO, &old_settings)?;
+    res
+}
+
+/// Line editor keymap that runs the history search widget on ctrl-r (instead of the line
+/// editor's own reverse search) and passes every other key to keymap.
+pub(crate) struct HistorySearchKeyMap {
+    keymap: Box<dyn KeyMap>,
+}
+
+impl HistorySearchKeyMap {
+    pub(crate) fn new(keymap: Box<dyn KeyMap>) -> Self {
+        Self { keymap }
+    }
+}
+
+impl KeyMap for HistorySearchKeyMap {
+    fn handle_key_core(&mut self, key: Key, editor: &mut Editor) -> io::Result<()> {
+        self.keymap.handle_key_core(key, editor)
+    }
+
+    fn init(&mut self, editor: &mut Editor) {
+        self.keymap.init(editor);
+    }
+
+    fn handle_key(
+        &mut self,
+        key: Key,
+        editor: &mut Editor,
+        handler: &mut dyn Completer,
+    ) -> io::Result<bool> {
+        if key != Key::Ctrl('r') {
+            return self.keymap.handle_key(key, editor, handler);
+        }
+        let initial = String::from(editor.current_buffer().clone());
+        if let Some(command) = search_widget(&initial)? {
+            editor.delete_all_before_cursor()?;
+            editor.delete_all_after_cursor()?;
+            editor.insert_str_after_cursor(&command)?;
+        }
+        // The widget cleared the line, this redraws it.
+        editor.move_cursor_to_end_of_line()?;
+        Ok(false)
+    }
+}
+
+fn search_loop(initial: &str) -> io::Result<Option<String>> {
+    // Do not close stdin when done.
+    let mut input = ManuallyDrop::new(unsafe { File::from_file_desc(STDIN_FILENO) });
+    let mut out = io::stdout();
+    let mut query = initial.to_string();
+    let mut mode = SearchMode::All;
+    let mut selected = 0;
+    let mut matches = search(&query, &mode.filter());
+    draw_search(&mut out, &query, mode, &matches, selected)?;
+    let mut byte = [0_u8; 1];
+    let mut pending = Vec::new();
+    loop {
+        if input.read(&mut byte)? == 0 {
+            // Timeout, a lone escape is a cancel (not the start of a key sequence).
+            if pending == [0x1b] {
+                return Ok(None);
+            }
+            continue;
+        }
+        let mut changed = false;
+        if !pending.is_empty() || byte[0] == 0x1b {
+            pending.push(byte[0]);
+            match &pending[..] {
+                [0x1b] | [0x1b, b'['] | [0x1b, b'O'] => continue,
+                [0x1b, _, b'A'] => selected = selected.saturating_sub(1),
+                [0x1b, _, b'B'] => {
+                    selected = (selected + 1).min(matches.len().clamp(1, SEARCH_ROWS) - 1)
+                }
+                _ => {}
+            }
+            pending.clear();
+        } else {
+            match byte[0] {
+                b'\r' | b'\n' => return Ok(matches.get(selected).map(|e| e.command.clone())),
+                0x03 | 0x07 => return Ok(None),
+                0x10 => selected = selected.saturating_sub(1),
+                0x0e => selected = (selected + 1).min(matches.len().clamp(1, SEARCH_ROWS) - 1),
+                b'\t' | 0x12 => {
+                    mode = mode.next();
+                    changed = true;
+                }
+                0x7f | 0x08 => changed = query.pop().is_some(),
+                0x15 => {
+                    query.clear();
+                    changed = true;
+                }
+                b if b >= 0x20 => {
+                    // Collect the rest of a multi-byte char.
+                    let mut buf = vec![b];
+                    while std::str::from_utf8(&buf).is_err() && buf.len() < 4 {
+                        if input.read(&mut byte)? == 1 {
+                            buf.push(byte[0]);
+                        }
+                    }
+                    query.push_str(&String::from_utf8_lossy(&buf));
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        if changed {
+            matches = search(&query, &mode.filter());
+            selected = 0;
+        }
+        draw_search(&mut out, &query, mode, &matches, selected)?;
+    }
+}
+
+fn entry_to_map(vm: &mut SloshVm, entry: &HistoryEntry) -> Value {
+    let mut map = VMHashMap::with_capacity(6);
+    let fields = [
+        ("command", vm.alloc_string(entry.command.clone())),
+        ("timestamp", (entry.timestamp as i64).into()),
+        ("cwd", vm.alloc_string(entry.cwd.clone())),
+        ("status", entry.status.into()),
+        ("duration", (entry.duration_ms as i64).into()),
+        ("session", vm.alloc_string(entry.session.clone())),
+    ];
+    for (key, val) in fields {
+        let key = Value::Keyword(vm.intern_static(key));
+        map.insert(vm, key, val);
+    }
+    vm.alloc_map(map)
+}
+
+fn string_val(vm: &SloshVm, val: Value, fn_name: &str) -> VMResult<String> {
+    match val {
+        Value::String(h) => Ok(vm.get_string(h).to_string()),
+        Value::StringConst(i) => Ok(vm.get_interned(i).to_string()),
+        _ => Err(VMError::new_compile(format!(
+            "{fn_name}: expected a string, got {}",
+            val.display_type(vm)
+        ))),
+    }
+}
+
+fn history_query(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let mut filter = HistoryFilter::default();
+    let mut query = None;
+    let mut limit = None;
+    let mut args = registers.iter();
+    while let Some(key) = args.next() {
+        let (Value::Keyword(k), Some(val)) = (key, args.next()) else {
+            return Err(VMError::new_compile(
+                "history-query: expected keyword value pairs",
+            ));
+        };
+        match vm.get_interned(*k) {
+            "query" => query = Some(string_val(vm, *val, "history-query")?),
+            "dir" => {
+                filter.dir = Some(match val {
+                    Value::True => current_dir(),
+                    _ => string_val(vm, *val, "history-query")?,
+                })
+            }
+            "success" => filter.success = !val.is_falsey(),
+            "session" => {
+                filter.session = Some(match val {
+                    Value::True => session_id(),
+                    _ => string_val(vm, *val, "history-query")?,
+                })
+            }
+            "limit" => limit = Some(val.get_int(vm)?.max(0) as usize),
+            key => {
+                return Err(VMError::new_compile(format!(
+                    "history-query: invalid key :{key}"
+                )))
+            }
+        }
+    }
+    let mut found = match query {
+        Some(query) => search(&query, &filter),
+        None => entries(&filter),
+    };
+    if let Some(limit) = limit {
+        found.truncate(limit);
+    }
+    vm.pause_gc();
+    let vals = found.iter().map(|e| entry_to_map(vm, e)).collect();
+    let res = vm.alloc_vector(vals);
+    vm.unpause_gc();
+    Ok(res)
+}
+
+fn history_search(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let initial = match registers {
+        [] => String::new(),
+        [query] => string_val(vm, *query, "history-search")?,
+        _ => {
+            return Err(VMError::new_compile(
+                "history-search: takes an optional initial query",
+            ))
+        }
+    };
+    if !Sys::is_tty(STDIN_FILENO) {
+        return Err(VMError::new(
+            "io",
+            "history-search: stdin is not a terminal",
+        ));
+    }
+    match search_widget(&initial) {
+        Ok(Some(command)) => Ok(vm.alloc_string(command)),
+        Ok(None) => Ok(Value::Nil),
+        Err(err) => Err(VMError::new("io", format!("history-search: {err}"))),
+    }
+}
+
+pub(crate) fn add_history_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "history-query",
+        history_query,
+        r#"Usage: (history-query :query "text" :dir "/path" :success #t :session #t :limit 10)
+
+Return a vector of history entries, each a map with :command, :timestamp (seconds since the
+epoch when it started), :cwd, :status, :duration (milliseconds) and :session.  All keys are
+optional.  :query fuzzy matches the commands and returns each matching command once ranked by
+the match and frecency, otherwise entries are most recent first.  :dir limits to commands run in
+a directory (#t for the current one), :success to commands with a 0 exit status and :session to
+a session id (#t for this shell).  History shared by several shells is merged as it is read.
+
+Section: shell
+
+Example:
+(test::assert-true (vec? (history-query :limit 1)))
+"#,
+    );
+    add_builtin(
+        env,
+        "history-search",
+        history_search,
+        r#"Usage: (history-search) (history-search initial-query)
+
+Interactive fuzzy search of the history, returns the selected command or nil if cancelled.
+Typing filters the commands (ranked by frecency), up/down or ctrl-p/ctrl-n select, tab or
+ctrl-r cycles between all commands, commands run in the current directory and successful
+commands, enter accepts and esc or ctrl-c cancels.  Ctrl-r in the REPL runs this search and
+puts the selected command on the line being edited.
+
+Section: shell
+"#,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn entry(command: &str, timestamp: u64, cwd: &str, status: i32) -> HistoryEntry {
+        HistoryEntry {
+            command: command.to_string(),
+            timestamp,
+            cwd: cwd.to_string(),
+            status,
+            duration_ms: 5,
+            session: "s1".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_history_line() {
+        let e = entry("echo 'a\tb'\\\nls", 100, "/tmp/x y", 1);
+        let line = e.to_line();
+        assert_eq!(line.matches('\n').count(), 1);
+        assert_eq!(
+            HistoryEntry::from_line(line.trim_end_matches('\n')),
+            Some(e)
+        );
+        assert_eq!(HistoryEntry::from_line("not an entry"), None);
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert_eq!(fuzzy_score("gco", "ls -l"), None);
+        assert!(fuzzy_score("gco", "git checkout").is_some());
+        assert!(fuzzy_score("check", "git checkout") > fuzzy_score("check", "c-h-e-c-k"));
+        assert!(fuzzy_score("Git", "git status").is_none());
+        assert!(fuzzy_score("git", "GIT status").is_some());
+    }
+
+    #[test]
+    fn test_rank_and_merge() {
+        let now = now_secs();
+        let entries = vec![
+            entry("git status", now - 100 * 86400, "/a", 0),
+            entry("git stash", now - 10, "/b", 0),
+            entry("git status", now - 100, "/a", 0),
+            entry("git status", now - 50, "/a", 1),
+            entry("grep stuff", now - 5, "/b", 2),
+        ];
+        let ranked = rank(&entries, "gis", &HistoryFilter::default());
+        let commands: Vec<&str> = ranked.iter().map(|e| &e.command[..]).collect();
+        assert_eq!(commands, vec!["git status", "git stash"]);
+        assert_eq!(ranked[0].status, 1);
+        let filter = HistoryFilter {
+            dir: Some("/b".to_string()),
+            success: true,
+            session: None,
+        };
+        let ranked = rank(&entries, "", &filter);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].command, "git stash");
+
+        // Entries appended by another shell (and a partial line) are merged on refresh.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history-meta");
+        let mut store = HistoryStore {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        store.append(&entries[0]).unwrap();
+        store.refresh();
+        assert_eq!(store.entries.len(), 1);
+        let mut other = OpenOptions::new().append(true).open(&path).unwrap();
+        other.write_all(entries[1].to_line().as_bytes()).unwrap();
+        other.write_all(b"123\ts2\t0").unwrap();
+        store.refresh();
+        assert_eq!(store.entries.len(), 2);
+        other.write_all(b"\t1\t/c\tpwd\n").unwrap();
+        store.refresh();
+        assert_eq!(store.entries.len(), 3);
+        assert_eq!(store.entries[2].command, "pwd");
+    }
+}
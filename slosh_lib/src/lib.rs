@@ -8,6 +8,7 @@ use std::io::{BufRead, ErrorKind, Write};
 use std::ops::DerefMut;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use std::{env, fs};
 
 pub use sl_compiler::{compile, Reader};
@@ -20,34 +21,50 @@ use sl_compiler::reader::*;
 use bridge_adapters::add_builtin;
 use builtins::collections::setup_collection_builtins;
 use builtins::conversions::add_conv_builtins;
+use builtins::coroutine::add_coroutine_builtins;
+use builtins::coverage::{add_coverage_builtins, write_coverage};
 use builtins::fs_meta::add_fs_meta_builtins;
 use builtins::fs_temp::add_fs_temp_builtins;
+use builtins::gc::add_gc_builtins;
 use builtins::io::add_io_builtins;
+use builtins::iter::add_iter_builtins;
 use builtins::math::add_math_builtins;
 use builtins::print::{add_print_builtins, display_value};
+use builtins::profile::{add_profile_builtins, report_profile, DEFAULT_FOLDED_FILE};
 use builtins::rand::add_rand_builtins;
 use builtins::string::add_str_builtins;
 use builtins::{add_global_value, add_misc_builtins};
-use sl_liner::vi::AlphanumericAndVariableKeywordRule;
-use sl_liner::{keymap, ColorClosure, Context, Prompt};
+use sl_liner::{ColorClosure, Context, Prompt};
 
+mod completion_specs;
 mod completions;
 pub mod debug;
+mod highlight;
+mod history;
 mod liner_rules;
+mod prompt;
+mod repl_settings;
 
 pub use sl_compiler::load_eval::load_one_expression;
 pub use sl_compiler::load_eval::run_reader;
 mod shell_builtins;
+mod worker;
 
+use crate::completion_specs::add_completion_builtins;
 use crate::completions::ShellCompleter;
+use crate::highlight::add_highlight_builtins;
+use crate::history::add_history_builtins;
 use crate::liner_rules::make_editor_rules;
-use crate::shell_builtins::add_shell_builtins;
+use crate::prompt::{add_prompt_builtins, next_prompt, transient_prompt};
+use crate::repl_settings::{add_repl_settings, update_repl_settings};
+use crate::shell_builtins::{add_shell_builtins, run_exit_trap, run_traps};
+use crate::worker::add_worker_builtins;
 use debug::*;
 use shell::config::get_config;
 use shell::platform::{Platform, Sys, STDIN_FILENO};
 use sl_compiler::load_eval::{add_load_builtins, load_internal, SLSHRC};
 use sl_compiler::pass1::pass1;
-use slvm::{VMError, VMResult, Value, INT_BITS, INT_MAX, INT_MIN};
+use slvm::{Chunk, Coverage, Profiler, VMError, VMResult, Value, INT_BITS, INT_MAX, INT_MIN};
 
 thread_local! {
     /// Env (job control status, etc) for the shell.
@@ -61,46 +78,6 @@ thread_local! {
 
 const PROMPT_FN: &str = "prompt";
 
-fn get_prompt(env: &mut SloshVm) -> String {
-    let i_val = env.intern("__prompt");
-    if let Some(idx) = env.global_intern_slot(i_val) {
-        match env.get_global(idx) {
-            Value::Lambda(h) => {
-                let l = env.get_lambda(h);
-                match env.do_call(l, &[], None) {
-                    Ok(v) => match v {
-                        Value::StringConst(i) => env.get_interned(i).to_string(),
-                        Value::String(h) => env.get_string(h).to_string(),
-                        _ => v.display_value(env),
-                    },
-                    Err(e) => {
-                        eprintln!("Error getting prompt: {e}");
-                        "slosh> ".to_string()
-                    }
-                }
-            }
-            Value::Closure(h) => {
-                let (l, tcaps) = env.get_closure(h);
-                let caps = Vec::from(tcaps);
-                match env.do_call(l, &[], Some(&caps[..])) {
-                    Ok(v) => match v {
-                        Value::StringConst(i) => env.get_interned(i).to_string(),
-                        Value::String(h) => env.get_string(h).to_string(),
-                        _ => v.display_value(env),
-                    },
-                    Err(e) => {
-                        eprintln!("Error getting prompt: {e}");
-                        "slosh> ".to_string()
-                    }
-                }
-            }
-            _ => env.get_global(idx).display_value(env),
-        }
-    } else {
-        "slosh> ".to_string()
-    }
-}
-
 /// Given a [`SloshVm`] and a String, usually the rc file for slosh, set
 /// the *load-path* global variable to facilitate proper loading of scripts.
 ///
@@ -288,14 +265,21 @@ fn get_color_closure() -> Option<ColorClosure> {
                         res
                     })
                 })),
-                _ => None,
+                _ => Some(native_color_closure()),
             }
         } else {
-            None
+            Some(native_color_closure())
         }
     })
 }
 
+/// Color closure for the built in highlighter, used unless __line_handler is a function.
+fn native_color_closure() -> ColorClosure {
+    Box::new(|input: &str| -> String {
+        ENV.with(|renv| highlight::highlight(&mut renv.borrow_mut(), input))
+    })
+}
+
 fn get_usage(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     if registers.len() > 1 {
         Err(VMError::new_compile(
@@ -343,34 +327,51 @@ fn get_usage(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     }
 }
 
+/// Build the signature, (name args*), for a lambda's chunk from it's debug args.
+fn signature(vm: &SloshVm, name: &str, l: &Chunk) -> Option<String> {
+    let args = l.dbg_args.as_ref()?;
+    let total = (l.args + l.opt_args) as usize;
+    let mut sig = format!("({name}");
+    for (i, a) in args.iter().take(total).enumerate() {
+        if l.rest && i == total - 1 {
+            if let Some(keys) = &l.key_args {
+                sig.push_str(" &key");
+                for k in keys {
+                    sig.push(' ');
+                    sig.push_str(vm.get_interned(*k));
+                }
+                continue;
+            }
+            sig.push_str(" &");
+        } else if l.opt_args > 0 && i == l.args as usize {
+            sig.push_str(" %");
+        }
+        sig.push(' ');
+        sig.push_str(vm.get_interned(*a));
+    }
+    sig.push(')');
+    Some(sig)
+}
+
 pub fn usage(vm: &mut SloshVm, slot: u32, sym: &Value) -> String {
     let name = sym.display_value(vm);
-    let mut doc_str = String::new();
-    let sym = vm.get_global(slot);
-    let args = match sym {
-        Value::Lambda(h) => {
-            let l = vm.get_lambda(h);
-            l.dbg_args.clone()
-        }
-        Value::Closure(h) => {
-            let (l, _h) = vm.get_closure(h);
-            l.dbg_args.clone()
-        }
+    let l = match vm.get_global(slot) {
+        Value::Lambda(h) => vm.get_lambda(h),
+        Value::Closure(h) => vm.get_closure(h).0,
         _ => {
-            return doc_str;
+            return String::new();
         }
     };
-    if let Some(args) = args {
-        doc_str.push('(');
-        doc_str.push_str(&name);
-        for a in args {
-            let arg = vm.get_interned(a);
-            doc_str.push(' ');
-            doc_str.push_str(arg);
-        }
-        doc_str.push(')');
+    if let Some(arities) = &l.arities {
+        // Show the signature of each arity.
+        let sigs: Vec<String> = arities
+            .chunks()
+            .filter_map(|arity| signature(vm, &name, arity))
+            .collect();
+        sigs.join(" ")
+    } else {
+        signature(vm, &name, &l).unwrap_or_default()
     }
-    doc_str
 }
 
 pub fn set_builtins(env: &mut SloshVm) {
@@ -387,6 +388,12 @@ pub fn set_builtins(env: &mut SloshVm) {
     add_math_builtins(env);
     add_doc_builtins(env);
     add_math_builtins(env);
+    add_profile_builtins(env);
+    add_coverage_builtins(env);
+    add_gc_builtins(env);
+    add_coroutine_builtins(env);
+    add_iter_builtins(env);
+    add_worker_builtins(env);
 
     env.set_named_global("*int-bits*", (INT_BITS as i64).into());
     env.set_named_global("*int-max*", INT_MAX.into());
@@ -516,6 +523,11 @@ Section: shell"#
 pub fn set_builtins_shell(env: &mut SloshVm) {
     set_builtins(env);
     add_shell_builtins(env);
+    add_highlight_builtins(env);
+    add_completion_builtins(env);
+    add_history_builtins(env);
+    add_repl_settings(env);
+    add_prompt_builtins(env);
     env.set_global_builtin("dump-regs", builtin_dump_regs);
 
     let uid = Sys::current_uid();
@@ -525,6 +537,19 @@ pub fn set_builtins_shell(env: &mut SloshVm) {
     env.set_named_global("*uid*", uid.into());
     env.set_named_global("*euid*", euid.into());
     env.set_named_global("*last-status*", 0.into());
+    let pipe_status = env.alloc_vector(vec![0.into()]);
+    add_global_value(
+        env,
+        "*pipe-status*",
+        pipe_status,
+        "Usage: *pipe-status*
+
+A vector of the exit status of each command in the last foreground shell job (in pipeline
+order).  This is also available to shell commands as $PIPESTATUS.
+
+Section: shell
+",
+    );
     // Initialize the HOST variable
     let host: OsString = Sys::gethostname().unwrap_or_else(|| "Operating system hostname is not a string capable of being parsed by native platform???".into());
     env::set_var("HOST", host);
@@ -543,6 +568,10 @@ fn run_slosh(modify_vm: fn(&mut SloshVm) -> ()) -> i32 {
     if let Some(config) = get_config() {
         ENV.with(|renv| {
             let mut env = renv.borrow_mut();
+            if config.coverage.is_some() {
+                // Start early so library code loaded during setup is included.
+                env.start_coverage(Coverage::new());
+            }
             env.pause_gc();
             set_builtins_shell(&mut env);
             modify_vm(&mut env);
@@ -551,6 +580,7 @@ fn run_slosh(modify_vm: fn(&mut SloshVm) -> ()) -> i32 {
         if config.command.is_none() && config.script.is_none() {
             load_core_slosh();
             load_sloshrc_inner();
+            start_profile(config.profile);
             if Sys::is_tty(STDIN_FILENO) {
                 status = run_shell_tty();
             } else {
@@ -565,6 +595,7 @@ fn run_slosh(modify_vm: fn(&mut SloshVm) -> ()) -> i32 {
                 shell::run::setup_shell_tty(STDIN_FILENO);
             }
             let tcommand = command.trim_start();
+            start_profile(config.profile);
             status = if tcommand.starts_with('(') || tcommand.starts_with("$(") {
                 ENV.with(|env| {
                     exec_expression(command, &mut env.borrow_mut());
@@ -589,10 +620,13 @@ fn run_slosh(modify_vm: fn(&mut SloshVm) -> ()) -> i32 {
             if Sys::is_tty(STDIN_FILENO) {
                 shell::run::setup_shell_tty(STDIN_FILENO);
             }
+            start_profile(config.profile);
             status = ENV.with(|renv| {
                 let mut env = renv.borrow_mut();
                 let script = env.intern(&script);
                 let script = env.get_interned(script);
+                // Line numbers restart for the script (like load).
+                env.set_line_num(1);
                 match load_internal(&mut env, script) {
                     Ok(_) => 0,
                     Err(err) => {
@@ -602,44 +636,72 @@ fn run_slosh(modify_vm: fn(&mut SloshVm) -> ()) -> i32 {
                 }
             });
         }
+        ENV.with(|env| run_exit_trap(&mut env.borrow_mut()));
+        finish_profile();
+        if let Some(lcov_file) = &config.coverage {
+            finish_coverage(lcov_file);
+        }
     }
     status
 }
 
+/// Turn on the profiler for the main VM if requested (--profile).
+fn start_profile(profile: bool) {
+    if profile {
+        ENV.with(|env| {
+            env.borrow_mut().start_profiling(Profiler::new());
+        });
+    }
+}
+
+/// If the main VM is profiling then stop and report.
+fn finish_profile() {
+    ENV.with(|env| {
+        if let Some(profiler) = env.borrow_mut().stop_profiling() {
+            if let Err(err) = report_profile(&profiler, DEFAULT_FOLDED_FILE) {
+                eprintln!("ERROR: {err}");
+            }
+        }
+    });
+}
+
+/// Stop recording coverage for the main VM and merge it into lcov_file.
+fn finish_coverage(lcov_file: &str) {
+    ENV.with(|env| {
+        if let Some(coverage) = env.borrow_mut().stop_coverage() {
+            if let Err(err) = write_coverage(&coverage.line_coverage(), lcov_file, true) {
+                eprintln!("ERROR: {err}");
+            }
+        }
+    });
+}
+
 fn run_shell_tty() -> i32 {
     let mut con = Context::new();
     //con.set_completer(Box::new(FilenameCompleter::new(Some("."))));
     con.set_completer(Box::new(ShellCompleter::new()));
     con.set_editor_rules(make_editor_rules());
-    let mut vi = keymap::Vi::new();
-    let vi_keywords = vec!["_", "-"];
-    vi.set_keyword_rule(Box::new(AlphanumericAndVariableKeywordRule::new(
-        vi_keywords,
-    )));
-    /*if let Some((ch1, ch2, timeout)) = repl_settings.vi_esc_sequence {
-        vi.set_esc_sequence(ch1, ch2, timeout);
-    }
-    vi.set_normal_prompt_prefix(repl_settings.vi_normal_prompt_prefix.clone());
-    vi.set_normal_prompt_suffix(repl_settings.vi_normal_prompt_suffix.clone());
-    vi.set_insert_prompt_prefix(repl_settings.vi_insert_prompt_prefix.clone());
-    vi.set_insert_prompt_suffix(repl_settings.vi_insert_prompt_suffix.clone());*/
-    //Box::new(keymap::Emacs::new())
-    con.set_keymap(Box::new(vi));
-
-    if let Err(e) = con.history.set_file_name_and_load_history(history_file()) {
-        eprintln!("Error loading history: {e}");
-    }
+    // Keymap, history file, etc are set from *repl-settings* before each line.
+    let mut repl_settings = None;
     shell::run::setup_shell_tty(STDIN_FILENO);
     SHELL_ENV.with(|jobs| {
         jobs.borrow_mut().cap_term();
     });
-    let mut status = 0;
+    let mut status;
     loop {
         SHELL_ENV.with(|jobs| {
             jobs.borrow_mut().reap_procs();
         });
-        let prompt = ENV.with(|env| get_prompt(&mut env.borrow_mut()));
-        let res = match con.read_line(Prompt::from(prompt), get_color_closure()) {
+        run_pending_traps();
+        ENV.with(|env| update_repl_settings(&env.borrow(), &mut con, &mut repl_settings));
+        let prompt = next_prompt();
+        // Line editor history search (ctrl-s) prefers commands run in this directory.
+        con.history.set_search_context(
+            env::current_dir()
+                .ok()
+                .map(|d| d.to_string_lossy().to_string()),
+        );
+        let res = match con.read_line(Prompt::from(prompt.clone()), get_color_closure()) {
             Ok(input) => input,
             Err(err) => match err.kind() {
                 ErrorKind::UnexpectedEof => {
@@ -663,6 +725,7 @@ fn run_shell_tty() -> i32 {
         if res.is_empty() {
             continue;
         }
+        transient_prompt(&prompt, &res);
 
         let res = if res.contains("\\\n") {
             res.replace("\\\n", "")
@@ -670,36 +733,55 @@ fn run_shell_tty() -> i32 {
             res
         };
         con.history.push(&res).expect("Failed to push history.");
-        status = exec_expr_or_run_command(&res, status);
+        let start_time = SystemTime::now();
+        let start = Instant::now();
+        status = exec_expr_or_run_command(&res);
+        history::record(&res, status, start_time, start.elapsed());
     }
     status
 }
 
-fn exec_expr_or_run_command(res: &String, mut status: i32) -> i32 {
+fn exec_expr_or_run_command(res: &String) -> i32 {
     if res.starts_with('(') || res.starts_with("$(") {
-        ENV.with(|env| {
-            exec_expression(res.clone(), &mut env.borrow_mut());
-        });
+        ENV.with(|env| exec_expression(res.clone(), &mut env.borrow_mut()))
     } else {
-        status = run_command(res);
+        run_command(res)
     }
-    status
 }
 
 fn run_command(res: &String) -> i32 {
-    let status = SHELL_ENV.with(|jobs| {
-        shell::run::run_one_command(res, &mut jobs.borrow_mut()).unwrap_or_else(|err| {
+    let (status, pipe_status) = SHELL_ENV.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let status = shell::run::run_one_command(res, &mut jobs).unwrap_or_else(|err| {
             eprintln!("ERROR executing {res}: {err}");
             1
-        })
+        });
+        (status, jobs.pipe_status().to_vec())
     });
     ENV.with(|env| {
-        env.borrow_mut()
-            .set_named_global("*last-status*", status.into());
+        set_status_globals(&mut env.borrow_mut(), status, &pipe_status);
     });
     status
 }
 
+/// Run the traps (shell commands or lisp handlers) for any signals received.
+fn run_pending_traps() {
+    ENV.with(|env| {
+        let mut env = env.borrow_mut();
+        if let Err(err) = run_traps(&mut env) {
+            eprintln!("ERROR in trap: {}", err.display(&env));
+        }
+    });
+}
+
+/// Set *last-status* and *pipe-status* for the last shell job run.
+pub(crate) fn set_status_globals(env: &mut SloshVm, status: i32, pipe_status: &[i32]) {
+    env.set_named_global("*last-status*", status.into());
+    let pipe_status = pipe_status.iter().map(|s| (*s).into()).collect();
+    let pipe_status = env.alloc_vector(pipe_status);
+    env.set_named_global("*pipe-status*", pipe_status);
+}
+
 fn run_shell_with_stdin() -> i32 {
     // No tty so just grab lines from stdin and try to use them....
     let mut res = String::new();
@@ -709,14 +791,18 @@ fn run_shell_with_stdin() -> i32 {
         SHELL_ENV.with(|jobs| {
             jobs.borrow_mut().reap_procs();
         });
+        run_pending_traps();
         if bytes == 0 {
             break;
         }
         if res.is_empty() {
             continue;
         }
-        status = exec_expr_or_run_command(&res, status);
+        status = exec_expr_or_run_command(&res);
         res.clear();
+        if SHELL_ENV.with(|jobs| jobs.borrow().errexit_failed()) {
+            break;
+        }
     }
     SHELL_ENV.with(|jobs| {
         jobs.borrow_mut().reap_procs();
@@ -729,8 +815,10 @@ fn read_expression_to_list(res: String, env: &mut SloshVm) -> Result<Vec<Value>,
     reader.collect()
 }
 
-fn exec_expression(res: String, env: &mut SloshVm) {
+/// Run the lisp in res, returns 0 if it all ran or 1 on an error.
+fn exec_expression(res: String, env: &mut SloshVm) -> i32 {
     let exps = read_expression_to_list(res, env);
+    let mut status = 0;
     match exps {
         Ok(exps) => {
             for exp in exps {
@@ -738,14 +826,15 @@ fn exec_expression(res: String, env: &mut SloshVm) {
                 let mut state = CompileState::new_state(PROMPT_FN, line_num, None);
                 if let Err(e) = pass1(env, &mut state, exp) {
                     eprintln!("Compile error (pass1), line {}: {}", env.line_num(), e);
-                    return;
+                    return 1;
                 }
                 if let Err(e) = compile(env, &mut state, exp, 0) {
                     if e.key == "compile" || e.key == "read" {
                         eprintln!("Compile error, line {}: {}", env.line_num(), e);
-                        return;
+                        return 1;
                     } else {
                         eprintln!("Comp Time ERROR: {}", e.display(env));
+                        status = 1;
                         if let Some(err_frame) = env.err_frame() {
                             let line = err_frame.current_line().unwrap_or(0);
                             eprintln!(
@@ -764,7 +853,7 @@ fn exec_expression(res: String, env: &mut SloshVm) {
                         env.line_num(),
                         e
                     );
-                    return;
+                    return 1;
                 }
                 let chunk = Arc::new(state.chunk.clone());
                 match env.execute(chunk.clone()) {
@@ -775,6 +864,7 @@ fn exec_expression(res: String, env: &mut SloshVm) {
                     }
                     Err(err) => {
                         eprintln!("ERROR: {}", err.display(env));
+                        status = 1;
                         if let Some(err_frame) = env.err_frame() {
                             let line = err_frame.current_line().unwrap_or(0);
                             eprintln!(
@@ -789,8 +879,12 @@ fn exec_expression(res: String, env: &mut SloshVm) {
                 }
             }
         }
-        Err(err) => println!("Reader error: {err}"),
+        Err(err) => {
+            println!("Reader error: {err}");
+            status = 1;
+        }
     }
+    status
 }
 
 #[cfg(test)]
@@ -855,4 +949,31 @@ mod tests {
         });
         assert_eq!(v, 2i64);
     }
+
+    #[test]
+    fn test_load_core_nested() {
+        let tmp_dir = TempDir::with_prefix("test_load_core").unwrap();
+        let home_path = tmp_dir.path().to_str().unwrap().to_string();
+        // A (load ...) compiles all of core before running it, so the macros core uses while
+        // loading (cond for instance) can not depend on functions core defines.
+        let v = ENV.with(|env| {
+            let mut vm = env.borrow_mut();
+            set_builtins_shell(vm.deref_mut());
+            set_initial_load_path(vm.deref_mut(), vec![&home_path]);
+            _ = exec(vm.deref_mut(), "(load \"core.slosh\")");
+            let v = exec(
+                vm.deref_mut(),
+                "(handler-bind ((:negative (fn (c) (invoke-restart 'use-value 3))))
+                    (restart-case (cond ((find-restart 'missing) 1) (#t (err :negative -1)))
+                        (use-value (v) v)))",
+            );
+            match v {
+                Value::Int(i) => from_i56(&i),
+                _ => {
+                    panic!("Value should be an integer");
+                }
+            }
+        });
+        assert_eq!(v, 3i64);
+    }
 }
@@ -74,7 +74,11 @@ fn check_balanced_delimiters_shell(input: &str) -> bool {
         }
     }
 
-    parens <= 0 && braces <= 0 && !single_quote && !double_quote
+    parens <= 0
+        && braces <= 0
+        && !single_quote
+        && !double_quote
+        && !shell::parse::is_unterminated_heredoc(input)
 }
 
 /// If first non-whitespace char is '(' assume this is an sexp.
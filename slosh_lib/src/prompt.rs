@@ -0,0 +1,352 @@
+//! The REPL prompt: __prompt, a right side __rprompt, a __transient_prompt that replaces the
+//! prompt once a line is entered and async segments (prompt-async) that run shell commands in the
+//! background.
+//!
+//! The line editor can not be redrawn while it is reading a line, so while async segments are
+//! running the prompt is drawn here and redrawn as they finish.  The editor takes over once a
+//! key is pressed (or they are all done), segments that finish after that are shown at the next
+//! prompt.
+
+use crate::ENV;
+use bridge_adapters::add_builtin;
+use compile_state::state::{SloshVm, SloshVmTrait};
+use shell::platform::{Platform, Sys, STDIN_FILENO, STDOUT_FILENO};
+use slvm::{VMError, VMResult, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_PROMPT: &str = "slosh> ";
+
+/// An async prompt segment (the output of a shell command).
+struct Segment {
+    /// Directory the command was last run in.
+    cwd: PathBuf,
+    /// Output of the last run, None until it has finished once in cwd.
+    value: Option<String>,
+    /// Receives the output of the command while it is running.
+    running: Option<Receiver<String>>,
+    /// The prompt the command was last started for.
+    started_for: Option<u64>,
+}
+
+thread_local! {
+    // Async segments by command.
+    static SEGMENTS: RefCell<HashMap<String, Segment>> = RefCell::new(HashMap::new());
+    // Incremented for each new prompt so segments are rerun once per prompt (not per redraw).
+    static PROMPT_COUNT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Call (or display) the global name, None if it is not defined.
+fn prompt_value(env: &mut SloshVm, name: &str) -> Option<String> {
+    let i_val = env.intern(name);
+    let idx = env.global_intern_slot(i_val)?;
+    let res = match env.get_global(idx) {
+        Value::Lambda(h) => {
+            let l = env.get_lambda(h);
+            env.do_call(l, &[], None)
+        }
+        Value::Closure(h) => {
+            let (l, tcaps) = env.get_closure(h);
+            let caps = Vec::from(tcaps);
+            env.do_call(l, &[], Some(&caps[..]))
+        }
+        v => Ok(v),
+    };
+    match res {
+        Ok(Value::StringConst(i)) => Some(env.get_interned(i).to_string()),
+        Ok(Value::String(h)) => Some(env.get_string(h).to_string()),
+        Ok(v) => Some(v.display_value(env)),
+        Err(e) => {
+            eprintln!("Error getting {name}: {e}");
+            None
+        }
+    }
+}
+
+/// The prompt from __prompt (or the default prompt).
+pub(crate) fn get_prompt(env: &mut SloshVm) -> String {
+    prompt_value(env, "__prompt").unwrap_or_else(|| DEFAULT_PROMPT.to_string())
+}
+
+/// Width of text on the terminal (ignoring escape sequences).
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                // Parameters then a final byte in @..~.
+                for ch in chars.by_ref() {
+                    if ('@'..='~').contains(&ch) {
+                        break;
+                    }
+                }
+            } else {
+                chars.next();
+            }
+        } else if !ch.is_control() {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// Number of terminal rows text takes up with cols columns.
+fn rows(text: &str, cols: usize) -> usize {
+    text.split('\n')
+        .map(|line| visible_width(line).max(1).div_ceil(cols.max(1)))
+        .sum()
+}
+
+fn columns() -> usize {
+    Sys::terminal_columns(STDOUT_FILENO).unwrap_or(80)
+}
+
+/// The prompt to give the line editor (with the __rprompt if there is one).
+///
+/// With a multi line prompt the right prompt goes at the end of the line above the input line,
+/// otherwise it is returned to be drawn on the input line before the editor starts.
+fn build_prompt(env: &mut SloshVm) -> (String, Option<String>) {
+    let mut prompt = get_prompt(env);
+    let Some(rprompt) = prompt_value(env, "__rprompt").filter(|r| !r.is_empty()) else {
+        return (prompt, None);
+    };
+    let cols = columns();
+    let rwidth = visible_width(&rprompt);
+    if let Some(last_nl) = prompt.rfind('\n') {
+        let line_start = prompt[..last_nl].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let width = visible_width(&prompt[line_start..last_nl]);
+        if width + rwidth < cols {
+            let pad = " ".repeat(cols - width - rwidth);
+            prompt.insert_str(last_nl, &format!("{pad}{rprompt}"));
+        }
+        (prompt, None)
+    } else if visible_width(&prompt) + rwidth < cols {
+        (prompt, Some(rprompt))
+    } else {
+        (prompt, None)
+    }
+}
+
+/// Write rprompt right aligned on the current line, leaving the cursor at the start of the line.
+fn draw_rprompt(out: &mut impl Write, rprompt: &str) -> io::Result<()> {
+    let col = columns() - visible_width(rprompt) + 1;
+    write!(out, "\r\x1b[{col}G{rprompt}\r")?;
+    out.flush()
+}
+
+/// Write prompt (and rprompt) with the cursor left at the end of prompt.
+fn draw_prompt(out: &mut impl Write, prompt: &str, rprompt: &Option<String>) -> io::Result<()> {
+    if let Some(rprompt) = rprompt {
+        draw_rprompt(out, rprompt)?;
+    }
+    write!(out, "{}", prompt.replace('\n', "\r\n"))?;
+    out.flush()
+}
+
+/// Erase text drawn (starting at column 0) with the cursor on its last row.
+fn erase(out: &mut impl Write, text: &str) -> io::Result<()> {
+    let up = rows(text, columns()) - 1;
+    if up > 0 {
+        write!(out, "\x1b[{up}F")?;
+    }
+    write!(out, "\r\x1b[J")?;
+    out.flush()
+}
+
+/// Update async segments that have finished, true if any did.
+fn collect_finished() -> bool {
+    SEGMENTS.with(|segments| {
+        let mut finished = false;
+        for segment in segments.borrow_mut().values_mut() {
+            if let Some(running) = &segment.running {
+                match running.try_recv() {
+                    Ok(value) => {
+                        segment.value = Some(value);
+                        segment.running = None;
+                        finished = true;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => segment.running = None,
+                }
+            }
+        }
+        finished
+    })
+}
+
+fn segments_running() -> bool {
+    SEGMENTS.with(|segments| segments.borrow().values().any(|s| s.running.is_some()))
+}
+
+/// Draw the prompt while async segments run, redrawing it as they finish until there is input.
+fn wait_for_segments(prompt: &mut String, rprompt: &mut Option<String>) -> io::Result<()> {
+    let mut out = io::stdout();
+    let old_settings = Sys::set_raw_mode(STDIN_FILENO)?;
+    let res = (|| loop {
+        draw_prompt(&mut out, prompt, rprompt)?;
+        let mut finished = false;
+        while !finished && segments_running() {
+            if Sys::wait_for_input(STDIN_FILENO, Duration::from_millis(50))? {
+                break;
+            }
+            finished = collect_finished();
+        }
+        erase(&mut out, prompt)?;
+        if !finished {
+            return Ok(());
+        }
+        (*prompt, *rprompt) = ENV.with(|env| build_prompt(&mut env.borrow_mut()));
+    })();
+    Sys::set_term_settings(STDIN_FILENO, &old_settings)?;
+    res
+}
+
+/// Get the prompt for the next line, this waits on (and redraws for) async segments.
+pub(crate) fn next_prompt() -> String {
+    PROMPT_COUNT.with(|count| count.set(count.get() + 1));
+    let (mut prompt, mut rprompt) = ENV.with(|env| build_prompt(&mut env.borrow_mut()));
+    if segments_running() {
+        if let Err(err) = wait_for_segments(&mut prompt, &mut rprompt) {
+            eprintln!("Error drawing prompt: {err}");
+        }
+    }
+    if let Some(rprompt) = rprompt {
+        // The editor draws the prompt after this without clearing the line.
+        let _ = draw_rprompt(&mut io::stdout(), &rprompt);
+    }
+    prompt
+}
+
+/// If __transient_prompt is set replace prompt and the entered line with it and the line.
+/// Call right after the line is read (with the cursor on the line after it).
+pub(crate) fn transient_prompt(prompt: &str, line: &str) {
+    let Some(transient) = ENV.with(|env| prompt_value(&mut env.borrow_mut(), "__transient_prompt"))
+    else {
+        return;
+    };
+    let mut out = io::stdout();
+    let up = rows(&format!("{prompt}{line}"), columns());
+    let _ = write!(
+        out,
+        "\x1b[{up}F\x1b[J{transient}{}\r\n",
+        line.replace('\n', "\r\n")
+    );
+    let _ = out.flush();
+}
+
+/// Run command with sh in the background, the output (trimmed) is sent when done.
+fn start_segment(command: &str, cwd: &Path) -> Receiver<String> {
+    let (tx, rx) = channel();
+    let command = command.to_string();
+    let cwd = cwd.to_path_buf();
+    thread::spawn(move || {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .current_dir(cwd)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        let value = match output {
+            Ok(output) => String::from_utf8_lossy(&output.stdout)
+                .trim_end()
+                .to_string(),
+            Err(_) => String::new(),
+        };
+        let _ = tx.send(value);
+    });
+    rx
+}
+
+/// The current value of the async segment for command (starting it if needed).
+fn segment_value(command: &str, placeholder: &str) -> String {
+    let prompt_count = PROMPT_COUNT.with(|count| count.get());
+    let cwd = env::current_dir().unwrap_or_default();
+    collect_finished();
+    SEGMENTS.with(|segments| {
+        let mut segments = segments.borrow_mut();
+        let segment = segments
+            .entry(command.to_string())
+            .or_insert_with(|| Segment {
+                cwd: cwd.clone(),
+                value: None,
+                running: None,
+                started_for: None,
+            });
+        if segment.cwd != cwd {
+            // The output is for another directory, a new run is needed.
+            segment.cwd = cwd.clone();
+            segment.value = None;
+            segment.running = None;
+            segment.started_for = None;
+        }
+        if segment.running.is_none() && segment.started_for != Some(prompt_count) {
+            segment.running = Some(start_segment(command, &cwd));
+            segment.started_for = Some(prompt_count);
+        }
+        segment
+            .value
+            .clone()
+            .unwrap_or_else(|| placeholder.to_string())
+    })
+}
+
+fn prompt_async(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (command, placeholder) = match registers {
+        [command] => (command.get_string(vm)?.to_string(), String::new()),
+        [command, placeholder] => (
+            command.get_string(vm)?.to_string(),
+            placeholder.get_string(vm)?.to_string(),
+        ),
+        _ => {
+            return Err(VMError::new_compile(
+                "prompt-async: takes a command and optional placeholder",
+            ))
+        }
+    };
+    let value = segment_value(&command, &placeholder);
+    Ok(vm.alloc_string(value))
+}
+
+pub(crate) fn add_prompt_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "prompt-async",
+        prompt_async,
+        r#"Usage: (prompt-async command) (prompt-async command placeholder)
+
+For use in __prompt or __rprompt, run the shell command (with sh) in the background and return
+its output (trimmed) or placeholder (default "") until it finishes.  The prompt is redrawn when
+it finishes unless a key has already been pressed.  The command runs again for each prompt and
+the last output is shown while it runs, unless the directory changed.
+
+Section: shell
+
+Example:
+(test::assert-equal "..." (prompt-async "sleep 1; echo done" "..."))
+"#,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_width() {
+        assert_eq!(visible_width("\x1b[32m[ns]:\x1b[34m~/\x1b[37m"), 7);
+        assert_eq!(visible_width("λ > "), 4);
+        assert_eq!(rows("abc", 80), 1);
+        assert_eq!(rows("\x1b[31mabc\ndef", 2), 4);
+        assert_eq!(rows("\n", 80), 2);
+    }
+}
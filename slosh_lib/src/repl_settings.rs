@@ -0,0 +1,560 @@
+//! Line editor settings for the REPL from the *repl-settings* map.
+//!
+//! The map is read before each line is read so changes (from init.slosh or the REPL) take effect
+//! at the next prompt.
+
+use crate::{history, ENV};
+use builtins::add_global_value;
+use compile_state::state::{SloshVm, SloshVmTrait};
+use sl_console::event::Key;
+use sl_liner::keymap::KeyMap;
+use sl_liner::vi::AlphanumericAndVariableKeywordRule;
+use sl_liner::{keymap, Completer, Context, Editor};
+use slvm::vm_hashmap::VMHashMap;
+use slvm::Value;
+use std::cell::RefCell;
+use std::env;
+use std::io;
+
+/// Name of the global map with the REPL settings.
+const SETTINGS_NAME: &str = "*repl-settings*";
+
+const DEFAULT_MAX_HISTORY: usize = 1000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Keys {
+    Vi,
+    Emacs,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ReplSettings {
+    pub key_bindings: Keys,
+    pub max_history: usize,
+    pub history_file: String,
+    pub vi_esc_sequence: Option<(char, char, u32)>,
+    pub vi_normal_prompt_prefix: Option<String>,
+    pub vi_normal_prompt_suffix: Option<String>,
+    pub vi_insert_prompt_prefix: Option<String>,
+    pub vi_insert_prompt_suffix: Option<String>,
+    pub bindings: Vec<KeyBinding>,
+}
+
+/// A key sequence from :bindings and the lambda it calls.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct KeyBinding {
+    spec: String,
+    keys: Vec<Key>,
+    lambda: Value,
+}
+
+impl Default for ReplSettings {
+    fn default() -> Self {
+        ReplSettings {
+            key_bindings: Keys::Vi,
+            max_history: DEFAULT_MAX_HISTORY,
+            history_file: crate::history_file(),
+            vi_esc_sequence: None,
+            vi_normal_prompt_prefix: None,
+            vi_normal_prompt_suffix: None,
+            vi_insert_prompt_prefix: None,
+            vi_insert_prompt_suffix: None,
+            bindings: Vec::new(),
+        }
+    }
+}
+
+thread_local! {
+    // Errors from the last load, so a bad setting is only reported once.
+    static LAST_ERRORS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn optional_string(vm: &SloshVm, key: &str, val: Value) -> Result<Option<String>, String> {
+    if val.is_nil() {
+        Ok(None)
+    } else {
+        val.get_string(vm)
+            .map(|s| Some(s.to_string()))
+            .map_err(|_| format!(":{key} must be a string or nil"))
+    }
+}
+
+fn vi_esc_sequence(vm: &SloshVm, val: Value) -> Result<Option<(char, char, u32)>, String> {
+    if val.is_nil() {
+        return Ok(None);
+    }
+    let err =
+        || ":vi-esc-sequence must be a vector of a two char string and a timeout in ms".to_string();
+    let parts: Vec<Value> = val.iter(vm).collect();
+    let [keys, ms] = parts[..] else {
+        return Err(err());
+    };
+    let keys = keys.get_string(vm).map_err(|_| err())?;
+    let ms = ms.get_int(vm).map_err(|_| err())?;
+    let mut chars = keys.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(ch1), Some(ch2), None) if ms >= 0 => Ok(Some((ch1, ch2, ms as u32))),
+        _ => Err(err()),
+    }
+}
+
+/// Parse one key, ctrl-x, alt-x, f1-f12, a named key or a single char.
+fn parse_key(key: &str) -> Option<Key> {
+    let single = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => Some(ch),
+            _ => None,
+        }
+    };
+    if let Some(ch) = key.strip_prefix("ctrl-").and_then(single) {
+        return Some(Key::Ctrl(ch));
+    }
+    if let Some(ch) = key.strip_prefix("alt-").and_then(single) {
+        return Some(Key::Alt(ch));
+    }
+    if let Some(n) = key.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return (1..=12).contains(&n).then_some(Key::F(n));
+    }
+    Some(match key {
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "delete" => Key::Delete,
+        "backspace" => Key::Backspace,
+        "esc" => Key::Esc,
+        "tab" => Key::Char('\t'),
+        _ => Key::Char(single(key)?),
+    })
+}
+
+fn bindings(vm: &SloshVm, val: Value) -> Result<Vec<KeyBinding>, String> {
+    if val.is_nil() {
+        return Ok(Vec::new());
+    }
+    let Value::Map(handle) = val else {
+        return Err(":bindings must be a map of key sequences to lambdas".to_string());
+    };
+    let mut bindings = Vec::new();
+    for (spec, lambda) in vm.get_map(handle).iter() {
+        let spec = spec
+            .get_string(vm)
+            .map_err(|_| ":bindings keys must be strings".to_string())?;
+        let keys: Option<Vec<Key>> = spec.split_whitespace().map(parse_key).collect();
+        let keys = match keys {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => return Err(format!(":bindings invalid key sequence {spec}")),
+        };
+        if !matches!(
+            lambda,
+            Value::Lambda(_) | Value::Closure(_) | Value::Builtin(_)
+        ) {
+            return Err(format!(":bindings {spec} must be bound to a lambda"));
+        }
+        bindings.push(KeyBinding {
+            spec: spec.to_string(),
+            keys,
+            lambda,
+        });
+    }
+    // Map order is not stable, keep the settings comparable between loads.
+    bindings.sort_by(|a, b| a.spec.cmp(&b.spec));
+    Ok(bindings)
+}
+
+/// Call a binding's lambda with the line being edited, a string result replaces the line.
+fn run_binding(vm: &mut SloshVm, lambda: Value, line: &str) -> Option<String> {
+    let line = vm.alloc_string(line.to_string());
+    vm.heap_sticky(line);
+    let res = vm.call_fn(lambda, &[line]);
+    vm.heap_unsticky(line);
+    match res {
+        Ok(Value::String(h)) => Some(vm.get_string(h).to_string()),
+        Ok(Value::StringConst(i)) => Some(vm.get_interned(i).to_string()),
+        Ok(_) => None,
+        Err(err) => {
+            eprintln!("{SETTINGS_NAME}: key binding error: {err}");
+            None
+        }
+    }
+}
+
+enum BindingLookup {
+    Run(Value),
+    Pending,
+    Unbound(Vec<Key>),
+}
+
+/// Line editor keymap for :bindings, bound key sequences call their lambda and everything else
+/// goes to keymap.
+struct BindingsKeyMap {
+    keymap: Box<dyn KeyMap>,
+    bindings: Vec<KeyBinding>,
+    // Keys typed so far that start a bound sequence.
+    pending: Vec<Key>,
+}
+
+impl BindingsKeyMap {
+    fn new(keymap: Box<dyn KeyMap>, bindings: Vec<KeyBinding>) -> Self {
+        Self {
+            keymap,
+            bindings,
+            pending: Vec::new(),
+        }
+    }
+
+    fn lookup(&mut self, key: Key) -> BindingLookup {
+        self.pending.push(key);
+        if let Some(binding) = self.bindings.iter().find(|b| b.keys == self.pending) {
+            self.pending.clear();
+            BindingLookup::Run(binding.lambda)
+        } else if self
+            .bindings
+            .iter()
+            .any(|b| b.keys.starts_with(&self.pending))
+        {
+            BindingLookup::Pending
+        } else {
+            BindingLookup::Unbound(std::mem::take(&mut self.pending))
+        }
+    }
+}
+
+impl KeyMap for BindingsKeyMap {
+    fn handle_key_core(&mut self, key: Key, editor: &mut Editor) -> io::Result<()> {
+        self.keymap.handle_key_core(key, editor)
+    }
+
+    fn init(&mut self, editor: &mut Editor) {
+        self.pending.clear();
+        self.keymap.init(editor);
+    }
+
+    fn handle_key(
+        &mut self,
+        key: Key,
+        editor: &mut Editor,
+        handler: &mut dyn Completer,
+    ) -> io::Result<bool> {
+        match self.lookup(key) {
+            BindingLookup::Run(lambda) => {
+                let line = String::from(editor.current_buffer().clone());
+                let new_line = ENV.with(|env| run_binding(&mut env.borrow_mut(), lambda, &line));
+                if let Some(new_line) = new_line {
+                    editor.delete_all_before_cursor()?;
+                    editor.delete_all_after_cursor()?;
+                    editor.insert_str_after_cursor(&new_line)?;
+                }
+                Ok(false)
+            }
+            BindingLookup::Pending => Ok(false),
+            BindingLookup::Unbound(keys) => {
+                // Keys that started a bound sequence but did not finish it go through as typed.
+                for key in keys {
+                    if self.keymap.handle_key(key, editor, handler)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Expand a leading ~ to $HOME.
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix('~'), env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{home}{rest}")
+        }
+        _ => path.to_string(),
+    }
+}
+
+impl ReplSettings {
+    /// Settings from *repl-settings*, invalid entries keep the default and are returned as errors.
+    pub(crate) fn load(vm: &SloshVm) -> (Self, Vec<String>) {
+        let mut settings = Self::default();
+        let mut errors = Vec::new();
+        let map = vm
+            .get_if_interned(SETTINGS_NAME)
+            .and_then(|i| vm.global_intern_slot(i))
+            .map(|slot| vm.get_global(slot));
+        let Some(Value::Map(handle)) = map else {
+            if map.is_some() {
+                errors.push(format!("{SETTINGS_NAME} must be a map"));
+            }
+            return (settings, errors);
+        };
+        for (key, val) in vm.get_map(handle).iter() {
+            let Value::Keyword(key) = key else {
+                errors.push(format!(
+                    "keys must be keywords, got {}",
+                    key.display_value(vm)
+                ));
+                continue;
+            };
+            let key = vm.get_interned(key);
+            let res = match key {
+                "keybindings" => match val {
+                    Value::Keyword(k) if vm.get_interned(k) == "vi" => {
+                        settings.key_bindings = Keys::Vi;
+                        Ok(())
+                    }
+                    Value::Keyword(k) if vm.get_interned(k) == "emacs" => {
+                        settings.key_bindings = Keys::Emacs;
+                        Ok(())
+                    }
+                    _ => Err(":keybindings must be :vi or :emacs".to_string()),
+                },
+                "max-history" => match val.get_int(vm) {
+                    Ok(max) if max >= 0 => {
+                        settings.max_history = max as usize;
+                        Ok(())
+                    }
+                    _ => Err(":max-history must be a positive integer".to_string()),
+                },
+                "history-file" => match val.get_string(vm) {
+                    Ok(file) => {
+                        settings.history_file = expand_home(file);
+                        Ok(())
+                    }
+                    Err(_) => Err(":history-file must be a string".to_string()),
+                },
+                "vi-esc-sequence" => {
+                    vi_esc_sequence(vm, val).map(|esc| settings.vi_esc_sequence = esc)
+                }
+                "vi-normal-prompt-prefix" => {
+                    optional_string(vm, key, val).map(|s| settings.vi_normal_prompt_prefix = s)
+                }
+                "vi-normal-prompt-suffix" => {
+                    optional_string(vm, key, val).map(|s| settings.vi_normal_prompt_suffix = s)
+                }
+                "vi-insert-prompt-prefix" => {
+                    optional_string(vm, key, val).map(|s| settings.vi_insert_prompt_prefix = s)
+                }
+                "vi-insert-prompt-suffix" => {
+                    optional_string(vm, key, val).map(|s| settings.vi_insert_prompt_suffix = s)
+                }
+                "bindings" => bindings(vm, val).map(|b| settings.bindings = b),
+                _ => Err(format!("unknown setting :{key}")),
+            };
+            if let Err(err) = res {
+                errors.push(err);
+            }
+        }
+        (settings, errors)
+    }
+
+    /// Apply these settings to the line editor, prev is what was last applied (if anything).
+    pub(crate) fn apply(&self, con: &mut Context, prev: Option<&ReplSettings>) {
+        let keymap: Box<dyn keymap::KeyMap> = match self.key_bindings {
+            Keys::Vi => {
+                let mut vi = keymap::Vi::new();
+                let vi_keywords = vec!["_", "-"];
+                vi.set_keyword_rule(Box::new(AlphanumericAndVariableKeywordRule::new(
+                    vi_keywords,
+                )));
+                if let Some((ch1, ch2, timeout)) = self.vi_esc_sequence {
+                    vi.set_esc_sequence(ch1, ch2, timeout);
+                }
+                vi.set_normal_prompt_prefix(self.vi_normal_prompt_prefix.clone());
+                vi.set_normal_prompt_suffix(self.vi_normal_prompt_suffix.clone());
+                vi.set_insert_prompt_prefix(self.vi_insert_prompt_prefix.clone());
+                vi.set_insert_prompt_suffix(self.vi_insert_prompt_suffix.clone());
+                Box::new(vi)
+            }
+            Keys::Emacs => Box::new(keymap::Emacs::new()),
+        };
+        let keymap = Box::new(history::HistorySearchKeyMap::new(keymap));
+        if self.bindings.is_empty() {
+            con.set_keymap(keymap);
+        } else {
+            con.set_keymap(Box::new(BindingsKeyMap::new(keymap, self.bindings.clone())));
+        }
+        con.history.set_max_history_size(self.max_history);
+        if prev
+            .map(|p| p.history_file != self.history_file)
+            .unwrap_or(true)
+        {
+            if let Err(e) = con
+                .history
+                .set_file_name_and_load_history(&self.history_file)
+            {
+                eprintln!("Error loading history: {e}");
+            }
+            history::set_history_file(format!("{}-meta", self.history_file));
+        }
+    }
+}
+
+/// Load *repl-settings* and apply them to con if they changed from current.
+pub(crate) fn update_repl_settings(
+    vm: &SloshVm,
+    con: &mut Context,
+    current: &mut Option<ReplSettings>,
+) {
+    let (settings, errors) = ReplSettings::load(vm);
+    LAST_ERRORS.with(|last| {
+        let mut last = last.borrow_mut();
+        if *last != errors {
+            for err in &errors {
+                eprintln!("{SETTINGS_NAME}: {err}");
+            }
+            *last = errors;
+        }
+    });
+    if current.as_ref() != Some(&settings) {
+        settings.apply(con, current.as_ref());
+        *current = Some(settings);
+    }
+}
+
+/// Add the *repl-settings* map with the default settings.
+pub(crate) fn add_repl_settings(env: &mut SloshVm) {
+    env.pause_gc();
+    let mut map = VMHashMap::with_capacity(3);
+    let key = Value::Keyword(env.intern_static("keybindings"));
+    let val = Value::Keyword(env.intern_static("vi"));
+    map.insert(env, key, val);
+    let key = Value::Keyword(env.intern_static("max-history"));
+    map.insert(env, key, (DEFAULT_MAX_HISTORY as i64).into());
+    let key = Value::Keyword(env.intern_static("history-file"));
+    let val = env.alloc_string(crate::history_file());
+    map.insert(env, key, val);
+    let settings = env.alloc_map(map);
+    add_global_value(
+        env,
+        SETTINGS_NAME,
+        settings,
+        r#"Usage: (set! *repl-settings*.:keybindings :emacs)
+
+Map of the REPL line editor settings, read before each prompt so changes take effect at the next
+line.  The keys are:
+:keybindings - :vi (the default) or :emacs.
+:max-history - the most lines of history to keep.
+:history-file - the file the history is loaded from and saved to.
+:vi-esc-sequence - a vector of a two char string and a timeout in milliseconds, typing the chars
+within the timeout is escape in vi insert mode, for example ["jk" 200].
+:vi-normal-prompt-prefix, :vi-normal-prompt-suffix, :vi-insert-prompt-prefix and
+:vi-insert-prompt-suffix - strings put around the prompt to show the vi mode (or nil).
+:bindings - a map of key sequences to lambdas.  A key sequence is a string of space separated
+keys (ctrl-x, alt-x, f1-f12, up, down, left, right, home, end, delete, backspace, esc, tab or a
+single char), for example "ctrl-x ctrl-e".  Typing the keys calls the lambda with the line being
+edited, if it returns a string that replaces the line.
+Invalid settings are reported and left at their default.
+
+Section: shell
+
+Example:
+(test::assert-equal :vi *repl-settings*.:keybindings)
+"#,
+    );
+    env.unpause_gc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile_state::state::new_slosh_vm;
+    use compiler_test_utils::exec;
+
+    #[test]
+    fn test_load_repl_settings() {
+        let mut vm = new_slosh_vm();
+        let (settings, errors) = ReplSettings::load(&vm);
+        assert_eq!(settings, ReplSettings::default());
+        assert!(errors.is_empty());
+
+        add_repl_settings(&mut vm);
+        let name = vm.intern(SETTINGS_NAME);
+        let handle = match vm.get_global(vm.global_intern_slot(name).unwrap()) {
+            Value::Map(handle) => handle,
+            _ => panic!("settings not a map"),
+        };
+        let set = |vm: &mut SloshVm, key: &'static str, val: Value| {
+            let key = Value::Keyword(vm.intern_static(key));
+            let mut map = vm.get_map(handle).clone();
+            map.insert(vm, key, val);
+            *vm.get_map_mut(handle).unwrap() = map;
+        };
+        let emacs = Value::Keyword(vm.intern_static("emacs"));
+        set(&mut vm, "keybindings", emacs);
+        let esc = vec![vm.alloc_string("jk".to_string()), 150.into()];
+        let esc = vm.alloc_vector(esc);
+        set(&mut vm, "vi-esc-sequence", esc);
+        let prefix = vm.alloc_string("[N]".to_string());
+        set(&mut vm, "vi-normal-prompt-prefix", prefix);
+        set(&mut vm, "max-history", (-1).into());
+        let (settings, errors) = ReplSettings::load(&vm);
+        assert_eq!(settings.key_bindings, Keys::Emacs);
+        assert_eq!(settings.vi_esc_sequence, Some(('j', 'k', 150)));
+        assert_eq!(settings.vi_normal_prompt_prefix, Some("[N]".to_string()));
+        assert_eq!(settings.max_history, DEFAULT_MAX_HISTORY);
+        assert_eq!(errors, vec![":max-history must be a positive integer"]);
+    }
+
+    #[test]
+    fn test_key_bindings() {
+        let mut vm = new_slosh_vm();
+        exec(&mut vm, "(def called nil)");
+        let lambda = exec(
+            &mut vm,
+            "(fn (line) (set! called line) (str line \" done\"))",
+        );
+        let mut map = VMHashMap::with_capacity(2);
+        let key = vm.alloc_string("ctrl-x ctrl-e".to_string());
+        map.insert(&vm, key, lambda);
+        let key = vm.alloc_string("f5".to_string());
+        map.insert(&vm, key, lambda);
+        let bindings_map = vm.alloc_map(map);
+        let mut map = VMHashMap::with_capacity(1);
+        let key = Value::Keyword(vm.intern_static("bindings"));
+        map.insert(&vm, key, bindings_map);
+        let settings = vm.alloc_map(map);
+        vm.set_named_global(SETTINGS_NAME, settings);
+
+        let (settings, errors) = ReplSettings::load(&vm);
+        assert!(errors.is_empty(), "{errors:?}");
+        let specs: Vec<&str> = settings.bindings.iter().map(|b| b.spec.as_str()).collect();
+        assert_eq!(specs, vec!["ctrl-x ctrl-e", "f5"]);
+
+        let mut keys = BindingsKeyMap::new(Box::new(keymap::Emacs::new()), settings.bindings);
+        assert!(matches!(
+            keys.lookup(Key::Ctrl('x')),
+            BindingLookup::Pending
+        ));
+        // A key that does not finish the sequence sends the pending keys through.
+        match keys.lookup(Key::Char('a')) {
+            BindingLookup::Unbound(keys) => assert_eq!(keys, vec![Key::Ctrl('x'), Key::Char('a')]),
+            _ => panic!("expected unbound keys"),
+        }
+        assert!(matches!(
+            keys.lookup(Key::Ctrl('x')),
+            BindingLookup::Pending
+        ));
+        let BindingLookup::Run(bound) = keys.lookup(Key::Ctrl('e')) else {
+            panic!("expected a bound key sequence");
+        };
+        assert!(matches!(keys.lookup(Key::F(5)), BindingLookup::Run(_)));
+
+        assert_eq!(
+            run_binding(&mut vm, bound, "ls -l"),
+            Some("ls -l done".to_string())
+        );
+        let called = exec(&mut vm, "called");
+        assert_eq!(called.get_string(&vm).unwrap(), "ls -l");
+
+        let bad = vm.alloc_string("ctrl-xx".to_string());
+        let mut map = VMHashMap::with_capacity(1);
+        map.insert(&vm, bad, lambda);
+        let bad = vm.alloc_map(map);
+        assert_eq!(
+            bindings(&vm, bad),
+            Err(":bindings invalid key sequence ctrl-xx".to_string())
+        );
+    }
+}
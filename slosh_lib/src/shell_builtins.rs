@@ -1,13 +1,17 @@
-use crate::SHELL_ENV;
+use crate::{set_status_globals, SHELL_ENV};
 use bridge_adapters::add_builtin;
 use compile_state::state::SloshVm;
+use shell::jobs::Trap;
 use shell::platform::{FromFileDesc, Platform, Sys};
+use shell::signals::TrapSignal;
+use sl_compiler::load_eval::apply_callable;
 use slvm::io::HeapIo;
 use slvm::{VMError, VMResult, Value};
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::env::VarError;
 use std::fs::File;
-use std::io::{BufRead, ErrorKind};
+use std::io::BufRead;
 use std::{env, io};
 
 fn sh(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
@@ -51,34 +55,43 @@ fn sh(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     }
     let mut command = String::new();
     for exp in new_regs {
+        if !command.is_empty() {
+            command.push(' ');
+        }
         match exp {
             Value::String(h) => command.push_str(vm.get_string(h)),
             Value::StringConst(i) => command.push_str(vm.get_interned(i)),
             _ => command.push_str(&exp.display_value(vm)),
         }
-        command.push(' ');
     }
     if command.is_empty() {
         return Err(VMError::new_compile("sh: empty command"));
     }
     let mut fork_res = Ok(0);
-    let mut run_res = Err(io::Error::new(ErrorKind::Other, "broken parse"));
-    SHELL_ENV.with(|jobs_ref| {
-        let jobs = &mut jobs_ref.borrow_mut();
-        run_res = shell::parse::parse_line(jobs, &command)
-    });
-    let mut run = run_res
+    let mut run = shell::parse::parse_line(&command)
         .map_err(|e| VMError::new_compile(format!("sh: {e}")))?
         .into_run();
     if !fds_close.is_empty() {
         run.fds_to_internal(&fds_close);
     }
     let background = false; // !result.is_empty();
+    let mut pipe_status = vec![];
+    let mut errexit = false;
     SHELL_ENV.with(|jobs_ref| {
         let jobs = &mut jobs_ref.borrow_mut();
         fork_res = shell::run::run_job(&run, jobs, background);
+        pipe_status = jobs.pipe_status().to_vec();
+        errexit = jobs.errexit_failed();
     });
     let fork_res = fork_res.map_err(|e| VMError::new_compile(format!("sh 2: {e}")))?;
+    set_status_globals(vm, fork_res, &pipe_status);
+    if errexit {
+        // Stop the script (or form) that ran the command.
+        return Err(VMError::new(
+            "sh",
+            format!("errexit: {command} exited with status {fork_res}"),
+        ));
+    }
     if result.is_empty() {
         Ok(fork_res.into())
     } else {
@@ -90,23 +103,20 @@ fn sh(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
 fn sh_str(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     let mut command = String::new();
     for exp in registers {
+        if !command.is_empty() {
+            command.push(' ');
+        }
         match exp {
             Value::String(h) => command.push_str(vm.get_string(*h)),
             Value::StringConst(i) => command.push_str(vm.get_interned(*i)),
             _ => command.push_str(&exp.display_value(vm)),
         }
-        command.push(' ');
     }
     if command.is_empty() {
         return Err(VMError::new_compile("$sh: empty command"));
     }
     let mut fork_res = Ok(0);
-    let mut run_res = Err(io::Error::new(ErrorKind::Other, "broken parse"));
-    SHELL_ENV.with(|jobs_ref| {
-        let jobs = &mut jobs_ref.borrow_mut();
-        run_res = shell::parse::parse_line(jobs, &command)
-    });
-    let mut run = run_res
+    let mut run = shell::parse::parse_line(&command)
         .map_err(|e| VMError::new_compile(format!("$sh: {e}")))?
         .into_run();
     let (input, output) = Sys::anon_pipe()?;
@@ -154,6 +164,114 @@ fn env_var(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
     }
 }
 
+thread_local! {
+    /// Global slots holding the lisp trap handlers (so they are GC roots).
+    static TRAP_HANDLERS: RefCell<HashMap<TrapSignal, u32>> = RefCell::new(HashMap::new());
+}
+
+fn trap_signal_arg(vm: &SloshVm, val: Value) -> VMResult<TrapSignal> {
+    let name = match val {
+        Value::String(h) => vm.get_string(h).to_string(),
+        Value::StringConst(i) | Value::Keyword(i) | Value::Symbol(i) => {
+            vm.get_interned(i).to_string()
+        }
+        Value::Int(_) => val.display_value(vm),
+        _ => {
+            return Err(VMError::new_compile(
+                "trap: signal must be a string, keyword or int",
+            ))
+        }
+    };
+    TrapSignal::from_name(&name)
+        .ok_or_else(|| VMError::new_compile(format!("trap: {name}: invalid signal specification")))
+}
+
+fn trap(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (sig, handler) = match registers {
+        [sig, handler] => (trap_signal_arg(vm, *sig)?, *handler),
+        _ => {
+            return Err(VMError::new_compile(
+                "trap: wrong number of args, expected signal and handler",
+            ))
+        }
+    };
+    let trap = match handler {
+        Value::Nil => None,
+        Value::String(h) if vm.get_string(h).is_empty() => Some(Trap::Ignore),
+        Value::StringConst(i) if vm.get_interned(i).is_empty() => Some(Trap::Ignore),
+        Value::String(h) => Some(Trap::Command(vm.get_string(h).to_string())),
+        Value::StringConst(i) => Some(Trap::Command(vm.get_interned(i).to_string())),
+        Value::Lambda(_) | Value::Closure(_) | Value::Builtin(_) => Some(Trap::Handler),
+        _ => {
+            return Err(VMError::new_compile(
+                "trap: handler must be a function, command string or nil",
+            ))
+        }
+    };
+    // Keep the handler in a global slot so it is not garbage collected.
+    let slot = TRAP_HANDLERS.with(|handlers| handlers.borrow().get(&sig).copied());
+    if trap == Some(Trap::Handler) {
+        let slot = slot.unwrap_or_else(|| {
+            let slot = vm.reserve_global();
+            TRAP_HANDLERS.with(|handlers| handlers.borrow_mut().insert(sig, slot));
+            slot
+        });
+        vm.set_global(slot, handler);
+    } else if let Some(slot) = slot {
+        vm.set_global(slot, Value::Nil);
+    }
+    SHELL_ENV
+        .with(|jobs| jobs.borrow_mut().set_trap(sig, trap))
+        .map_err(|e| VMError::new("trap", format!("{sig}: {e}")))?;
+    Ok(Value::Nil)
+}
+
+/// Call the lisp handler for sig (it is passed the signal name).
+fn run_trap_handler(vm: &mut SloshVm, sig: TrapSignal) -> VMResult<()> {
+    if let Some(slot) = TRAP_HANDLERS.with(|handlers| handlers.borrow().get(&sig).copied()) {
+        let handler = vm.get_global(slot);
+        if !handler.is_nil() {
+            let name = vm.alloc_string(sig.name().to_string());
+            apply_callable(vm, handler, &[name])?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the traps for any signals received, this is the VM safe point function.
+pub(crate) fn run_traps(vm: &mut SloshVm) -> VMResult<()> {
+    let handlers = SHELL_ENV.with(|jobs| match jobs.try_borrow_mut() {
+        Ok(mut jobs) => {
+            jobs.run_pending_traps();
+            jobs.take_pending_handlers()
+        }
+        // Shell code is running, the traps will be run when it finishes.
+        Err(_) => vec![],
+    });
+    for sig in handlers {
+        run_trap_handler(vm, sig)?;
+    }
+    Ok(())
+}
+
+/// Run the EXIT trap (if set), call when the shell exits.
+pub(crate) fn run_exit_trap(vm: &mut SloshVm) {
+    let handler = SHELL_ENV.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        if jobs.trap(TrapSignal::Exit) == Some(&Trap::Handler) {
+            true
+        } else {
+            jobs.run_trap(TrapSignal::Exit);
+            false
+        }
+    });
+    if handler {
+        if let Err(err) = run_trap_handler(vm, TrapSignal::Exit) {
+            eprintln!("trap EXIT: {}", err.display(vm));
+        }
+    }
+}
+
 pub fn add_shell_builtins(env: &mut SloshVm) {
     add_builtin(
         env,
@@ -168,4 +286,27 @@ pub fn add_shell_builtins(env: &mut SloshVm) {
         "Runs a shell command and returns it's status.",
     );
     add_builtin(env, "env", env_var, "Retrieves an environment variable.");
+    add_builtin(
+        env,
+        "trap",
+        trap,
+        r#"Usage: (trap signal handler)
+
+Set the handler for signal (a string, keyword or number such as "INT", :TERM or 10, or
+EXIT for when the shell exits).  The handler is a function that is called with the signal
+name, a shell command string, "" to ignore the signal or nil to reset it to the default.
+Handlers run at a safe point after the signal is received (between VM instructions or
+shell commands), not in the signal handler itself.  The shell trap builtin shares these traps.
+
+Section: shell
+
+Example:
+(def trap-test-sig nil)
+(trap :USR1 (fn (sig) (set! trap-test-sig sig)))
+(sh "sh -c 'kill -USR1 $PPID'")
+(trap :USR1 nil)
+(test::assert-equal "USR1" trap-test-sig)
+"#,
+    );
+    env.set_safe_point(shell::signals::trap_pending_flag(), run_traps);
 }
@@ -0,0 +1,607 @@
+//! Workers, scripts run on their own thread in a new (isolated) VM.
+//!
+//! Values are deep copied between VMs (see SendValue) over channels.  The parent refers to a
+//! worker by an integer id (like a job), the worker talks to its parent with parent-send and
+//! parent-recv.
+//!
+//! Errors in a worker come back to the parent as error values.  A panic in a worker is caught and
+//! returned the same way, but only when panics unwind.  Built with panic = "abort" (the workspace
+//! release profile) a panic in a worker still aborts the whole process.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bridge_adapters::add_builtin;
+use compile_state::state::{new_slosh_vm, SloshVm, SloshVmTrait};
+use sl_compiler::load_eval::{apply_callable, eval_exp, load_internal};
+use slvm::{SendValue, VMError, VMResult, Value};
+
+use crate::{set_builtins, set_initial_load_path};
+
+/// What a worker runs, a form that evaluates to a lambda or a file to load.
+enum Source {
+    Form(SendValue),
+    File(String),
+}
+
+/// The parent's side of a worker.
+struct Worker {
+    to_worker: Sender<SendValue>,
+    from_worker: Receiver<SendValue>,
+    result: Receiver<SendValue>,
+    thread: JoinHandle<()>,
+}
+
+/// The worker's side of the channels to its parent.
+struct Parent {
+    to_parent: Sender<SendValue>,
+    from_parent: Receiver<SendValue>,
+}
+
+thread_local! {
+    static WORKERS: RefCell<HashMap<i64, Worker>> = RefCell::new(HashMap::new());
+    /// Set in worker threads only.
+    static PARENT: RefCell<Option<Parent>> = const { RefCell::new(None) };
+}
+
+static NEXT_WORKER_ID: AtomicI64 = AtomicI64::new(1);
+
+/// Build the VM for a worker, the builtins (without the shell) and core.slosh.
+fn new_worker_vm(load_path: &[String]) -> SloshVm {
+    let mut vm = new_slosh_vm();
+    vm.pause_gc();
+    set_builtins(&mut vm);
+    set_initial_load_path(&mut vm, load_path.iter().map(|p| &p[..]).collect());
+    if let Err(err) = load_internal(&mut vm, "core.slosh") {
+        eprintln!("ERROR: {err}");
+    }
+    vm.unpause_gc();
+    vm
+}
+
+/// Load source in vm and call it with args, returns the result to send to the parent.
+fn run_worker(vm: &mut SloshVm, source: Source, args: SendValue) -> VMResult<SendValue> {
+    let main = match source {
+        Source::Form(form) => {
+            let form = vm.import_value(form);
+            eval_exp(vm, form)?
+        }
+        Source::File(name) => {
+            let name = vm.intern(&name);
+            let name = vm.get_interned(name);
+            load_internal(vm, name)?
+        }
+    };
+    let res = match main {
+        Value::Lambda(_) | Value::Closure(_) | Value::Builtin(_) => {
+            vm.heap_sticky(main);
+            let args = vm.import_value(args);
+            vm.heap_sticky(args);
+            let arg_vals = if let Value::Vector(h) = args {
+                vm.get_vector(h).to_vec()
+            } else {
+                Vec::new()
+            };
+            let res = apply_callable(vm, main, &arg_vals);
+            vm.heap_unsticky(args);
+            vm.heap_unsticky(main);
+            res?
+        }
+        _ => main,
+    };
+    vm.export_value(res)
+}
+
+/// Run a worker's body catching a panic (a bug in the VM or a builtin) as an error value so it
+/// does not take down the parent.  Can not help if panics abort.
+fn catch_worker_panic(id: i64, body: impl FnOnce() -> SendValue) -> SendValue {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let msg = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        SendValue::error("worker", format!("worker {id} panicked: {msg}"))
+    })
+}
+
+fn get_timeout(vm: &SloshVm, name: &str, timeout: Option<&Value>) -> VMResult<Option<Duration>> {
+    match timeout {
+        None => Ok(None),
+        Some(timeout) => match timeout.get_int(vm) {
+            Ok(ms) if ms >= 0 => Ok(Some(Duration::from_millis(ms as u64))),
+            _ => Err(VMError::new_vm(format!(
+                "{name}: timeout must be a non-negative number of milliseconds"
+            ))),
+        },
+    }
+}
+
+/// Receive a value (waiting at most timeout), timing out or the other side going away produce an
+/// error value (:timeout or :closed).
+fn recv(vm: &mut SloshVm, rx: &Receiver<SendValue>, timeout: Option<Duration>) -> Value {
+    let val = match timeout {
+        Some(timeout) => match rx.recv_timeout(timeout) {
+            Ok(val) => val,
+            Err(RecvTimeoutError::Timeout) => SendValue::error("timeout", "timed out"),
+            Err(RecvTimeoutError::Disconnected) => SendValue::error("closed", "channel closed"),
+        },
+        None => rx
+            .recv()
+            .unwrap_or_else(|_| SendValue::error("closed", "channel closed")),
+    };
+    vm.import_value(val)
+}
+
+fn get_worker_id(vm: &SloshVm, name: &str, worker: Value) -> VMResult<i64> {
+    let id = worker.get_int(vm)?;
+    if WORKERS.with(|workers| workers.borrow().contains_key(&id)) {
+        Ok(id)
+    } else {
+        Err(VMError::new(
+            "worker",
+            format!("{name}: {id} is not a running (unjoined) worker"),
+        ))
+    }
+}
+
+/// Remove the worker from the table (to wait on it without holding a borrow while the VM runs),
+/// put it back with put_worker.
+fn take_worker(vm: &SloshVm, name: &str, worker: Value) -> VMResult<(i64, Worker)> {
+    let id = get_worker_id(vm, name, worker)?;
+    let worker = WORKERS.with(|workers| workers.borrow_mut().remove(&id));
+    Ok((id, worker.expect("worker checked above")))
+}
+
+fn put_worker(id: i64, worker: Worker) {
+    WORKERS.with(|workers| workers.borrow_mut().insert(id, worker));
+}
+
+fn worker_spawn(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let Some((source, args)) = registers.split_first() else {
+        return Err(VMError::new_vm(
+            "worker-spawn: requires a lambda form or file name",
+        ));
+    };
+    let source = match source {
+        Value::String(h) => Source::File(vm.get_string(*h).to_string()),
+        Value::StringConst(i) => Source::File(vm.get_interned(*i).to_string()),
+        Value::Lambda(_) | Value::Closure(_) => {
+            // Compiled code refers to this VM's globals and heap, the worker compiles the source.
+            return Err(VMError::new(
+                "worker",
+                "worker-spawn: can not send a compiled lambda to a worker, pass its source (a quoted fn form)",
+            ));
+        }
+        form => Source::Form(vm.export_value(*form)?),
+    };
+    let args = SendValue::Vector(
+        args.iter()
+            .map(|arg| vm.export_value(*arg))
+            .collect::<VMResult<Vec<SendValue>>>()?,
+    );
+    let load_path = vm.intern("*load-path*");
+    let load_path = match vm.global_intern_slot(load_path) {
+        Some(slot) => match vm.get_global(slot) {
+            Value::Vector(h) => vm
+                .get_vector(h)
+                .iter()
+                .map(|p| p.pretty_value(vm))
+                .collect(),
+            _ => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+
+    let id = NEXT_WORKER_ID.fetch_add(1, Ordering::Relaxed);
+    let (to_worker, from_parent) = channel();
+    let (to_parent, from_worker) = channel();
+    let (result_tx, result) = channel();
+    let thread = thread::Builder::new()
+        .name(format!("slosh-worker-{id}"))
+        .spawn(move || {
+            PARENT.with(|parent| {
+                *parent.borrow_mut() = Some(Parent {
+                    to_parent,
+                    from_parent,
+                })
+            });
+            let res = catch_worker_panic(id, || {
+                let mut vm = new_worker_vm(&load_path);
+                run_worker(&mut vm, source, args)
+                    .unwrap_or_else(|err| SendValue::from_vm_error(&vm, &err))
+            });
+            // Drop the channels before sending the result so the parent sees them closed.
+            PARENT.with(|parent| parent.borrow_mut().take());
+            let _ = result_tx.send(res);
+        })
+        .map_err(|e| VMError::new("worker", format!("worker-spawn: {e}")))?;
+    WORKERS.with(|workers| {
+        workers.borrow_mut().insert(
+            id,
+            Worker {
+                to_worker,
+                from_worker,
+                result,
+                thread,
+            },
+        )
+    });
+    Ok(id.into())
+}
+
+fn worker_send(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [worker, val] = registers {
+        let id = get_worker_id(vm, "worker-send", *worker)?;
+        let val = vm.export_value(*val)?;
+        let sent = WORKERS.with(|workers| workers.borrow()[&id].to_worker.send(val).is_ok());
+        Ok(if sent { Value::True } else { Value::False })
+    } else {
+        Err(VMError::new_vm("worker-send: takes a worker and a value"))
+    }
+}
+
+fn worker_recv(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (worker, timeout) = match registers {
+        [worker] => (*worker, None),
+        [worker, timeout] => (*worker, Some(timeout)),
+        _ => {
+            return Err(VMError::new_vm(
+                "worker-recv: takes a worker and optional timeout",
+            ))
+        }
+    };
+    let timeout = get_timeout(vm, "worker-recv", timeout)?;
+    let (id, w) = take_worker(vm, "worker-recv", worker)?;
+    let res = recv(vm, &w.from_worker, timeout);
+    put_worker(id, w);
+    Ok(res)
+}
+
+fn worker_join(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let (worker, timeout) = match registers {
+        [worker] => (*worker, None),
+        [worker, timeout] => (*worker, Some(timeout)),
+        _ => {
+            return Err(VMError::new_vm(
+                "worker-join: takes a worker and optional timeout",
+            ))
+        }
+    };
+    let timeout = get_timeout(vm, "worker-join", timeout)?;
+    let (id, w) = take_worker(vm, "worker-join", worker)?;
+    let res = match timeout {
+        Some(timeout) => w.result.recv_timeout(timeout),
+        None => w.result.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    let res = match res {
+        Ok(res) => {
+            let _ = w.thread.join();
+            res
+        }
+        Err(RecvTimeoutError::Timeout) => {
+            put_worker(id, w);
+            SendValue::error("timeout", "worker-join: timed out")
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            // Panics are caught and sent as the result so this should not happen.
+            let _ = w.thread.join();
+            SendValue::error("worker", format!("worker {id} exited without a result"))
+        }
+    };
+    Ok(vm.import_value(res))
+}
+
+fn worker_is_done(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [worker] = registers {
+        let id = get_worker_id(vm, "worker-done?", *worker)?;
+        let done = WORKERS.with(|workers| workers.borrow()[&id].thread.is_finished());
+        Ok(if done { Value::True } else { Value::False })
+    } else {
+        Err(VMError::new_vm("worker-done?: takes a worker"))
+    }
+}
+
+fn not_in_worker(name: &str) -> VMError {
+    VMError::new("worker", format!("{name}: not running in a worker"))
+}
+
+fn parent_send(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    if let [val] = registers {
+        let val = vm.export_value(*val)?;
+        let sent = PARENT.with(|parent| {
+            parent
+                .borrow()
+                .as_ref()
+                .map(|parent| parent.to_parent.send(val).is_ok())
+        });
+        match sent {
+            Some(true) => Ok(Value::True),
+            Some(false) => Ok(Value::False),
+            None => Err(not_in_worker("parent-send")),
+        }
+    } else {
+        Err(VMError::new_vm("parent-send: takes a value"))
+    }
+}
+
+fn parent_recv(vm: &mut SloshVm, registers: &[Value]) -> VMResult<Value> {
+    let timeout = match registers {
+        [] => None,
+        [timeout] => get_timeout(vm, "parent-recv", Some(timeout))?,
+        _ => return Err(VMError::new_vm("parent-recv: takes an optional timeout")),
+    };
+    let parent = PARENT.with(|parent| parent.borrow_mut().take());
+    let Some(parent) = parent else {
+        return Err(not_in_worker("parent-recv"));
+    };
+    let res = recv(vm, &parent.from_parent, timeout);
+    PARENT.with(|p| *p.borrow_mut() = Some(parent));
+    Ok(res)
+}
+
+pub fn add_worker_builtins(env: &mut SloshVm) {
+    add_builtin(
+        env,
+        "worker-spawn",
+        worker_spawn,
+        r#"Usage: (worker-spawn lambda-form-or-file & args) -> worker
+
+Start a worker, a new VM on its own thread, and return its id.  The worker
+evaluates lambda-form (the source of a lambda as a quoted form, a compiled
+lambda refers to the parent's globals and heap so it can not be sent) or
+loads the named file, if the result is callable it is called with args.  Args,
+the result and messages (see worker-send and parent-send) are deep copied
+between the VMs so they must be data (not functions, files etc).  The worker
+shares nothing with the parent except the load path.  Use worker-join to get
+the worker's result, an error in the worker is returned as an error value.
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn (x y) (+ x y)) 1 2))
+(test::assert-equal 3 (worker-join w))
+(def w (worker-spawn '(fn () (err :bad "oops"))))
+(let (res (worker-join w))
+    (test::assert-true (err? res))
+    (test::assert-equal :bad (car res)))
+(test::assert-error (worker-spawn '(fn (f) (f)) (fn () 1)))
+(test::assert-error (worker-spawn (fn () 1)))
+"#,
+    );
+    add_builtin(
+        env,
+        "worker-send",
+        worker_send,
+        r#"Usage: (worker-send worker value) -> #t/#f
+
+Send a copy of value to worker (it receives it with parent-recv).  Returns
+false if the worker has finished.
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn () (let (x (parent-recv)) (* x 2)))))
+(test::assert-true (worker-send w 21))
+(test::assert-equal 42 (worker-join w))
+"#,
+    );
+    add_builtin(
+        env,
+        "worker-recv",
+        worker_recv,
+        r#"Usage: (worker-recv worker timeout-ms?) -> value
+
+Receive the next value the worker sent with parent-send, waits at most
+timeout-ms milliseconds if provided.  Returns a :timeout error value if it
+times out and a :closed error value if the worker has finished and there
+are no more values.
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn (n) (dotimes-i i n (parent-send i)) :done) 3))
+(test::assert-equal 0 (worker-recv w))
+(test::assert-equal 1 (worker-recv w))
+(test::assert-equal 2 (worker-recv w))
+(test::assert-equal :closed (car (worker-recv w)))
+(test::assert-equal :done (worker-join w))
+(def w (worker-spawn '(fn () (parent-recv))))
+(test::assert-equal :timeout (car (worker-recv w 10)))
+(worker-send w nil)
+(worker-join w)
+"#,
+    );
+    add_builtin(
+        env,
+        "worker-join",
+        worker_join,
+        r#"Usage: (worker-join worker timeout-ms?) -> value
+
+Wait for worker to finish and return its result (waits at most timeout-ms
+milliseconds if provided).  If the worker raised an error (or crashed) the
+error is returned as an error value, if it times out a :timeout error value
+is returned and the worker can be joined again.  Once joined the worker id
+is no longer valid.
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn () (parent-recv) :finished)))
+(test::assert-equal :timeout (car (worker-join w 10)))
+(test::assert-false (worker-done? w))
+(worker-send w 1)
+(test::assert-equal :finished (worker-join w 1000))
+(test::assert-error (worker-join w))
+(def w (worker-spawn "no-such-file.slosh"))
+(test::assert-true (err? (worker-join w)))
+"#,
+    );
+    add_builtin(
+        env,
+        "worker-done?",
+        worker_is_done,
+        r#"Usage: (worker-done? worker) -> #t/#f
+
+True if worker has finished running (worker-join will not wait).
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn () (parent-recv))))
+(test::assert-false (worker-done? w))
+(worker-send w 1)
+(worker-join w)
+"#,
+    );
+    add_builtin(
+        env,
+        "parent-send",
+        parent_send,
+        r#"Usage: (parent-send value) -> #t/#f
+
+Send a copy of value from a worker to its parent (it receives it with
+worker-recv).  Returns false if the parent is gone, it is an error to call
+this outside of a worker.
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn () (parent-send [1 2 3]) (parent-send {:a 1}))))
+(test::assert-equal [1 2 3] (worker-recv w))
+(test::assert-equal 1 (get (worker-recv w) :a))
+(worker-join w)
+(test::assert-error (parent-send 1))
+"#,
+    );
+    add_builtin(
+        env,
+        "parent-recv",
+        parent_recv,
+        r#"Usage: (parent-recv timeout-ms?) -> value
+
+Receive the next value the parent sent to this worker with worker-send, waits
+at most timeout-ms milliseconds if provided.  Returns a :timeout error value
+if it times out and a :closed error value if the parent is gone.  It is an
+error to call this outside of a worker.
+
+Section: worker
+
+Example:
+(def w (worker-spawn '(fn () (let (total 0, x (parent-recv)) (while (not (err? x)) (set! total (+ total x)) (set! x (parent-recv 100))) total))))
+(worker-send w 1)
+(worker-send w 2)
+(test::assert-equal 3 (worker-join w))
+(test::assert-error (parent-recv))
+"#,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_slosh_vm_with_builtins;
+    use compiler_test_utils::exec;
+
+    fn error_key(vm: &SloshVm, val: Value) -> String {
+        match val {
+            Value::Error(h) => vm.get_interned(vm.get_error(h).keyword).to_string(),
+            _ => panic!("expected an error value, got {}", val.display_value(vm)),
+        }
+    }
+
+    #[test]
+    fn test_worker_send_errors() {
+        let mut vm = new_slosh_vm_with_builtins();
+        let w = exec(&mut vm, "(def w (worker-spawn '(fn () :done)))");
+        assert_eq!(exec(&mut vm, "(worker-join w)").display_value(&vm), ":done");
+        // Joined workers are gone.
+        let res = worker_send(&mut vm, &[w, 1.into()]);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!(
+                "[worker]: worker-send: {} is not a running (unjoined) worker",
+                w.get_int(&vm).unwrap()
+            )
+        );
+
+        // A finished (not joined) worker can not receive anything.
+        let w = exec(&mut vm, "(def w (worker-spawn '(fn () :done)))");
+        while !worker_is_done(&mut vm, &[w]).unwrap().is_true() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(worker_send(&mut vm, &[w, 1.into()]).unwrap(), Value::False);
+        let res = worker_recv(&mut vm, &[w]).unwrap();
+        assert_eq!(error_key(&vm, res), "closed");
+
+        // Values that are not data can not be sent.
+        let lambda = exec(&mut vm, "(fn () 1)");
+        assert!(worker_send(&mut vm, &[w, lambda]).is_err());
+        assert!(worker_spawn(&mut vm, &[lambda]).is_err());
+        assert_eq!(exec(&mut vm, "(worker-join w)").display_value(&vm), ":done");
+
+        assert!(parent_send(&mut vm, &[1.into()]).is_err());
+        assert!(parent_recv(&mut vm, &[]).is_err());
+    }
+
+    #[test]
+    fn test_worker_circular_values() {
+        let mut vm = new_slosh_vm_with_builtins();
+        let w = exec(&mut vm, "(def w (worker-spawn '(fn () (parent-recv))))");
+        let vector = vm.alloc_vector(vec![1.into(), 2.into()]);
+        if let Value::Vector(h) = vector {
+            vm.get_vector_mut(h).unwrap()[1] = vector;
+        }
+        vm.heap_sticky(vector);
+        assert!(worker_send(&mut vm, &[w, vector]).is_err());
+        assert!(worker_spawn(&mut vm, &[w, vector]).is_err());
+        // Shared (but not circular) values are fine.
+        let shared = vm.alloc_vector(vec![1.into()]);
+        let twice = vm.alloc_vector(vec![shared, shared]);
+        assert_eq!(worker_send(&mut vm, &[w, twice]).unwrap(), Value::True);
+        vm.heap_unsticky(vector);
+        assert_eq!(
+            exec(&mut vm, "(worker-join w)").display_value(&vm),
+            "[[1] [1]]"
+        );
+    }
+
+    #[test]
+    fn test_worker_join_timeout() {
+        let mut vm = new_slosh_vm_with_builtins();
+        let w = exec(
+            &mut vm,
+            "(def w (worker-spawn '(fn () (parent-recv) :finished)))",
+        );
+        let res = worker_join(&mut vm, &[w, 0.into()]).unwrap();
+        assert_eq!(error_key(&vm, res), "timeout");
+        let res = worker_join(&mut vm, &[w, 10.into()]).unwrap();
+        assert_eq!(error_key(&vm, res), "timeout");
+        assert!(worker_join(&mut vm, &[w, (-1).into()]).is_err());
+        assert_eq!(worker_is_done(&mut vm, &[w]).unwrap(), Value::False);
+        assert_eq!(worker_send(&mut vm, &[w, 1.into()]).unwrap(), Value::True);
+        assert_eq!(
+            worker_join(&mut vm, &[w, 5000.into()])
+                .unwrap()
+                .display_value(&vm),
+            ":finished"
+        );
+        assert!(worker_join(&mut vm, &[w]).is_err());
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let res = catch_worker_panic(7, || panic!("worker bug"));
+        assert_eq!(
+            res,
+            SendValue::error("worker", "worker 7 panicked: worker bug")
+        );
+        let res = catch_worker_panic(7, || SendValue::Int(1));
+        assert_eq!(res, SendValue::Int(1));
+    }
+}
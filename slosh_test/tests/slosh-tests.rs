@@ -18,6 +18,8 @@ pub fn get_slosh_exe() -> PathBuf {
 /// EXE only works [with integration tests](https://doc.rust-lang.org/rust-by-example/testing/integration_testing.html)
 /// To Execute:
 ///     cargo test --package slosh_test --test slosh-tests run_slosh_tests -- --exact
+/// To also collect an lcov coverage report of the slosh code exercised:
+///     SLOSH_COVERAGE=/tmp/slosh.lcov cargo test --package slosh_test --test slosh-tests
 ///
 fn run_slosh_tests() {
     let test_script = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("run-tests.slosh");
@@ -31,7 +33,12 @@ fn run_slosh_tests() {
 
     let output = temp_env::with_var("HOME", home_dir, || {
         println!("HOME:\n{}", env!("HOME"));
-        let output = Command::new(slosh_path.unwrap())
+        let mut command = Command::new(slosh_path.unwrap());
+        // Set SLOSH_COVERAGE to an lcov file to collect coverage of the lisp code the tests run.
+        if let Some(lcov_file) = std::env::var_os("SLOSH_COVERAGE") {
+            command.arg("--coverage").arg(lcov_file);
+        }
+        let output = command
             .arg(test_script)
             .output()
             .expect("Failed to execute command");
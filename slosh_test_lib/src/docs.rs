@@ -76,16 +76,10 @@ lazy_static! {
         exemption_set.insert("*int-bits*");
         exemption_set.insert("get-prop");
         exemption_set.insert("expand-macro");
+        exemption_set.insert("%set-restarts!");
 
         // slosh specific colors
         exemption_set.insert("get-rgb-seq");
-        exemption_set.insert("tok-slsh-form-color");
-        exemption_set.insert("tok-slsh-fcn-color");
-        exemption_set.insert("tok-default-color");
-        exemption_set.insert("tok-sys-command-color");
-        exemption_set.insert("tok-sys-alias-color");
-        exemption_set.insert("tok-string-color");
-        exemption_set.insert("tok-invalid-color");
 
         exemption_set.insert("*fg-default*");
         exemption_set.insert("*fg-black*");
@@ -707,13 +707,13 @@ pub(crate) fn full_legacy_sl_sh_forms_metadata() -> &'static [(&'static str, boo
         ("timer", true, ""),
         ("to-degrees", true, ""),
         ("to-radians", true, ""),
-        ("tok-default-color", true, ""),
-        ("tok-invalid-color", true, ""),
-        ("tok-slsh-fcn-color", true, ""),
-        ("tok-slsh-form-color", true, ""),
-        ("tok-string-color", true, ""),
-        ("tok-sys-alias-color", true, ""),
-        ("tok-sys-command-color", true, ""),
+        ("tok-default-color", true, "*syntax-theme*"),
+        ("tok-invalid-color", true, "*syntax-theme*"),
+        ("tok-slsh-fcn-color", true, "*syntax-theme*"),
+        ("tok-slsh-form-color", true, "*syntax-theme*"),
+        ("tok-string-color", true, "*syntax-theme*"),
+        ("tok-sys-alias-color", true, "*syntax-theme*"),
+        ("tok-sys-command-color", true, "*syntax-theme*"),
         ("token-delim", true, ""),
         ("true?", true, ""),
         ("type", true, ""),
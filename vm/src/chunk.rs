@@ -1,4 +1,5 @@
 use std::cmp::Ordering;
+use std::sync::Arc;
 
 use crate::opcodes::*;
 use crate::{Interned, VMError, VMResult, Value};
@@ -23,8 +24,84 @@ pub struct Chunk {
     pub args: u16,
     pub opt_args: u16,
     pub rest: bool,
+    // If set this chunk only dispatches on argument count, its constants are the lambdas for each
+    // arity (in order) and its captures are the concatenated captures of those lambdas.
+    pub arities: Option<Arities>,
 
     pub dbg_args: Option<Vec<Interned>>,
+    // Names of any &key arguments (these are destructured from the rest argument).
+    pub key_args: Option<Vec<Interned>>,
+}
+
+/// Dispatch table of a multi-arity chunk, built when it is compiled so a call only indexes it.
+#[derive(Clone, Debug)]
+pub struct Arities {
+    /// Chunk for each arity (in order) and the offset of its captures in the captures of the
+    /// multi-arity chunk.
+    lambdas: Vec<(Arc<Chunk>, usize)>,
+    /// Index into lambdas for each argument count up to the most any fixed arity accepts.
+    by_num_args: Vec<Option<usize>>,
+    /// Index of the arity for any larger argument count (the first one with a rest argument).
+    rest: Option<usize>,
+}
+
+impl Arities {
+    pub fn new(chunks: Vec<Arc<Chunk>>) -> Self {
+        // Fewest and most (None for any number) arguments each arity accepts.
+        let range = |c: &Chunk| {
+            if c.rest {
+                (c.args - 1, None)
+            } else {
+                (c.args, Some(c.args + c.opt_args))
+            }
+        };
+        let table_len = chunks
+            .iter()
+            .map(|c| match range(c) {
+                (min, None) => min,
+                (_, Some(max)) => max,
+            })
+            .max()
+            .map(|max| max as usize + 1)
+            .unwrap_or(0);
+        let by_num_args = (0..table_len)
+            .map(|num_args| {
+                chunks.iter().position(|c| {
+                    let (min, max) = range(c);
+                    num_args >= min as usize && max.is_none_or(|max| num_args <= max as usize)
+                })
+            })
+            .collect();
+        let rest = chunks.iter().position(|c| c.rest);
+        let mut cap_offset = 0;
+        let lambdas = chunks
+            .into_iter()
+            .map(|c| {
+                let offset = cap_offset;
+                cap_offset += c.captures.as_ref().map(|c| c.len()).unwrap_or(0);
+                (c, offset)
+            })
+            .collect();
+        Self {
+            lambdas,
+            by_num_args,
+            rest,
+        }
+    }
+
+    /// The chunk that accepts num_args and the offset of its captures.
+    pub fn select(&self, num_args: u16) -> Option<&(Arc<Chunk>, usize)> {
+        let idx = match self.by_num_args.get(num_args as usize) {
+            Some(idx) => *idx,
+            None => self.rest,
+        };
+        idx.map(|idx| &self.lambdas[idx])
+    }
+
+    /// The chunk for each arity in order.
+    pub fn chunks(&self) -> impl Iterator<Item = &Arc<Chunk>> {
+        self.lambdas.iter().map(|(c, _)| c)
+    }
 }
 
 impl Chunk {
@@ -43,10 +120,17 @@ impl Chunk {
             args: 0,
             opt_args: 0,
             rest: false,
+            arities: None,
             dbg_args: None,
+            key_args: None,
         }
     }
 
+    /// First source line this chunk was compiled from.
+    pub fn start_line(&self) -> u32 {
+        self.start_line
+    }
+
     fn encode_operand(&mut self, op: u16, wide: bool) {
         if wide {
             self.code.push(((op & 0xFF00) >> 8) as u8);
@@ -120,6 +204,24 @@ impl Chunk {
         None
     }
 
+    /// Return the source lines that have code in this chunk (in order, no duplicates).
+    pub fn code_lines(&self) -> Vec<u32> {
+        let mut line = self.start_line;
+        let mut lines = Vec::new();
+        for o in &self.line_numbers {
+            if (o & 0x40) > 0 {
+                line += (o & 0x3f) as u32;
+            }
+            if (o & 0x80) > 0 {
+                line += 1;
+            }
+            if (o & 0x40) == 0 && (o & 0x3f) > 0 && lines.last() != Some(&line) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
     pub fn line_to_offset(&self, line: u32) -> Option<usize> {
         if line > self.last_line {
             return None;
@@ -358,6 +460,45 @@ impl Chunk {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_arities() {
+        let arity = |args, opt_args, rest, captures: usize| {
+            let mut chunk = Chunk::new("no_file", 0);
+            chunk.args = args;
+            chunk.opt_args = opt_args;
+            chunk.rest = rest;
+            if captures > 0 {
+                chunk.captures = Some(vec![0; captures]);
+            }
+            Arc::new(chunk)
+        };
+        // (fn ((a) ..) ((a % b) ..) ((a b c d) ..) ((a b & r) ..)),
+        // the rest arity counts r in args.
+        let arities = Arities::new(vec![
+            arity(1, 0, false, 2),
+            arity(1, 1, false, 0),
+            arity(4, 0, false, 0),
+            arity(3, 0, true, 1),
+        ]);
+        let selected = |num_args| {
+            arities
+                .select(num_args)
+                .map(|(c, offset)| (c.args, c.opt_args, c.rest, *offset))
+        };
+        assert_eq!(selected(0), None);
+        assert_eq!(selected(1), Some((1, 0, false, 0)));
+        assert_eq!(selected(2), Some((1, 1, false, 2)));
+        assert_eq!(selected(3), Some((3, 0, true, 2)));
+        assert_eq!(selected(4), Some((4, 0, false, 2)));
+        assert_eq!(selected(5), Some((3, 0, true, 2)));
+        assert_eq!(selected(u16::MAX), Some((3, 0, true, 2)));
+
+        let arities = Arities::new(vec![arity(0, 0, false, 0), arity(2, 0, false, 0)]);
+        assert!(arities.select(1).is_none());
+        assert!(arities.select(3).is_none());
+        assert_eq!(arities.chunks().count(), 2);
+    }
+
     #[test]
     fn test_encode0() {
         let mut chunk = Chunk::new("no_file", 0);
@@ -565,6 +706,7 @@ mod tests {
         assert!(chunk.line_to_offset(0).is_none());
         assert!(chunk.line_to_offset(201).is_none());
         assert!(chunk.line_to_offset(101).is_none());
+        assert_eq!(chunk.code_lines(), vec![1, 2, 3, 4, 30, 200]);
         assert!(chunk.encode0(RET, Some(1)).is_err());
     }
 }
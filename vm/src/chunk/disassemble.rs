@@ -466,6 +466,12 @@ impl Chunk {
                 println!();
                 Ok(false)
             }
+            YIELD => {
+                print!("YIELD  \t");
+                disassemble_operand!(code, true, wide);
+                println!();
+                Ok(false)
+            }
             CCC => {
                 print!("CCC    \t");
                 disassemble_operand!(code, true, wide);
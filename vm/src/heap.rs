@@ -1,15 +1,18 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::bits::FLAG_MUT;
 use crate::{get_code, Chunk, FxHashMap, Interned, VMError, VMResult, Value};
 pub mod handle;
 pub use crate::handle::Handle;
 use crate::heap::io::HeapIo;
+use crate::heap::seq_iter::SeqIter;
 use crate::heap::storage::Storage;
 use crate::vm_hashmap::VMHashMap;
 
 pub mod bits;
 pub mod io;
+pub mod seq_iter;
 mod storage;
 pub mod vm_hashmap;
 
@@ -23,6 +26,8 @@ pub struct CallFrame {
     pub this_fn: Option<Value>,
     pub defers: Vec<Value>,
     pub on_error: Option<Value>,
+    /// The restarts (see restart-case) when the frame was created.
+    pub restarts: Value,
     pub called: Value,
 }
 
@@ -46,6 +51,36 @@ pub struct Continuation {
     pub stack: Vec<Value>,
 }
 
+/// Where a coroutine is in its life cycle.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    /// Created but never resumed.
+    New,
+    /// Stopped at a yield.
+    Suspended,
+    /// Currently running (or resuming another coroutine).
+    Running,
+    /// Returned or raised an error, can not be resumed.
+    Dead,
+}
+
+/// A coroutine runs a lambda on its own stack.  While it is running the VM owns that stack, when it
+/// yields the stack and the frame to continue from are saved here so nothing is copied.
+#[derive(Clone, Debug)]
+pub struct Coroutine {
+    pub lambda: Value,
+    /// A generator returns :*iter-empty* (instead of its result) when done so it is an iterator.
+    pub generator: bool,
+    pub status: CoroutineStatus,
+    /// Frame to continue from when suspended.
+    pub frame: Option<CallFrame>,
+    /// Register (absolute stack index) that gets the value passed to resume.
+    pub resume_reg: usize,
+    /// The value passed to the last yield until resume picks it up.
+    pub yielded: Value,
+    pub stack: Vec<Value>,
+}
+
 // This is anything that can live on the heap.  Values normally live on the
 // stack or as constants.
 #[derive(Clone, Debug)]
@@ -82,11 +117,42 @@ impl MutState {
     }
 }
 
+/// Default number of collections between major (full) collections.
+pub const DEFAULT_MAJOR_INTERVAL: u32 = 8;
+
+/// Garbage collection counts and pause times.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GcStats {
+    pub minor_collections: u64,
+    pub major_collections: u64,
+    pub total_pause: Duration,
+    pub last_pause: Duration,
+    pub max_pause: Duration,
+}
+
+/// Object counts for one of the heap's storage pools.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PoolStats {
+    pub live: usize,
+    pub old: usize,
+    pub capacity: usize,
+}
+
+/// The heap is a non-moving, generational mark and sweep collector.
+///
+/// Objects that survive a collection become old, a minor collection only traces and frees young
+/// objects (old objects are assumed live) so most collections do not have to walk long lived data.
+/// Any mutable access to an old object (the *_mut getters and set_property, so SETCOL, XAR, XDR,
+/// VECPSH etc) is a write barrier that adds it to the remembered set which is traced on the next
+/// minor collection.  Every major_interval collections (or when a minor collection does not free
+/// enough space) a major collection traces everything.
 //#[derive(Debug)]
 pub struct Heap {
     objects: Storage<Object>,
     callframes: Storage<CallFrame>,
     continuations: Storage<Continuation>,
+    coroutines: Storage<Coroutine>,
+    iters: Storage<SeqIter>,
     errors: Storage<Error>,
     pairs: Storage<(Value, Value)>,
     values: Storage<Value>,
@@ -94,6 +160,11 @@ pub struct Heap {
     props: Option<FxHashMap<Value, Arc<FxHashMap<Interned, Value>>>>,
     greys: Vec<Value>,
     paused: u32,
+    // Old objects that have been written to since the last collection.
+    remembered: Vec<Value>,
+    major_interval: u32,
+    minors_since_major: u32,
+    stats: GcStats,
 }
 
 impl Default for Heap {
@@ -115,6 +186,8 @@ macro_rules! value_op {
             $crate::Value::Lambda(handle) => $heap.objects.$op(handle.idx()),
             $crate::Value::Closure(handle) => $heap.objects.$op(handle.idx()),
             $crate::Value::Continuation(handle) => $heap.continuations.$op(handle.idx()),
+            $crate::Value::Coroutine(handle) => $heap.coroutines.$op(handle.idx()),
+            $crate::Value::Iter(handle) => $heap.iters.$op(handle.idx()),
             $crate::Value::CallFrame(handle) => $heap.callframes.$op(handle.idx()),
             $crate::Value::Value(handle) => $heap.values.$op(handle.idx()),
 
@@ -151,6 +224,8 @@ impl Heap {
             objects: Storage::default(),
             callframes: Storage::default(),
             continuations: Storage::default(),
+            coroutines: Storage::default(),
+            iters: Storage::default(),
             errors: Storage::default(),
             pairs: Storage::default(),
             values: Storage::default(),
@@ -158,6 +233,10 @@ impl Heap {
             props: Some(FxHashMap::default()),
             greys: vec![],
             paused: 0,
+            remembered: vec![],
+            major_interval: DEFAULT_MAJOR_INTERVAL,
+            minors_since_major: 0,
+            stats: GcStats::default(),
         }
     }
 
@@ -189,12 +268,49 @@ impl Heap {
         self.objects.set_grow_factor(grow_factor);
     }
 
+    /// Set the number of collections between major collections (0 or 1 makes every collection a
+    /// major collection, i.e. turns off generational collection).
+    pub fn set_major_interval(&mut self, major_interval: u32) {
+        self.major_interval = major_interval;
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.stats
+    }
+
+    /// Object counts for each storage pool by name.
+    pub fn pool_stats(&self) -> Vec<(&'static str, PoolStats)> {
+        macro_rules! pool {
+            ($name:expr, $pool:expr) => {
+                (
+                    $name,
+                    PoolStats {
+                        live: $pool.live_objects(),
+                        old: $pool.old_objects(),
+                        capacity: $pool.capacity(),
+                    },
+                )
+            };
+        }
+        vec![
+            pool!("objects", self.objects),
+            pool!("callframes", self.callframes),
+            pool!("continuations", self.continuations),
+            pool!("coroutines", self.coroutines),
+            pool!("iters", self.iters),
+            pool!("errors", self.errors),
+            pool!("pairs", self.pairs),
+            pool!("values", self.values),
+            pool!("ios", self.ios),
+        ]
+    }
+
     fn alloc<MarkFunc>(&mut self, obj: Object, flags: u8, mark_roots: MarkFunc) -> Handle
     where
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.objects.live_objects() >= self.objects.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Handle::new32(self.objects.alloc(obj, flags))
     }
@@ -210,7 +326,7 @@ impl Heap {
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.pairs.live_objects() >= self.pairs.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Value::Pair(self.pairs.alloc((car, cdr), mutable.flag()).into())
     }
@@ -288,17 +404,37 @@ impl Heap {
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.continuations.live_objects() >= self.continuations.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Value::Continuation(Handle::new32(self.continuations.alloc(k, 0)))
     }
 
+    pub fn alloc_coroutine<MarkFunc>(&mut self, co: Coroutine, mark_roots: MarkFunc) -> Value
+    where
+        MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
+    {
+        if self.coroutines.live_objects() >= self.coroutines.capacity() && self.paused == 0 {
+            self.gc(mark_roots);
+        }
+        Value::Coroutine(Handle::new32(self.coroutines.alloc(co, FLAG_MUT)))
+    }
+
+    pub fn alloc_iter<MarkFunc>(&mut self, iter: SeqIter, mark_roots: MarkFunc) -> Value
+    where
+        MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
+    {
+        if self.iters.live_objects() >= self.iters.capacity() && self.paused == 0 {
+            self.gc(mark_roots);
+        }
+        Value::Iter(Handle::new32(self.iters.alloc(iter, FLAG_MUT)))
+    }
+
     pub fn alloc_callframe<MarkFunc>(&mut self, frame: CallFrame, mark_roots: MarkFunc) -> Value
     where
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.callframes.live_objects() >= self.callframes.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Value::CallFrame(Handle::new32(self.callframes.alloc(frame, 0)))
     }
@@ -313,7 +449,7 @@ impl Heap {
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.values.live_objects() >= self.values.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Value::Value(self.values.alloc(val, mutable.flag()).into())
     }
@@ -328,7 +464,7 @@ impl Heap {
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.errors.live_objects() >= self.errors.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Value::Error(self.errors.alloc(error, mutable.flag()).into())
     }
@@ -343,7 +479,7 @@ impl Heap {
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
         if self.ios.live_objects() >= self.ios.capacity() && self.paused == 0 {
-            self.collect(mark_roots);
+            self.gc(mark_roots);
         }
         Value::Io(self.ios.alloc(io, mutable.flag()).into())
     }
@@ -379,6 +515,7 @@ impl Heap {
         if !self.objects.is_mutable(handle.idx()) {
             return Err(VMError::new_heap("Vector is not mutable!"));
         }
+        self.write_barrier(Value::Vector(handle));
         if let Some(Object::Vector(v)) = self.objects.get_mut(handle.idx()) {
             Ok(Arc::make_mut(v))
         } else {
@@ -398,6 +535,7 @@ impl Heap {
         if !self.objects.is_mutable(handle.idx()) {
             return Err(VMError::new_heap("Map is not mutable!"));
         }
+        self.write_barrier(Value::Map(handle));
         if let Some(Object::Map(map)) = self.objects.get_mut(handle.idx()) {
             Ok(Arc::make_mut(map))
         } else {
@@ -425,6 +563,7 @@ impl Heap {
         if !self.pairs.is_mutable(handle.idx()) {
             return Err(VMError::new_heap("Pair is not mutable!"));
         }
+        self.write_barrier(Value::Pair(handle));
         if let Some(pair) = self.pairs.get_mut(handle.idx()) {
             Ok((&mut pair.0, &mut pair.1))
         } else {
@@ -433,6 +572,7 @@ impl Heap {
     }
 
     pub fn get_pair_mut_override(&mut self, handle: Handle) -> (&mut Value, &mut Value) {
+        self.write_barrier(Value::Pair(handle));
         if let Some(pair) = self.pairs.get_mut(handle.idx()) {
             (&mut pair.0, &mut pair.1)
         } else {
@@ -472,6 +612,40 @@ impl Heap {
         }
     }
 
+    pub fn get_coroutine(&self, handle: Handle) -> &Coroutine {
+        if let Some(co) = self.coroutines.get(handle.idx()) {
+            co
+        } else {
+            panic!("Handle {} is not a coroutine!", handle.idx());
+        }
+    }
+
+    pub fn get_coroutine_mut(&mut self, handle: Handle) -> &mut Coroutine {
+        self.write_barrier(Value::Coroutine(handle));
+        if let Some(co) = self.coroutines.get_mut(handle.idx()) {
+            co
+        } else {
+            panic!("Handle {} is not a coroutine!", handle.idx());
+        }
+    }
+
+    pub fn get_iter(&self, handle: Handle) -> &SeqIter {
+        if let Some(iter) = self.iters.get(handle.idx()) {
+            iter
+        } else {
+            panic!("Handle {} is not an iterator!", handle.idx());
+        }
+    }
+
+    pub fn get_iter_mut(&mut self, handle: Handle) -> &mut SeqIter {
+        self.write_barrier(Value::Iter(handle));
+        if let Some(iter) = self.iters.get_mut(handle.idx()) {
+            iter
+        } else {
+            panic!("Handle {} is not an iterator!", handle.idx());
+        }
+    }
+
     pub fn get_callframe(&self, handle: Handle) -> &CallFrame {
         if let Some(call_frame) = self.callframes.get(handle.idx()) {
             call_frame
@@ -489,6 +663,7 @@ impl Heap {
     }
 
     pub fn get_value_mut(&mut self, handle: Handle) -> &mut Value {
+        self.write_barrier(Value::Value(handle));
         if let Some(value) = self.values.get_mut(handle.idx()) {
             value
         } else {
@@ -538,6 +713,14 @@ impl Heap {
         mark!(self, value);
     }
 
+    /// Record a write to val, if it is old it goes in the remembered set so anything new it now
+    /// references is found by the next minor collection.
+    fn write_barrier(&mut self, val: Value) {
+        if value_op!(self, val, remember, false) {
+            self.remembered.push(val);
+        }
+    }
+
     fn mark_trace(&mut self, val: Value) {
         mark!(self, val);
         self.greys.push(val);
@@ -560,6 +743,7 @@ impl Heap {
         if let Some(on_error) = call_frame.on_error {
             self.mark_trace(on_error);
         }
+        self.mark_trace(call_frame.restarts);
         self.mark_trace(call_frame.called);
     }
 
@@ -646,6 +830,30 @@ impl Heap {
                     self.mark_trace(*obj);
                 }
             }
+            Value::Coroutine(handle) => {
+                let co = self
+                    .coroutines
+                    .get(handle.idx())
+                    .expect("Invalid coroutine handle!");
+                let mut greys = vec![co.lambda, co.yielded];
+                greys.extend_from_slice(Self::coroutine_stack(co));
+                if let Some(frame) = co.frame.clone() {
+                    self.mark_call_frame(&frame);
+                }
+                for obj in greys {
+                    self.mark_trace(obj);
+                }
+            }
+            Value::Iter(handle) => {
+                let mut greys = Vec::new();
+                self.iters
+                    .get(handle.idx())
+                    .expect("Invalid iterator handle!")
+                    .trace(&mut greys);
+                for obj in greys {
+                    self.mark_trace(obj);
+                }
+            }
             Value::CallFrame(handle) => {
                 let call_frame = self
                     .callframes
@@ -686,42 +894,157 @@ impl Heap {
         if let Some(on_error) = frame.on_error {
             greys.push(on_error);
         }
+        greys.push(frame.restarts);
         greys.push(frame.called);
     }
 
-    fn collect<MarkFunc>(&mut self, mut mark_roots: MarkFunc)
+    /// The part of a suspended coroutine's stack that is in use (empty if not suspended).
+    fn coroutine_stack(co: &Coroutine) -> &[Value] {
+        if let Some(frame) = &co.frame {
+            let stack_max = frame.stack_top + frame.chunk.input_regs + frame.chunk.extra_regs;
+            &co.stack[..=stack_max]
+        } else {
+            &[]
+        }
+    }
+
+    /// Collect garbage when a pool is full, usually a minor collection but a major one every
+    /// major_interval collections or when a minor collection leaves a pool (nearly) full.
+    /// After a major collection the pools are grown so there is room for new objects.
+    fn gc<MarkFunc>(&mut self, mut mark_roots: MarkFunc)
     where
         MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
     {
+        if self.minors_since_major + 1 < self.major_interval {
+            self.collect_minor(&mut mark_roots);
+            if !self.is_nearly_full() {
+                return;
+            }
+        }
+        self.collect(mark_roots);
+        self.objects.grow();
+        self.callframes.grow();
+        self.continuations.grow();
+        self.coroutines.grow();
+        self.iters.grow();
+        self.errors.grow();
+        self.pairs.grow();
+        self.values.grow();
+    }
+
+    /// Run a collection now (even if GC is paused), major for a full collection otherwise minor.
+    pub fn force_gc<MarkFunc>(&mut self, mark_roots: MarkFunc, major: bool)
+    where
+        MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
+    {
+        if major {
+            self.collect(mark_roots);
+        } else {
+            self.collect_minor(mark_roots);
+        }
+    }
+
+    /// True if any pool has less than 1/8 of its capacity free.
+    fn is_nearly_full(&self) -> bool {
+        fn nearly_full<T: Clone>(pool: &Storage<T>) -> bool {
+            pool.live_objects() + pool.capacity() / 8 >= pool.capacity()
+        }
+        nearly_full(&self.objects)
+            || nearly_full(&self.callframes)
+            || nearly_full(&self.continuations)
+            || nearly_full(&self.coroutines)
+            || nearly_full(&self.iters)
+            || nearly_full(&self.errors)
+            || nearly_full(&self.pairs)
+            || nearly_full(&self.values)
+    }
+
+    /// Major (full) collection, traces and frees objects from both generations.
+    fn collect<MarkFunc>(&mut self, mark_roots: MarkFunc)
+    where
+        MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
+    {
+        let start = Instant::now();
         self.objects.clear_marks();
         self.callframes.clear_marks();
         self.continuations.clear_marks();
+        self.coroutines.clear_marks();
+        self.iters.clear_marks();
         self.errors.clear_marks();
         self.pairs.clear_marks();
         self.values.clear_marks();
+        self.remembered.clear();
+        self.mark_and_sweep(mark_roots, false);
+        self.minors_since_major = 0;
+        self.stats.major_collections += 1;
+        self.record_pause(start.elapsed());
+    }
+
+    /// Minor collection, only traces and frees young objects (plus tracing the remembered set).
+    fn collect_minor<MarkFunc>(&mut self, mark_roots: MarkFunc)
+    where
+        MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
+    {
+        let start = Instant::now();
+        self.objects.clear_young_marks();
+        self.callframes.clear_young_marks();
+        self.continuations.clear_young_marks();
+        self.coroutines.clear_young_marks();
+        self.iters.clear_young_marks();
+        self.errors.clear_young_marks();
+        self.pairs.clear_young_marks();
+        self.values.clear_young_marks();
+        self.mark_and_sweep(mark_roots, true);
+        self.minors_since_major += 1;
+        self.stats.minor_collections += 1;
+        self.record_pause(start.elapsed());
+    }
+
+    fn record_pause(&mut self, pause: Duration) {
+        self.stats.last_pause = pause;
+        self.stats.total_pause += pause;
+        if pause > self.stats.max_pause {
+            self.stats.max_pause = pause;
+        }
+    }
+
+    fn mark_and_sweep<MarkFunc>(&mut self, mut mark_roots: MarkFunc, young_only: bool)
+    where
+        MarkFunc: FnMut(&mut Heap) -> VMResult<()>,
+    {
         mark_roots(self).expect("Failed to mark the roots!");
         let mut greys = vec![];
-        self.callframes.trace_all_live(|frame| {
+        self.callframes.trace_live(young_only, |frame| {
             Self::initial_mark_call_frame(frame, &mut greys);
         });
-        self.continuations.trace_all_live(|k| {
+        self.continuations.trace_live(young_only, |k| {
             Self::initial_mark_call_frame(&k.frame, &mut greys);
             for obj in &k.stack {
                 greys.push(*obj);
             }
         });
-        self.errors.trace_all_live(|err| {
+        self.coroutines.trace_live(young_only, |co| {
+            greys.push(co.lambda);
+            greys.push(co.yielded);
+            greys.extend_from_slice(Self::coroutine_stack(co));
+            if let Some(frame) = &co.frame {
+                Self::initial_mark_call_frame(frame, &mut greys);
+            }
+        });
+        self.iters
+            .trace_live(young_only, |iter| iter.trace(&mut greys));
+        self.errors.trace_live(young_only, |err| {
             greys.push(err.data);
         });
-        self.pairs.trace_all_live(|pair| {
+        self.pairs.trace_live(young_only, |pair| {
             greys.push(pair.0);
             greys.push(pair.1);
         });
-        self.values.trace_all_live(|val| {
+        self.values.trace_live(young_only, |val| {
             greys.push(*val);
         });
         let mut objs = Vec::new();
-        self.objects.trace_all_live(|obj| {
+        self.objects.trace_live(young_only, |obj| {
             // this cloning is not great...
             objs.push(obj.clone());
         });
@@ -731,6 +1054,11 @@ impl Heap {
         for v in greys.drain(..) {
             self.mark_trace(v);
         }
+        // Old objects that were written to may now reference young objects.
+        for val in std::mem::take(&mut self.remembered) {
+            value_op!(self, val, retrace, ());
+            self.greys.push(val);
+        }
         while let Some(val) = self.greys.pop() {
             if !self.is_traced_and_set(val) {
                 self.trace(val);
@@ -741,11 +1069,22 @@ impl Heap {
         props.retain(|key, _val| self.is_live(*key));
         self.props = Some(props);
         self.objects.set_all_dead(Object::Empty);
+        // Survivors are now old.
+        self.objects.promote_live();
+        self.callframes.promote_live();
+        self.continuations.promote_live();
+        self.coroutines.promote_live();
+        self.iters.promote_live();
+        self.errors.promote_live();
+        self.pairs.promote_live();
+        self.values.promote_live();
     }
 
     pub fn live_objects(&self) -> usize {
         self.objects.live_objects()
             + self.continuations.live_objects()
+            + self.coroutines.live_objects()
+            + self.iters.live_objects()
             + self.callframes.live_objects()
             + self.pairs.live_objects()
             + self.values.live_objects()
@@ -762,6 +1101,8 @@ impl Heap {
     }
 
     pub fn set_property(&mut self, key_value: Value, prop: Interned, value: Value) {
+        // Properties are traced with their key so treat this as a write to the key.
+        self.write_barrier(key_value);
         if let Some(map) = self.props_mut().get_mut(&key_value) {
             let map = Arc::make_mut(map);
             map.insert(prop, value);
@@ -1117,4 +1458,116 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_generations() -> VMResult<()> {
+        let mut heap = Heap::default();
+        let outers = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let outers_mark = outers.clone();
+        let mark_roots = |heap: &mut Heap| -> VMResult<()> {
+            for h in outers_mark.borrow().iter() {
+                heap.mark(*h);
+            }
+            Ok(())
+        };
+        let outer = heap.alloc_pair(1.into(), Value::Nil, MutState::Mutable, mark_roots);
+        outers.borrow_mut().push(outer);
+        heap.alloc_pair(2.into(), Value::Nil, MutState::Mutable, mark_roots);
+        heap.collect(mark_roots);
+        assert_eq!(heap.live_objects(), 1);
+        assert_eq!(heap.pairs.old_objects(), 1);
+
+        // A young pair only reachable from the old pair must be found through the write barrier.
+        let young = heap.alloc_pair(3.into(), Value::Nil, MutState::Mutable, mark_roots);
+        let (car, _) = heap.get_pair_mut(outer.get_handle().unwrap())?;
+        *car = young;
+        heap.alloc_pair(4.into(), Value::Nil, MutState::Mutable, mark_roots);
+        heap.collect_minor(mark_roots);
+        assert_eq!(heap.live_objects(), 2);
+        assert_eq!(heap.pairs.old_objects(), 2);
+        if let (Value::Pair(h), Value::Nil) = heap.get_pair(outer.get_handle().unwrap()) {
+            if let (Value::Int(v), Value::Nil) = heap.get_pair(h) {
+                assert_eq!(from_i56(&v), 3);
+            } else {
+                panic!();
+            }
+        } else {
+            panic!();
+        }
+
+        // Old garbage is only freed by a major collection.
+        outers.borrow_mut().clear();
+        heap.collect_minor(mark_roots);
+        assert_eq!(heap.live_objects(), 2);
+        heap.collect(mark_roots);
+        assert_eq!(heap.live_objects(), 0);
+        assert_eq!(heap.pairs.old_objects(), 0);
+
+        let stats = heap.gc_stats();
+        assert_eq!(stats.minor_collections, 2);
+        assert_eq!(stats.major_collections, 2);
+        assert!(stats.max_pause >= stats.last_pause);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_barriers() -> VMResult<()> {
+        let mut heap = Heap::default();
+        // Only used to hash map keys.
+        let vm = crate::Vm::new();
+        let roots = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let roots_mark = roots.clone();
+        let mark_roots = |heap: &mut Heap| -> VMResult<()> {
+            for h in roots_mark.borrow().iter() {
+                heap.mark(*h);
+            }
+            Ok(())
+        };
+        let vector = heap.alloc_vector(vec![], MutState::Mutable, mark_roots);
+        let map = heap.alloc_map(VMHashMap::default(), MutState::Mutable, mark_roots);
+        let value = heap.alloc_value(Value::Nil, MutState::Mutable, mark_roots);
+        roots.borrow_mut().extend([vector, map, value]);
+        heap.collect(mark_roots);
+        assert_eq!(heap.objects.old_objects(), 2);
+        assert_eq!(heap.values.old_objects(), 1);
+
+        // The second round makes sure old objects are remembered again after a minor collection.
+        for round in 0..2 {
+            // Young objects only reachable from old ones (through the write barriers).
+            let string = heap.alloc_string(format!("round {round}"), MutState::Mutable, mark_roots);
+            heap.get_vector_mut(vector.get_handle().unwrap())?
+                .push(string);
+            let pair = heap.alloc_pair(round.into(), Value::Nil, MutState::Mutable, mark_roots);
+            heap.get_map_mut(map.get_handle().unwrap())?
+                .insert(&vm, round.into(), pair);
+            let boxed = heap.alloc_pair(round.into(), Value::Nil, MutState::Mutable, mark_roots);
+            *heap.get_value_mut(value.get_handle().unwrap()) = boxed;
+            let garbage_string = heap.alloc_string("garbage".into(), MutState::Mutable, mark_roots);
+            let garbage_pair =
+                heap.alloc_pair(Value::Nil, Value::Nil, MutState::Mutable, mark_roots);
+
+            heap.collect_minor(mark_roots);
+            assert!(heap.is_live(string));
+            assert!(heap.is_live(pair));
+            assert!(heap.is_live(boxed));
+            assert!(!heap.is_live(garbage_string));
+            assert!(!heap.is_live(garbage_pair));
+            let strings: Vec<&str> = heap
+                .get_vector(vector.get_handle().unwrap())
+                .iter()
+                .map(|s| heap.get_string(s.get_handle().unwrap()))
+                .collect();
+            assert_eq!(strings.len(), round as usize + 1);
+            assert_eq!(strings[round as usize], format!("round {round}"));
+            assert_eq!(heap.get_value(value.get_handle().unwrap()), boxed);
+            assert!(heap.remembered.is_empty());
+        }
+        assert_eq!(heap.get_map(map.get_handle().unwrap()).len(), 2);
+
+        // The first boxed pair was replaced, it is old so only a major collection frees it.
+        assert_eq!(heap.pairs.live_objects(), 4);
+        heap.collect(mark_roots);
+        assert_eq!(heap.pairs.live_objects(), 3);
+        Ok(())
+    }
 }
@@ -2,6 +2,10 @@ pub const FLAG_MARK: u8 = 0x01;
 pub const FLAG_STICKY: u8 = 0x02;
 pub const FLAG_MUT: u8 = 0x04;
 pub const FLAG_TRACED: u8 = 0x08;
+/// Object survived a collection (is in the old generation).
+pub const FLAG_OLD: u8 = 0x10;
+/// Old object that has been written to since the last collection (is in the remembered set).
+pub const FLAG_DIRTY: u8 = 0x20;
 
 #[macro_export]
 macro_rules! is_bit_set {
@@ -41,3 +45,7 @@ pub fn is_mutable(flag: u8) -> bool {
 pub fn is_traced(flag: u8) -> bool {
     is_bit_set!(flag, FLAG_TRACED)
 }
+
+pub fn is_old(flag: u8) -> bool {
+    is_bit_set!(flag, FLAG_OLD)
+}
@@ -1,6 +1,6 @@
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::sync::{Arc, Mutex, MutexGuard};
 
 #[derive(Copy, Clone, Debug)]
@@ -93,6 +93,30 @@ pub struct IoGuard<'a> {
     io: MutexGuard<'a, Io>,
 }
 
+impl IoGuard<'_> {
+    /// Read bytes into buf until delim (inclusive) or EOF, returns the number of bytes read.
+    /// Buffered readers and stdin use BufRead, an unbuffered file is read a byte at a time so
+    /// nothing past delim is consumed.
+    pub fn read_until(&mut self, delim: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
+        match &mut *self.io {
+            Io::FileReadBuf(io) => io.read_until(delim, buf),
+            Io::StdIn => io::stdin().lock().read_until(delim, buf),
+            io => {
+                let mut byte = [0_u8];
+                let mut read = 0;
+                while io.read(&mut byte)? == 1 {
+                    buf.push(byte[0]);
+                    read += 1;
+                    if byte[0] == delim {
+                        break;
+                    }
+                }
+                Ok(read)
+            }
+        }
+    }
+}
+
 impl Read for IoGuard<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.io.read(buf)
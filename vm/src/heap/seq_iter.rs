@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+use crate::Value;
+
+/// State of a native (lazy) iterator.  Calling one produces its next element or :*iter-empty*.
+/// Iterators wrapped by adapters (iter) can be any iterator, native or a lisp callable.
+/// Temporary elements are kept in the state (not on the Rust stack) so they are traced while
+/// calling back into the VM.
+#[derive(Clone, Debug)]
+pub enum SeqIter {
+    /// Exhausted, always produces :*iter-empty*.
+    Empty,
+    Vector {
+        vector: Value,
+        idx: usize,
+    },
+    List {
+        list: Value,
+    },
+    Chars {
+        string: Value,
+        byte_idx: usize,
+    },
+    Range {
+        next: i64,
+        end: Option<i64>,
+        step: i64,
+    },
+    Lines {
+        io: Value,
+        /// Line buffer, reused for each line.
+        buf: Vec<u8>,
+    },
+    Map {
+        iter: Value,
+        lambda: Value,
+    },
+    Filter {
+        iter: Value,
+        lambda: Value,
+    },
+    Take {
+        iter: Value,
+        remaining: usize,
+    },
+    Drop {
+        iter: Value,
+        skip: usize,
+    },
+    TakeWhile {
+        iter: Value,
+        lambda: Value,
+    },
+    DropWhile {
+        iter: Value,
+        lambda: Value,
+        dropping: bool,
+    },
+    Enumerate {
+        iter: Value,
+        idx: i64,
+    },
+    Zip {
+        iters: Vec<Value>,
+        buf: Vec<Value>,
+    },
+    Chain {
+        iters: Vec<Value>,
+        idx: usize,
+    },
+    Interleave {
+        iters: Vec<Value>,
+        idx: usize,
+    },
+    FlatMap {
+        iter: Value,
+        lambda: Value,
+        /// Iterator for the current element (Undefined between elements).
+        inner: Value,
+    },
+    Partition {
+        iter: Value,
+        size: usize,
+        buf: Vec<Value>,
+    },
+    Windowed {
+        iter: Value,
+        size: usize,
+        window: VecDeque<Value>,
+    },
+    Dedupe {
+        iter: Value,
+        /// Last element produced (Undefined before the first).
+        last: Value,
+    },
+    Cycle {
+        /// Source iterator, Undefined once it is exhausted.
+        iter: Value,
+        seen: Vec<Value>,
+        idx: usize,
+    },
+}
+
+impl SeqIter {
+    /// Push all the values this iterator references onto greys (for the GC).
+    pub fn trace(&self, greys: &mut Vec<Value>) {
+        match self {
+            SeqIter::Empty | SeqIter::Range { .. } => {}
+            SeqIter::Vector { vector: val, .. }
+            | SeqIter::List { list: val }
+            | SeqIter::Chars { string: val, .. }
+            | SeqIter::Lines { io: val, .. }
+            | SeqIter::Take { iter: val, .. }
+            | SeqIter::Drop { iter: val, .. }
+            | SeqIter::Enumerate { iter: val, .. } => greys.push(*val),
+            SeqIter::Map { iter, lambda }
+            | SeqIter::Filter { iter, lambda }
+            | SeqIter::TakeWhile { iter, lambda }
+            | SeqIter::DropWhile { iter, lambda, .. } => {
+                greys.push(*iter);
+                greys.push(*lambda);
+            }
+            SeqIter::Zip { iters, buf } => {
+                greys.extend_from_slice(iters);
+                greys.extend_from_slice(buf);
+            }
+            SeqIter::Chain { iters, .. } | SeqIter::Interleave { iters, .. } => {
+                greys.extend_from_slice(iters)
+            }
+            SeqIter::FlatMap {
+                iter,
+                lambda,
+                inner,
+            } => {
+                greys.push(*iter);
+                greys.push(*lambda);
+                greys.push(*inner);
+            }
+            SeqIter::Partition { iter, buf, .. } => {
+                greys.push(*iter);
+                greys.extend_from_slice(buf);
+            }
+            SeqIter::Windowed { iter, window, .. } => {
+                greys.push(*iter);
+                greys.extend(window.iter());
+            }
+            SeqIter::Dedupe { iter, last } => {
+                greys.push(*iter);
+                greys.push(*last);
+            }
+            SeqIter::Cycle { iter, seen, .. } => {
+                greys.push(*iter);
+                greys.extend_from_slice(seen);
+            }
+        }
+    }
+}
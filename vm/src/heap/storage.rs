@@ -1,5 +1,6 @@
 use crate::bits::{
-    is_live, is_marked, is_mutable, is_traced, FLAG_MARK, FLAG_MUT, FLAG_STICKY, FLAG_TRACED,
+    is_live, is_marked, is_mutable, is_old, is_traced, FLAG_DIRTY, FLAG_MARK, FLAG_MUT, FLAG_OLD,
+    FLAG_STICKY, FLAG_TRACED,
 };
 use crate::{clear_bit, is_bit_set, set_bit};
 
@@ -11,6 +12,9 @@ pub(super) struct Storage<T: Clone> {
     live_objects: usize,
     sticky_objects: usize,
     grow_factor: f64,
+    // Slots before this are in use (nothing is freed between collections) so start the search for
+    // a free slot here.
+    next_free: usize,
 }
 
 impl<T: Clone> Storage<T> {
@@ -23,6 +27,7 @@ impl<T: Clone> Storage<T> {
             live_objects: 0,
             sticky_objects: 0,
             grow_factor: 2.0,
+            next_free: 0,
         }
     }
 
@@ -46,14 +51,19 @@ impl<T: Clone> Storage<T> {
         self.live_objects
     }
 
+    /// Grow capacity to live_objects * grow_factor if that is larger than the current capacity.
+    pub fn grow(&mut self) {
+        let new_min = (self.live_objects as f64 * self.grow_factor) as usize;
+        if new_min > self.capacity {
+            self.capacity = new_min;
+            self.flags.reserve(new_min - self.flags.len());
+            self.vals.reserve((new_min - self.vals.len()) + 1);
+        }
+    }
+
     pub fn alloc(&mut self, obj: T, flags: u8) -> u32 {
         if self.live_objects >= self.capacity {
-            let new_min = (self.live_objects as f64 * self.grow_factor) as usize;
-            if new_min > self.capacity {
-                self.capacity = new_min;
-                self.flags.reserve(new_min - self.flags.len());
-                self.vals.reserve((new_min - self.vals.len()) + 1);
-            }
+            self.grow();
         }
         if self.vals.len() < self.capacity {
             let idx = self.vals.len();
@@ -62,12 +72,13 @@ impl<T: Clone> Storage<T> {
             self.live_objects += 1;
             idx as u32
         } else {
-            for (idx, flag) in self.flags.iter_mut().enumerate() {
+            for (idx, flag) in self.flags.iter_mut().enumerate().skip(self.next_free) {
                 if !is_live(*flag) {
                     self.live_objects += 1;
                     *flag = flags | FLAG_MARK;
                     self.vals.push(obj);
                     self.vals.swap_remove(idx);
+                    self.next_free = idx + 1;
                     return idx as u32;
                 }
             }
@@ -75,10 +86,42 @@ impl<T: Clone> Storage<T> {
         }
     }
 
+    /// Number of live objects in the old generation.
+    pub fn old_objects(&self) -> usize {
+        self.flags
+            .iter()
+            .filter(|flag| is_old(**flag) && is_live(**flag))
+            .count()
+    }
+
+    /// Clear the marks on all objects (for a major collection), this also empties the old
+    /// generation.
     pub fn clear_marks(&mut self) {
         self.live_objects = 0;
+        self.next_free = 0;
+        for flag in self.flags.iter_mut() {
+            clear_bit!(*flag, FLAG_MARK);
+            clear_bit!(*flag, FLAG_TRACED);
+            clear_bit!(*flag, FLAG_OLD);
+            clear_bit!(*flag, FLAG_DIRTY);
+            // if it is sticky mark it
+            if is_bit_set!(*flag, FLAG_STICKY) {
+                self.live_objects += 1;
+                set_bit!(*flag, FLAG_MARK);
+            }
+        }
+    }
+
+    /// Clear the marks on young objects only (for a minor collection).
+    /// Old objects stay marked and traced so they are neither collected nor traced again.
+    pub fn clear_young_marks(&mut self) {
         self.live_objects = 0;
+        self.next_free = 0;
         for flag in self.flags.iter_mut() {
+            if is_old(*flag) {
+                self.live_objects += 1;
+                continue;
+            }
             clear_bit!(*flag, FLAG_MARK);
             clear_bit!(*flag, FLAG_TRACED);
             // if it is sticky mark it
@@ -89,6 +132,43 @@ impl<T: Clone> Storage<T> {
         }
     }
 
+    /// Move every live object into the old generation (done at the end of a collection).
+    pub fn promote_live(&mut self) {
+        for flag in self.flags.iter_mut() {
+            if is_live(*flag) {
+                set_bit!(*flag, FLAG_OLD);
+            } else {
+                clear_bit!(*flag, FLAG_OLD);
+                clear_bit!(*flag, FLAG_DIRTY);
+            }
+        }
+    }
+
+    /// Write barrier, returns true if the object at index is old and was not already remembered.
+    /// The caller must add it to the remembered set so it is traced on the next minor collection.
+    pub fn remember(&mut self, idx: usize) -> bool {
+        if let Some(flag) = self.flags.get_mut(idx) {
+            if is_old(*flag) && !is_bit_set!(*flag, FLAG_DIRTY) {
+                set_bit!(*flag, FLAG_DIRTY);
+                return true;
+            }
+            false
+        } else {
+            panic!("Invalid object handle in remember!")
+        }
+    }
+
+    /// Take a remembered object out of the remembered set and clear its traced bit so it will be
+    /// traced again.
+    pub fn retrace(&mut self, idx: usize) {
+        if let Some(flag) = self.flags.get_mut(idx) {
+            clear_bit!(*flag, FLAG_DIRTY);
+            clear_bit!(*flag, FLAG_TRACED);
+        } else {
+            panic!("Invalid object handle in retrace!")
+        }
+    }
+
     /// Is the object at index still alive after GC.
     pub fn is_live(&self, idx: usize) -> bool {
         if let Some(flag) = self.flags.get(idx) {
@@ -169,9 +249,10 @@ impl<T: Clone> Storage<T> {
         }
     }
 
-    pub fn trace_all_live<FN: FnMut(&T)>(&mut self, mut trace: FN) {
+    /// Trace all the live objects, if young_only then old objects are skipped (minor collection).
+    pub fn trace_live<FN: FnMut(&T)>(&mut self, young_only: bool, mut trace: FN) {
         for (flag, value) in self.flags.iter_mut().zip(self.vals.iter()) {
-            if is_live(*flag) {
+            if is_live(*flag) && !(young_only && is_old(*flag)) {
                 set_bit!(*flag, FLAG_TRACED);
                 trace(value);
             }
@@ -19,6 +19,9 @@ pub use crate::vm::*;
 pub mod interner;
 pub use crate::interner::*;
 
+pub mod send_value;
+pub use crate::send_value::*;
+
 pub mod fxhasher;
 pub use crate::fxhasher::*;
 
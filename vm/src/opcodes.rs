@@ -98,9 +98,12 @@ pub const MKERR: OpCode = FLOW_BASE + 24;
 pub const ISERR: OpCode = FLOW_BASE + 25;
 // ISOK A B - R(A) is #f if R(B) is an error type, #t otherwise
 pub const ISOK: OpCode = FLOW_BASE + 26;
+// YIELD A - suspend the running coroutine, resume returns R(A) and R(A) is set to the value passed
+// to the next resume
+pub const YIELD: OpCode = FLOW_BASE + 27;
 
 // Basic math
-const MATH_BASE: OpCode = FLOW_BASE + 27;
+const MATH_BASE: OpCode = FLOW_BASE + 28;
 // ADD A B - set R(A) = R(A) + R(B)
 pub const ADD: OpCode = MATH_BASE;
 // SUB A B - set R(A) = R(A) - R(B)
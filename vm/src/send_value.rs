@@ -0,0 +1,256 @@
+//! Values that can be sent to another VM (on another thread).
+//!
+//! A SendValue is a deep copy of a value that does not refer to any heap, export a value from one
+//! VM and import it into another.  Only data can be sent (no functions, files etc).
+
+use std::collections::HashSet;
+
+use crate::vm_hashmap::{VMHashMap, ValHash};
+use crate::{Error, GVm, Handle, VMError, VMErrorObj, VMResult, Value};
+
+/// A deep copy of a value, independent of any VM.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SendValue {
+    Byte(u8),
+    Int(i64),
+    Float(f64),
+    Char(char),
+    CharCluster(String),
+    Symbol(String),
+    Keyword(String),
+    StringConst(String),
+    True,
+    False,
+    Nil,
+    Undefined,
+    String(String),
+    Bytes(Vec<u8>),
+    Vector(Vec<SendValue>),
+    Map(Vec<(SendValue, SendValue)>),
+    /// A list of elements and the final cdr (Nil for a proper list).
+    List(Vec<SendValue>, Box<SendValue>),
+    Error(String, Box<SendValue>),
+}
+
+impl SendValue {
+    /// An error value with key and message, used to report errors across VMs.
+    pub fn error(key: &str, message: impl Into<String>) -> Self {
+        SendValue::Error(key.to_string(), Box::new(SendValue::String(message.into())))
+    }
+
+    /// Convert a VMError (from another VM) to an error value.
+    pub fn from_vm_error<ENV>(vm: &GVm<ENV>, err: &VMError) -> Self {
+        match &err.obj {
+            VMErrorObj::Message(msg) => Self::error(err.key, msg.clone()),
+            VMErrorObj::Object(obj) => match vm.export_value(*obj) {
+                Ok(err @ SendValue::Error(_, _)) => err,
+                Ok(obj) => SendValue::Error(err.key.to_string(), Box::new(obj)),
+                Err(e) => Self::error(err.key, e.to_string()),
+            },
+        }
+    }
+}
+
+/// Add handle to the containers currently being copied, seeing one again means the value is
+/// circular.
+fn enter(path: &mut HashSet<Handle>, handle: Handle) -> VMResult<()> {
+    if path.insert(handle) {
+        Ok(())
+    } else {
+        Err(VMError::new_vm("send: can not send a circular value"))
+    }
+}
+
+impl<ENV> GVm<ENV> {
+    /// Deep copy val so it can be sent to another VM.  Errors if val is or contains something
+    /// that can not be copied (for instance a lambda or file) or is circular.
+    pub fn export_value(&self, val: Value) -> VMResult<SendValue> {
+        self.export_inner(val, &mut HashSet::new())
+    }
+
+    fn export_inner(&self, val: Value, path: &mut HashSet<Handle>) -> VMResult<SendValue> {
+        Ok(match val {
+            Value::Byte(b) => SendValue::Byte(b),
+            Value::Int(_) => SendValue::Int(val.get_int(self)?),
+            Value::Float(_) => SendValue::Float(val.get_float(self)?),
+            Value::CodePoint(ch) => SendValue::Char(ch),
+            Value::CharCluster(_, _) | Value::CharClusterLong(_) => {
+                SendValue::CharCluster(val.pretty_value(self))
+            }
+            Value::Symbol(i) => SendValue::Symbol(self.get_interned(i).to_string()),
+            Value::Keyword(i) => SendValue::Keyword(self.get_interned(i).to_string()),
+            Value::StringConst(i) => SendValue::StringConst(self.get_interned(i).to_string()),
+            Value::True => SendValue::True,
+            Value::False => SendValue::False,
+            Value::Nil => SendValue::Nil,
+            Value::Undefined => SendValue::Undefined,
+            Value::String(h) => SendValue::String(self.get_string(h).to_string()),
+            Value::Bytes(h) => SendValue::Bytes(self.get_bytes(h).to_vec()),
+            Value::Value(h) => self.export_inner(self.get_value(h), path)?,
+            Value::Vector(h) => {
+                enter(path, h)?;
+                let v = self
+                    .get_vector(h)
+                    .iter()
+                    .map(|v| self.export_inner(*v, path))
+                    .collect::<VMResult<Vec<SendValue>>>()?;
+                path.remove(&h);
+                SendValue::Vector(v)
+            }
+            Value::Map(h) => {
+                enter(path, h)?;
+                let mut map = Vec::with_capacity(self.get_map(h).len());
+                for (key, val) in self.get_map(h).iter() {
+                    map.push((self.export_inner(key, path)?, self.export_inner(val, path)?));
+                }
+                path.remove(&h);
+                SendValue::Map(map)
+            }
+            Value::List(h, idx) => {
+                enter(path, h)?;
+                let v = self.get_vector(h)[idx as usize..]
+                    .iter()
+                    .map(|v| self.export_inner(*v, path))
+                    .collect::<VMResult<Vec<SendValue>>>()?;
+                path.remove(&h);
+                SendValue::List(v, Box::new(SendValue::Nil))
+            }
+            Value::Pair(_) => {
+                // Walk the cdrs in a loop so long lists do not use up the Rust stack.
+                let mut items = Vec::new();
+                let mut pairs = Vec::new();
+                let mut tail = val;
+                while let Value::Pair(h) = tail {
+                    enter(path, h)?;
+                    pairs.push(h);
+                    let (car, cdr) = self.get_pair(h);
+                    items.push(self.export_inner(car, path)?);
+                    tail = cdr;
+                }
+                let tail = self.export_inner(tail, path)?;
+                for h in pairs {
+                    path.remove(&h);
+                }
+                SendValue::List(items, Box::new(tail))
+            }
+            Value::Error(h) => {
+                let err = self.get_error(h);
+                SendValue::Error(
+                    self.get_interned(err.keyword).to_string(),
+                    Box::new(self.export_inner(err.data, path)?),
+                )
+            }
+            _ => {
+                return Err(VMError::new_vm(format!(
+                    "send: can not send a {} to another VM",
+                    val.display_type(self)
+                )))
+            }
+        })
+    }
+
+    /// Allocate a copy of val in this VM.
+    pub fn import_value(&mut self, val: SendValue) -> Value {
+        self.pause_gc();
+        let res = self.import_inner(val);
+        self.unpause_gc();
+        res
+    }
+
+    fn import_inner(&mut self, val: SendValue) -> Value {
+        match val {
+            SendValue::Byte(b) => Value::Byte(b),
+            SendValue::Int(i) => i.into(),
+            SendValue::Float(f) => f.into(),
+            SendValue::Char(ch) => Value::CodePoint(ch),
+            SendValue::CharCluster(s) => self.alloc_char(&s),
+            SendValue::Symbol(s) => Value::Symbol(self.intern(&s)),
+            SendValue::Keyword(s) => Value::Keyword(self.intern(&s)),
+            SendValue::StringConst(s) => Value::StringConst(self.intern(&s)),
+            SendValue::True => Value::True,
+            SendValue::False => Value::False,
+            SendValue::Nil => Value::Nil,
+            SendValue::Undefined => Value::Undefined,
+            SendValue::String(s) => self.alloc_string(s),
+            SendValue::Bytes(b) => self.alloc_bytes(b),
+            SendValue::Vector(v) => {
+                let v = v.into_iter().map(|v| self.import_inner(v)).collect();
+                self.alloc_vector(v)
+            }
+            SendValue::Map(m) => {
+                let mut map = VMHashMap::with_capacity(m.len());
+                for (key, val) in m {
+                    let key = self.import_inner(key);
+                    let val = self.import_inner(val);
+                    map.insert_id(ValHash::from_value(self, key), val);
+                }
+                self.alloc_map(map)
+            }
+            SendValue::List(items, tail) => {
+                let mut list = self.import_inner(*tail);
+                let items: Vec<Value> = items.into_iter().map(|v| self.import_inner(v)).collect();
+                for item in items.into_iter().rev() {
+                    list = self.alloc_pair(item, list);
+                }
+                list
+            }
+            SendValue::Error(key, data) => {
+                let keyword = self.intern(&key);
+                let data = self.import_inner(*data);
+                self.alloc_error(Error { keyword, data })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vm;
+
+    #[test]
+    fn test_send_value() {
+        let mut vm = Vm::new();
+        let mut other = Vm::new();
+
+        let s = vm.alloc_string("text".to_string());
+        let k = Value::Keyword(vm.intern("key"));
+        let tail = vm.alloc_pair(s, 1.into());
+        let list = vm.alloc_pair(k, tail);
+        let mut map = VMHashMap::new();
+        map.insert(&vm, k, 2.5.into());
+        let map = vm.alloc_map(map);
+        let ch = vm.alloc_char("e\u{301}");
+        let vector = vm.alloc_vector(vec![list, map, Value::CodePoint('x'), ch, Value::Nil]);
+
+        let sent = vm.export_value(vector).unwrap();
+        assert_eq!(
+            sent,
+            SendValue::Vector(vec![
+                SendValue::List(
+                    vec![
+                        SendValue::Keyword("key".to_string()),
+                        SendValue::String("text".to_string())
+                    ],
+                    Box::new(SendValue::Int(1))
+                ),
+                SendValue::Map(vec![(
+                    SendValue::Keyword("key".to_string()),
+                    SendValue::Float(2.5)
+                )]),
+                SendValue::Char('x'),
+                SendValue::CharCluster("e\u{301}".to_string()),
+                SendValue::Nil,
+            ])
+        );
+        let received = other.import_value(sent.clone());
+        assert_eq!(other.export_value(received).unwrap(), sent);
+
+        // Circular values can not be sent.
+        let pair = vm.alloc_pair(1.into(), Value::Nil);
+        if let Value::Pair(h) = pair {
+            *vm.get_pair_mut(h).unwrap().1 = pair;
+        }
+        assert!(vm.export_value(pair).is_err());
+    }
+}
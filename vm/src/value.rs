@@ -190,6 +190,8 @@ pub enum Value {
     Lambda(Handle),
     Closure(Handle),
     Continuation(Handle),
+    Coroutine(Handle),
+    Iter(Handle),
     CallFrame(Handle),
     Value(Handle),
     Error(Handle),
@@ -353,6 +355,8 @@ impl Value {
             Value::Lambda(handle) => Some(*handle),
             Value::Closure(handle) => Some(*handle),
             Value::Continuation(handle) => Some(*handle),
+            Value::Coroutine(handle) => Some(*handle),
+            Value::Iter(handle) => Some(*handle),
             Value::CallFrame(handle) => Some(*handle),
             Value::Value(handle) => Some(*handle),
             Value::Error(handle) => Some(*handle),
@@ -496,6 +500,8 @@ impl Value {
             Value::Lambda(_) => "#<Lambda>".to_string(),
             Value::Closure(_) => "#<Lambda>".to_string(),
             Value::Continuation(_) => "#<Continuation>".to_string(),
+            Value::Coroutine(_) => "#<Coroutine>".to_string(),
+            Value::Iter(_) => "#<Iter>".to_string(),
             Value::CallFrame(_) => "#<CallFrame>".to_string(),
             Value::Vector(handle) => {
                 let v = vm.get_vector(*handle);
@@ -586,6 +592,8 @@ impl Value {
             Value::Lambda(_) => ValueType::Lambda,
             Value::Closure(_) => ValueType::Closure,
             Value::Continuation(_) => ValueType::Continuation,
+            Value::Coroutine(_) => ValueType::Coroutine,
+            Value::Iter(_) => ValueType::Iter,
             Value::CallFrame(_) => ValueType::CallFrame,
             Value::Error(_) => ValueType::Error,
             Value::Io(_) => ValueType::Io,
@@ -653,6 +661,8 @@ impl Value {
             | Value::Lambda(_)
             | Value::Closure(_)
             | Value::Continuation(_)
+            | Value::Coroutine(_)
+            | Value::Iter(_)
             | Value::CallFrame(_)
             | Value::Value(_)
             | Value::Error(_)
@@ -749,6 +759,8 @@ pub const SLOSH_UNDEFINED: &str = "Undefined";
 pub const SLOSH_LAMBDA: &str = "Lambda";
 pub const SLOSH_CLOSURE: &str = "Lambda";
 pub const SLOSH_CONTINUATION: &str = "Continuation";
+pub const SLOSH_COROUTINE: &str = "Coroutine";
+pub const SLOSH_ITER: &str = "Iter";
 pub const SLOSH_CALLFRAME: &str = "CallFrame";
 pub const SLOSH_VECTOR: &str = "Vector";
 pub const SLOSH_MAP: &str = "Map";
@@ -783,6 +795,8 @@ pub enum ValueType {
     Lambda,
     Closure,
     Continuation,
+    Coroutine,
+    Iter,
     CallFrame,
     Error,
     Io,
@@ -816,6 +830,8 @@ impl From<ValueType> for &'static str {
             ValueType::Lambda => SLOSH_LAMBDA,
             ValueType::Closure => SLOSH_LAMBDA,
             ValueType::Continuation => SLOSH_CONTINUATION,
+            ValueType::Coroutine => SLOSH_COROUTINE,
+            ValueType::Iter => SLOSH_ITER,
             ValueType::CallFrame => SLOSH_CALLFRAME,
             ValueType::Vector => SLOSH_VECTOR,
             ValueType::Map => SLOSH_MAP,
@@ -1,10 +1,11 @@
 use std::alloc;
 use std::alloc::Layout;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 use crate::{
-    from_i56, CallFrame, CallFunc, CallFuncSig, Chunk, Globals, Handle, Heap, Interner, VMError,
-    VMErrorObj, VMResult, Value, HALT,
+    from_i56, CallFrame, CallFunc, CallFuncSig, Chunk, Globals, Handle, Heap, Interned, Interner,
+    VMError, VMErrorObj, VMResult, Value, HALT,
 };
 
 mod cons;
@@ -13,13 +14,22 @@ mod storage;
 pub mod macros;
 mod call;
 mod call_collection;
+mod coroutine;
+mod coverage;
 mod exec_loop;
+mod profile;
+mod seq_iter;
+pub use coverage::*;
+pub use profile::*;
 
 /// Size (in elements/Values) of the stack.
 pub const STACK_CAP: usize = 1024;
 
 const DEAD_CODE: [u8; 3] = [HALT, HALT, HALT];
 
+/// Function called between instructions when its flag is set (see [`GVm::set_safe_point`]).
+pub type SafePointFunc<ENV> = fn(&mut GVm<ENV>) -> VMResult<()>;
+
 /// Hold state of the VM, this is for making re-entrant calls on the VM.
 struct VmState {
     stack_top: usize,
@@ -28,19 +38,38 @@ struct VmState {
     current_ip: *const u8,
     this_fn: Option<Value>,
     on_error: Option<Value>,
+    restarts: Value,
     defers: Vec<Value>,
 }
 
+/// Saved state of the caller of a running coroutine, restored when the coroutine yields or returns.
+struct CoroutineCaller {
+    coroutine: Handle,
+    // The running coroutine's stack, the VM stack points into this.
+    co_stack: Vec<Value>,
+    // Stacks the coroutine grew out of while running, a builtin it called may still have a slice
+    // of its registers in one so they are freed when it yields or returns.
+    retired: Vec<Vec<Value>>,
+    stack: *mut Value,
+    stack_cap: usize,
+    k_stack_top: Option<usize>,
+    state: VmState,
+}
+
 pub struct GVm<ENV> {
     interner: Interner,
     heap: Option<Heap>,
     //stack: [Value; STACK_CAP],
     stack: *mut Value,
+    // Number of Values in stack, STACK_CAP except for a coroutine's stack.
+    stack_cap: usize,
     registers: *mut Value,
     globals: Globals,
     buitins: Vec<CallFunc<ENV>>,
     this_fn: Option<Value>,
     on_error: Option<Value>,
+    // List of restarts established by restart-case (dynamically scoped like on_error).
+    restarts: Value,
 
     err_frame: Option<CallFrame>,
     stack_top: usize,
@@ -50,6 +79,14 @@ pub struct GVm<ENV> {
     current_ip_ptr: *const u8,
     callframe_id: usize,
     defers: Vec<Value>,
+    // Callers of running coroutines (innermost last), None marks a re-entrant call (do_call etc)
+    // that a coroutine can not yield across.
+    co_callers: Vec<Option<CoroutineCaller>>,
+    // The :*iter-empty* keyword that ends iterators.
+    iter_empty: Interned,
+    profiler: Option<Box<Profiler>>,
+    coverage: Option<Box<Coverage>>,
+    safe_point: Option<(&'static AtomicBool, SafePointFunc<ENV>)>,
     env: ENV,
 }
 
@@ -82,15 +119,19 @@ impl<ENV> GVm<ENV> {
             let val = unsafe { stack.add(i).as_mut().expect("cant be null!") };
             *val = Value::Undefined;
         }
+        let mut interner = Interner::with_capacity(8192);
+        let iter_empty = interner.intern_static("*iter-empty*");
         Self {
-            interner: Interner::with_capacity(8192),
+            interner,
             heap: Some(Heap::new()),
             stack, //: [Value::Undefined; STACK_CAP],
+            stack_cap: STACK_CAP,
             registers: stack,
             globals,
             buitins: Vec::new(),
             this_fn: None,
             on_error: None,
+            restarts: Value::Nil,
             err_frame: None,
             stack_top: 0,
             k_stack_top: None,
@@ -99,6 +140,11 @@ impl<ENV> GVm<ENV> {
             current_ip_ptr: DEAD_CODE.as_ptr(),
             callframe_id: 0,
             defers: Vec::new(),
+            co_callers: Vec::new(),
+            iter_empty,
+            profiler: None,
+            coverage: None,
+            safe_point: None,
             env,
         }
     }
@@ -107,6 +153,16 @@ impl<ENV> GVm<ENV> {
         self.this_fn
     }
 
+    /// The current restarts (a list, nil if none), see restart-case.
+    pub fn restarts(&self) -> Value {
+        self.restarts
+    }
+
+    /// Set the current restarts, like on_error these are restored when the current call returns.
+    pub fn set_restarts(&mut self, restarts: Value) {
+        self.restarts = restarts;
+    }
+
     pub fn stack(&self, idx: usize) -> Value {
         unsafe { *self.stack.add(idx) }
     }
@@ -116,11 +172,11 @@ impl<ENV> GVm<ENV> {
     }
 
     pub fn stack_slice(&self) -> &[Value] {
-        unsafe { std::slice::from_raw_parts(self.stack, STACK_CAP) }
+        unsafe { std::slice::from_raw_parts(self.stack, self.stack_cap) }
     }
 
     pub fn stack_slice_mut(&mut self) -> &mut [Value] {
-        unsafe { std::slice::from_raw_parts_mut(self.stack, STACK_CAP) }
+        unsafe { std::slice::from_raw_parts_mut(self.stack, self.stack_cap) }
     }
 
     /// Return the register for idx.
@@ -130,7 +186,10 @@ impl<ENV> GVm<ENV> {
 
     pub fn register_slice<'b>(&self) -> &'b [Value] {
         unsafe {
-            std::slice::from_raw_parts(self.stack.add(self.stack_top), STACK_CAP - self.stack_top)
+            std::slice::from_raw_parts(
+                self.stack.add(self.stack_top),
+                self.stack_cap - self.stack_top,
+            )
         }
     }
 
@@ -164,6 +223,19 @@ impl<ENV> GVm<ENV> {
         &self.env
     }
 
+    /// Call func between instructions whenever flag is set.  This lets code outside the VM (a
+    /// signal handler for instance) request that something run at a point where it is safe to
+    /// call back into the VM.  Func is responsible for clearing the flag and is not called
+    /// recursively.
+    pub fn set_safe_point(&mut self, flag: &'static AtomicBool, func: SafePointFunc<ENV>) {
+        self.safe_point = Some((flag, func));
+    }
+
+    /// Remove the safe point function (see [`GVm::set_safe_point`]).
+    pub fn clear_safe_point(&mut self) {
+        self.safe_point = None;
+    }
+
     pub fn env_mut(&mut self) -> &mut ENV {
         &mut self.env
     }
@@ -428,6 +500,7 @@ impl<ENV> GVm<ENV> {
             current_ip: self.current_ip_ptr,
             this_fn: self.this_fn,
             on_error: self.on_error,
+            restarts: self.restarts,
             defers: std::mem::take(&mut self.defers),
         }
     }
@@ -440,6 +513,7 @@ impl<ENV> GVm<ENV> {
         self.current_ip_ptr = state.current_ip;
         self.this_fn = state.this_fn;
         self.on_error = state.on_error;
+        self.restarts = state.restarts;
         self.defers = std::mem::take(&mut state.defers);
     }
 
@@ -453,11 +527,50 @@ impl<ENV> GVm<ENV> {
         params: &[Value],
         caps: Option<&[Handle]>,
     ) -> VMResult<Value> {
+        let (chunk, caps) = self.call_arity(chunk, params.len(), caps)?;
         let mut vm_state = self.save_state();
         self.this_fn = None;
         self.on_error = None;
+        self.restarts = Value::Nil;
         self.stack_top = self.stack_max + 1;
+        if let Err(e) = self.setup_frameless_call(&chunk, params, caps) {
+            self.restore_state(&mut vm_state);
+            return Err(e);
+        }
+        let barrier = self.push_co_barrier();
+        let res = self
+            .execute2(chunk, false)
+            .map(|_| self.stack(self.stack_top));
+        self.pop_co_barrier(barrier);
+        self.restore_state(&mut vm_state);
+        res
+    }
 
+    /// If chunk is multi-arity select the lambda for num_args (and it's captures).
+    fn call_arity<'c>(
+        &self,
+        chunk: Arc<Chunk>,
+        num_args: usize,
+        caps: Option<&'c [Handle]>,
+    ) -> VMResult<(Arc<Chunk>, Option<&'c [Handle]>)> {
+        if let Some(arities) = &chunk.arities {
+            let (l, cap_offset) = Self::select_arity(arities, num_args as u16)?;
+            let num_caps = l.captures.as_ref().map(|c| c.len()).unwrap_or(0);
+            Ok((l, caps.map(|caps| &caps[cap_offset..cap_offset + num_caps])))
+        } else {
+            Ok((chunk, caps))
+        }
+    }
+
+    /// Setup the registers at stack_top to call chunk with params and captures.  This does not
+    /// make a call frame so RET/SRET will return control back to the caller of exec_loop.
+    fn setup_frameless_call(
+        &mut self,
+        chunk: &Arc<Chunk>,
+        params: &[Value],
+        caps: Option<&[Handle]>,
+    ) -> VMResult<()> {
+        self.reserve_stack(self.stack_top + chunk.input_regs + chunk.extra_regs)?;
         self.stack_max = self.stack_top + chunk.input_regs + chunk.extra_regs;
 
         // We don't have a call frame, this will cause RET/SRET to return control back when called.
@@ -468,7 +581,7 @@ impl<ENV> GVm<ENV> {
         }
         self.make_registers();
         if chunk.rest {
-            let (rest_reg, h) = self.setup_rest(&chunk, 0, params.len() as u16);
+            let (rest_reg, h) = self.setup_rest(chunk, 0, params.len() as u16);
             if let Some(caps) = caps {
                 let cap_first = (chunk.args + chunk.opt_args + 1) as usize;
                 for (i, c) in caps.iter().enumerate() {
@@ -482,9 +595,7 @@ impl<ENV> GVm<ENV> {
                 mov_register!(self, cap_first + i, Value::Value(*c));
             }
         }
-        let res = self.execute2(chunk).map(|_| self.stack(self.stack_top));
-        self.restore_state(&mut vm_state);
-        res
+        Ok(())
     }
 
     /// Executes chunk.  Will save the current VM state and restore on success or leave it on error.
@@ -495,13 +606,18 @@ impl<ENV> GVm<ENV> {
         let ip = self.ip_ptr;
         let this_fn = self.this_fn;
         let on_error = self.on_error;
+        let restarts = self.restarts;
+        self.reserve_stack(self.stack_max + chunk.input_regs + chunk.extra_regs)?;
         self.this_fn = None;
         self.stack_top = self.stack_max;
         self.stack_max = self.stack_top + chunk.input_regs + chunk.extra_regs;
 
         // Return on error without resetting the VM.
         // This is to allow debugging a live image/vm.
-        self.execute2(chunk)?;
+        let barrier = self.push_co_barrier();
+        let res = self.execute2(chunk, false);
+        self.pop_co_barrier(barrier);
+        res?;
         let res = self.stack(self.stack_top);
 
         self.stack_top = stack_top;
@@ -509,6 +625,7 @@ impl<ENV> GVm<ENV> {
         self.ip_ptr = ip;
         self.this_fn = this_fn;
         self.on_error = on_error;
+        self.restarts = restarts;
         Ok(res)
     }
 
@@ -517,6 +634,7 @@ impl<ENV> GVm<ENV> {
     pub fn reset(&mut self) {
         self.this_fn = None;
         self.on_error = None;
+        self.restarts = Value::Nil;
         self.err_frame = None;
         self.stack_top = 0;
         self.stack_max = 0;
@@ -527,12 +645,13 @@ impl<ENV> GVm<ENV> {
         self.defers = Vec::new();
     }
 
-    fn execute2(&mut self, chunk: Arc<Chunk>) -> VMResult<()> {
+    /// Run chunk until it returns, if skip_init then continue from the current ip_ptr and registers.
+    fn execute2(&mut self, chunk: Arc<Chunk>, skip_init: bool) -> VMResult<()> {
         let mut chunk = chunk;
 
         let mut done = false;
         let mut result = Ok(());
-        let mut skip_init = false;
+        let mut skip_init = skip_init;
         while !done {
             result = if let Err((e, echunk)) = self.exec_loop(chunk.clone(), skip_init) {
                 skip_init = false;
@@ -546,6 +665,7 @@ impl<ENV> GVm<ENV> {
                         this_fn: self.this_fn,
                         defers: std::mem::take(&mut self.defers),
                         on_error: self.on_error,
+                        restarts: self.restarts,
                         called: Value::Undefined,
                     });
                 }
@@ -1146,6 +1266,50 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tcall_collection() -> VMResult<()> {
+        // A lambda that tail calls a collection, run without a call frame (like do_call does) has
+        // to return the collection's result not keep executing after the TCALL.
+        let mut vm = Vm::new();
+        let vector = vm.alloc_vector(vec![10.into(), 20.into(), 30.into()]);
+        let mut map = crate::vm_hashmap::VMHashMap::new();
+        map.insert(&vm, 1.into(), 100.into());
+        let map = vm.alloc_map(map);
+        for (collection, expected) in [(vector, 20), (map, 100)] {
+            let mut chunk = Chunk::new("no_file", 1);
+            let line = 1;
+            let const1 = chunk.add_constant(collection) as u16;
+            chunk.encode2(CONST, 2, const1, Some(line)).unwrap();
+            chunk.encode2(TCALL, 2, 1, Some(line)).unwrap();
+            // The TCALL will keep this from executing.
+            chunk.encode1(SRET, 2, Some(line))?;
+            chunk.args = 1;
+            chunk.input_regs = 2;
+            chunk.extra_regs = 1;
+            let result = vm.do_call(Arc::new(chunk), &[1.into()], None)?;
+            assert_eq!(result.get_int(&vm)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_call_fn_builtin() -> VMResult<()> {
+        fn add_b(vm: &mut Vm, registers: &[Value]) -> VMResult<Value> {
+            Ok((registers[0].get_int(vm)? + registers[1].get_int(vm)?).into())
+        }
+        let mut vm = Vm::new();
+        let add = vm.add_builtin(add_b);
+        let res = vm.call_fn(add, &[1.into(), 2.into()])?;
+        assert_eq!(res.get_int(&vm)?, 3);
+        assert_eq!(vm.stack_max, 0);
+        // No room on the stack for the args is an error not a write past the end.
+        vm.stack_max = STACK_CAP - 2;
+        let err = vm.call_fn(add, &[1.into(), 2.into()]).unwrap_err();
+        assert_eq!(err.to_string(), "[rt]: Stack overflow.");
+        assert_eq!(vm.stack_max, STACK_CAP - 2);
+        Ok(())
+    }
+
     #[test]
     fn test_jumps() -> VMResult<()> {
         let mut vm = Vm::new();
@@ -1621,4 +1785,102 @@ mod tests {
         assert!(byte != float);
         assert!(int != float);
     }
+
+    #[test]
+    fn test_profile() -> VMResult<()> {
+        let mut chunk = Chunk::new("prof_file", 1);
+        chunk.add_constant(1.into());
+        chunk.add_constant(2.into());
+        chunk.encode2(CONST, 0, 0, Some(1))?;
+        chunk.encode2(CONST, 1, 1, Some(2))?;
+        chunk.encode2(ADD, 0, 1, Some(2))?;
+        chunk.encode0(RET, Some(3))?;
+        let chunk = Arc::new(chunk);
+        let mut vm = Vm::new();
+        assert!(!vm.is_profiling());
+        assert!(vm
+            .start_profiling(Profiler::with_sample_interval(1))
+            .is_none());
+        assert!(vm.is_profiling());
+        vm.execute(chunk.clone())?;
+        let profiler = vm.stop_profiling().expect("should be profiling");
+        assert!(!vm.is_profiling());
+        assert_eq!(vm.stack(0).get_int(&vm)?, 3);
+
+        let chunks = profiler.chunk_counts();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].0, "prof_file:1");
+        assert_eq!(chunks[0].1.instructions, 4);
+        let mut lines = profiler.line_counts();
+        lines.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
+        let lines: Vec<(&str, u64)> = lines
+            .iter()
+            .map(|(n, c)| (n.as_str(), c.instructions))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![("prof_file:1", 1), ("prof_file:2", 2), ("prof_file:3", 1)]
+        );
+        assert!(profiler.folded_stacks().starts_with("prof_file:1 "));
+        assert!(profiler.report(10).contains("4 instructions"));
+
+        // Only every third instruction is sampled (and charged with three instructions).
+        vm.start_profiling(Profiler::with_sample_interval(3));
+        vm.execute(chunk.clone())?;
+        vm.execute(chunk.clone())?;
+        let profiler = vm.stop_profiling().expect("should be profiling");
+        assert_eq!(profiler.instructions(), 8);
+        let mut lines = profiler.line_counts();
+        lines.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
+        let lines: Vec<(&str, u64)> = lines
+            .iter()
+            .map(|(n, c)| (n.as_str(), c.instructions))
+            .collect();
+        assert_eq!(lines, vec![("prof_file:2", 6)]);
+        assert!(profiler.report(10).contains("8 instructions"));
+
+        // Not profiling so nothing more is collected.
+        vm.execute(chunk)?;
+        assert!(vm.stop_profiling().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_coverage() -> VMResult<()> {
+        let mut inner = Chunk::new("cov_file.slosh", 5);
+        inner.encode0(RET, Some(6))?;
+        let mut chunk = Chunk::new("cov_file.slosh", 1);
+        let mut vm = Vm::new();
+        let lambda = vm.alloc_lambda(Arc::new(inner));
+        chunk.add_constant(lambda);
+        chunk.add_constant(2.into());
+        chunk.encode2(CONST, 0, 1, Some(1))?;
+        chunk.encode2(CONST, 1, 0, Some(2))?;
+        chunk.encode0(RET, Some(3))?;
+        let chunk = Arc::new(chunk);
+        assert!(vm.start_coverage(Coverage::new()).is_none());
+        vm.execute(chunk.clone())?;
+        vm.execute(chunk)?;
+        let coverage = vm.stop_coverage().expect("should be covering");
+        assert!(vm.coverage().is_none());
+
+        let lines = coverage.line_coverage();
+        let file = &lines.files()["cov_file.slosh"];
+        let file: Vec<(u32, u64)> = file.iter().map(|(l, c)| (*l, *c)).collect();
+        // The lambda was never called so line 6 has code but no hits.
+        assert_eq!(file, vec![(1, 2), (2, 2), (3, 2), (6, 0)]);
+
+        let lcov = lines.to_lcov();
+        assert_eq!(
+            lcov,
+            "TN:\nSF:cov_file.slosh\nDA:1,2\nDA:2,2\nDA:3,2\nDA:6,0\nLF:4\nLH:3\nend_of_record\n"
+        );
+        let mut merged = LineCoverage::from_lcov(&lcov).expect("valid lcov");
+        assert_eq!(merged, lines);
+        merged.merge(&lines);
+        assert_eq!(merged.files()["cov_file.slosh"][&1], 4);
+        assert_eq!(merged.files()["cov_file.slosh"][&6], 0);
+        assert!(LineCoverage::from_lcov("SF:x\nDA:1\n").is_err());
+        Ok(())
+    }
 }
@@ -2,7 +2,7 @@
 
 use std::sync::Arc;
 
-use crate::{mov_register, CallFrame, Chunk, Continuation, GVm, VMError, VMResult, Value};
+use crate::{mov_register, Arities, CallFrame, Chunk, Continuation, GVm, VMError, VMResult, Value};
 
 impl<ENV> GVm<ENV> {
     /// Setup the rest (&) arguments for a callable.
@@ -26,6 +26,20 @@ impl<ENV> GVm<ENV> {
         (rest_reg.into(), v)
     }
 
+    /// Select the lambda of a multi-arity chunk that accepts num_args, returns it and the offset of
+    /// its captures within the captures of the multi-arity chunk.
+    pub(crate) fn select_arity(arities: &Arities, num_args: u16) -> VMResult<(Arc<Chunk>, usize)> {
+        if let Some((l, cap_offset)) = arities.select(num_args) {
+            Ok((l.clone(), *cap_offset))
+        } else {
+            let valid: Vec<String> = arities.chunks().map(|l| arity_str(l)).collect();
+            Err(VMError::new_vm(format!(
+                "Wrong number of arguments ({num_args}), valid arities: {}.",
+                valid.join(", ")
+            )))
+        }
+    }
+
     fn k_unshared_stack(&self, stack_top: usize, k: &Continuation) -> Option<(usize, &Vec<Value>)> {
         if !k.stack.is_empty() {
             if k.frame.stack_top >= stack_top {
@@ -96,6 +110,7 @@ impl<ENV> GVm<ENV> {
             this_fn: self.this_fn,
             defers,
             on_error: self.on_error,
+            restarts: self.restarts,
             called,
         };
         self.callframe_id += 1;
@@ -118,7 +133,12 @@ impl<ENV> GVm<ENV> {
                 let current_ip = frame.current_ip;
                 let this_fn = frame.this_fn;
                 let on_error = frame.on_error;
+                let restarts = frame.restarts;
                 let new_chunk = frame.chunk.clone();
+                // Clear used regs (like SRET) so captured values are not overwritten later.
+                for r in self.stack_top + 1..=self.stack_max {
+                    *self.stack_mut(r) = Value::Undefined;
+                }
                 self.copy_frame_defers(); // Do this BEFORE we change stack_top...
                 self.stack_top = stack_top;
                 self.stack_max = self.stack_top + new_chunk.input_regs + new_chunk.extra_regs;
@@ -126,6 +146,7 @@ impl<ENV> GVm<ENV> {
                 self.current_ip_ptr = current_ip;
                 self.this_fn = this_fn;
                 self.on_error = on_error;
+                self.restarts = restarts;
                 *self.stack_mut(res_reg) = res;
                 new_chunk
             } else {
@@ -137,6 +158,11 @@ impl<ENV> GVm<ENV> {
             chunk
         }
     }
+    /// Make sure the stack has room for the registers of l called at first_reg.
+    fn reserve_call_stack(&mut self, l: &Chunk, first_reg: u16) -> VMResult<()> {
+        self.reserve_stack(self.stack_top + first_reg as usize + l.input_regs + l.extra_regs)
+    }
+
     /// Main function to match and execute anything that is callable.
     pub fn make_call(
         &mut self,
@@ -171,7 +197,16 @@ impl<ENV> GVm<ENV> {
             }
             Value::Lambda(handle) => {
                 let l = self.heap().get_lambda(handle);
+                let l = if let Some(arities) = &l.arities {
+                    Self::select_arity(arities, num_args)
+                        .map_err(|e| (e, chunk.clone()))?
+                        .0
+                } else {
+                    l
+                };
                 check_num_args(&l, num_args).map_err(|e| (e, chunk.clone()))?;
+                self.reserve_call_stack(&l, first_reg)
+                    .map_err(|e| (e, chunk.clone()))?;
                 if l.rest {
                     let (rest_reg, h) = self.setup_rest(&l, first_reg, num_args);
                     *self.stack_mut(self.stack_top + rest_reg) = h;
@@ -187,14 +222,20 @@ impl<ENV> GVm<ENV> {
                 self.stack_max = self.stack_top + l.input_regs + l.extra_regs;
                 self.this_fn = Some(lambda);
                 self.ip_ptr = get_code!(l);
-                // XXX TODO- maybe test for stack overflow vs waiting for a panic.
                 self.clear_opts(&l, first_reg, num_args);
                 Ok(l)
             }
             Value::Closure(handle) => {
                 let stack_top = self.stack_top;
                 let (l, _) = self.heap().get_closure(handle);
+                let (l, cap_offset) = if let Some(arities) = &l.arities {
+                    Self::select_arity(arities, num_args).map_err(|e| (e, chunk.clone()))?
+                } else {
+                    (l, 0)
+                };
                 check_num_args(&l, num_args).map_err(|e| (e, chunk.clone()))?;
+                self.reserve_call_stack(&l, first_reg)
+                    .map_err(|e| (e, chunk.clone()))?;
                 let cap_first = if l.rest {
                     let (rest_reg, h) = self.setup_rest(&l, first_reg, num_args);
                     *self.stack_mut(self.stack_top + rest_reg) = h;
@@ -206,7 +247,8 @@ impl<ENV> GVm<ENV> {
                 // Take the heap so we can mutate self.  Put it back when done or will panic on next access.
                 let heap = self.heap.take().expect("VM must have a Heap!");
                 let caps = heap.get_closure_captures(handle);
-                for (i, c) in caps.iter().enumerate() {
+                let num_caps = l.captures.as_ref().map(|c| c.len()).unwrap_or(0);
+                for (i, c) in caps[cap_offset..cap_offset + num_caps].iter().enumerate() {
                     *self.stack_mut(self.stack_top + cap_first + i) = Value::Value(*c);
                 }
                 // Put the heap back, if this doesn't happen will panic on next access attempt.
@@ -271,6 +313,27 @@ impl<ENV> GVm<ENV> {
                     .map_err(|e| (e, chunk.clone()))?;
                 Ok(self.finish_special_call(chunk, tail_call, first_reg, res))
             }
+            Value::Iter(handle) => {
+                if num_args != 0 {
+                    return Err((VMError::new_vm("Iterator takes no arguments."), chunk));
+                }
+                let res = self.iter_next(handle).map_err(|e| (e, chunk.clone()))?;
+                Ok(self.finish_special_call(chunk, tail_call, first_reg, res))
+            }
+            Value::Coroutine(handle) => {
+                let arg = match num_args {
+                    0 => None,
+                    1 => Some(self.register_unref(first_reg as usize + 1)),
+                    _ => {
+                        return Err((
+                            VMError::new_vm("Coroutine takes zero or one argument."),
+                            chunk,
+                        ))
+                    }
+                };
+                let res = self.resume(handle, arg).map_err(|e| (e, chunk.clone()))?;
+                Ok(self.finish_special_call(chunk, tail_call, first_reg, res))
+            }
             Value::Pair(_) | Value::List(_, _) => {
                 let res = self
                     .call_list(lambda, first_reg, num_args)
@@ -296,6 +359,12 @@ impl<ENV> GVm<ENV> {
             // Had to break this out for continuations. Handling defers makes this necessary.
             match lambda {
                 Value::Continuation(h) => {
+                    let frame = &self.heap().get_continuation(h).frame;
+                    let stack_max =
+                        frame.stack_top + frame.chunk.input_regs + frame.chunk.extra_regs;
+                    if let Err(e) = self.reserve_stack(stack_max) {
+                        return Err((e, result?));
+                    }
                     // Take the heap so we can mutate self.  Put it back when down or will panic on next access.
                     let heap = self.heap.take().expect("VM must have a Heap!");
                     let k = heap.get_continuation(h);
@@ -313,6 +382,7 @@ impl<ENV> GVm<ENV> {
                     self.current_ip_ptr = k.frame.current_ip;
                     self.this_fn = k.frame.this_fn;
                     self.on_error = k.frame.on_error;
+                    self.restarts = k.frame.restarts;
                     let chunk = k.frame.chunk.clone();
                     // Put the heap back, if this doesn't happen will panic on next access attempt.
                     self.heap = Some(heap);
@@ -359,8 +429,19 @@ impl<ENV> GVm<ENV> {
     }
 }
 
+/// Describe the number of args a chunk accepts for error messages.
+fn arity_str(l: &Chunk) -> String {
+    if l.rest {
+        format!("{}+", l.args - 1)
+    } else if l.opt_args > 0 {
+        format!("{}-{}", l.args, l.args + l.opt_args)
+    } else {
+        format!("{}", l.args)
+    }
+}
+
 /// Verify the number of args provided will work with a chunk.
-fn check_num_args(l: &Chunk, num_args: u16) -> VMResult<()> {
+pub(super) fn check_num_args(l: &Chunk, num_args: u16) -> VMResult<()> {
     if l.rest {
         if num_args < (l.args - 1) {
             return Err(VMError::new_vm(format!(
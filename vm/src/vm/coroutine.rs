@@ -0,0 +1,259 @@
+//! Vm functions for coroutines (and generators).
+//!
+//! Each coroutine has its own stack.  Resuming one points the VM at that stack and runs it in a
+//! nested exec loop until it yields (the YIELD opcode saves the frame to continue from and returns
+//! from the loop) or returns, then the caller's stack and state are restored.  Nothing is copied
+//! on a yield or resume.  A coroutine's stack starts small (most generators make few nested calls)
+//! and is grown when a call needs more room.
+
+use std::sync::Arc;
+
+use crate::vm::call::check_num_args;
+use crate::vm::{CoroutineCaller, STACK_CAP};
+use crate::{
+    CallFrame, Chunk, Coroutine, CoroutineStatus, GVm, Handle, Heap, VMError, VMResult, Value,
+};
+
+/// Initial size (in Values) of a coroutine's stack.
+const CO_STACK_MIN: usize = 64;
+
+impl<ENV> GVm<ENV> {
+    /// Create a new coroutine that will run lambda when first resumed.  If generator is true then
+    /// resuming it after it returns produces :*iter-empty* instead of an error.
+    pub fn make_coroutine(&mut self, lambda: Value, generator: bool) -> VMResult<Value> {
+        let lambda = lambda.unref(self);
+        if !matches!(lambda, Value::Lambda(_) | Value::Closure(_)) {
+            return Err(VMError::new_vm(format!(
+                "coroutine: requires a lambda, got {}.",
+                lambda.display_type(self)
+            )));
+        }
+        Ok(self.alloc_coroutine(Coroutine {
+            lambda,
+            generator,
+            status: CoroutineStatus::New,
+            frame: None,
+            resume_reg: 0,
+            yielded: Value::Undefined,
+            stack: Vec::new(),
+        }))
+    }
+
+    /// Run the coroutine until it yields or returns and produce the yielded (or returned) value.
+    /// The first resume passes arg (if provided) to the coroutine's lambda, after that arg is the
+    /// result of the yield the coroutine is suspended at.
+    pub fn resume(&mut self, handle: Handle, arg: Option<Value>) -> VMResult<Value> {
+        let co = self.get_coroutine(handle);
+        let (status, lambda, generator) = (co.status, co.lambda, co.generator);
+        match status {
+            CoroutineStatus::Running => {
+                return Err(VMError::new(
+                    "coroutine",
+                    "resume: coroutine is already running",
+                ))
+            }
+            CoroutineStatus::Dead if generator => return Ok(self.iter_empty_value()),
+            CoroutineStatus::Dead => {
+                return Err(VMError::new("coroutine", "resume: coroutine is dead"))
+            }
+            CoroutineStatus::New | CoroutineStatus::Suspended => {}
+        }
+        // Work out how to start the coroutine before switching stacks so errors leave it alone.
+        let start = if status == CoroutineStatus::New {
+            let (chunk, caps) = match lambda {
+                Value::Lambda(h) => (self.get_lambda(h), None),
+                Value::Closure(h) => {
+                    let (l, caps) = self.get_closure(h);
+                    (l, Some(caps.to_vec()))
+                }
+                _ => return Err(VMError::new_vm("resume: coroutine requires a lambda.")),
+            };
+            let params: Vec<Value> = arg.into_iter().collect();
+            let (chunk, caps) = self.call_arity(chunk, params.len(), caps.as_deref())?;
+            check_num_args(&chunk, params.len() as u16)?;
+            Some((chunk, caps.map(|c| c.to_vec()), params))
+        } else {
+            None
+        };
+
+        let co = self.get_coroutine_mut(handle);
+        co.status = CoroutineStatus::Running;
+        let frame = co.frame.take();
+        let resume_reg = co.resume_reg;
+        let mut co_stack = std::mem::take(&mut co.stack);
+        if co_stack.is_empty() {
+            co_stack = vec![Value::Undefined; CO_STACK_MIN];
+        }
+        let caller = CoroutineCaller {
+            coroutine: handle,
+            co_stack,
+            retired: Vec::new(),
+            stack: self.stack,
+            stack_cap: self.stack_cap,
+            k_stack_top: self.k_stack_top.take(),
+            state: self.save_state(),
+        };
+        self.co_callers.push(Some(caller));
+        if let Some(Some(caller)) = self.co_callers.last_mut() {
+            self.stack = caller.co_stack.as_mut_ptr();
+            self.stack_cap = caller.co_stack.len();
+        }
+
+        let res = if let Some((chunk, caps, params)) = start {
+            self.this_fn = None;
+            self.on_error = None;
+            self.restarts = Value::Nil;
+            self.stack_top = 0;
+            self.setup_frameless_call(&chunk, &params, caps.as_deref())
+                .and_then(|_| self.execute2(chunk, false))
+        } else if let Some(frame) = frame {
+            self.stack_top = frame.stack_top;
+            self.stack_max = frame.stack_top + frame.chunk.input_regs + frame.chunk.extra_regs;
+            self.ip_ptr = frame.ip;
+            self.current_ip_ptr = frame.current_ip;
+            self.this_fn = frame.this_fn;
+            self.on_error = frame.on_error;
+            self.restarts = frame.restarts;
+            self.defers = frame.defers;
+            *self.stack_mut(resume_reg) = arg.unwrap_or(Value::Nil);
+            self.make_registers();
+            self.execute2(frame.chunk, true)
+        } else {
+            Err(VMError::new_vm("resume: suspended coroutine has no frame."))
+        };
+        let result = self.stack(self.stack_top);
+
+        let Some(Some(caller)) = self.co_callers.pop() else {
+            panic!("Coroutine caller missing on return from resume!");
+        };
+        let mut state = caller.state;
+        self.stack = caller.stack;
+        self.stack_cap = caller.stack_cap;
+        self.k_stack_top = caller.k_stack_top;
+        self.restore_state(&mut state);
+        self.make_registers();
+        let co = self.get_coroutine_mut(handle);
+        match res {
+            Ok(()) if co.status == CoroutineStatus::Suspended => {
+                co.stack = caller.co_stack;
+                Ok(std::mem::replace(&mut co.yielded, Value::Undefined))
+            }
+            Ok(()) => {
+                co.status = CoroutineStatus::Dead;
+                if generator {
+                    Ok(self.iter_empty_value())
+                } else {
+                    Ok(result)
+                }
+            }
+            Err(e) => {
+                co.status = CoroutineStatus::Dead;
+                co.frame = None;
+                // The error frame refers to the coroutine's (now gone) stack.
+                self.err_frame = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Make sure the stack has room for registers up to stack_max.  Only a coroutine's stack can
+    /// grow (up to STACK_CAP), it is replaced by a larger copy and the VM is pointed at that.
+    pub(super) fn reserve_stack(&mut self, stack_max: usize) -> VMResult<()> {
+        if stack_max < self.stack_cap {
+            return Ok(());
+        }
+        let stack = self.stack;
+        let caller = self
+            .co_callers
+            .iter_mut()
+            .rev()
+            .flatten()
+            .next()
+            .filter(|caller| std::ptr::eq(caller.co_stack.as_ptr(), stack));
+        match caller {
+            Some(caller) if stack_max < STACK_CAP => {
+                let cap = (stack_max + 1).next_power_of_two().min(STACK_CAP);
+                let mut co_stack = Vec::with_capacity(cap);
+                co_stack.extend_from_slice(&caller.co_stack);
+                co_stack.resize(cap, Value::Undefined);
+                let old = std::mem::replace(&mut caller.co_stack, co_stack);
+                caller.retired.push(old);
+                self.stack = caller.co_stack.as_mut_ptr();
+                self.stack_cap = cap;
+                self.make_registers();
+                Ok(())
+            }
+            _ => Err(VMError::new_vm("Stack overflow.")),
+        }
+    }
+
+    /// Suspend the running coroutine, R(reg) is the value to yield and gets the value passed to
+    /// the next resume.  The exec loop must return after this.
+    pub(super) fn yield_coroutine(&mut self, chunk: &Arc<Chunk>, reg: u16) -> VMResult<()> {
+        let handle = if let Some(Some(caller)) = self.co_callers.last() {
+            caller.coroutine
+        } else {
+            return Err(VMError::new(
+                "coroutine",
+                "yield: not in a coroutine (can not yield across a builtin such as apply)",
+            ));
+        };
+        let frame = CallFrame {
+            id: self.callframe_id,
+            chunk: chunk.clone(),
+            ip: self.ip_ptr,
+            current_ip: self.current_ip_ptr,
+            stack_top: self.stack_top,
+            this_fn: self.this_fn,
+            defers: std::mem::take(&mut self.defers),
+            on_error: self.on_error,
+            restarts: self.restarts,
+            called: Value::Undefined,
+        };
+        let yielded = self.register_unref(reg as usize);
+        let resume_reg = self.stack_top + reg as usize;
+        let co = self.get_coroutine_mut(handle);
+        co.frame = Some(frame);
+        co.resume_reg = resume_reg;
+        co.yielded = yielded;
+        co.status = CoroutineStatus::Suspended;
+        Ok(())
+    }
+
+    /// Stop coroutines from yielding across a re-entrant call into the VM (do_call etc).  Returns
+    /// true if a barrier was pushed (pass to pop_co_barrier).
+    pub(super) fn push_co_barrier(&mut self) -> bool {
+        if self.co_callers.is_empty() {
+            false
+        } else {
+            self.co_callers.push(None);
+            true
+        }
+    }
+
+    pub(super) fn pop_co_barrier(&mut self, pushed: bool) {
+        if pushed {
+            self.co_callers.pop();
+        }
+    }
+
+    /// Mark the stacks and state of any callers suspended while a coroutine runs.
+    pub(super) fn mark_co_callers(&self, heap: &mut Heap) {
+        for caller in self.co_callers.iter().flatten() {
+            heap.mark(Value::Coroutine(caller.coroutine));
+            for i in 0..=caller.state.stack_max {
+                heap.mark(unsafe { *caller.stack.add(i) });
+            }
+            if let Some(this_fn) = caller.state.this_fn {
+                heap.mark(this_fn);
+            }
+            if let Some(on_error) = caller.state.on_error {
+                heap.mark(on_error);
+            }
+            heap.mark(caller.state.restarts);
+            for defer in &caller.state.defers {
+                heap.mark(*defer);
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use crate::{Chunk, FxHashMap, GVm, Value};
+
+/// Executed line counts by file name then line number.
+///
+/// Lines that have code but were never executed are present with a count of 0 so reports can show
+/// what was missed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineCoverage {
+    files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl LineCoverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map of file name to (line number to execution count).
+    pub fn files(&self) -> &BTreeMap<String, BTreeMap<u32, u64>> {
+        &self.files
+    }
+
+    /// Add count executions for file/line (use 0 to mark a line as having code).
+    pub fn add(&mut self, file: &str, line: u32, count: u64) {
+        if !self.files.contains_key(file) {
+            self.files.insert(file.to_string(), BTreeMap::new());
+        }
+        if let Some(lines) = self.files.get_mut(file) {
+            *lines.entry(line).or_default() += count;
+        }
+    }
+
+    /// Add all the counts from other to this coverage.
+    pub fn merge(&mut self, other: &LineCoverage) {
+        for (file, lines) in &other.files {
+            for (line, count) in lines {
+                self.add(file, *line, *count);
+            }
+        }
+    }
+
+    /// Parse the line data (SF and DA records) from an lcov tracefile.
+    pub fn from_lcov(text: &str) -> Result<Self, String> {
+        let mut result = Self::new();
+        let mut file: Option<&str> = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(name) = line.strip_prefix("SF:") {
+                file = Some(name);
+            } else if let Some(data) = line.strip_prefix("DA:") {
+                let mut parts = data.split(',');
+                let (Some(file), Some(line_no), Some(count)) = (file, parts.next(), parts.next())
+                else {
+                    return Err(format!("invalid lcov DA record on line {}", i + 1));
+                };
+                match (line_no.parse::<u32>(), count.parse::<u64>()) {
+                    (Ok(line_no), Ok(count)) => result.add(file, line_no, count),
+                    _ => return Err(format!("invalid lcov DA record on line {}", i + 1)),
+                }
+            } else if line == "end_of_record" {
+                file = None;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Produce an lcov tracefile (line coverage only).
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        for (file, lines) in &self.files {
+            let _ = writeln!(out, "TN:");
+            let _ = writeln!(out, "SF:{file}");
+            for (line, count) in lines {
+                let _ = writeln!(out, "DA:{line},{count}");
+            }
+            let hit = lines.values().filter(|c| **c > 0).count();
+            let _ = writeln!(out, "LF:{}", lines.len());
+            let _ = writeln!(out, "LH:{hit}");
+            let _ = writeln!(out, "end_of_record");
+        }
+        out
+    }
+}
+
+/// Records which instructions execute while the VM runs.
+///
+/// Every chunk that executes is registered along with any lambdas in its constants (so functions
+/// that are never called still show up with their lines unexecuted).  Each chunk gets a counter per
+/// code offset when it is first seen, so recording an instruction only looks the chunk up when
+/// execution moves to a different chunk.  Offsets are mapped to source lines when line_coverage()
+/// is called.
+#[derive(Default)]
+pub struct Coverage {
+    chunks: Vec<ChunkCounts>,
+    // Chunk address to its index in chunks.
+    index: FxHashMap<usize, usize>,
+    last_chunk: usize,
+    last_index: usize,
+}
+
+struct ChunkCounts {
+    // Keep the chunk alive so its address remains a unique key for the run.
+    chunk: Arc<Chunk>,
+    // Execution count for each offset in the chunk's code.
+    counts: Vec<u32>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Chunks compiled from eval, the REPL or other strings do not have a source file.
+    fn is_source_file(file_name: &str) -> bool {
+        !(file_name.is_empty() || file_name == "no_file" || file_name.starts_with("none/"))
+    }
+
+    /// Line counts for all the chunks seen (lines with code but no executions are included).
+    pub fn line_coverage(&self) -> LineCoverage {
+        let mut result = LineCoverage::new();
+        for ChunkCounts { chunk, counts } in &self.chunks {
+            if Self::is_source_file(chunk.file_name) {
+                for line in chunk.code_lines() {
+                    result.add(chunk.file_name, line, 0);
+                }
+                for (offset, count) in counts.iter().enumerate() {
+                    if *count > 0 {
+                        if let Some(line) = chunk.offset_to_line(offset) {
+                            result.add(chunk.file_name, line, *count as u64);
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<ENV> GVm<ENV> {
+    /// Start recording coverage, returns any coverage that was already being recorded.
+    pub fn start_coverage(&mut self, coverage: Coverage) -> Option<Coverage> {
+        self.coverage.replace(Box::new(coverage)).map(|c| *c)
+    }
+
+    /// Stop recording coverage and return the results (if coverage was on).
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take().map(|c| *c)
+    }
+
+    /// Current coverage results if recording (recording continues).
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_deref()
+    }
+
+    /// Called from the exec loop for each instruction when recording coverage.
+    pub(super) fn coverage_instruction(&mut self, chunk: &Arc<Chunk>) {
+        let key = Arc::as_ptr(chunk) as usize;
+        if self.coverage.as_ref().is_some_and(|c| c.last_chunk != key) {
+            if let Some(mut coverage) = self.coverage.take() {
+                coverage.last_index = self.coverage_register_chunk(&mut coverage, chunk);
+                coverage.last_chunk = key;
+                self.coverage = Some(coverage);
+            }
+        }
+        let offset = unsafe { self.current_ip_ptr.offset_from(get_code!(chunk)) as usize };
+        if let Some(coverage) = self.coverage.as_deref_mut() {
+            if let Some(count) = coverage.chunks[coverage.last_index].counts.get_mut(offset) {
+                *count = count.saturating_add(1);
+            }
+        }
+    }
+
+    /// Register chunk and (recursively) any lambdas in its constants, returns chunk's index.
+    fn coverage_register_chunk(&self, coverage: &mut Coverage, chunk: &Arc<Chunk>) -> usize {
+        let key = Arc::as_ptr(chunk) as usize;
+        if let Some(idx) = coverage.index.get(&key) {
+            return *idx;
+        }
+        let idx = coverage.chunks.len();
+        coverage.chunks.push(ChunkCounts {
+            chunk: chunk.clone(),
+            counts: vec![0; chunk.code.len()],
+        });
+        coverage.index.insert(key, idx);
+        for constant in &chunk.constants {
+            if let Value::Lambda(h) = constant {
+                let lambda = self.get_lambda(*h);
+                self.coverage_register_chunk(coverage, &lambda);
+            }
+        }
+        idx
+    }
+}
@@ -2,10 +2,10 @@ use crate::opcodes::*;
 use crate::vm_hashmap::{VMHashMap, ValHash};
 use crate::{
     from_i56, CallFrame, Chunk, Continuation, Error, GVm, VMError, VMErrorObj, VMResult, Value,
-    STACK_CAP,
 };
 use std::marker::PhantomData;
 use std::num::TryFromIntError;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use unicode_segmentation::UnicodeSegmentation;
 
@@ -333,7 +333,17 @@ impl<ENV> GVm<ENV> {
     /// Need to check this for some lambda -> builtin tail calls so SRET does not execute and
     /// munge the return.
     fn tail_builtin_exit(&self, lambda: Value) -> bool {
-        matches!(lambda, Value::Builtin(_)) && self.call_frame().is_none()
+        // Anything that make_call handles with finish_special_call (builtins and collections).
+        matches!(
+            lambda,
+            Value::Builtin(_)
+                | Value::Map(_)
+                | Value::Vector(_)
+                | Value::Pair(_)
+                | Value::List(_, _)
+                | Value::Coroutine(_)
+                | Value::Iter(_)
+        ) && self.call_frame().is_none()
     }
 
     /** Implementation of the INC bytecode. */
@@ -444,7 +454,7 @@ impl<ENV> GVm<ENV> {
                 let regs = unsafe {
                     std::slice::from_raw_parts_mut(
                         self.stack.add(self.stack_top),
-                        STACK_CAP - self.stack_top,
+                        self.stack_cap - self.stack_top,
                     )
                 };
                 for reg in regs
@@ -462,6 +472,22 @@ impl<ENV> GVm<ENV> {
                 wide = false;
             }
             self.current_ip_ptr = self.ip_ptr;
+            if self.profiler.is_some() {
+                self.profile_instruction(&chunk);
+            }
+            if self.coverage.is_some() {
+                self.coverage_instruction(&chunk);
+            }
+            if let Some((flag, func)) = self.safe_point {
+                // Not between WIDE and the instruction it modifies.
+                if opcode != WIDE && flag.load(Ordering::Acquire) {
+                    self.safe_point = None;
+                    let res = func(self);
+                    self.safe_point = Some((flag, func));
+                    self.make_registers();
+                    res.map_err(|e| (e, chunk.clone()))?;
+                }
+            }
             opcode = decode_u8!(self.ip_ptr);
             match opcode {
                 NOP => {}
@@ -485,6 +511,7 @@ impl<ENV> GVm<ENV> {
                             let current_ip = frame.current_ip;
                             let this_fn = frame.this_fn;
                             let on_error = frame.on_error;
+                            let restarts = frame.restarts;
                             chunk = frame.chunk.clone();
                             self.copy_frame_defers(); // Do this BEFORE we change stack_top...
                             self.stack_top = stack_top;
@@ -494,6 +521,7 @@ impl<ENV> GVm<ENV> {
                             self.current_ip_ptr = current_ip;
                             self.this_fn = this_fn;
                             self.on_error = on_error;
+                            self.restarts = restarts;
                         } else {
                             return Ok(());
                         }
@@ -519,6 +547,7 @@ impl<ENV> GVm<ENV> {
                             let current_ip = frame.current_ip;
                             let this_fn = frame.this_fn;
                             let on_error = frame.on_error;
+                            let restarts = frame.restarts;
                             chunk = frame.chunk.clone();
                             self.copy_frame_defers(); // Do this BEFORE we change stack_top...
                             self.stack_top = stack_top;
@@ -528,6 +557,7 @@ impl<ENV> GVm<ENV> {
                             self.current_ip_ptr = current_ip;
                             self.this_fn = this_fn;
                             self.on_error = on_error;
+                            self.restarts = restarts;
                         } else {
                             *self.stack_mut(old_top) = val;
                             return Ok(());
@@ -932,6 +962,12 @@ impl<ENV> GVm<ENV> {
                     };
                     set_register!(self, dest as usize, val);
                 }
+                YIELD => {
+                    let reg = decode1!(self.ip_ptr, wide);
+                    self.yield_coroutine(&chunk, reg)
+                        .map_err(|e| (e, chunk.clone()))?;
+                    return Ok(());
+                }
                 NOT => {
                     let (dest, val) = decode2!(self.ip_ptr, wide);
                     let val = self.register(val as usize);
@@ -1002,6 +1038,7 @@ impl<ENV> GVm<ENV> {
                         this_fn: self.this_fn,
                         defers,
                         on_error: self.on_error,
+                        restarts: self.restarts,
                         called: Value::Undefined,
                     };
                     let mut stack = Vec::with_capacity(self.stack_max);
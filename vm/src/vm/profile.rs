@@ -0,0 +1,283 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Chunk, FxHashMap, GVm};
+
+/// Default number of instructions between timing samples.
+pub const PROFILE_SAMPLE_INTERVAL: u32 = 128;
+
+/// Sampled instruction count and time for a chunk or a source line.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ProfileCounts {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+impl ProfileCounts {
+    fn add(&mut self, other: &ProfileCounts) {
+        self.instructions += other.instructions;
+        self.time += other.time;
+    }
+}
+
+/// Collects per chunk/offset instruction counts and timings by sampling while the VM runs.
+///
+/// Executed instructions are only counted, every sample_interval instructions the current
+/// instruction is charged with those sample_interval instructions and the elapsed wall time (as
+/// is its call stack for folded stack output).  The maps are only touched when sampling and
+/// offsets are only mapped to source lines when a report is produced so the hot path stays cheap.
+pub struct Profiler {
+    // Keep the chunks alive so their addresses remain unique keys for the run.
+    chunks: FxHashMap<usize, Arc<Chunk>>,
+    counts: FxHashMap<(usize, usize), ProfileCounts>,
+    stacks: FxHashMap<String, u64>,
+    instructions: u64,
+    sample_interval: u32,
+    countdown: u32,
+    last_sample: Instant,
+    started: Instant,
+    elapsed: Duration,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::with_sample_interval(PROFILE_SAMPLE_INTERVAL)
+    }
+
+    /// Create a profiler that takes a timing sample every sample_interval instructions.
+    pub fn with_sample_interval(sample_interval: u32) -> Self {
+        let sample_interval = sample_interval.max(1);
+        let now = Instant::now();
+        Self {
+            chunks: FxHashMap::default(),
+            counts: FxHashMap::default(),
+            stacks: FxHashMap::default(),
+            instructions: 0,
+            sample_interval,
+            countdown: sample_interval,
+            last_sample: now,
+            started: now,
+            elapsed: Duration::default(),
+        }
+    }
+
+    /// Total wall time this profiler has been collecting.
+    pub fn elapsed(&self) -> Duration {
+        if self.elapsed.is_zero() {
+            self.started.elapsed()
+        } else {
+            self.elapsed
+        }
+    }
+
+    /// Stop the clock, time after this is not included in elapsed().
+    pub fn finish(&mut self) {
+        self.elapsed = self.started.elapsed();
+    }
+
+    /// Merge the results of other into this profiler (used for nested profiling).
+    pub fn merge(&mut self, other: Profiler) {
+        for (k, chunk) in other.chunks {
+            self.chunks.entry(k).or_insert(chunk);
+        }
+        for (k, counts) in other.counts {
+            self.counts.entry(k).or_default().add(&counts);
+        }
+        for (k, nanos) in other.stacks {
+            *self.stacks.entry(k).or_default() += nanos;
+        }
+        self.instructions += other.instructions;
+        // Time spent in the merged profile has already been charged to it.
+        self.last_sample = Instant::now();
+    }
+
+    /// Count an instruction, returns true when it is time to take a sample.
+    #[inline]
+    fn tick(&mut self) -> bool {
+        self.instructions += 1;
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.sample_interval;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sample(&mut self, chunk: &Arc<Chunk>, offset: usize, stack: String) {
+        let key = Arc::as_ptr(chunk) as usize;
+        self.chunks.entry(key).or_insert_with(|| chunk.clone());
+        let now = Instant::now();
+        let time = now - self.last_sample;
+        self.last_sample = now;
+        let counts = self.counts.entry((key, offset)).or_default();
+        counts.instructions += self.sample_interval as u64;
+        counts.time += time;
+        *self.stacks.entry(stack).or_default() += time.as_nanos() as u64;
+    }
+
+    /// Total number of instructions executed while profiling (not sampled).
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Label used for a chunk in reports and stacks.
+    fn chunk_name(chunk: &Chunk) -> String {
+        format!("{}:{}", chunk.file_name, chunk.start_line())
+    }
+
+    /// Counts rolled up by chunk, sorted by time then instructions (descending).
+    pub fn chunk_counts(&self) -> Vec<(String, ProfileCounts)> {
+        let mut by_chunk: FxHashMap<usize, ProfileCounts> = FxHashMap::default();
+        for ((key, _), counts) in &self.counts {
+            by_chunk.entry(*key).or_default().add(counts);
+        }
+        let mut result: Vec<(String, ProfileCounts)> = by_chunk
+            .into_iter()
+            .map(|(key, counts)| (Self::chunk_name(&self.chunks[&key]), counts))
+            .collect();
+        Self::sort_counts(&mut result);
+        result
+    }
+
+    /// Counts rolled up by source line (file:line), sorted by time then instructions (descending).
+    pub fn line_counts(&self) -> Vec<(String, ProfileCounts)> {
+        let mut by_line: FxHashMap<(&'static str, u32), ProfileCounts> = FxHashMap::default();
+        for ((key, offset), counts) in &self.counts {
+            let chunk = &self.chunks[key];
+            let line = chunk.offset_to_line(*offset).unwrap_or(0);
+            by_line
+                .entry((chunk.file_name, line))
+                .or_default()
+                .add(counts);
+        }
+        let mut result: Vec<(String, ProfileCounts)> = by_line
+            .into_iter()
+            .map(|((file, line), counts)| (format!("{file}:{line}"), counts))
+            .collect();
+        Self::sort_counts(&mut result);
+        result
+    }
+
+    fn sort_counts(counts: &mut [(String, ProfileCounts)]) {
+        counts.sort_by(|(n1, c1), (n2, c2)| {
+            c2.time
+                .cmp(&c1.time)
+                .then(c2.instructions.cmp(&c1.instructions))
+                .then(n1.cmp(n2))
+        });
+    }
+
+    /// Produce a human readable report with the top limit chunks and lines.
+    pub fn report(&self, limit: usize) -> String {
+        let total_time: Duration = self.counts.values().map(|c| c.time).sum();
+        let pct = |time: Duration| {
+            if total_time.is_zero() {
+                0.0
+            } else {
+                time.as_secs_f64() * 100.0 / total_time.as_secs_f64()
+            }
+        };
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Profile: {} instructions, {:.3}ms elapsed, {:.3}ms sampled",
+            self.instructions,
+            self.elapsed().as_secs_f64() * 1000.0,
+            total_time.as_secs_f64() * 1000.0
+        );
+        for (title, counts) in [
+            ("Chunks", self.chunk_counts()),
+            ("Lines", self.line_counts()),
+        ] {
+            let _ = writeln!(out, "\n{title}:");
+            let _ = writeln!(
+                out,
+                "{:>12} {:>7} {:>14}  location",
+                "time(ms)", "%time", "instructions"
+            );
+            for (name, c) in counts.iter().take(limit) {
+                let _ = writeln!(
+                    out,
+                    "{:>12.3} {:>6.2}% {:>14}  {}",
+                    c.time.as_secs_f64() * 1000.0,
+                    pct(c.time),
+                    c.instructions,
+                    name
+                );
+            }
+            if counts.len() > limit {
+                let _ = writeln!(out, "... {} more", counts.len() - limit);
+            }
+        }
+        out
+    }
+
+    /// Produce folded stacks (one "outer;...;inner nanoseconds" line per stack), this is the
+    /// input format for flamegraph tools (inferno, flamegraph.pl).
+    pub fn folded_stacks(&self) -> String {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().collect();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, nanos) in stacks {
+            let _ = writeln!(out, "{stack} {nanos}");
+        }
+        out
+    }
+}
+
+impl<ENV> GVm<ENV> {
+    /// Start collecting a profile, returns any profiler that was already active.
+    pub fn start_profiling(&mut self, profiler: Profiler) -> Option<Profiler> {
+        self.profiler.replace(Box::new(profiler)).map(|p| *p)
+    }
+
+    /// Stop profiling and return the collected results (if profiling was on).
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take().map(|mut p| {
+            p.finish();
+            *p
+        })
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiler.is_some()
+    }
+
+    /// Called from the exec loop for each instruction when profiling.
+    #[inline]
+    pub(super) fn profile_instruction(&mut self, chunk: &Arc<Chunk>) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            if profiler.tick() {
+                self.profile_sample(chunk);
+            }
+        }
+    }
+
+    #[cold]
+    fn profile_sample(&mut self, chunk: &Arc<Chunk>) {
+        let offset = unsafe { self.current_ip_ptr.offset_from(get_code!(chunk)) as usize };
+        let stack = self.folded_stack(chunk);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.sample(chunk, offset, stack);
+        }
+    }
+
+    /// Build the current call stack as a folded stack string (outer most frame first).
+    fn folded_stack(&self, chunk: &Chunk) -> String {
+        let mut frames: Vec<String> = self
+            .get_call_stack()
+            .map(|frame| Profiler::chunk_name(&frame.chunk))
+            .collect();
+        frames.reverse();
+        frames.push(Profiler::chunk_name(chunk));
+        frames.join(";")
+    }
+}
@@ -0,0 +1,512 @@
+//! Vm functions for native (lazy) iterators.
+//!
+//! An iterator is anything that can be called with no arguments to produce its next element or
+//! :*iter-empty* when done.  Native iterators (Value::Iter) step in Rust so a pipeline of them only
+//! allocates the elements it produces (a line from a file for instance), never call frames or
+//! closure state.  Lisp iterators (mk-iter, generators) can be mixed in freely.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::seq_iter::SeqIter;
+use crate::{GVm, Handle, VMError, VMResult, Value};
+
+impl<ENV> GVm<ENV> {
+    /// The :*iter-empty* keyword an iterator returns when it is done.
+    pub fn iter_empty_value(&self) -> Value {
+        Value::Keyword(self.iter_empty)
+    }
+
+    pub fn is_iter_empty(&self, val: Value) -> bool {
+        val == Value::Keyword(self.iter_empty)
+    }
+
+    /// True if val is an iterator (native or a callable with the :is-iter property).
+    pub fn is_iter(&self, val: Value) -> bool {
+        match val {
+            Value::Iter(_) => true,
+            Value::Lambda(_) | Value::Closure(_) | Value::Coroutine(_) => {
+                if let Some(is_iter) = self.interner.get_if_interned("is-iter") {
+                    self.get_heap_property_interned(val, is_iter)
+                        .map(|v| v.is_truthy())
+                        .unwrap_or(false)
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Return val as an iterator, iterators are returned as is and collections, strings and files
+    /// are wrapped in a native iterator.
+    pub fn make_iter(&mut self, val: Value) -> VMResult<Value> {
+        let val = val.unref(self);
+        let iter = match val {
+            _ if self.is_iter(val) => return Ok(val),
+            Value::Vector(_) => SeqIter::Vector {
+                vector: val,
+                idx: 0,
+            },
+            Value::Pair(_) | Value::List(_, _) | Value::Nil => SeqIter::List { list: val },
+            Value::String(_) | Value::StringConst(_) => SeqIter::Chars {
+                string: val,
+                byte_idx: 0,
+            },
+            Value::Io(_) => SeqIter::Lines {
+                io: val,
+                buf: Vec::new(),
+            },
+            _ => {
+                return Err(VMError::new(
+                    "iter",
+                    format!(
+                        "iter: requires a list, vector, string, file or existing iterator, got {}",
+                        val.display_type(self)
+                    ),
+                ))
+            }
+        };
+        Ok(self.alloc_iter(iter))
+    }
+
+    /// Produce the next element of any iterator.
+    pub fn iter_next_value(&mut self, iter: Value) -> VMResult<Value> {
+        match iter {
+            Value::Iter(handle) => self.iter_next(handle),
+            _ => self.call_fn(iter, &[]),
+        }
+    }
+
+    /// Call a callable from Rust.  Args are copied to the stack so they stay rooted during the
+    /// call, lambdas are run with do_call so no call frame is allocated.
+    pub fn call_fn(&mut self, lambda: Value, args: &[Value]) -> VMResult<Value> {
+        match lambda {
+            Value::Lambda(h) => {
+                let l = self.get_lambda(h);
+                self.do_call(l, args, None)
+            }
+            Value::Closure(h) => {
+                let (l, caps) = self.get_closure(h);
+                let caps = caps.to_vec();
+                self.do_call(l, args, Some(&caps))
+            }
+            Value::Builtin(idx) => {
+                let first = self.stack_max + 1;
+                self.reserve_stack(first + args.len())?;
+                for (i, arg) in args.iter().enumerate() {
+                    *self.stack_mut(first + i) = *arg;
+                }
+                let stack_max = self.stack_max;
+                self.stack_max = first + args.len();
+                let f = self.buitins[idx as usize].func;
+                let args = unsafe { std::slice::from_raw_parts(self.stack.add(first), args.len()) };
+                let res = f(self, args);
+                self.stack_max = stack_max;
+                res
+            }
+            Value::Coroutine(h) => match args {
+                [] => self.resume(h, None),
+                [arg] => self.resume(h, Some(*arg)),
+                _ => Err(VMError::new_vm("Coroutine takes zero or one argument.")),
+            },
+            Value::Iter(h) if args.is_empty() => self.iter_next(h),
+            Value::Value(h) => self.call_fn(self.get_value(h), args),
+            _ => Err(VMError::new_vm(format!(
+                "Not a callable {}.",
+                lambda.display_type(self)
+            ))),
+        }
+    }
+
+    fn call_pred(&mut self, lambda: Value, arg: Value) -> VMResult<bool> {
+        Ok(self.call_fn(lambda, &[arg])?.is_truthy())
+    }
+
+    /// Produce the next element of a native iterator.
+    pub fn iter_next(&mut self, handle: Handle) -> VMResult<Value> {
+        let empty = self.iter_empty_value();
+        let res = match self.get_iter(handle) {
+            SeqIter::Empty => return Ok(empty),
+            SeqIter::Vector { vector, idx } => {
+                let idx = *idx;
+                let val = if let Value::Vector(h) = *vector {
+                    self.get_vector(h).get(idx).copied()
+                } else {
+                    None
+                };
+                if let Some(val) = val {
+                    if let SeqIter::Vector { idx, .. } = self.get_iter_mut(handle) {
+                        *idx += 1;
+                    }
+                    val
+                } else {
+                    empty
+                }
+            }
+            SeqIter::List { list } => {
+                if let Some((car, cdr)) = list.get_pair(self) {
+                    *self.get_iter_mut(handle) = SeqIter::List { list: cdr };
+                    car
+                } else {
+                    empty
+                }
+            }
+            SeqIter::Chars { string, byte_idx } => {
+                let byte_idx = *byte_idx;
+                let s = match *string {
+                    Value::String(h) => self.get_string(h),
+                    Value::StringConst(i) => self.get_interned(i),
+                    _ => "",
+                };
+                // The string may have been changed since the last element so use get.
+                let ch = s.get(byte_idx..).and_then(|s| s.graphemes(true).next());
+                if let Some(ch) = ch {
+                    let len = ch.len();
+                    let val = if len <= 6 {
+                        let mut b = [0_u8; 6];
+                        b[0..len].copy_from_slice(ch.as_bytes());
+                        Value::CharCluster(len as u8, b)
+                    } else {
+                        let ch = ch.to_string();
+                        self.alloc_char(&ch)
+                    };
+                    if let SeqIter::Chars { byte_idx, .. } = self.get_iter_mut(handle) {
+                        *byte_idx += len;
+                    }
+                    val
+                } else {
+                    empty
+                }
+            }
+            SeqIter::Range { next, end, step } => {
+                let (val, end, step) = (*next, *end, *step);
+                let done = match end {
+                    Some(end) => (step > 0 && val >= end) || (step < 0 && val <= end),
+                    None => false,
+                };
+                if done {
+                    empty
+                } else {
+                    if let SeqIter::Range { next, .. } = self.get_iter_mut(handle) {
+                        *next += step;
+                    }
+                    val.into()
+                }
+            }
+            SeqIter::Lines { io, .. } => {
+                let Value::Io(io) = *io else {
+                    return Ok(empty);
+                };
+                let mut buf = if let SeqIter::Lines { buf, .. } = self.get_iter_mut(handle) {
+                    std::mem::take(buf)
+                } else {
+                    Vec::new()
+                };
+                buf.clear();
+                let read = self.get_io(io).get_io().read_until(b'\n', &mut buf);
+                let line = match read {
+                    Ok(0) => Ok(None),
+                    Ok(_) => std::str::from_utf8(&buf)
+                        .map(|s| Some(s.to_string()))
+                        .map_err(|e| VMError::new("read", e.to_string())),
+                    Err(e) => Err(VMError::new("io", e.to_string())),
+                };
+                if let SeqIter::Lines { buf: b, .. } = self.get_iter_mut(handle) {
+                    *b = buf;
+                }
+                match line? {
+                    Some(line) => self.alloc_string(line),
+                    None => empty,
+                }
+            }
+            SeqIter::Map { iter, lambda } => {
+                let (iter, lambda) = (*iter, *lambda);
+                let val = self.iter_next_value(iter)?;
+                if self.is_iter_empty(val) {
+                    empty
+                } else {
+                    return self.call_fn(lambda, &[val]);
+                }
+            }
+            SeqIter::Filter { iter, lambda } => {
+                let (iter, lambda) = (*iter, *lambda);
+                loop {
+                    let val = self.iter_next_value(iter)?;
+                    if self.is_iter_empty(val) {
+                        break empty;
+                    }
+                    if self.call_pred(lambda, val)? {
+                        return Ok(val);
+                    }
+                }
+            }
+            SeqIter::Take { iter, remaining } => {
+                let (iter, remaining) = (*iter, *remaining);
+                if remaining == 0 {
+                    empty
+                } else {
+                    if let SeqIter::Take { remaining, .. } = self.get_iter_mut(handle) {
+                        *remaining -= 1;
+                    }
+                    let val = self.iter_next_value(iter)?;
+                    if remaining > 1 && !self.is_iter_empty(val) {
+                        return Ok(val);
+                    }
+                    // Done, release the source.
+                    *self.get_iter_mut(handle) = SeqIter::Empty;
+                    return Ok(val);
+                }
+            }
+            SeqIter::Drop { iter, skip } => {
+                let (iter, skip) = (*iter, *skip);
+                if skip > 0 {
+                    if let SeqIter::Drop { skip, .. } = self.get_iter_mut(handle) {
+                        *skip = 0;
+                    }
+                    for _ in 0..skip {
+                        let val = self.iter_next_value(iter)?;
+                        if self.is_iter_empty(val) {
+                            break;
+                        }
+                    }
+                }
+                return self.iter_next_value(iter);
+            }
+            SeqIter::TakeWhile { iter, lambda } => {
+                let (iter, lambda) = (*iter, *lambda);
+                let val = self.iter_next_value(iter)?;
+                if !self.is_iter_empty(val) && self.call_pred(lambda, val)? {
+                    return Ok(val);
+                }
+                empty
+            }
+            SeqIter::DropWhile {
+                iter,
+                lambda,
+                dropping,
+            } => {
+                let (iter, lambda, dropping) = (*iter, *lambda, *dropping);
+                let mut val = self.iter_next_value(iter)?;
+                if dropping {
+                    while !self.is_iter_empty(val) && self.call_pred(lambda, val)? {
+                        val = self.iter_next_value(iter)?;
+                    }
+                    if let SeqIter::DropWhile { dropping, .. } = self.get_iter_mut(handle) {
+                        *dropping = false;
+                    }
+                }
+                return Ok(val);
+            }
+            SeqIter::Enumerate { iter, idx } => {
+                let (iter, idx) = (*iter, *idx);
+                let val = self.iter_next_value(iter)?;
+                if self.is_iter_empty(val) {
+                    empty
+                } else {
+                    if let SeqIter::Enumerate { idx, .. } = self.get_iter_mut(handle) {
+                        *idx += 1;
+                    }
+                    // val is not rooted.
+                    self.pause_gc();
+                    let pair = self.alloc_pair(idx.into(), val);
+                    self.unpause_gc();
+                    return Ok(pair);
+                }
+            }
+            SeqIter::Zip { iters, .. } => {
+                let num = iters.len();
+                if let SeqIter::Zip { buf, .. } = self.get_iter_mut(handle) {
+                    // In case an error left a partial set of elements.
+                    buf.clear();
+                }
+                for i in 0..num {
+                    let iter = if let SeqIter::Zip { iters, .. } = self.get_iter(handle) {
+                        iters[i]
+                    } else {
+                        break;
+                    };
+                    let val = self.iter_next_value(iter)?;
+                    if self.is_iter_empty(val) {
+                        break;
+                    }
+                    if let SeqIter::Zip { buf, .. } = self.get_iter_mut(handle) {
+                        buf.push(val);
+                    }
+                }
+                let vals = if let SeqIter::Zip { buf, .. } = self.get_iter(handle) {
+                    buf.clone()
+                } else {
+                    Vec::new()
+                };
+                if num > 0 && vals.len() == num {
+                    let res = self.alloc_vector(vals);
+                    if let SeqIter::Zip { buf, .. } = self.get_iter_mut(handle) {
+                        buf.clear();
+                    }
+                    res
+                } else {
+                    *self.get_iter_mut(handle) = SeqIter::Empty;
+                    empty
+                }
+            }
+            SeqIter::Chain { idx, .. } => {
+                let mut idx = *idx;
+                loop {
+                    let iter = match self.get_iter(handle) {
+                        SeqIter::Chain { iters, .. } if idx < iters.len() => iters[idx],
+                        _ => break empty,
+                    };
+                    let val = self.iter_next_value(iter)?;
+                    if !self.is_iter_empty(val) {
+                        break val;
+                    }
+                    idx += 1;
+                    if let SeqIter::Chain { idx: i, .. } = self.get_iter_mut(handle) {
+                        *i = idx;
+                    }
+                }
+            }
+            SeqIter::Interleave { iters, idx } => {
+                let (iter, idx, num) = (iters[*idx], *idx, iters.len());
+                let val = self.iter_next_value(iter)?;
+                if self.is_iter_empty(val) {
+                    empty
+                } else {
+                    if let SeqIter::Interleave { idx: i, .. } = self.get_iter_mut(handle) {
+                        *i = (idx + 1) % num;
+                    }
+                    val
+                }
+            }
+            SeqIter::FlatMap { iter, lambda, .. } => {
+                let (iter, lambda) = (*iter, *lambda);
+                loop {
+                    let inner = if let SeqIter::FlatMap { inner, .. } = self.get_iter(handle) {
+                        *inner
+                    } else {
+                        break empty;
+                    };
+                    if inner != Value::Undefined {
+                        let val = self.iter_next_value(inner)?;
+                        if !self.is_iter_empty(val) {
+                            break val;
+                        }
+                    }
+                    let val = self.iter_next_value(iter)?;
+                    if self.is_iter_empty(val) {
+                        break empty;
+                    }
+                    let items = self.call_fn(lambda, &[val])?;
+                    // items is not rooted until it is saved.
+                    self.pause_gc();
+                    let inner = self.make_iter(items);
+                    self.unpause_gc();
+                    let inner = inner?;
+                    if let SeqIter::FlatMap { inner: i, .. } = self.get_iter_mut(handle) {
+                        *i = inner;
+                    }
+                }
+            }
+            SeqIter::Partition { iter, size, .. } => {
+                let (iter, size) = (*iter, *size);
+                for _ in 0..size {
+                    let val = self.iter_next_value(iter)?;
+                    if self.is_iter_empty(val) {
+                        break;
+                    }
+                    if let SeqIter::Partition { buf, .. } = self.get_iter_mut(handle) {
+                        buf.push(val);
+                    }
+                }
+                let vals = if let SeqIter::Partition { buf, .. } = self.get_iter(handle) {
+                    buf.clone()
+                } else {
+                    Vec::new()
+                };
+                if vals.is_empty() {
+                    *self.get_iter_mut(handle) = SeqIter::Empty;
+                    empty
+                } else {
+                    let res = self.alloc_vector(vals);
+                    if let SeqIter::Partition { buf, .. } = self.get_iter_mut(handle) {
+                        buf.clear();
+                    }
+                    res
+                }
+            }
+            SeqIter::Windowed { iter, size, window } => {
+                let (iter, size) = (*iter, *size);
+                // The first window needs size elements, after that one more per window.
+                let needed = if window.len() < size {
+                    size - window.len()
+                } else {
+                    1
+                };
+                for _ in 0..needed {
+                    let val = self.iter_next_value(iter)?;
+                    if self.is_iter_empty(val) {
+                        *self.get_iter_mut(handle) = SeqIter::Empty;
+                        return Ok(empty);
+                    }
+                    if let SeqIter::Windowed { window, .. } = self.get_iter_mut(handle) {
+                        if window.len() == size {
+                            window.pop_front();
+                        }
+                        window.push_back(val);
+                    }
+                }
+                let vals: Vec<Value> =
+                    if let SeqIter::Windowed { window, .. } = self.get_iter(handle) {
+                        window.iter().copied().collect()
+                    } else {
+                        Vec::new()
+                    };
+                self.alloc_vector(vals)
+            }
+            SeqIter::Dedupe { iter, last } => {
+                let (iter, mut last) = (*iter, *last);
+                loop {
+                    let val = self.iter_next_value(iter)?;
+                    if self.is_iter_empty(val) {
+                        *self.get_iter_mut(handle) = SeqIter::Empty;
+                        break empty;
+                    }
+                    if last == Value::Undefined || self.is_equal_pair(last, val)? == Value::False {
+                        if let SeqIter::Dedupe { last, .. } = self.get_iter_mut(handle) {
+                            *last = val;
+                        }
+                        break val;
+                    }
+                    last = val;
+                }
+            }
+            SeqIter::Cycle { iter, .. } => {
+                let iter = *iter;
+                if iter != Value::Undefined {
+                    let val = self.iter_next_value(iter)?;
+                    let done = self.is_iter_empty(val);
+                    if let SeqIter::Cycle { iter, seen, .. } = self.get_iter_mut(handle) {
+                        if done {
+                            *iter = Value::Undefined;
+                        } else {
+                            seen.push(val);
+                            return Ok(val);
+                        }
+                    }
+                }
+                if let SeqIter::Cycle { seen, idx, .. } = self.get_iter_mut(handle) {
+                    if seen.is_empty() {
+                        empty
+                    } else {
+                        let val = seen[*idx];
+                        *idx = (*idx + 1) % seen.len();
+                        val
+                    }
+                } else {
+                    empty
+                }
+            }
+        };
+        Ok(res)
+    }
+}
@@ -1,7 +1,11 @@
 //! Vm code to access storage, heap, stack, globals, etc.
 
+use crate::heap::seq_iter::SeqIter;
 use crate::heap::Error;
-use crate::{CallFrame, Chunk, Continuation, Handle, Heap, Interned, MutState, VMResult, Value};
+use crate::{
+    CallFrame, Chunk, Continuation, Coroutine, GcStats, Handle, Heap, Interned, MutState,
+    PoolStats, VMResult, Value,
+};
 use std::sync::Arc;
 
 use crate::io::HeapIo;
@@ -215,6 +219,22 @@ impl<ENV> GVm<ENV> {
         res
     }
 
+    pub fn alloc_coroutine(&mut self, co: Coroutine) -> Value {
+        let mut heap = self.heap.take().expect("VM must have a Heap!");
+        // alloc must not save mark_roots (it does not) since we broke heap away from self.
+        let res = heap.alloc_coroutine(co, |heap| self.mark_roots(heap));
+        self.heap = Some(heap);
+        res
+    }
+
+    pub fn alloc_iter(&mut self, iter: SeqIter) -> Value {
+        let mut heap = self.heap.take().expect("VM must have a Heap!");
+        // alloc must not save mark_roots (it does not) since we broke heap away from self.
+        let res = heap.alloc_iter(iter, |heap| self.mark_roots(heap));
+        self.heap = Some(heap);
+        res
+    }
+
     pub fn alloc_callframe(&mut self, frame: CallFrame) -> Value {
         let mut heap = self.heap.take().expect("VM must have a Heap!");
         // alloc must not save mark_roots (it does not) since we broke heap away from self.
@@ -273,6 +293,23 @@ impl<ENV> GVm<ENV> {
         self.heap_mut().unpause_gc();
     }
 
+    /// Run a garbage collection now, major for a full collection otherwise minor (young objects
+    /// only).
+    pub fn gc_collect(&mut self, major: bool) {
+        let mut heap = self.heap.take().expect("VM must have a Heap!");
+        heap.force_gc(|heap| self.mark_roots(heap), major);
+        self.heap = Some(heap);
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap().gc_stats()
+    }
+
+    /// Live, old and capacity counts for each heap storage pool.
+    pub fn gc_pool_stats(&self) -> Vec<(&'static str, PoolStats)> {
+        self.heap().pool_stats()
+    }
+
     pub fn get_heap_property(&self, key_val: Value, prop: &str) -> Option<Value> {
         if let Some(interned) = self.get_if_interned(prop) {
             self.heap().get_property(key_val, interned)
@@ -358,6 +395,22 @@ impl<ENV> GVm<ENV> {
         self.heap().get_continuation(handle)
     }
 
+    pub fn get_coroutine(&self, handle: Handle) -> &Coroutine {
+        self.heap().get_coroutine(handle)
+    }
+
+    pub fn get_coroutine_mut(&mut self, handle: Handle) -> &mut Coroutine {
+        self.heap_mut().get_coroutine_mut(handle)
+    }
+
+    pub fn get_iter(&self, handle: Handle) -> &SeqIter {
+        self.heap().get_iter(handle)
+    }
+
+    pub fn get_iter_mut(&mut self, handle: Handle) -> &mut SeqIter {
+        self.heap_mut().get_iter_mut(handle)
+    }
+
     pub fn get_callframe(&self, handle: Handle) -> &CallFrame {
         self.heap().get_callframe(handle)
     }
@@ -424,6 +477,7 @@ impl<ENV> GVm<ENV> {
         if let Some(on_error) = self.on_error {
             heap.mark(on_error);
         }
+        heap.mark(self.restarts);
         // TODO: XXX do we need this?  Probably but maybe not.
         if let Some(err_frame) = &self.err_frame {
             heap.mark_call_frame(err_frame);
@@ -431,6 +485,7 @@ impl<ENV> GVm<ENV> {
         for defer in &self.defers {
             heap.mark(*defer);
         }
+        self.mark_co_callers(heap);
         Ok(())
     }
 }